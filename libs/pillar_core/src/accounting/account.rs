@@ -2,7 +2,7 @@
 use pillar_crypto::types::StdByteArray;
 use serde::{Deserialize, Serialize};
 
-use crate::reputation::history::NodeHistory;
+use crate::{primitives::transaction::Transaction, reputation::history::NodeHistory};
 
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -21,6 +21,8 @@ pub struct Account{
     pub balance: u64,
     // The nonce of the account, to prevent replay attacks
     pub nonce: u64,
+    // The public key that signs for the account, if it has been rotated away from the address
+    pub authorized_key: Option<StdByteArray>,
     // a tracking of blocks/transactions that lead to this balance
     pub history: Option<NodeHistory>
 }
@@ -34,7 +36,84 @@ impl Account{
             address,
             balance,
             nonce: 0,
+            authorized_key: None,
             history: None,
         }
     }
-}
\ No newline at end of file
+
+    /// The public key that transactions from this account must be signed with
+    pub fn verifying_key(&self) -> StdByteArray {
+        self.authorized_key.unwrap_or(self.address)
+    }
+}
+
+/// A change to apply to a single account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccountDelta {
+    // amount added to the balance
+    pub credit: u64,
+    // amount removed from the balance
+    pub debit: u64,
+    // how many times the nonce advances
+    pub nonce: u64,
+}
+
+impl AccountDelta {
+    /// Applies the change to an account, failing on overdraft or overflow
+    pub fn apply(&self, account: &mut Account) -> Result<(), std::io::Error> {
+        let overflow = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "Account update overflows");
+        account.balance = account.balance
+            .checked_add(self.credit).ok_or_else(overflow)?
+            .checked_sub(self.debit).ok_or_else(
+                || std::io::Error::new(std::io::ErrorKind::InvalidInput, "Insufficient balance for account update")
+            )?;
+        account.nonce = account.nonce.checked_add(self.nonce).ok_or_else(overflow)?;
+        Ok(())
+    }
+}
+
+/// What `account` can spend right now, given transactions still waiting in the pool.
+/// Only pending transactions sent by the account, at or past its nonce, count - pending credits are not spendable until confirmed.
+///
+/// # Returns
+///
+/// * `(available_balance, next_nonce)` - the confirmed balance less pending debits, and the nonce the next transaction must use
+pub fn spendable_state(account: &Account, pending: &[Transaction]) -> (u64, u64) {
+    let (debits, count) = pending.iter()
+        .filter(|transaction| transaction.header.sender == account.address && transaction.header.nonce >= account.nonce)
+        .fold((0u64, 0u64), |(debits, count), transaction| (debits.saturating_add(transaction.header.amount), count + 1));
+    (account.balance.saturating_sub(debits), account.nonce.saturating_add(count))
+}
+
+#[cfg(test)]
+mod tests {
+    use pillar_crypto::hashing::DefaultHash;
+
+    use crate::primitives::transaction::Transaction;
+
+    use super::{spendable_state, Account};
+
+    #[test]
+    fn test_spendable_state() {
+        let mut account = Account::new([1; 32], 100);
+        account.nonce = 3;
+        let transaction = |sender, amount, nonce| Transaction::new(sender, [2; 32], amount, 0, nonce, &mut DefaultHash::new());
+
+        assert_eq!(spendable_state(&account, &[]), (100, 3));
+        let mut pending = vec![];
+        for (i, amount) in [10, 25, 5].into_iter().enumerate() {
+            pending.push(transaction([1; 32], amount, 3 + i as u64));
+        }
+        assert_eq!(spendable_state(&account, &pending[..1]), (90, 4));
+        assert_eq!(spendable_state(&account, &pending), (60, 6));
+
+        // other senders, and stale nonces already confirmed, do not count
+        pending.push(transaction([3; 32], 50, 0));
+        pending.push(transaction([1; 32], 50, 2));
+        assert_eq!(spendable_state(&account, &pending), (60, 6));
+
+        // pending debits past the balance leave nothing to spend
+        pending.push(transaction([1; 32], 80, 6));
+        assert_eq!(spendable_state(&account, &pending), (0, 7));
+    }
+}
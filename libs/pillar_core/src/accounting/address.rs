@@ -0,0 +1,218 @@
+use std::fmt::Display;
+
+use pillar_crypto::types::{StdByteArray, STANDARD_ARRAY_LENGTH};
+
+/// The human readable prefix of encoded addresses
+pub const ADDRESS_PREFIX: &str = "pillar";
+
+/// The bech32 alphabet - one character per 5 bits
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+/// The bech32 checksum generator
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+/// The bech32m checksum constant
+const CHECKSUM_CONSTANT: u32 = 0x2bc830a3;
+/// characters in the checksum
+const CHECKSUM_LENGTH: usize = 6;
+/// The length of an encoded address with the default prefix
+pub const ENCODED_ADDRESS_LENGTH: usize = ADDRESS_PREFIX.len() + 1 + (STANDARD_ARRAY_LENGTH * 8).div_ceil(5) + CHECKSUM_LENGTH;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressError {
+    /// There is no separator between the prefix and the data
+    MissingSeparator,
+    /// The prefix is not the one expected
+    WrongPrefix(String),
+    /// The address has upper and lower case characters
+    MixedCase,
+    /// The character is not in the alphabet
+    InvalidCharacter(char),
+    /// The data does not decode to an address - the number of characters after the separator
+    InvalidLength(usize),
+    /// The checksum does not match - most likely a typo
+    InvalidChecksum,
+}
+
+impl Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressError::MissingSeparator => write!(f, "Address has no separator"),
+            AddressError::WrongPrefix(prefix) => write!(f, "Address has the wrong prefix: {prefix}"),
+            AddressError::MixedCase => write!(f, "Address mixes upper and lower case"),
+            AddressError::InvalidCharacter(c) => write!(f, "Address has an invalid character: {c}"),
+            AddressError::InvalidLength(length) => write!(f, "Address has an invalid length: {length}"),
+            AddressError::InvalidChecksum => write!(f, "Address checksum does not match"),
+        }
+    }
+}
+
+/// A way to write addresses for people to read and type
+pub trait AddressEncoding {
+    fn encode(&self, address: &StdByteArray) -> String;
+    fn decode(&self, encoded: &str) -> Result<StdByteArray, AddressError>;
+}
+
+/// Bech32m - a prefix, a separator, and the data with a checksum that catches any single typo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bech32Encoding {
+    pub prefix: &'static str,
+}
+
+impl Default for Bech32Encoding {
+    fn default() -> Self {
+        Bech32Encoding { prefix: ADDRESS_PREFIX }
+    }
+}
+
+impl AddressEncoding for Bech32Encoding {
+    fn encode(&self, address: &StdByteArray) -> String {
+        let data = convert_bits(address, 8, 5, true).unwrap();
+        let checksum = create_checksum(self.prefix, &data);
+        let characters = data.iter().chain(checksum.iter()).map(|&value| CHARSET[value as usize] as char);
+        format!("{}1{}", self.prefix, characters.collect::<String>())
+    }
+
+    fn decode(&self, encoded: &str) -> Result<StdByteArray, AddressError> {
+        if encoded.chars().any(|c| c.is_ascii_lowercase()) && encoded.chars().any(|c| c.is_ascii_uppercase()) {
+            return Err(AddressError::MixedCase);
+        }
+        let encoded = encoded.to_ascii_lowercase();
+        let (prefix, data) = encoded.rsplit_once('1').ok_or(AddressError::MissingSeparator)?;
+        if prefix != self.prefix {
+            return Err(AddressError::WrongPrefix(prefix.to_string()));
+        }
+        let data = data.chars().map(|c| {
+            CHARSET.iter().position(|&x| x as char == c).map(|value| value as u8).ok_or(AddressError::InvalidCharacter(c))
+        }).collect::<Result<Vec<u8>, AddressError>>()?;
+        if data.len() < CHECKSUM_LENGTH {
+            return Err(AddressError::InvalidLength(data.len()));
+        }
+        if polymod(expand_prefix(prefix).into_iter().chain(data.iter().copied())) != CHECKSUM_CONSTANT {
+            return Err(AddressError::InvalidChecksum);
+        }
+        convert_bits(&data[..data.len() - CHECKSUM_LENGTH], 5, 8, false)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(AddressError::InvalidLength(data.len()))
+    }
+}
+
+/// Encode an address with the default encoding
+pub fn encode_address(address: &StdByteArray) -> String {
+    Bech32Encoding::default().encode(address)
+}
+
+/// Decode an address written with the default encoding
+pub fn decode_address(encoded: &str) -> Result<StdByteArray, AddressError> {
+    Bech32Encoding::default().decode(encoded)
+}
+
+fn polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    let mut checksum: u32 = 1;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+/// The prefix as checksum input - high bits, a zero, then low bits
+fn expand_prefix(prefix: &str) -> Vec<u8> {
+    prefix.bytes().map(|b| b >> 5)
+        .chain([0])
+        .chain(prefix.bytes().map(|b| b & 31))
+        .collect()
+}
+
+fn create_checksum(prefix: &str, data: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    let values = expand_prefix(prefix).into_iter()
+        .chain(data.iter().copied())
+        .chain([0; CHECKSUM_LENGTH]);
+    let checksum = polymod(values) ^ CHECKSUM_CONSTANT;
+    std::array::from_fn(|i| ((checksum >> (5 * (CHECKSUM_LENGTH - 1 - i))) & 31) as u8)
+}
+
+/// Regroup `data` from `from` bit values into `to` bit values.
+/// Without padding, leftover bits must be zero and fewer than `from`.
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let (mut accumulator, mut bits) = (0u32, 0u32);
+    let max_value = (1 << to) - 1;
+    let mut result = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    for &value in data {
+        accumulator = ((accumulator << from) | value as u32) & ((1 << (from + to - 1)) - 1);
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((accumulator >> bits) & max_value) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((accumulator << (to - bits)) & max_value) as u8);
+        }
+    } else if bits >= from || ((accumulator << (to - bits)) & max_value) != 0 {
+        return None;
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction};
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for _ in 0..16 {
+            let address = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+            let encoded = encode_address(&address);
+            assert!(encoded.starts_with("pillar1"));
+            assert_eq!(encoded.len(), ENCODED_ADDRESS_LENGTH);
+            assert_eq!(decode_address(&encoded), Ok(address));
+            // case does not matter, as long as it is consistent
+            assert_eq!(decode_address(&encoded.to_ascii_uppercase()), Ok(address));
+        }
+        assert_eq!(decode_address(&encode_address(&[0; 32])), Ok([0; 32]));
+    }
+
+    #[test]
+    fn test_single_typo_fails_checksum() {
+        let encoded = encode_address(&[7; 32]);
+        let data_start = ADDRESS_PREFIX.len() + 1;
+        for i in data_start..encoded.len() {
+            for &replacement in CHARSET {
+                let mut typo = encoded.clone().into_bytes();
+                if typo[i] == replacement {
+                    continue;
+                }
+                typo[i] = replacement;
+                let typo = String::from_utf8(typo).unwrap();
+                assert_eq!(decode_address(&typo), Err(AddressError::InvalidChecksum), "{typo}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_addresses() {
+        let encoded = encode_address(&[7; 32]);
+        let other_prefix = Bech32Encoding { prefix: "other" };
+        assert_eq!(other_prefix.decode(&encoded), Err(AddressError::WrongPrefix("pillar".into())));
+        assert_eq!(other_prefix.decode(&other_prefix.encode(&[7; 32])), Ok([7; 32]));
+        assert_eq!(decode_address("pillar"), Err(AddressError::MissingSeparator));
+        let data_start = ADDRESS_PREFIX.len() + 1;
+        let mut invalid = encoded.clone();
+        invalid.replace_range(data_start..data_start + 1, "b");
+        assert_eq!(decode_address(&invalid), Err(AddressError::InvalidCharacter('b')));
+        let mut mixed = encoded.clone();
+        mixed.replace_range(..1, "P");
+        assert_eq!(decode_address(&mixed), Err(AddressError::MixedCase));
+        assert_eq!(decode_address("pillar1qqqq"), Err(AddressError::InvalidLength(4)));
+        // a valid checksum over too little data
+        let short = format!("pillar1{}", create_checksum("pillar", &[]).iter().map(|&v| CHARSET[v as usize] as char).collect::<String>());
+        assert_eq!(decode_address(&short), Err(AddressError::InvalidLength(6)));
+    }
+}
@@ -1,3 +1,4 @@
 pub mod account;
 pub mod wallet;
-pub mod state;
\ No newline at end of file
+pub mod state;
+pub mod address;
\ No newline at end of file
@@ -1,17 +1,56 @@
-use std::{collections::HashMap, fmt::Debug, sync::{Arc, Mutex}};
+use std::{borrow::Borrow, collections::HashMap, fmt::Debug, sync::{Arc, Mutex}};
 
-use pillar_crypto::{merkle_trie::MerkleTrie, types::StdByteArray};
+use pillar_crypto::{hashing::{DefaultHash, HashFunction, Hashable}, merkle_trie::{MerkleTrie, NodeKey}, proofs::{generate_proof_of_state, trie_path, verify_trie_range, TrieMerkleProof}, serialization::PillarSerialize, types::StdByteArray};
+use serde::{Deserialize, Serialize};
 
-use crate::{accounting::account::Account, primitives::block::{Block, BlockHeader}, protocol::{difficulty::get_reward_from_depth_and_stampers, pow::{get_difficulty_for_block, POR_INCLUSION_MINIMUM, POR_MINER_SHARE_DIVISOR}, reputation::get_current_reputations_for_stampers_from_state}, reputation::history::NodeHistory};
+use crate::{accounting::account::{Account, AccountDelta}, primitives::{block::{Block, BlockHeader}, errors::BlockValidationError}, protocol::{difficulty::get_reward_from_depth_and_stampers, pow::{is_por_enabled, POR_INCLUSION_MINIMUM, POR_MINER_SHARE_DIVISOR}, reputation::get_current_reputations_for_stampers_from_state, reward::{MinerRewardPolicy, RewardPolicy}}, reputation::history::NodeHistory};
 
 pub type ReputationMap = HashMap<StdByteArray, NodeHistory>;
 
-#[derive(Clone, Default)]
+/// The most accounts served in one state range response, by default
+pub const MAX_ACCOUNTS_PER_RANGE: usize = 256;
+
+#[derive(Clone)]
 pub struct StateManager{
     // The mapping from address to account
     pub state_trie: Arc<Mutex<MerkleTrie<StdByteArray, Account>>>,
     /// mapping of reputations for peers
     pub reputations: Arc<Mutex<ReputationMap>>,
+    /// how the miner's share of each block reward is paid out
+    pub reward_policy: Arc<dyn RewardPolicy>,
+    /// the size of the state under each known root, kept up to date as branches are added and removed
+    state_sizes: Arc<Mutex<HashMap<StdByteArray, StateSizeStats>>>,
+    /// the sum of every balance under each known root, kept up to date like the sizes
+    supplies: Arc<Mutex<HashMap<StdByteArray, u64>>>,
+}
+
+/// The size of the account state under one state root
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StateSizeStats {
+    /// The number of accounts
+    pub accounts: u64,
+    /// The serialized size of the accounts, in bytes. Trie nodes are not counted
+    pub bytes: u64,
+}
+
+impl StateSizeStats {
+    /// Measures a full list of accounts
+    pub fn of(accounts: &[Account]) -> Self {
+        StateSizeStats {
+            accounts: accounts.len() as u64,
+            bytes: accounts.iter().map(serialized_size).sum(),
+        }
+    }
+}
+
+fn serialized_size(account: &Account) -> u64 {
+    bincode::serialized_size(account).unwrap_or(0)
+}
+
+impl Default for StateManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Debug for StateManager {
@@ -21,6 +60,217 @@ impl Debug for StateManager {
     }
 }
 
+/// The accounts of a state range, as served by `StateManager::account_range`, each with its proof.
+/// Ranges are over trie paths - the hash of each address - as only in that order can a peer check that nothing was left out
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct AccountRange {
+    /// the accounts in path order. The account just before the range and the one just after it
+    /// are included where they exist, to show where the range ends
+    pub accounts: Vec<(TrieMerkleProof, Account)>,
+    /// the path to continue from, if the response was cut short
+    pub next: Option<StdByteArray>,
+}
+
+impl AccountRange {
+    /// The accounts with paths from `start` to `end` inclusive, leaving out the neighbours
+    pub fn within(&self, start: StdByteArray, end: StdByteArray) -> impl Iterator<Item = &Account> {
+        self.accounts.iter().map(|(_, account)| account).filter(move |account| (start..=end).contains(&trie_path(&account.address)))
+    }
+}
+
+/// A full export of the account state under a single state root.
+/// Accounts are kept in canonical order (ascending by address) so that
+/// two nodes holding the same state produce byte-identical snapshots.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StateSnapshot {
+    /// The state root the snapshot was taken at
+    pub state_root: StdByteArray,
+    /// Every account under the root, sorted by address
+    pub accounts: Vec<Account>,
+    /// Hash over the state root and the ordered, serialized accounts
+    pub manifest_hash: StdByteArray,
+}
+
+impl StateSnapshot {
+    /// Builds a snapshot from an arbitrary ordering of accounts
+    ///
+    /// # Arguments
+    ///
+    /// * `state_root` - The state root the accounts belong to
+    /// * `accounts` - The accounts, in any order
+    /// * `hasher` - The hash function used for the manifest
+    pub fn new(state_root: StdByteArray, mut accounts: Vec<Account>, hasher: &mut impl HashFunction) -> Result<Self, std::io::Error> {
+        accounts.sort_by_key(|account| account.address);
+        let mut snapshot = StateSnapshot {
+            state_root,
+            accounts,
+            manifest_hash: [0; 32],
+        };
+        snapshot.manifest_hash = snapshot.hash(hasher)?;
+        Ok(snapshot)
+    }
+
+    /// The manifest hash of a snapshot of `accounts` under `state_root`, without building the snapshot.
+    /// The accounts must already be in canonical order, such as from `StateManager::accounts_iter`,
+    /// so an export can hash accounts as it streams them
+    pub fn manifest_hash(state_root: StdByteArray, accounts: impl IntoIterator<Item = impl Borrow<Account>>, hasher: &mut impl HashFunction) -> Result<StdByteArray, std::io::Error> {
+        hasher.update(state_root);
+        for account in accounts {
+            hasher.update(bincode::serialize(account.borrow()).map_err(std::io::Error::other)?);
+        }
+        hasher.digest()
+    }
+
+    /// Checks that the accounts are in canonical order and that the manifest hash matches
+    pub fn verify(&self, hasher: &mut impl HashFunction) -> bool {
+        let ordered = self.accounts.windows(2).all(|pair| pair[0].address < pair[1].address);
+        ordered && self.hash(hasher).is_ok_and(|hash| hash == self.manifest_hash)
+    }
+}
+
+impl Hashable for StateSnapshot {
+    fn hash(&self, hasher: &mut impl HashFunction) -> Result<StdByteArray, std::io::Error> {
+        StateSnapshot::manifest_hash(self.state_root, &self.accounts, hasher)
+    }
+}
+
+impl PillarSerialize for StateSnapshot {}
+
+/// Streams the accounts under one state root in ascending address order. See `StateManager::accounts_iter`
+pub struct AccountsIter {
+    state_trie: Arc<Mutex<MerkleTrie<StdByteArray, Account>>>,
+    // the trie node holding each account, by address - accounts are only read as they are yielded
+    index: std::vec::IntoIter<(StdByteArray, NodeKey)>,
+}
+
+impl Iterator for AccountsIter {
+    type Item = Account;
+
+    fn next(&mut self) -> Option<Account> {
+        for (_, node) in self.index.by_ref() {
+            // the lock is taken per account, so a slow consumer does not hold up the chain
+            // an account whose branch was trimmed since the index was built is skipped
+            if let Some(account) = self.state_trie.lock().expect("Failed to lock state trie").get_at(node) {
+                return Some(account);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.index.len()))
+    }
+}
+
+/// An account that is not the same in two states
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountDiff {
+    pub address: StdByteArray,
+    /// The account in the first state, if it exists there
+    pub before: Option<Account>,
+    /// The account in the second state, if it exists there
+    pub after: Option<Account>,
+}
+
+impl AccountDiff {
+    /// The (before, after) balances, treating a missing account as empty
+    pub fn balances(&self) -> (u64, u64) {
+        (self.before.as_ref().map_or(0, |a| a.balance), self.after.as_ref().map_or(0, |a| a.balance))
+    }
+
+    /// The (before, after) nonces, treating a missing account as new
+    pub fn nonces(&self) -> (u64, u64) {
+        (self.before.as_ref().map_or(0, |a| a.nonce), self.after.as_ref().map_or(0, |a| a.nonce))
+    }
+}
+
+/// Verifies that `account` is in the state under `state_root`.
+/// The root can be any root the caller trusts - a light client can hold on to an old one,
+/// and check proofs against it long after the chain has moved on.
+/// The account holds its address, so the proof fixes the whole account, not just a balance
+pub fn verify_account_proof(account: &Account, proof: &TrieMerkleProof, state_root: StdByteArray) -> bool {
+    match bincode::serialize(account) {
+        Ok(value) => proof.verify(value, state_root, &mut DefaultHash::new()),
+        Err(_) => false,
+    }
+}
+
+/// Rebuilds the state trie from `accounts` and checks that its root is `claimed_root`.
+/// The accounts may come in any order, but each address only once - and there must be at least one,
+/// as there is no root over an empty state
+pub fn verify_state_root(accounts: &[Account], claimed_root: StdByteArray) -> bool {
+    let Some((first, rest)) = accounts.split_first() else {
+        return false;
+    };
+    let updates: HashMap<StdByteArray, Account> = rest.iter().map(|account| (account.address, account.clone())).collect();
+    if updates.len() != rest.len() || updates.contains_key(&first.address) {
+        return false;
+    }
+    let mut trie = MerkleTrie::new();
+    let root = trie.create_genesis(first.address, first.clone()).and_then(|root| match updates.is_empty() {
+        true => Ok(root),
+        false => trie.branch(Some(root), updates),
+    });
+    root.is_ok_and(|root| root == claimed_root)
+}
+
+/// Verifies a state range, as served by `StateManager::account_range`.
+/// Every account must be proven under `state_root`, and together they must show that no account with a path
+/// from `start` to `end` inclusive was left out. A response cut short only covers up to its last account in the range,
+/// and must continue just after it
+pub fn verify_account_range(range: &AccountRange, start: StdByteArray, end: StdByteArray, state_root: StdByteArray) -> bool {
+    if !range.accounts.iter().all(|(proof, account)| verify_account_proof(account, proof, state_root)) {
+        return false;
+    }
+    let keys = range.accounts.iter().map(|(proof, account)| (trie_path(&account.address), proof)).collect::<Vec<_>>();
+    let end = match range.next {
+        None => end,
+        Some(next) => {
+            let Some(last) = keys.iter().map(|(path, _)| *path).rfind(|path| (start..=end).contains(path)) else {
+                return false;
+            };
+            if next_path(last) != Some(next) {
+                return false;
+            }
+            last
+        },
+    };
+    verify_trie_range(&keys, start, end)
+}
+
+/// The path right after `path`, if there is one
+fn next_path(mut path: StdByteArray) -> Option<StdByteArray> {
+    let last = path.iter().rposition(|b| *b != 0xff)?;
+    path[last] += 1;
+    path[last + 1..].fill(0);
+    Some(path)
+}
+
+/// Lists every account that differs between two snapshots, in address order.
+/// Accounts that exist in only one of the snapshots are included.
+pub fn diff_states(a: &StateSnapshot, b: &StateSnapshot) -> Vec<AccountDiff> {
+    let mut diffs = vec![];
+    let (mut before, mut after) = (a.accounts.iter().peekable(), b.accounts.iter().peekable());
+    // both account lists are sorted by address, so walk them together
+    loop {
+        let (old, new) = match (before.peek(), after.peek()) {
+            (None, None) => break,
+            (Some(old), Some(new)) if old.address == new.address => (before.next(), after.next()),
+            (Some(old), Some(new)) if old.address < new.address => (before.next(), None),
+            (Some(_), None) => (before.next(), None),
+            _ => (None, after.next()),
+        };
+        if old != new {
+            diffs.push(AccountDiff {
+                address: old.or(new).unwrap().address,
+                before: old.cloned(),
+                after: new.cloned(),
+            });
+        }
+    }
+    diffs
+}
+
 fn div_up(x: u64, y: u64) -> u64 {
     if y == 0 {
         panic!("Division by zero");
@@ -34,9 +284,67 @@ impl StateManager{
         StateManager {
             state_trie: Arc::new(Mutex::new(MerkleTrie::new())),
             reputations: Arc::new(Mutex::new(HashMap::new())),
+            reward_policy: Arc::new(MinerRewardPolicy),
+            state_sizes: Arc::new(Mutex::new(HashMap::new())),
+            supplies: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// The number of accounts and their serialized size under `root`.
+    /// Roots made through the state manager are tracked as they are branched - others are measured once.
+    pub fn state_size(&self, root: StdByteArray) -> StateSizeStats {
+        let state_trie = self.state_trie.lock().expect("Failed to lock state trie");
+        self.size_under(&state_trie, root)
+    }
+
+    fn size_under(&self, state_trie: &MerkleTrie<StdByteArray, Account>, root: StdByteArray) -> StateSizeStats {
+        *self.state_sizes.lock().expect("Failed to lock state sizes")
+            .entry(root)
+            .or_insert_with(|| StateSizeStats::of(&state_trie.get_all(root)))
+    }
+
+    /// The total supply under `root` - the sum of every balance.
+    /// Roots made through the state manager are tracked as they are branched - others are measured once.
+    pub fn total_supply(&self, root: StdByteArray) -> u64 {
+        let state_trie = self.state_trie.lock().expect("Failed to lock state trie");
+        self.supply_under(&state_trie, root)
+    }
+
+    fn supply_under(&self, state_trie: &MerkleTrie<StdByteArray, Account>, root: StdByteArray) -> u64 {
+        *self.supplies.lock().expect("Failed to lock supplies")
+            .entry(root)
+            .or_insert_with(|| state_trie.get_all(root).iter().fold(0u64, |supply, account| supply.saturating_add(account.balance)))
+    }
+
+    /// Branches the trie from `root` with `updates`, and records the size and supply of the new state
+    /// from those under `root` and the accounts that changed.
+    fn branch_tracked(
+        &self,
+        state_trie: &mut MerkleTrie<StdByteArray, Account>,
+        root: StdByteArray,
+        updates: HashMap<StdByteArray, Account>,
+    ) -> Result<StdByteArray, std::io::Error> {
+        let mut size = self.size_under(state_trie, root);
+        let mut supply = self.supply_under(state_trie, root);
+        for (address, account) in &updates {
+            match state_trie.get(address, root) {
+                Some(old) => {
+                    size.bytes = (size.bytes + serialized_size(account)).saturating_sub(serialized_size(&old));
+                    supply = supply.saturating_add(account.balance).saturating_sub(old.balance);
+                },
+                None => {
+                    size.accounts += 1;
+                    size.bytes += serialized_size(account);
+                    supply = supply.saturating_add(account.balance);
+                }
+            }
+        }
+        let new_root = state_trie.branch(Some(root), updates)?;
+        self.state_sizes.lock().expect("Failed to lock state sizes").insert(new_root, size);
+        self.supplies.lock().expect("Failed to lock supplies").insert(new_root, supply);
+        Ok(new_root)
+    }
+
     pub fn get_account(&self, address: &StdByteArray, state_root: StdByteArray) -> Option<Account> {
         let state_trie = self.state_trie.lock().expect("Failed to lock state trie");
         state_trie.get(address, state_root)
@@ -51,9 +359,94 @@ impl StateManager{
         self.state_trie.lock().unwrap().get_all(root)
     }
 
+    /// Streams the accounts under `root` in canonical (ascending address) order, for exports too large to collect.
+    /// Only an index of addresses is held - each account is read from the state as it is yielded.
+    /// An unknown root yields nothing
+    pub fn accounts_iter(&self, root: StdByteArray) -> AccountsIter {
+        let mut index: Vec<(StdByteArray, NodeKey)> = self.state_trie.lock().expect("Failed to lock state trie")
+            .iter(root)
+            .map(|(node, account)| (account.address, node))
+            .collect();
+        index.sort_unstable_by_key(|(address, _)| *address);
+        AccountsIter {
+            state_trie: self.state_trie.clone(),
+            index: index.into_iter(),
+        }
+    }
+
+    /// Exports the full account state under `root` as a canonical snapshot
+    pub fn snapshot(&self, root: StdByteArray, hasher: &mut impl HashFunction) -> Result<StateSnapshot, std::io::Error> {
+        StateSnapshot::new(root, self.get_all_accounts(root), hasher)
+    }
+
+    /// A proof that the account at `address` is in the state under `state_root`, and the account.
+    /// `None` if the root is unknown, or there is no such account
+    pub fn account_proof(&self, address: &StdByteArray, state_root: StdByteArray) -> Option<(TrieMerkleProof, Account)> {
+        let state_trie = self.state_trie.lock().expect("Failed to lock state trie");
+        generate_proof_of_state(&state_trie, *address, Some(state_root), &mut DefaultHash::new())
+    }
+
+    /// Every account with a path from `start` to `end` inclusive under `state_root`, in path order,
+    /// each with the proof that it is in the state, along with the accounts just outside the range.
+    /// At most `limit` accounts in the range are served - past that, the range says where to continue from.
+    /// Empty if the root is unknown
+    pub fn account_range(&self, start: StdByteArray, end: StdByteArray, state_root: StdByteArray, limit: usize) -> AccountRange {
+        let mut paths = self.state_trie.lock().expect("Failed to lock state trie")
+            .iter(state_root)
+            .map(|(_, account)| (trie_path(&account.address), account.address))
+            .collect::<Vec<_>>();
+        paths.sort_unstable();
+        let first = paths.partition_point(|(path, _)| *path < start);
+        let mut last = paths.partition_point(|(path, _)| *path <= end).max(first);
+        let mut next = None;
+        if last - first > limit.max(1) {
+            last = first + limit.max(1);
+            next = next_path(paths[last - 1].0);
+        }
+        // a range cut short ends at its last account, so it needs no neighbour after it
+        let to = if next.is_some() { last } else { (last + 1).min(paths.len()) };
+        let accounts = paths[first.saturating_sub(1)..to].iter()
+            .filter_map(|(_, address)| self.account_proof(address, state_root))
+            .collect();
+        AccountRange { accounts, next }
+    }
+
+    /// Applies a batch of account changes on top of `root` in a single pass.
+    /// Each account is read at most once, and all writes go into one new branch.
+    /// Multiple updates to the same address are applied in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The state root to apply the changes on
+    /// * `updates` - The address and change for every update
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(StdByteArray)` - the new state root. This is `root` if there are no updates
+    /// * `Err(std::io::Error)` - if the root is unknown, or an update overdraws or overflows
+    pub fn apply_updates(&mut self, root: StdByteArray, updates: &[(StdByteArray, AccountDelta)]) -> Result<StdByteArray, std::io::Error> {
+        if updates.is_empty() {
+            return Ok(root);
+        }
+        let mut state_trie = self.state_trie.lock().expect("Failed to lock state trie");
+        let mut accounts: HashMap<StdByteArray, Account> = HashMap::new();
+        for (address, delta) in updates {
+            let account = accounts.entry(*address).or_insert_with(
+                || state_trie.get(address, root).unwrap_or(Account::new(*address, 0))
+            );
+            delta.apply(account)?;
+        }
+        self.branch_tracked(&mut state_trie, root, accounts)
+    }
+
+    /// Releases a state branched from `root`. The state is only dropped once every branch to it is removed,
+    /// so blocks that reach the same state do not lose it when one of them goes
     pub fn remove_branch(&mut self, root: StdByteArray){
         let mut state_trie = self.state_trie.lock().expect("Failed to lock state trie");
-        state_trie.trim_branch(root).expect("Failed to remove branch from state trie");
+        if state_trie.trim_branch(root).expect("Failed to remove branch from state trie") {
+            self.state_sizes.lock().expect("Failed to lock state sizes").remove(&root);
+            self.supplies.lock().expect("Failed to lock supplies").remove(&root);
+        }
     }
 
     /// Updates the accounts from the block
@@ -61,15 +454,26 @@ impl StateManager{
     /// This does NOT verify the block - VERIFY THE BLOCK FIRST
     /// This is called when a new block is added to the chain
     pub fn branch_from_block(&mut self, block: &Block, prev_header: &BlockHeader) -> StdByteArray{
+        self.try_branch_from_block(block, prev_header).expect("Issue with branching state trie")
+    }
+
+    /// Updates the accounts from the block, like `branch_from_block`, failing instead of panicking
+    /// if the result breaks the supply invariant - no account may hold more than the supply before
+    /// the block plus the block reward. That can only happen through a consensus bug.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(StdByteArray)` - the new state root
+    /// * `Err(BlockValidationError::SupplyExceeded)` - if an account would hold more than the supply. Nothing is branched
+    pub fn try_branch_from_block(&mut self, block: &Block, prev_header: &BlockHeader) -> Result<StdByteArray, BlockValidationError>{
         // grab info on the stampers from the previous block
         let previous_reputations = get_current_reputations_for_stampers_from_state(
             self,
             prev_header,
             &block.header,
         );
-        let (_, por_enabled) = get_difficulty_for_block(
-            &block.header,
-            &previous_reputations.values().cloned().collect(),
+        let por_enabled = is_por_enabled(
+            &previous_reputations.values().cloned().collect::<Vec<f64>>(),
         );
         // Update the accounts from the block
         let mut state_updates: HashMap<StdByteArray, Account> = HashMap::new();
@@ -92,6 +496,13 @@ impl StateManager{
                     
                 }
             };
+            sender.balance -= transaction.header.amount;
+            sender.nonce += 1;
+            if let Some(key) = transaction.header.rotate_key {
+                sender.authorized_key = Some(key);
+            }
+            // insert the sender first, so a transaction to oneself sees the update
+            state_updates.insert(sender.address, sender);
             // may need to make a new public account for the receiver under the established public key
             let mut receiver = match state_updates.get(&transaction.header.receiver){
                 Some(account) => account.clone(),
@@ -99,11 +510,7 @@ impl StateManager{
                     state_trie.get(&transaction.header.receiver, state_root).unwrap_or(Account::new(transaction.header.receiver, 0))
                 },
             };
-            // update balances
-            sender.balance -= transaction.header.amount;
-            sender.nonce += 1;
             receiver.balance += transaction.header.amount;
-            state_updates.insert(sender.address, sender);
             state_updates.insert(receiver.address, receiver);
         }
         // add the miner reward. this reward will be based upon the blocks difficulty, and the number of stamps.
@@ -117,7 +524,19 @@ impl StateManager{
                 state_trie.get(&miner_address, state_root).unwrap_or(Account::new(miner_address, 0))
             }
         };
-        miner_account.balance += if !por_enabled {reward} else {div_up(reward, POR_MINER_SHARE_DIVISOR)};
+        let miner_share = if !por_enabled {reward} else {div_up(reward, POR_MINER_SHARE_DIVISOR)};
+        for (address, amount) in self.reward_policy.outputs(miner_address, block.header.depth, miner_share) {
+            if address == miner_address {
+                miner_account.balance += amount;
+                continue;
+            }
+            let mut account = match state_updates.get(&address) {
+                Some(account) => account.clone(),
+                None => state_trie.get(&address, state_root).unwrap_or(Account::new(address, 0)),
+            };
+            account.balance += amount;
+            state_updates.insert(address, account);
+        }
         if miner_account.history.is_none(){
             miner_account.history = Some(NodeHistory::new(miner_address));
         }
@@ -169,7 +588,419 @@ impl StateManager{
             history.settle_stampers(block.header);
             state_updates.insert(stamper.address, stamper);
         }
+        let supply = self.supply_under(&state_trie, state_root).saturating_add(reward);
+        if let Some(account) = state_updates.values().find(|account| account.balance > supply) {
+            tracing::error!("Account {:?} would hold {} of a supply of {}", account.address, account.balance, supply);
+            return Err(BlockValidationError::SupplyExceeded(account.address, supply));
+        }
         // branch the state trie with the updates
-        state_trie.branch(Some(state_root), state_updates).expect("Issue with branching state trie")
+        self.branch_tracked(&mut state_trie, state_root, state_updates).map_err(
+            |e| BlockValidationError::Other(e.to_string())
+        )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use pillar_crypto::{hashing::DefaultHash, proofs::trie_path, serialization::PillarSerialize, types::StdByteArray};
+
+    use pillar_crypto::hashing::Hashable;
+
+    use crate::accounting::account::{Account, AccountDelta};
+    use crate::primitives::block::{Block, BlockTail};
+    use crate::primitives::transaction::Transaction;
+    use crate::protocol::chain::get_genesis_block;
+
+    use crate::primitives::errors::BlockValidationError;
+    use crate::protocol::reward::RewardPolicy;
+
+    use super::{diff_states, get_reward_from_depth_and_stampers, verify_account_proof, verify_account_range, verify_state_root, AccountRange, StateManager, StateSizeStats, StateSnapshot};
+
+    fn accounts() -> Vec<Account> {
+        (1..=16u8).map(|i| Account::new([i.wrapping_mul(37); 32], i as u64 * 10)).collect()
+    }
+
+    fn build_state(state_manager: &StateManager, accounts: &[Account]) -> StdByteArray {
+        let mut trie = state_manager.state_trie.lock().unwrap();
+        let root = trie.create_genesis(accounts[0].address, accounts[0].clone()).unwrap();
+        let updates: HashMap<StdByteArray, Account> = accounts[1..].iter().map(|a| (a.address, a.clone())).collect();
+        trie.branch(Some(root), updates).unwrap()
+    }
+
+    #[test]
+    fn test_verify_state_root() {
+        let state_manager = StateManager::new();
+        let mut accounts = accounts();
+        let root = build_state(&state_manager, &accounts);
+        assert!(verify_state_root(&accounts, root));
+        // order does not matter
+        accounts.reverse();
+        assert!(verify_state_root(&accounts, root));
+
+        // a missing, extra, changed, or repeated account does not match
+        assert!(!verify_state_root(&accounts[1..], root));
+        let mut extra = accounts.clone();
+        extra.push(Account::new([255; 32], 1));
+        assert!(!verify_state_root(&extra, root));
+        let mut changed = accounts.clone();
+        changed[3].balance += 1;
+        assert!(!verify_state_root(&changed, root));
+        let mut repeated = accounts.clone();
+        repeated.push(accounts[0].clone());
+        assert!(!verify_state_root(&repeated, root));
+        assert!(!verify_state_root(&[], root));
+
+        // a single account has a root of its own
+        let single = StateManager::new();
+        let root = single.state_trie.lock().unwrap().create_genesis(accounts[0].address, accounts[0].clone()).unwrap();
+        assert!(verify_state_root(&accounts[..1], root));
+    }
+
+    fn updates() -> Vec<(StdByteArray, AccountDelta)> {
+        let mut updates = vec![];
+        for i in 1..=16u8 {
+            let address = [i.wrapping_mul(37); 32];
+            updates.push((address, AccountDelta { credit: 5, debit: 0, nonce: 0 }));
+            updates.push((address, AccountDelta { credit: 0, debit: 3, nonce: 1 }));
+        }
+        updates
+    }
+
+    #[test]
+    fn test_apply_updates_matches_individual() {
+        let mut individual = StateManager::new();
+        let mut root = build_state(&individual, &accounts());
+        let nodes_before = individual.state_trie.lock().unwrap().n_nodes();
+        for update in updates() {
+            root = individual.apply_updates(root, &[update]).unwrap();
+        }
+        let individual_nodes = individual.state_trie.lock().unwrap().n_nodes() - nodes_before;
+
+        let mut batched = StateManager::new();
+        let batch_root = build_state(&batched, &accounts());
+        let nodes_before = batched.state_trie.lock().unwrap().n_nodes();
+        let batch_root = batched.apply_updates(batch_root, &updates()).unwrap();
+        let batch_nodes = batched.state_trie.lock().unwrap().n_nodes() - nodes_before;
+
+        assert_eq!(root, batch_root);
+        let individual_snapshot = individual.snapshot(root, &mut DefaultHash::new()).unwrap();
+        let batch_snapshot = batched.snapshot(batch_root, &mut DefaultHash::new()).unwrap();
+        assert_eq!(individual_snapshot, batch_snapshot);
+        for account in batch_snapshot.accounts {
+            let original = accounts().into_iter().find(|a| a.address == account.address).unwrap();
+            assert_eq!(account.balance, original.balance + 2);
+            assert_eq!(account.nonce, 1);
+        }
+        // one branch instead of one per update
+        assert!(batch_nodes < individual_nodes);
+    }
+
+    #[test]
+    fn test_verify_account_proof_historical_root() {
+        let mut state_manager = StateManager::new();
+        let accounts = accounts();
+        let old_root = build_state(&state_manager, &accounts);
+        let new_root = state_manager.apply_updates(old_root, &updates()).unwrap();
+        assert_ne!(old_root, new_root);
+
+        let address = accounts[3].address;
+        let (old_proof, old_account) = state_manager.account_proof(&address, old_root).unwrap();
+        assert_eq!(old_account, accounts[3]);
+        let (new_proof, new_account) = state_manager.account_proof(&address, new_root).unwrap();
+        assert_ne!(old_account, new_account);
+
+        // the old proof still holds against the old root, after the state has moved on
+        assert!(verify_account_proof(&old_account, &old_proof, old_root));
+        assert!(verify_account_proof(&new_account, &new_proof, new_root));
+        // but not against another root
+        assert!(!verify_account_proof(&old_account, &old_proof, new_root));
+        assert!(!verify_account_proof(&new_account, &new_proof, old_root));
+        // and not for another account
+        assert!(!verify_account_proof(&new_account, &old_proof, old_root));
+        assert!(state_manager.account_proof(&address, [0; 32]).is_none());
+    }
+
+    #[test]
+    fn test_apply_updates_rejects_overdraft() {
+        let mut state_manager = StateManager::new();
+        let root = build_state(&state_manager, &accounts());
+        let address = accounts()[0].address;
+        let result = state_manager.apply_updates(root, &[
+            (address, AccountDelta { credit: 0, debit: 11, nonce: 0 })
+        ]);
+        assert!(result.is_err());
+        // credits earlier in the batch are counted
+        let result = state_manager.apply_updates(root, &[
+            (address, AccountDelta { credit: 1, debit: 0, nonce: 0 }),
+            (address, AccountDelta { credit: 0, debit: 11, nonce: 0 }),
+        ]);
+        assert!(result.is_ok());
+        assert_eq!(state_manager.apply_updates(root, &[]).unwrap(), root);
+    }
+
+    #[test]
+    fn test_state_size_tracks_branches() {
+        let mut state_manager = StateManager::new();
+        let accounts = accounts();
+        let root = build_state(&state_manager, &accounts);
+        // measured on first use
+        let size = state_manager.state_size(root);
+        assert_eq!(size, StateSizeStats::of(&accounts));
+        assert_eq!(size.accounts, 16);
+
+        // new accounts are added, updated ones change size in place
+        let new_address = [200; 32];
+        let grown = state_manager.apply_updates(root, &[
+            (new_address, AccountDelta { credit: 1, debit: 0, nonce: 0 }),
+            (accounts[0].address, AccountDelta { credit: 0, debit: 0, nonce: 1 }),
+        ]).unwrap();
+        let grown_size = state_manager.state_size(grown);
+        assert_eq!(grown_size.accounts, 17);
+        assert_eq!(grown_size, StateSizeStats::of(&state_manager.get_all_accounts(grown)));
+        // a block adds a receiver, and a history to the miner
+        let prev_header = get_genesis_block(Some(grown)).header;
+        let transaction = Transaction::new(accounts[1].address, [201; 32], 4, 0, 0, &mut DefaultHash::new());
+        let block = Block::new(
+            prev_header.hash(&mut DefaultHash::new()).unwrap(), 0, 0, vec![transaction], Some(accounts[2].address),
+            BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+        );
+        let mined = state_manager.branch_from_block(&block, &prev_header);
+        let mined_size = state_manager.state_size(mined);
+        assert_eq!(mined_size.accounts, 18);
+        assert!(mined_size.bytes > grown_size.bytes);
+        assert_eq!(mined_size, StateSizeStats::of(&state_manager.get_all_accounts(mined)));
+        // the parent state is unchanged
+        assert_eq!(state_manager.state_size(root), size);
+
+        // removing the branch removes its accounts
+        state_manager.remove_branch(grown);
+        assert_eq!(state_manager.state_size(root), size);
+        assert!(!state_manager.state_sizes.lock().unwrap().contains_key(&grown));
+    }
+
+    #[test]
+    fn test_snapshot_sorted_by_address() {
+        let state_manager = StateManager::new();
+        let root = build_state(&state_manager, &accounts());
+        let snapshot = state_manager.snapshot(root, &mut DefaultHash::new()).unwrap();
+        assert_eq!(snapshot.accounts.len(), 16);
+        assert!(snapshot.accounts.windows(2).all(|w| w[0].address < w[1].address));
+        assert!(snapshot.verify(&mut DefaultHash::new()));
+    }
+
+    #[test]
+    fn test_accounts_iter_streams_snapshot() {
+        let state_manager = StateManager::new();
+        let root = build_state(&state_manager, &accounts());
+        let snapshot = state_manager.snapshot(root, &mut DefaultHash::new()).unwrap();
+
+        let streamed: Vec<Account> = state_manager.accounts_iter(root).collect();
+        assert_eq!(streamed.len(), 16);
+        assert!(streamed.windows(2).all(|w| w[0].address < w[1].address));
+        assert_eq!(streamed, snapshot.accounts);
+        // the manifest can be hashed while streaming, without collecting
+        let manifest = StateSnapshot::manifest_hash(root, state_manager.accounts_iter(root), &mut DefaultHash::new()).unwrap();
+        assert_eq!(manifest, snapshot.manifest_hash);
+        assert_eq!(state_manager.accounts_iter([7; 32]).count(), 0);
+    }
+
+    #[test]
+    fn test_account_range() {
+        let state_manager = StateManager::new();
+        let root = build_state(&state_manager, &accounts());
+        let (min, max) = ([0; 32], [0xff; 32]);
+        let (low_end, high_start) = ([0x7f; 32], [0x80; 32]);
+        let low = state_manager.account_range(min, low_end, root, usize::MAX);
+        let high = state_manager.account_range(high_start, max, root, usize::MAX);
+        assert!(low.within(min, low_end).count() > 0 && high.within(high_start, max).count() > 0);
+        assert!(verify_account_range(&low, min, low_end, root));
+        assert!(verify_account_range(&high, high_start, max, root));
+        // the two ranges are the whole state
+        let mut joined: Vec<Account> = low.within(min, low_end).chain(high.within(high_start, max)).cloned().collect();
+        joined.sort_by_key(|account| account.address);
+        assert_eq!(joined, state_manager.snapshot(root, &mut DefaultHash::new()).unwrap().accounts);
+
+        // a changed account, a missing one, or a range it was not served for, fails
+        let mut tampered = low.clone();
+        tampered.accounts[0].1.balance += 1;
+        assert!(!verify_account_range(&tampered, min, low_end, root));
+        let mut missing = low.clone();
+        missing.accounts.remove(0);
+        assert!(!verify_account_range(&missing, min, low_end, root));
+        assert!(!verify_account_range(&high, min, low_end, root));
+        let mut reordered = low.clone();
+        reordered.accounts.reverse();
+        assert!(!verify_account_range(&reordered, min, low_end, root));
+        assert_eq!(state_manager.account_range(min, max, [9; 32], usize::MAX), AccountRange::default());
+    }
+
+    #[test]
+    fn test_account_range_paged_and_empty() {
+        let state_manager = StateManager::new();
+        let root = build_state(&state_manager, &accounts());
+        let (min, max) = ([0; 32], [0xff; 32]);
+        // pages of two, following the continuation, cover the state
+        let mut fetched = vec![];
+        let mut start = min;
+        loop {
+            let page = state_manager.account_range(start, max, root, 2);
+            assert!(page.within(start, max).count() <= 2);
+            assert!(verify_account_range(&page, start, max, root));
+            fetched.extend(page.within(start, max).cloned());
+            match page.next {
+                Some(next) => start = next,
+                None => break,
+            }
+        }
+        assert_eq!(fetched.len(), accounts().len());
+        // a continuation that skips ahead fails
+        let mut skipping = state_manager.account_range(min, max, root, 2);
+        skipping.next = skipping.next.and_then(super::next_path);
+        assert!(!verify_account_range(&skipping, min, max, root));
+
+        // a range between two accounts holds nothing, and its neighbours show it
+        let paths = fetched.iter().map(|account| trie_path(&account.address)).collect::<Vec<_>>();
+        let gap = super::next_path(paths[0]).unwrap();
+        let empty = state_manager.account_range(gap, gap, root, 2);
+        assert_eq!(empty.within(gap, gap).count(), 0);
+        assert!(verify_account_range(&empty, gap, gap, root));
+        // an empty answer for a range that holds an account fails
+        let mut hidden = state_manager.account_range(paths[1], paths[1], root, 2);
+        hidden.accounts.retain(|(_, account)| trie_path(&account.address) != paths[1]);
+        assert!(!verify_account_range(&hidden, paths[1], paths[1], root));
+    }
+
+    #[test]
+    fn test_identical_state_identical_snapshot_bytes() {
+        // node a inserts everything in one branch
+        let a = StateManager::new();
+        let root_a = build_state(&a, &accounts());
+        // node b builds the same state in reverse, one account per branch
+        let b = StateManager::new();
+        let mut reversed = accounts();
+        reversed.reverse();
+        let mut trie = b.state_trie.lock().unwrap();
+        let mut root_b = trie.create_genesis(reversed[0].address, reversed[0].clone()).unwrap();
+        for account in &reversed[1..] {
+            root_b = trie.branch(Some(root_b), HashMap::from([(account.address, account.clone())])).unwrap();
+        }
+        drop(trie);
+        assert_eq!(root_a, root_b);
+
+        let snapshot_a = a.snapshot(root_a, &mut DefaultHash::new()).unwrap();
+        let snapshot_b = b.snapshot(root_b, &mut DefaultHash::new()).unwrap();
+        assert_eq!(snapshot_a.manifest_hash, snapshot_b.manifest_hash);
+        assert_eq!(snapshot_a.serialize_pillar().unwrap(), snapshot_b.serialize_pillar().unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_ignores_input_order() {
+        let mut reversed = accounts();
+        reversed.reverse();
+        let a = StateSnapshot::new([1; 32], accounts(), &mut DefaultHash::new()).unwrap();
+        let b = StateSnapshot::new([1; 32], reversed, &mut DefaultHash::new()).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_snapshot_tamper_detected() {
+        let mut snapshot = StateSnapshot::new([1; 32], accounts(), &mut DefaultHash::new()).unwrap();
+        snapshot.accounts[3].balance += 1;
+        assert!(!snapshot.verify(&mut DefaultHash::new()));
+        let mut snapshot = StateSnapshot::new([1; 32], accounts(), &mut DefaultHash::new()).unwrap();
+        snapshot.accounts.swap(0, 1);
+        assert!(!snapshot.verify(&mut DefaultHash::new()));
+    }
+
+    #[test]
+    fn test_diff_states() {
+        let mut state_manager = StateManager::new();
+        let accounts = accounts();
+        let root = build_state(&state_manager, &accounts);
+        let prev_header = get_genesis_block(Some(root)).header;
+        let (sender, receiver, miner) = (accounts[0].address, [200; 32], accounts[5].address);
+        let transaction = Transaction::new(sender, receiver, 4, 0, 0, &mut DefaultHash::new());
+        let block = Block::new(
+            prev_header.hash(&mut DefaultHash::new()).unwrap(),
+            0,
+            0,
+            vec![transaction],
+            Some(miner),
+            BlockTail::default().stamps,
+            1,
+            None,
+            None,
+            &mut DefaultHash::new()
+        );
+        let new_root = state_manager.branch_from_block(&block, &prev_header);
+
+        let pre = state_manager.snapshot(root, &mut DefaultHash::new()).unwrap();
+        let post = state_manager.snapshot(new_root, &mut DefaultHash::new()).unwrap();
+        assert!(diff_states(&pre, &pre).is_empty());
+
+        let diffs = diff_states(&pre, &post);
+        let mut touched = vec![sender, receiver, miner];
+        touched.sort();
+        assert_eq!(diffs.iter().map(|diff| diff.address).collect::<Vec<_>>(), touched);
+        let diff = |address| diffs.iter().find(|diff| diff.address == address).unwrap();
+        assert_eq!(diff(sender).balances(), (10, 6));
+        assert_eq!(diff(sender).nonces(), (0, 1));
+        assert!(diff(receiver).before.is_none());
+        assert_eq!(diff(receiver).balances(), (0, 4));
+        assert_eq!(diff(miner).nonces(), (0, 0));
+        // the miner is touched through its reward and history
+        assert_eq!(diff(miner).balances(), (60, 60 + get_reward_from_depth_and_stampers(1, 0)));
+        assert!(diff(miner).after.as_ref().unwrap().history.is_some());
+
+        // reversing the order swaps before and after
+        let reversed = diff_states(&post, &pre);
+        assert_eq!(reversed.len(), 3);
+        assert!(reversed.iter().all(|r| diff(r.address).before == r.after && diff(r.address).after == r.before));
+    }
+
+    /// Pays the miner more than the reward - a consensus bug
+    #[derive(Debug)]
+    struct OverpayingPolicy(u64);
+
+    impl RewardPolicy for OverpayingPolicy {
+        fn outputs(&self, miner: StdByteArray, _depth: u64, reward: u64) -> Vec<(StdByteArray, u64)> {
+            vec![(miner, reward + self.0)]
+        }
+    }
+
+    #[test]
+    fn test_over_supply_credit_rejected() {
+        let mut state_manager = StateManager::new();
+        let accounts = accounts();
+        let root = build_state(&state_manager, &accounts);
+        let supply: u64 = accounts.iter().map(|account| account.balance).sum();
+        assert_eq!(state_manager.total_supply(root), supply);
+        let prev_header = get_genesis_block(Some(root)).header;
+        let miner = [200; 32];
+        let transaction = Transaction::new(accounts[0].address, [201; 32], 4, 0, 0, &mut DefaultHash::new());
+        let block = Block::new(
+            prev_header.hash(&mut DefaultHash::new()).unwrap(),
+            0,
+            0,
+            vec![transaction],
+            Some(miner),
+            BlockTail::default().stamps,
+            1,
+            None,
+            None,
+            &mut DefaultHash::new()
+        );
+        let reward = get_reward_from_depth_and_stampers(1, 0);
+        let new_root = state_manager.try_branch_from_block(&block, &prev_header).unwrap();
+        // transfers move the supply around, and the reward adds to it
+        assert_eq!(state_manager.total_supply(new_root), supply + reward);
+
+        let nodes = state_manager.state_trie.lock().unwrap().n_nodes();
+        state_manager.reward_policy = std::sync::Arc::new(OverpayingPolicy(supply + 1));
+        let result = state_manager.try_branch_from_block(&block, &prev_header);
+        assert!(matches!(result, Err(BlockValidationError::SupplyExceeded(address, s)) if address == miner && s == supply + reward));
+        assert_eq!(state_manager.state_trie.lock().unwrap().n_nodes(), nodes);
+    }
+}
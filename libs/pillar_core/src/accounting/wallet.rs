@@ -1,4 +1,24 @@
-use pillar_crypto::{signing::{DefaultSigner, DefaultVerifier, SigFunction, SigVerFunction, Signable}, types::StdByteArray};
+use pillar_crypto::{hashing::{DefaultHash, HashFunction}, signing::{DefaultSigner, DefaultVerifier, SigFunction, SigVerFunction, Signable}, types::StdByteArray};
+
+/// Prefixed to every key derivation, so derived keys never collide with other hashes of the seed
+const DERIVATION_TAG: &[u8] = b"pillar/derive";
+
+/// Derives the key pair at `index` from a wallet seed - the same seed and index always give the same keys.
+/// Each child is independent: a child private key reveals nothing about the seed or the other children.
+///
+/// # Returns
+///
+/// * `(private_key, address)` - the ed25519 private key, and its address
+pub fn derive_child(seed: &[u8], index: u32) -> (StdByteArray, StdByteArray) {
+    let mut hasher = DefaultHash::new();
+    hasher.update(DERIVATION_TAG);
+    hasher.update((seed.len() as u64).to_le_bytes());
+    hasher.update(seed);
+    hasher.update(index.to_le_bytes());
+    let private_key = hasher.digest().unwrap();
+    let address = DefaultSigner::new(private_key).get_verifying_function().to_bytes();
+    (private_key, address)
+}
 
 pub struct Wallet{
     pub address: StdByteArray,
@@ -17,6 +37,12 @@ impl Wallet {
         }
     }
 
+    /// The wallet at `index` derived from `seed`
+    pub fn derive(seed: &[u8], index: u32) -> Self {
+        let (private_key, address) = derive_child(seed, index);
+        Wallet::new(address, DefaultSigner::new(private_key))
+    }
+
     pub fn get_private_key(&self) -> [u8; 32] {
         self.to_bytes()
     }
@@ -46,4 +72,34 @@ impl SigFunction<32, 32, 64> for Wallet {
     fn sign(&mut self, data: &impl Signable<64>) -> [u8; 64] {
         self.signing_key.sign(data)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use pillar_crypto::{hashing::DefaultHash, signing::{DefaultVerifier, SigFunction, SigVerFunction, Signable}};
+
+    use crate::primitives::transaction::Transaction;
+
+    use super::{derive_child, Wallet};
+
+    #[test]
+    fn test_derive_child() {
+        let seed = b"correct horse battery staple";
+        assert_eq!(derive_child(seed, 0), derive_child(seed, 0));
+        assert_ne!(derive_child(seed, 0), derive_child(b"another seed", 0));
+
+        let mut addresses = HashSet::new();
+        for index in 0..8 {
+            let mut wallet = Wallet::derive(seed, index);
+            assert_eq!((wallet.get_private_key(), wallet.address), derive_child(seed, index));
+            assert!(DefaultVerifier::is_valid_key(&wallet.address));
+            assert!(addresses.insert(wallet.address));
+            // the derived key signs for the derived address
+            let mut transaction = Transaction::new(wallet.address, [2; 32], 1, 0, 0, &mut DefaultHash::new());
+            let signature = transaction.sign(&mut wallet);
+            assert!(wallet.get_verifying_function().verify(&signature, &transaction));
+        }
+    }
+}
@@ -1,14 +1,23 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::{HashMap, HashSet, VecDeque}, ops::Range, sync::Arc};
 
-use pillar_crypto::{hashing::DefaultHash, signing::{DefaultVerifier, SigVerFunction}, types::StdByteArray};
+use pillar_crypto::{hashing::{DefaultHash, HashFunction, Hashable}, signing::{DefaultVerifier, SigVerFunction}, types::StdByteArray};
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
 use crate::{
-    accounting::{account::Account, state::StateManager}, primitives::{block::{Block, BlockHeader}, errors::BlockValidationError, transaction::Transaction}, protocol::{chain::get_genesis_block, pow::get_difficulty_for_block, reputation::get_current_reputations_for_stampers}
+    accounting::{account::Account, state::{diff_states, AccountDiff, StateManager}}, primitives::{block::{Ancestry, Block, BlockHeader, PaymentProof, ValidationOptions}, errors::BlockValidationError, transaction::{Namespace, Transaction}}, protocol::{chain::get_genesis_block, clock::Clock, params::{ChainParams, Rule}, pow::get_work_from_difficulty}
 };
 
-use super::TrimmableChain;
+use super::{transaction_index::TransactionIndex, BlockObserver, TieBreak, TrimmableChain, ValidationLevel, FINALITY_DEPTH};
+
+/// The default number of transaction signatures verified together
+pub const SIGNATURE_BATCH_SIZE: usize = 64;
+/// The default number of future blocks held at once
+pub const MAX_HELD_BLOCKS: usize = 128;
+/// The default number of side chains kept beside the deepest chain
+pub const MAX_SIDE_CHAINS: usize = 32;
+/// The number of rejected block hashes remembered, so resubmissions are not validated again
+pub const MAX_REJECTED_BLOCKS: usize = 1024;
 
 /// Represents the state of the blockchain, including blocks, accounts, and chain parameters.
 #[derive(Debug, Serialize, Clone, Deserialize)]
@@ -27,6 +36,122 @@ pub struct Chain {
     /// The account manager for tracking account balances and nonces.
     #[serde(skip)]
     pub state_manager: StateManager,
+    /// The consensus parameters of the chain
+    #[serde(skip)]
+    pub params: ChainParams,
+    /// How strictly blocks are validated on acceptance
+    #[serde(skip)]
+    pub validation_level: ValidationLevel,
+    /// How the tip is picked between blocks at the same depth
+    #[serde(skip)]
+    pub tie_break: TieBreak,
+    /// How many transaction signatures are verified together. 0 or 1 verifies them one at a time
+    #[serde(skip)]
+    pub signature_batch_size: usize,
+    /// The time source for timestamp validation
+    #[serde(skip)]
+    pub clock: Clock,
+    /// Blocks slightly in the future, held until their timestamp is valid
+    #[serde(skip)]
+    pub held_blocks: Vec<Block>,
+    /// How many blocks may be held at once. When full, the oldest held block is dropped
+    #[serde(skip, default = "default_max_held_blocks")]
+    pub max_held_blocks: usize,
+    /// How many side chains are kept beside the deepest chain. Beyond it, the side chains with the least work are pruned
+    #[serde(skip, default = "default_max_side_chains")]
+    pub max_side_chains: usize,
+    /// How far below the tip a side chain must have forked off before it may be pruned
+    #[serde(skip, default = "default_finality_depth")]
+    pub finality_depth: u64,
+    /// The hashes of the blocks in the chain, by miner address
+    #[serde(skip)]
+    miner_index: HashMap<StdByteArray, HashSet<StdByteArray>>,
+    /// Where chain events are sent
+    #[serde(skip)]
+    subscribers: Vec<flume::Sender<ChainEvent>>,
+    /// Told about every block that joins or leaves the deepest chain
+    #[serde(skip)]
+    observers: Vec<Arc<dyn BlockObserver>>,
+    /// Recently rejected blocks, by a hash of the whole block, oldest first
+    #[serde(skip)]
+    rejected_blocks: VecDeque<StdByteArray>,
+    /// The block holding each transaction on the deepest chain, when enabled
+    #[serde(skip)]
+    transaction_index: Option<Arc<TransactionIndex>>,
+    /// Headers learned ahead of their blocks, such as from a shard during sync, by hash.
+    /// They only place blocks that have not arrived yet - under a checkpoint, for one
+    #[serde(skip)]
+    learned_headers: HashMap<StdByteArray, BlockHeader>,
+}
+
+fn default_max_held_blocks() -> usize {
+    MAX_HELD_BLOCKS
+}
+
+fn default_max_side_chains() -> usize {
+    MAX_SIDE_CHAINS
+}
+
+fn default_finality_depth() -> u64 {
+    FINALITY_DEPTH
+}
+
+/// Changes to the deepest chain, sent to subscribers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// A block extended the deepest chain
+    NewTip(StdByteArray),
+    /// The deepest chain switched to another branch
+    Reorg {
+        /// the tip before the switch
+        old_tip: StdByteArray,
+        /// the tip after the switch
+        new_tip: StdByteArray,
+        /// transactions from the abandoned branch that are not in the new one - no longer confirmed
+        unconfirmed: Vec<Transaction>,
+    },
+}
+
+/// A summary of the tip of a chain - enough for a peer to decide whether to sync
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChainTip {
+    /// The hash of the deepest block
+    pub hash: StdByteArray,
+    /// The header of the deepest block - includes the depth
+    pub header: BlockHeader,
+    /// The total work from genesis up to and including the tip
+    pub cumulative_work: u128,
+}
+
+/// What a hash refers to, for explorers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashLookup {
+    /// A block, on any branch
+    Block(Box<Block>),
+    /// A transaction, and the hash of the block holding it - on the deepest chain, where it is there
+    Transaction(Box<Transaction>, StdByteArray),
+    /// Neither a known block nor a mined transaction
+    Unknown,
+}
+
+/// What moving the deepest chain to another tip would do, worked out without changing the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReorgPlan {
+    /// The blocks that would leave the deepest chain, tip first
+    pub orphaned: Vec<StdByteArray>,
+    /// The blocks that would join the deepest chain, parent first
+    pub connected: Vec<StdByteArray>,
+    /// Transactions in the orphaned blocks that are not in the connected ones - these return to the mempool
+    pub unconfirmed: Vec<Transaction>,
+    /// Every account that differs between the state at the current tip and the state at the new tip
+    pub state_changes: Vec<AccountDiff>,
+}
+
+impl ChainTip {
+    /// Whether a chain with this tip is behind a chain with the `other` tip
+    pub fn is_behind(&self, other: &ChainTip) -> bool {
+        other.cumulative_work > self.cumulative_work
+    }
 }
 
 impl Chain {
@@ -50,6 +175,11 @@ impl Chain {
         let mut leaves = HashSet::new();
         leaves.insert(genisis_hash);
         
+        let mut miner_index: HashMap<StdByteArray, HashSet<StdByteArray>> = HashMap::new();
+        if let Some(miner) = genesis_block.header.miner_address {
+            miner_index.entry(miner).or_default().insert(genisis_hash);
+        }
+
         let mut blocks = HashMap::new();
         blocks.insert(genisis_hash, genesis_block);
 
@@ -60,7 +190,22 @@ impl Chain {
             deepest_hash: genisis_hash,
             leaves,
             headers,
-            state_manager
+            state_manager,
+            params: ChainParams::default(),
+            validation_level: ValidationLevel::default(),
+            tie_break: TieBreak::default(),
+            signature_batch_size: SIGNATURE_BATCH_SIZE,
+            clock: Clock::default(),
+            held_blocks: Vec::new(),
+            max_held_blocks: MAX_HELD_BLOCKS,
+            max_side_chains: MAX_SIDE_CHAINS,
+            finality_depth: FINALITY_DEPTH,
+            miner_index,
+            subscribers: Vec::new(),
+            observers: Vec::new(),
+            rejected_blocks: VecDeque::new(),
+            transaction_index: None,
+            learned_headers: HashMap::new(),
         }
     }
 
@@ -77,8 +222,13 @@ impl Chain {
         let mut all_hashes: HashSet<StdByteArray> = HashSet::new();
         let mut seen_prevs: HashSet<StdByteArray> = HashSet::new();
 
+        let mut miner_index: HashMap<StdByteArray, HashSet<StdByteArray>> = HashMap::new();
+
         for (hash, block) in &blocks {
             headers.insert(*hash, block.header);
+            if let Some(miner) = block.header.miner_address {
+                miner_index.entry(miner).or_default().insert(*hash);
+            }
             all_hashes.insert(*hash);
             seen_prevs.insert(block.header.previous_hash);
 
@@ -101,126 +251,217 @@ impl Chain {
             deepest_hash,
             leaves,
             state_manager: StateManager::new(),
+            params: ChainParams::default(),
+            validation_level: ValidationLevel::default(),
+            tie_break: TieBreak::default(),
+            signature_batch_size: SIGNATURE_BATCH_SIZE,
+            clock: Clock::default(),
+            held_blocks: Vec::new(),
+            max_held_blocks: MAX_HELD_BLOCKS,
+            max_side_chains: MAX_SIDE_CHAINS,
+            finality_depth: FINALITY_DEPTH,
+            miner_index,
+            subscribers: Vec::new(),
+            observers: Vec::new(),
+            rejected_blocks: VecDeque::new(),
+            transaction_index: None,
+            learned_headers: HashMap::new(),
         }
     }
     
-    /// Validates the structure and metadata of a block.
+    /// Validates a block against its parent in the chain, without applying it.
+    /// Blocks past the allowed drift, but within the hold window, are held if their proof of work is valid.
+    /// Everything else is `Block::validate_against`, with the chain as the ancestry
+    /// and the transaction checks of the validation level.
     #[instrument(skip_all, fields(block = ?block.hash))]
     fn validate_block(&self, block: &Block) -> Result<(), BlockValidationError> {
-        // check hash validity
-        if block.hash.is_none() {
+        let Some(hash) = block.hash else {
             tracing::info!("Block hash is None - Failing");
             return Err(BlockValidationError::MalformedBlock("Hash is not specified".into()));
+        };
+        // blocks past the drift, but within the hold window, are held until their time comes - or their parent arrives
+        let now = self.clock.now();
+        let max_timestamp = now.saturating_add(self.params.max_future_drift);
+        if block.header.timestamp > max_timestamp {
+            block.header.validate_at(hash, max_timestamp.saturating_add(self.params.future_hold_window), &mut DefaultHash::new())?;
+            tracing::info!("Block timestamp is in the hold window - Holding");
+            return Err(BlockValidationError::HeldTimestamp(block.header.timestamp));
+        }
+        let Some(parent) = self.headers.get(&block.header.previous_hash) else {
+            tracing::info!("Previous block not found: {:?}", block.header.previous_hash);
+            return Err(BlockValidationError::MalformedBlock("Previous block not found".into()));
+        };
+        let options = ValidationOptions {
+            now,
+            checks: self.validation_level.checks_for(hash, self.params.checkpoint, self),
+            signature_batch_size: self.signature_batch_size,
+        };
+        if let Err(error) = block.validate_against(block.header.previous_hash, parent, self, &self.state_manager, &self.params, options) {
+            tracing::info!("Block is invalid: {error:?} - Failing");
+            return Err(error);
+        }
+        tracing::info!("Block is valid - Continuing");
+        Ok(())
+    }
+
+    /// Get the tip of the deepest chain
+    pub fn get_tip(&self) -> ChainTip {
+        ChainTip {
+            hash: self.deepest_hash,
+            header: self.headers[&self.deepest_hash],
+            cumulative_work: self.get_cumulative_work(&self.deepest_hash).unwrap_or(0),
         }
-        // get all reputations according to previous block
-        let reputations = get_current_reputations_for_stampers(self, &block.header).values().cloned().collect::<Vec<f64>>();
+    }
 
-        let (expected_target, is_por) = get_difficulty_for_block(&block.header, &reputations);
+    /// Every tip the chain tracks - the deepest chain and each competing fork - most cumulative work first.
+    /// Tips with equal work put the deepest chain's tip first, then go by hash
+    pub fn all_tips(&self) -> Vec<ChainTip> {
+        let mut tips = self.leaves.iter()
+            .filter_map(|hash| Some(ChainTip {
+                hash: *hash,
+                header: *self.headers.get(hash)?,
+                cumulative_work: self.get_cumulative_work(hash)?,
+            }))
+            .collect::<Vec<_>>();
+        tips.sort_by_key(|tip| (std::cmp::Reverse(tip.cumulative_work), tip.hash != self.deepest_hash, tip.hash));
+        tips
+    }
 
-        if block.header.difficulty_target.is_none() || expected_target != block.header.difficulty_target.unwrap() {
-            tracing::info!("Block difficulty target is invalid - Failing");
-            return Err(BlockValidationError::MalformedBlock("Difficulty target does not match".into()));
+    /// The block at `depth` on the deepest chain, ignoring blocks on other forks
+    pub fn canonical_block_at(&self, depth: u64) -> Option<&Block> {
+        if depth > self.depth {
+            return None;
+        }
+        let mut hash = self.deepest_hash;
+        let mut header = self.headers.get(&hash)?;
+        while header.depth > depth {
+            hash = header.previous_hash;
+            header = self.headers.get(&hash)?;
         }
+        self.blocks.get(&hash)
+    }
 
-        if let Err(error) = block.header.validate(block.hash.unwrap(), &mut DefaultHash::new()) {
-            tracing::info!("Block header is not validated - Failing");
-            return Err(error);
+    /// Whether the block with hash `ancestor` is `descendant` or one of its ancestors.
+    /// Learned headers count, so this can place a block that has not arrived yet
+    pub fn is_ancestor(&self, ancestor: StdByteArray, descendant: StdByteArray) -> bool {
+        if ancestor == descendant {
+            return true;
         }
-        // check the previous hash exists
-        let previous_hash = block.header.previous_hash;
-        let previous_block = self.blocks.get(&previous_hash);
-
-        let valid = match previous_block {
-            Some(last_block) => {
-                if last_block.header.depth + 1 != block.header.depth{
-                    tracing::info!("Block depth is invalid - Failing");
-                    return Err(BlockValidationError::MalformedBlock("Depth does not match previous block".into()));
-                } else if block.header.timestamp < last_block.header.timestamp{
-                    tracing::info!("Block timestamp is invalid - Failing");
-                    return Err(BlockValidationError::MalformedBlock("Timestamp is before previous block".into()));
-                } else{
-                    Ok(())
-                }
-            },
-            None => {
-                tracing::info!("Previous block not found: {:?}", previous_hash);
-                Err(BlockValidationError::MalformedBlock("Previous block not found".into()))
-            }
+        let header = |hash: &StdByteArray| self.headers.get(hash).or_else(|| self.learned_headers.get(hash));
+        let Some(target) = header(&ancestor) else {
+            return false;
         };
+        let mut hash = descendant;
+        while let Some(header) = header(&hash) && header.depth > target.depth {
+            hash = header.previous_hash;
+        }
+        hash == ancestor
+    }
 
-        
-        valid?;
-
-        tracing::info!("Block is valid - Continuing");
-        Ok(())
+    /// Learns headers ahead of their blocks, such as from a shard during sync.
+    /// Each is kept under its own hash, so a header can only stand for the block it hashes to
+    pub fn learn_headers(&mut self, headers: impl IntoIterator<Item = BlockHeader>) {
+        let mut hasher = DefaultHash::new();
+        for header in headers {
+            if let Ok(hash) = header.hash(&mut hasher) && !self.headers.contains_key(&hash) {
+                self.learned_headers.insert(hash, header);
+            }
+        }
     }
 
-    /// Ensures that all transactions in a block are valid and do not exceed available funds.
-    /// 
-    /// Validates:
-    /// 1. No duplicate nonces for the same user.
-    /// 2. Sufficient balance for all transactions.
-    /// 3. Nonces are contiguous and start from the account's current nonce.
-    /// 
-    /// # Arguments
-    /// * `transactions` - A vector of transactions to validate.
-    /// * `state_root` - The state root to use for account lookups. The state should be the previous block.
-    #[instrument(skip_all, fields(transactions = ?transactions.iter().map(|t|t.hash).collect::<Vec<_>>()))]
-    fn validate_transaction_set(&mut self, transactions: &Vec<Transaction>, state_root: StdByteArray) -> Result<(), BlockValidationError> {
-        // we need to make sure that there are no duplicated nonce values under the same user
-        let per_user: HashMap<StdByteArray, Vec<&Transaction>> =
-            transactions
-                .iter()
-                .fold(HashMap::new(), |mut acc, tx| {
-                    acc.entry(tx.header.sender) // assuming this gives you the StdByteArray key
-                        .or_default()
-                        .push(tx);
-                    acc
-                });
-        tracing::debug!("Per user transactions: {:?}", per_user);
-        tracing::info!("Validating transaction set with {} users", per_user.len());
-        for (user, transactions) in per_user.iter() {
-            let account = self.state_manager.get_account(user, state_root).unwrap_or(Account::new(*user, 0));
-            // return true;
-            let total_sum: u64 = transactions.iter().map(|t| t.header.amount).sum();
-            if account.balance < total_sum {
-                tracing::info!("Account balance is insufficient for user {:?} - Failing", user);
-                return Err(BlockValidationError::TransactionInsufficientBalance(account.balance));
+    /// Bundles the proof that a transaction was mined on the deepest chain, for the payee to keep
+    pub fn payment_proof(&self, transaction: StdByteArray) -> Option<PaymentProof> {
+        let mut hash = self.deepest_hash;
+        loop {
+            let block = self.blocks.get(&hash)?;
+            if block.transactions.iter().any(|t| t.hash == transaction) {
+                return block.payment_proof(transaction);
             }
-            let mut nonces = vec![];
-            // now validate each individual transaction
-            for transaction in transactions {
-                nonces.push(transaction.header.nonce);
-                let result = self.validate_transaction(transaction, state_root);
-                if let Err(err) = result {
-                    tracing::info!("Invalid transaction - Failing");
-                    return Err(err);
-                }
+            if block.header.depth == 0 {
+                return None;
             }
-            // nonces need tto be contiguous
-            nonces.sort();
-            for i in 0..nonces.len() - 1 {
-                if nonces[i] + 1 != nonces[i + 1] {
-                    tracing::info!("Nonces are not contiguous for user {:?} - Failing", user);
-                    return Err(BlockValidationError::TransactionNonceMismatch(
-                        nonces[i] + 1,
-                        nonces[i + 1],
-                    ));
-                }
+            hash = block.header.previous_hash;
+        }
+    }
+
+    /// How many blocks of the deepest chain hold `transaction` or are built on it - 1 once mined, 0 if not on the deepest chain
+    pub fn confirmations(&self, transaction: StdByteArray) -> u64 {
+        let mut current = self.blocks.get(&self.deepest_hash);
+        while let Some(block) = current {
+            if block.transactions.iter().any(|t| t.hash == transaction) {
+                return self.depth - block.header.depth + 1;
             }
+            current = if block.header.depth == 0 { None } else { self.blocks.get(&block.header.previous_hash) };
+        }
+        0
+    }
 
-            // and the first one needs to be the accounts nonce
-            if nonces[0] != account.nonce {
-                tracing::info!("First nonce does not match account nonce for user {:?} - Failing", user);
-                return Err(BlockValidationError::TransactionNonceMismatch(
-                    account.nonce,
-                    nonces[0],
-                ));
+    /// Whether `hash` is a block hash or a transaction id, with the block or transaction it refers to.
+    /// Block hashes are checked first. A transaction is found on the deepest chain first, then on any other branch
+    pub fn lookup_hash(&self, hash: &StdByteArray) -> HashLookup {
+        if let Some(block) = self.blocks.get(hash) {
+            return HashLookup::Block(Box::new(block.clone()));
+        }
+        let holding = |block: &Block| block.transactions.iter()
+            .find(|transaction| transaction.hash == *hash)
+            .map(|transaction| HashLookup::Transaction(Box::new(*transaction), block.hash.unwrap()));
+        let mut canonical = HashSet::new();
+        let mut current = self.blocks.get(&self.deepest_hash);
+        while let Some(block) = current {
+            if let Some(found) = holding(block) {
+                return found;
             }
+            canonical.insert(block.hash.unwrap());
+            current = if block.header.depth == 0 { None } else { self.blocks.get(&block.header.previous_hash) };
+        }
+        self.blocks.values()
+            .filter(|block| !canonical.contains(&block.hash.unwrap()))
+            .find_map(holding)
+            .unwrap_or(HashLookup::Unknown)
+    }
 
+    /// Indexes the transactions of the deepest chain, so `block_containing` answers without walking the chain.
+    /// The index then follows the deepest chain through reorgs. Does nothing if already enabled
+    pub fn enable_transaction_index(&mut self) {
+        if self.transaction_index.is_some() {
+            return;
         }
+        let index = Arc::new(TransactionIndex::default());
+        self.add_observer_from_genesis(index.clone());
+        self.transaction_index = Some(index);
+    }
 
-        Ok(())
+    /// The hash of the block on the deepest chain holding `transaction`.
+    /// Always `None` unless the transaction index is enabled
+    pub fn block_containing(&self, transaction: &StdByteArray) -> Option<StdByteArray> {
+        self.transaction_index.as_ref()?.block_containing(transaction)
+    }
+
+    /// The transactions tagged with `namespace` in the blocks of the deepest chain at `depths`, shallowest first
+    pub fn transactions_with_namespace(&self, namespace: &Namespace, depths: Range<u64>) -> Vec<Transaction> {
+        let mut blocks = vec![];
+        let mut current = self.blocks.get(&self.deepest_hash);
+        while let Some(block) = current && block.header.depth >= depths.start {
+            if depths.contains(&block.header.depth) {
+                blocks.push(block);
+            }
+            current = if block.header.depth == 0 { None } else { self.blocks.get(&block.header.previous_hash) };
+        }
+        blocks.iter().rev()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| transaction.header.namespace == Some(*namespace))
+            .cloned()
+            .collect()
+    }
+
+    /// The hashes of the blocks in the chain mined by `address`, shallowest first
+    pub fn blocks_by_miner(&self, address: &StdByteArray) -> Vec<StdByteArray> {
+        let mut hashes: Vec<StdByteArray> = self.miner_index
+            .get(address)
+            .map(|mined| mined.iter().cloned().collect())
+            .unwrap_or_default();
+        hashes.sort_by_key(|hash| (self.headers[hash].depth, *hash));
+        hashes
     }
 
     /// Find the longest existing fork in the chain.
@@ -248,15 +489,24 @@ impl Chain {
     /// Validates an individual transaction for correctness.
     ///
     /// Checks:
-    /// 1. Signature validity.
-    /// 2. Hash integrity.
-    /// 3. Sufficient balance for the transaction amount.
+    /// 1. Signature validity, against the key currently controlling the sender's account.
+    /// 2. Hash integrity, and the shape of key rotations.
+    /// 3. The sender has an account, if the chain parameters reject unknown senders.
+    /// 4. Sufficient balance for the transaction amount.
+    /// 5. Any required block is on the deepest chain.
     #[instrument(skip_all, fields(transaction = ?transaction.hash))]
     pub(crate) fn validate_transaction(&self, transaction: &Transaction, state_root: StdByteArray) -> Result<(), BlockValidationError> {
+        if let Some(required) = transaction.header.required_block_hash
+            && !self.is_ancestor(required, self.deepest_hash) {
+            tracing::info!("Transaction requires a block that is not on the deepest chain - Failing");
+            return Err(BlockValidationError::MissingRequiredBlock(required));
+        }
         let sender = transaction.header.sender;
         let signature = transaction.signature;
+        // the transaction can go into the next block at the earliest
+        let account = self.get_sender_account(&sender, state_root, self.depth + 1)?;
         // check for signature
-        let validating_key: DefaultVerifier = DefaultVerifier::from_bytes(&sender);
+        let validating_key: DefaultVerifier = DefaultVerifier::from_bytes(&account.verifying_key());
         let signing_validity = match signature {
             Some(sig) => {
                 // let signature = Signature::from_bytes(&sig);
@@ -268,16 +518,30 @@ impl Chain {
             tracing::info!("Transaction signature is invalid - Failing");
             return Err(BlockValidationError::TransactionInvalidSignature);
         }
+        self.validate_transaction_contents(transaction, &account)
+    }
+
+    /// The account of a transaction sender under `state_root`.
+    /// Senders without an account get an empty one, unless the chain parameters reject unknown senders at `depth`.
+    fn get_sender_account(&self, sender: &StdByteArray, state_root: StdByteArray, depth: u64) -> Result<Account, BlockValidationError> {
+        match self.state_manager.get_account(sender, state_root) {
+            Some(account) => Ok(account),
+            None if self.params.enforces(Rule::RejectUnknownSenders, depth) => {
+                tracing::info!("Sender account {:?} does not exist - Failing", sender);
+                Err(BlockValidationError::TransactionUnknownSender(*sender))
+            },
+            None => Ok(Account::new(*sender, 0)),
+        }
+    }
+
+    /// Validates everything about a transaction but its signature
+    fn validate_transaction_contents(&self, transaction: &Transaction, account: &Account) -> Result<(), BlockValidationError> {
         // check the hash
-        if transaction.hash != transaction.header.hash(&mut DefaultHash::new()) {
-            tracing::info!("Transaction hash is invalid - Failing");
-            return Err(BlockValidationError::HashMismatch(
-                transaction.hash,
-                transaction.header.hash(&mut DefaultHash::new()),
-            ));
+        if let Err(err) = transaction.sanity_check(&mut DefaultHash::new()) {
+            tracing::info!("Transaction is malformed - Failing");
+            return Err(err);
         }
         // verify balance
-        let account = self.state_manager.get_account(&sender, state_root).unwrap_or(Account::new(sender, 0));
         if account.balance < transaction.header.amount {
             tracing::info!("Account balance is insufficient - Failing");
             return Err(BlockValidationError::TransactionInsufficientBalance(account.balance));
@@ -285,25 +549,21 @@ impl Chain {
         Ok(())
     }
 
-    /// Verifies the validity of a block, including its transactions and metadata.
-    pub fn verify_block(&mut self, block: &Block) -> Result<(), BlockValidationError> {
-        self.validate_block(block)?;
-        self.validate_transaction_set(
-            &block.transactions, 
-            self.blocks.get(&block.header.previous_hash).unwrap().header.state_root.unwrap()
-        )?;
-        Ok(())
+    /// Verifies the validity of a block, including its transactions and metadata, as in `validate_block`.
+    /// The state transition is checked when the block is settled
+    pub fn verify_block(&self, block: &Block) -> Result<(), BlockValidationError> {
+        self.validate_block(block)
     }
 
     /// Call this only after a block has been verified
     #[instrument(skip_all, fields(block = ?block.hash))]
     fn settle_new_block(&mut self, block: Block) -> Result<(), BlockValidationError>{
-        if self.blocks.get(&block.hash.unwrap()).is_some() {
+        if self.blocks.contains_key(&block.hash.unwrap()) {
             tracing::warn!("Block with hash {:?} already exists in the chain - skipping", block.hash);
             return Ok(());
         }
         let prev_header = self.headers.get(&block.header.previous_hash).expect("Previous block header must exist");
-        let new_root = self.state_manager.branch_from_block(&block, prev_header);
+        let new_root = self.state_manager.try_branch_from_block(&block, prev_header)?;
         // last check - is the root the same as the one in the block?
         if block.header.state_root.unwrap() != new_root {
             tracing::error!("Block state root does not match the computed state root - Failing");
@@ -315,17 +575,137 @@ impl Chain {
         self.leaves.remove(&block.header.previous_hash);
         self.leaves.insert(block.hash.unwrap());
         self.headers.insert(block.hash.unwrap(), block.header);
+        self.learned_headers.remove(&block.hash.unwrap());
+        if let Some(miner) = block.header.miner_address {
+            self.miner_index.entry(miner).or_default().insert(block.hash.unwrap());
+        }
         tracing::debug!("Block settled in chain, but need to update depth.");
         // update the depth - the depth of this block is checked in the verification
         // perhaps this is a fork deeper in the chain, so we do not always update 
-        if block.header.depth > self.depth {
-            tracing::info!("Chain depth expanded to {}", block.header.depth);
+        let wins_tie = block.header.depth == self.depth && self.tie_break.prefers(&block.hash.unwrap(), &self.deepest_hash);
+        if block.header.depth > self.depth || wins_tie {
+            tracing::info!("Chain tip moved to depth {}", block.header.depth);
+            let old_tip = self.deepest_hash;
             self.deepest_hash = block.hash.unwrap();
             self.depth = block.header.depth;
+            let event = if block.header.previous_hash == old_tip {
+                for observer in &self.observers {
+                    observer.on_connect(&block);
+                }
+                ChainEvent::NewTip(self.deepest_hash)
+            } else {
+                let (disconnected, connected) = self.reorg_branches(old_tip, self.deepest_hash);
+                for observer in &self.observers {
+                    disconnected.iter().for_each(|block| observer.on_disconnect(block));
+                    connected.iter().for_each(|block| observer.on_connect(block));
+                }
+                ChainEvent::Reorg {
+                    old_tip,
+                    new_tip: self.deepest_hash,
+                    unconfirmed: self.unconfirmed_by_reorg(old_tip, self.deepest_hash),
+                }
+            };
+            self.notify(event);
         }
         Ok(())
     }
 
+    /// Subscribe to changes of the deepest chain.
+    /// The subscription ends when the receiver is dropped.
+    pub fn subscribe(&mut self) -> flume::Receiver<ChainEvent> {
+        let (sender, receiver) = flume::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Send an event to every live subscriber, forgetting those that hung up
+    fn notify(&mut self, event: ChainEvent) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Register an observer of blocks joining and leaving the deepest chain
+    pub fn add_observer(&mut self, observer: Arc<dyn BlockObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Register an observer, first connecting it to every block already on the deepest chain, genesis first
+    pub fn add_observer_from_genesis(&mut self, observer: Arc<dyn BlockObserver>) {
+        let mut canonical = vec![];
+        let mut current = self.blocks.get(&self.deepest_hash);
+        while let Some(block) = current {
+            canonical.push(block);
+            current = if block.header.depth == 0 { None } else { self.blocks.get(&block.header.previous_hash) };
+        }
+        for block in canonical.iter().rev() {
+            observer.on_connect(block);
+        }
+        self.add_observer(observer);
+    }
+
+    /// The blocks leaving and joining the deepest chain when the tip moves from `old_tip` to `new_tip`.
+    ///
+    /// # Returns
+    ///
+    /// * `(disconnected, connected)` - the old branch from `old_tip` down, and the new branch up to `new_tip`,
+    ///   both excluding the common ancestor
+    fn reorg_branches(&self, old_tip: StdByteArray, new_tip: StdByteArray) -> (Vec<&Block>, Vec<&Block>) {
+        let (mut old, mut new) = (old_tip, new_tip);
+        let (mut disconnected, mut connected) = (vec![], vec![]);
+        // walk both branches back to the common ancestor
+        while old != new {
+            let (old_depth, new_depth) = (self.headers[&old].depth, self.headers[&new].depth);
+            if old_depth >= new_depth {
+                let block = &self.blocks[&old];
+                disconnected.push(block);
+                old = block.header.previous_hash;
+            }
+            if new_depth >= old_depth {
+                let block = &self.blocks[&new];
+                connected.push(block);
+                new = block.header.previous_hash;
+            }
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
+    /// Transactions in the branch from the fork point up to `old_tip`, that are not in the branch up to `new_tip`.
+    /// These are in chain order.
+    fn unconfirmed_by_reorg(&self, old_tip: StdByteArray, new_tip: StdByteArray) -> Vec<Transaction> {
+        let (disconnected, connected) = self.reorg_branches(old_tip, new_tip);
+        let reconfirmed: HashSet<StdByteArray> = connected.iter()
+            .flat_map(|block| block.transactions.iter().map(|transaction| transaction.hash))
+            .collect();
+        disconnected.iter().rev()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| !reconfirmed.contains(&transaction.hash))
+            .cloned()
+            .collect()
+    }
+
+    /// Previews a switch of the deepest chain to `new_tip`, without changing the chain.
+    /// Nothing is validated - `new_tip` should already be a block in the chain, such as the tip of a side chain.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(plan)` - the blocks and transactions the switch would move, and the state it would change
+    /// * `None` - if `new_tip` is not in the chain, or the state at either tip is not held
+    pub fn simulate_reorg(&self, new_tip: StdByteArray) -> Option<ReorgPlan> {
+        let old_tip = self.deepest_hash;
+        let old_root = self.headers.get(&old_tip)?.state_root?;
+        let new_root = self.headers.get(&new_tip)?.state_root?;
+        self.blocks.get(&new_tip)?;
+        let (disconnected, connected) = self.reorg_branches(old_tip, new_tip);
+        let before = self.state_manager.snapshot(old_root, &mut DefaultHash::new()).ok()?;
+        let after = self.state_manager.snapshot(new_root, &mut DefaultHash::new()).ok()?;
+        Some(ReorgPlan {
+            orphaned: disconnected.iter().map(|block| block.hash.unwrap()).collect(),
+            connected: connected.iter().map(|block| block.hash.unwrap()).collect(),
+            unconfirmed: self.unconfirmed_by_reorg(old_tip, new_tip),
+            state_changes: diff_states(&before, &after),
+        })
+    }
+
     /// Adds a new block to the chain if it is valid.
     ///
     /// # Arguments
@@ -334,17 +714,167 @@ impl Chain {
     ///
     /// # Returns
     ///
-    /// * `Ok(())` if the block is successfully added.
+    /// * `Ok(())` if the block is successfully added, or was already in the chain.
     /// * `Err(std::io::Error)` if the block is invalid.
+    /// * `Err(BlockValidationError::HeldTimestamp)` if the block is held until its timestamp is valid.
+    /// * `Err(BlockValidationError::AlreadyRejected)` if the same block was rejected before.
+    /// * `Err(BlockValidationError::HashCollision)` if a different block with the same hash is already in the chain.
     #[instrument(skip_all, fields(block = ?block.hash))]
     pub fn add_new_block(&mut self, block: Block) -> Result<(), BlockValidationError> {
-        self.verify_block(&block)?;
-        tracing::info!("Block is valid, settling...");
-        self.settle_new_block(block)?;
-        Ok(())
+        // identical headers are the same block - there is no need to validate them again.
+        // the hash is recomputed, since the hash that came with the block could be anything
+        let header_hash = block.header.hash(&mut DefaultHash::new())
+            .map_err(|e| BlockValidationError::MalformedBlock(e.to_string()))?;
+        if let Some(known) = self.blocks.get(&header_hash) {
+            // the header does not commit to transaction signatures - a copy with different contents must not replace the known block
+            if known.header != block.header || known.transactions != block.transactions {
+                tracing::warn!("Block reuses the hash of a different known block - rejecting");
+                return Err(BlockValidationError::HashCollision(header_hash));
+            }
+            tracing::debug!("Block is already in the chain - skipping");
+            return Ok(());
+        }
+        // the header does not commit to the transactions as carried, so a bad copy of a good block must not condemn it.
+        // the whole block is keyed - its header, and every transaction with its hash and signature
+        let rejection_key = {
+            let bytes = bincode::serialize(&block).map_err(|e| BlockValidationError::MalformedBlock(e.to_string()))?;
+            let mut hasher = DefaultHash::new();
+            hasher.update(bytes);
+            hasher.digest().unwrap()
+        };
+        if self.rejected_blocks.contains(&rejection_key) {
+            tracing::debug!("Block was already rejected - skipping");
+            return Err(BlockValidationError::AlreadyRejected(header_hash));
+        }
+        let result = match self.verify_block(&block) {
+            Ok(()) => {
+                tracing::info!("Block is valid, settling...");
+                let settled = self.settle_new_block(block.clone());
+                if settled.is_ok() && self.leaves.len() > self.max_side_chains + 1 {
+                    let best = self.deepest_hash;
+                    let pruned = self.prune_side_chains(&best, self.max_side_chains, self.finality_depth);
+                    tracing::debug!("Pruned {pruned} side chains");
+                }
+                settled
+            },
+            Err(error) => Err(error),
+        };
+        match result {
+            Err(BlockValidationError::HeldTimestamp(_)) if !self.held_blocks.iter().any(|held| held.hash == block.hash) => {
+                self.held_blocks.push(block);
+                // anyone can send future blocks - keep the newest so the pool cannot grow without bound
+                let excess = self.held_blocks.len().saturating_sub(self.max_held_blocks);
+                self.held_blocks.drain(..excess);
+            },
+            // later, the time may have moved on, or the parent may have arrived
+            Err(BlockValidationError::HeldTimestamp(_) | BlockValidationError::FutureTimestamp(_)) => {},
+            Err(_) if block.hash == Some(header_hash) && self.blocks.contains_key(&block.header.previous_hash) => {
+                self.rejected_blocks.push_back(rejection_key);
+                if self.rejected_blocks.len() > MAX_REJECTED_BLOCKS {
+                    self.rejected_blocks.pop_front();
+                }
+            },
+            _ => {},
+        }
+        result
     } 
+
+    /// Releases held blocks whose timestamps are now within the allowed drift, shallowest first,
+    /// so held children can follow held parents. They are settled like any other received block.
+    /// Blocks that are still too far in the future stay held.
+    ///
+    /// # Returns
+    ///
+    /// * The released blocks
+    pub fn release_held_blocks(&mut self) -> Vec<Block> {
+        let latest = self.clock.now().saturating_add(self.params.max_future_drift);
+        let (mut ready, waiting): (Vec<Block>, Vec<Block>) = std::mem::take(&mut self.held_blocks)
+            .into_iter()
+            .partition(|block| block.header.timestamp <= latest);
+        self.held_blocks = waiting;
+        ready.sort_by_key(|block| block.header.depth);
+        ready
+    }
 }
 
+/// Replays `blocks` from genesis, validating each block against the state left by the ones before it.
+/// This is the ground truth for state correctness - nothing is trusted but the genesis block.
+///
+/// # Arguments
+/// * `blocks` - The chain in order, starting with the genesis block. Each block must extend the one before it.
+///
+/// # Returns
+///
+/// * `Ok(Chain)` - the replayed chain. Its state is under `get_state_root`
+/// * `Err((index, error))` - the index of the first block that fails, and why
+pub fn replay_chain(blocks: &[Block]) -> Result<Chain, (usize, BlockValidationError)> {
+    let mut chain = Chain::new_with_genesis();
+    let Some(genesis) = blocks.first() else {
+        return Err((0, BlockValidationError::MalformedBlock("There is no genesis block to replay from".into())));
+    };
+    if genesis.hash != Some(chain.deepest_hash) {
+        return Err((0, BlockValidationError::HashMismatch(chain.deepest_hash, genesis.hash.unwrap_or_default())));
+    }
+    for (index, block) in blocks.iter().enumerate().skip(1) {
+        if block.header.previous_hash != chain.deepest_hash {
+            return Err((index, BlockValidationError::HashMismatch(chain.deepest_hash, block.header.previous_hash)));
+        }
+        chain.add_new_block(block.clone()).map_err(|error| (index, error))?;
+    }
+    Ok(chain)
+}
+
+/// Where `verify_step` has got to - the tip of the chain verified so far, and the hash of every block up to it,
+/// which the blocks after it are checked against as their ancestors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationCursor {
+    /// the last good block
+    pub tip: ChainTip,
+    /// the hashes of the tip and every block below it
+    ancestors: HashSet<StdByteArray>,
+}
+
+impl VerificationCursor {
+    /// A cursor at the genesis block of the chain to verify
+    pub fn new(genesis: ChainTip) -> Self {
+        VerificationCursor { ancestors: HashSet::from([genesis.hash]), tip: genesis }
+    }
+}
+
+impl Ancestry for VerificationCursor {
+    /// Only answers for the tip - the cursor knows nothing of other branches
+    fn is_ancestor(&self, ancestor: StdByteArray, descendant: StdByteArray) -> bool {
+        descendant == self.tip.hash && self.ancestors.contains(&ancestor)
+    }
+}
+
+/// Verifies `block` as the next block after `cursor`, the tip of the chain verified so far, and returns the cursor moved on to it.
+/// The checks are those of `Block::validate_against`, against the ancestors the cursor carries, then the state transition.
+/// The account state after the tip stays in `state_manager` under the tip's state root, so the cursor is all that needs keeping
+/// to pause a long verification and resume it later. Start from the tip of `Chain::new_with_genesis`, with its state manager.
+///
+/// # Returns
+///
+/// * `Ok(cursor)` - the cursor at `block`
+/// * `Err(error)` - why `block` fails. The last good block is still `cursor`
+pub fn verify_step(mut cursor: VerificationCursor, block: &Block, state_manager: &mut StateManager, params: &ChainParams, now: u64) -> Result<VerificationCursor, BlockValidationError> {
+    block.validate_against(cursor.tip.hash, &cursor.tip.header, &cursor, state_manager, params, ValidationOptions::full(now))?;
+    block.verify_state_transition(&cursor.tip.header, state_manager)?;
+    let work = get_work_from_difficulty(block.header.difficulty_target.unwrap_or(0));
+    cursor.tip = ChainTip {
+        hash: block.hash.unwrap(),
+        header: block.header,
+        cumulative_work: cursor.tip.cumulative_work.saturating_add(work),
+    };
+    cursor.ancestors.insert(cursor.tip.hash);
+    Ok(cursor)
+}
+
+impl Ancestry for Chain {
+    fn is_ancestor(&self, ancestor: StdByteArray, descendant: StdByteArray) -> bool {
+        Chain::is_ancestor(self, ancestor, descendant)
+    }
+}
 
 impl TrimmableChain for Chain {
     fn get_headers(&self) -> &HashMap<StdByteArray, BlockHeader> {
@@ -356,20 +886,37 @@ impl TrimmableChain for Chain {
     }
 
     fn remove_header(&mut self, hash: &StdByteArray) {
-        self.state_manager.remove_branch(self.headers.get(hash).unwrap().state_root.unwrap());
-        self.blocks.remove(hash);
+        // headers outlive their blocks - a block already removed has released its state, and must not release it twice
+        let Some(block) = self.blocks.remove(hash) else {
+            return;
+        };
+        self.state_manager.remove_branch(block.header.state_root.unwrap());
+        if let Some(miner) = block.header.miner_address
+            && let Some(mined) = self.miner_index.get_mut(&miner) {
+            mined.remove(hash);
+            if mined.is_empty() {
+                self.miner_index.remove(&miner);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction, Signable};
+    use pillar_crypto::vrf::VrfProof;
 
     use super::*;
     
-    use crate::primitives::block::BlockTail;
-    use crate::primitives::transaction::{Transaction};
-    use crate::protocol::pow::mine;
+    use crate::fixtures::{mined_block, mined_block_at, mined_block_on, signed_transaction};
+    use crate::primitives::block::{verify_payment_proof, BlockTail, Stamp};
+    use crate::primitives::transaction::{Transaction, TransactionHeader};
+    use crate::protocol::difficulty::{get_difficulty_from_depth, get_reward_from_depth_and_stampers, MIN_DIFFICULTY};
+    use crate::protocol::params::{Checkpoint, SoftForks};
+    use crate::protocol::pow::{get_work_from_difficulty, mine};
+    use crate::protocol::reward::{MinerRewardPolicy, TreasuryRewardPolicy};
 
     #[test]
     fn test_chain_creation() {
@@ -405,11 +952,1381 @@ mod tests {
         );
         let prev_header = chain.headers.get(&block.header.previous_hash).expect("Previous block header not found");
         let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-        mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+        mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
         let result = chain.add_new_block(block);
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_chain_tip_work() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_tip = chain.get_tip();
+        assert_eq!(genesis_tip.cumulative_work, 1);
+
+        let trans = signed_transaction(0);
+        let sender = trans.header.sender;
+        let mut block = Block::new(
+            chain.deepest_hash, 
+            0, 
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            vec![trans],
+            Some(sender),
+            BlockTail::default().stamps,
+            1,
+            None,
+            None,
+            &mut DefaultHash::new()
+        );
+        let prev_header = chain.headers.get(&block.header.previous_hash).expect("Previous block header not found");
+        let state_root = chain.state_manager.branch_from_block(&block, prev_header);
+        mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
+        let hash = block.hash.unwrap();
+        chain.add_new_block(block).unwrap();
+
+        let tip = chain.get_tip();
+        assert_eq!(tip.hash, hash);
+        assert_eq!(tip.header.depth, 1);
+        assert_eq!(tip.cumulative_work, 1 + get_work_from_difficulty(MIN_DIFFICULTY));
+        assert!(genesis_tip.is_behind(&tip));
+        assert!(!tip.is_behind(&genesis_tip));
+        assert!(!tip.is_behind(&tip));
+        assert_eq!(chain.get_cumulative_work(&[9; 32]), None);
+    }
+
+    #[tokio::test]
+    async fn test_validation_levels_unsigned_transaction() {
+        let mut chain = Chain::new_with_genesis();
+        let sender = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+        // never signed
+        let trans = Transaction::new(sender, [1;32], 0, 0, 0, &mut DefaultHash::new());
+        let block = mined_block(&mut chain, vec![trans], sender).await;
+        chain.params.checkpoint = Some(Checkpoint { depth: 1, hash: block.hash.unwrap() });
+
+        chain.validation_level = ValidationLevel::Full;
+        assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::TransactionInvalidSignature)));
+        chain.validation_level = ValidationLevel::Checkpointed;
+        assert!(chain.verify_block(&block).is_ok());
+        chain.validation_level = ValidationLevel::HeadersOnly;
+        assert!(chain.verify_block(&block).is_ok());
+        // being below a checkpoint is not enough - the block must lead to it
+        chain.params.checkpoint = Some(Checkpoint { depth: 2, hash: [9; 32] });
+        for level in [ValidationLevel::Checkpointed, ValidationLevel::HeadersOnly] {
+            chain.validation_level = level;
+            assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::TransactionInvalidSignature)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_levels_learned_headers() {
+        // a chain of unsigned blocks, built where they are trusted
+        let mut source = Chain::new_with_genesis();
+        let genesis_hash = source.deepest_hash;
+        let now = source.clock.now();
+        let sender = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+        let unsigned = |nonce: u64| Transaction::new(sender, [1;32], 0, 0, nonce, &mut DefaultHash::new());
+        let first = mined_block_on(&mut source, genesis_hash, vec![unsigned(0)], sender, now).await;
+        source.headers.insert(first.hash.unwrap(), first.header);
+        let second = mined_block_on(&mut source, first.hash.unwrap(), vec![unsigned(1)], sender, now + 1).await;
+        let sibling = mined_block_on(&mut source, genesis_hash, vec![unsigned(0)], [2; 32], now + 2).await;
+
+        let mut chain = Chain::new_with_genesis();
+        chain.validation_level = ValidationLevel::Checkpointed;
+        chain.params.checkpoint = Some(Checkpoint { depth: 2, hash: second.hash.unwrap() });
+        // nothing places the first block under the checkpoint yet
+        assert!(matches!(chain.verify_block(&first), Err(BlockValidationError::TransactionInvalidSignature)));
+        chain.learn_headers([first.header, second.header, sibling.header]);
+        chain.add_new_block(first.clone()).unwrap();
+        chain.add_new_block(second.clone()).unwrap();
+        assert_eq!(chain.deepest_hash, second.hash.unwrap());
+        // a learned block off the checkpointed chain is fully checked
+        assert!(matches!(chain.verify_block(&sibling), Err(BlockValidationError::TransactionInvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn test_validation_levels_nonce_gap() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        // account nonce is 0
+        let mut trans = Transaction::new(sender, [1;32], 0, 0, 3, &mut DefaultHash::new());
+        trans.sign(&mut signing_key);
+        let block = mined_block(&mut chain, vec![trans], sender).await;
+        chain.params.checkpoint = Some(Checkpoint { depth: 1, hash: block.hash.unwrap() });
+
+        chain.validation_level = ValidationLevel::Full;
+        assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::TransactionNonceMismatch(0, 3))));
+        chain.validation_level = ValidationLevel::Checkpointed;
+        assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::TransactionNonceMismatch(0, 3))));
+        chain.validation_level = ValidationLevel::HeadersOnly;
+        assert!(chain.verify_block(&block).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_validation_levels_above_checkpoint() {
+        let mut chain = Chain::new_with_genesis();
+        let sender = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+        let trans = Transaction::new(sender, [1;32], 0, 0, 0, &mut DefaultHash::new());
+        let block = mined_block(&mut chain, vec![trans], sender).await;
+        // only genesis is checkpointed
+        chain.params.checkpoint = Some(Checkpoint { depth: 0, hash: chain.deepest_hash });
+        for level in [ValidationLevel::Full, ValidationLevel::Checkpointed, ValidationLevel::HeadersOnly] {
+            chain.validation_level = level;
+            assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::TransactionInvalidSignature)));
+        }
+        // no checkpoint at all
+        chain.params.checkpoint = None;
+        for level in [ValidationLevel::Full, ValidationLevel::Checkpointed, ValidationLevel::HeadersOnly] {
+            chain.validation_level = level;
+            assert!(chain.verify_block(&block).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_levels_checkpoint_mismatch() {
+        let mut chain = Chain::new_with_genesis();
+        let trans = signed_transaction(0);
+        let sender = trans.header.sender;
+        let block = mined_block(&mut chain, vec![trans], sender).await;
+        assert!(chain.verify_block(&block).is_ok());
+        chain.params.checkpoint = Some(Checkpoint { depth: 1, hash: [9; 32] });
+        for level in [ValidationLevel::Full, ValidationLevel::Checkpointed, ValidationLevel::HeadersOnly] {
+            chain.validation_level = level;
+            assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::CheckpointMismatch(1))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_equal_timestamps() {
+        for allow_equal_timestamps in [false, true] {
+            let mut chain = Chain::new_with_genesis();
+            chain.params.allow_equal_timestamps = allow_equal_timestamps;
+            let mut signing_key = DefaultSigner::generate_random();
+            let sender = signing_key.get_verifying_function().to_bytes();
+            let mut signed = |nonce: u64| {
+                let mut transaction = Transaction::new(sender, [3; 32], 0, 0, nonce, &mut DefaultHash::new());
+                transaction.sign(&mut signing_key);
+                transaction
+            };
+            let first = mined_block(&mut chain, vec![signed(0)], sender).await;
+            chain.add_new_block(first.clone()).unwrap();
+            let second = mined_block_at(&mut chain, vec![signed(1)], sender, first.header.timestamp).await;
+            let result = chain.add_new_block(second);
+            assert_eq!(result.is_ok(), allow_equal_timestamps, "{result:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reorg_across_soft_fork() {
+        let mut chain = Chain::new_with_genesis();
+        // unknown senders are rejected from depth 3
+        chain.params.soft_forks = SoftForks::default().activate(Rule::RejectUnknownSenders, 3);
+        let genesis = chain.deepest_hash;
+        let now = chain.clock.now();
+        let mut miner_key = DefaultSigner::generate_random();
+        let miner = miner_key.get_verifying_function().to_bytes();
+
+        let mut tip = genesis;
+        for depth in 1..=2 {
+            let block = mined_block_on(&mut chain, tip, vec![signed_transaction(0)], [1; 32], now + depth).await;
+            chain.add_new_block(block.clone()).unwrap();
+            tip = block.hash.unwrap();
+        }
+        let old_tip = tip;
+
+        // a competing branch is checked under the rules at the depth of each block, not those of the tip it replaces
+        let mut tip = genesis;
+        let mut fork = vec![];
+        for depth in 1..=2 {
+            let block = mined_block_on(&mut chain, tip, vec![signed_transaction(0)], miner, now + depth).await;
+            chain.add_new_block(block.clone()).unwrap();
+            tip = block.hash.unwrap();
+            fork.push(tip);
+        }
+        assert_eq!(chain.deepest_hash, old_tip);
+        // past activation, the stricter rule applies
+        let block = mined_block_on(&mut chain, tip, vec![signed_transaction(0)], miner, now + 3).await;
+        assert!(matches!(chain.add_new_block(block), Err(BlockValidationError::TransactionUnknownSender(_))));
+        let mut known = Transaction::new(miner, [3; 32], 0, 0, 0, &mut DefaultHash::new());
+        known.sign(&mut miner_key);
+        let block = mined_block_on(&mut chain, tip, vec![known], miner, now + 3).await;
+        chain.add_new_block(block.clone()).unwrap();
+        fork.push(block.hash.unwrap());
+
+        // the reorg keeps the pre-activation blocks with unknown senders
+        assert_eq!(chain.deepest_hash, block.hash.unwrap());
+        assert!(fork.iter().all(|hash| chain.is_ancestor(*hash, chain.deepest_hash)));
+    }
+
+    #[tokio::test]
+    async fn test_assume_valid() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+        let sender = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+        let unsigned = |nonce: u64| Transaction::new(sender, [1;32], 0, 0, nonce, &mut DefaultHash::new());
+        // nothing is assumed by default
+        let block = mined_block(&mut chain, vec![unsigned(0)], sender).await;
+        assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::TransactionInvalidSignature)));
+
+        // a chain of unsigned blocks, built where they are trusted
+        let mut source = Chain::new_with_genesis();
+        let first = mined_block_on(&mut source, genesis_hash, vec![unsigned(0)], sender, now).await;
+        source.headers.insert(first.hash.unwrap(), first.header);
+        let second = mined_block_on(&mut source, first.hash.unwrap(), vec![unsigned(1)], sender, now + 1).await;
+
+        // being below the assumed valid block is not enough
+        chain.params.assume_valid = Some(Checkpoint { depth: 2, hash: second.hash.unwrap() });
+        assert!(matches!(chain.verify_block(&first), Err(BlockValidationError::TransactionInvalidSignature)));
+        // unsigned transactions are accepted in blocks the assumed valid block builds on
+        chain.learn_headers([first.header, second.header]);
+        chain.add_new_block(first.clone()).unwrap();
+        chain.add_new_block(second.clone()).unwrap();
+        // but not in another block below it
+        let sibling = mined_block_on(&mut chain, genesis_hash, vec![unsigned(0)], [2; 32], now + 2).await;
+        assert!(matches!(chain.verify_block(&sibling), Err(BlockValidationError::TransactionInvalidSignature)));
+        // and everything above it is fully validated
+        let block = mined_block(&mut chain, vec![unsigned(2)], sender).await;
+        assert!(matches!(chain.add_new_block(block), Err(BlockValidationError::TransactionInvalidSignature)));
+    }
+
+    #[tokio::test]
+    async fn test_signature_batch_size() {
+        let mut chain = Chain::new_with_genesis();
+        let mut transactions = vec![];
+        for _ in 0..5 {
+            let transaction = signed_transaction(0);
+            transactions.push(transaction);
+        }
+        let valid = mined_block(&mut chain, transactions.clone(), [9; 32]).await;
+        // swap two signatures - each is still a real signature, just not over its transaction
+        let signature = transactions[1].signature;
+        transactions[1].signature = transactions[3].signature;
+        transactions[3].signature = signature;
+        let tampered = mined_block(&mut chain, transactions, [9; 32]).await;
+
+        for batch_size in [0, 1, 2, 5, SIGNATURE_BATCH_SIZE] {
+            chain.signature_batch_size = batch_size;
+            assert!(chain.verify_block(&valid).is_ok());
+            assert!(matches!(chain.verify_block(&tampered), Err(BlockValidationError::TransactionInvalidSignature)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_depth_overflow_rejected() {
+        let mut chain = Chain::new_with_genesis();
+        // keep the difficulty mineable at extreme depths
+        chain.params.retarget_window = u64::MAX;
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let genesis_root = chain.get_state_root().unwrap();
+        // a parent already at the maximum depth
+        let mut trans = Transaction::new(sender, [1;32], 0, 0, 0, &mut DefaultHash::new());
+        trans.sign(&mut signing_key);
+        let parent = Block::new(
+            chain.deepest_hash, 0, 1, vec![trans], Some(sender),
+            BlockTail::default().stamps, u64::MAX, Some(0), Some(genesis_root), &mut DefaultHash::new()
+        );
+        let parent_hash = parent.hash.unwrap();
+        chain.headers.insert(parent_hash, parent.header);
+        chain.blocks.insert(parent_hash, parent);
+
+        for depth in [u64::MAX, 0, 1] {
+            let mut trans = Transaction::new(sender, [1;32], 0, 0, 0, &mut DefaultHash::new());
+            trans.sign(&mut signing_key);
+            let mut child = Block::new(
+                parent_hash, 0, 2, vec![trans], Some(sender),
+                BlockTail::default().stamps, depth, None, None, &mut DefaultHash::new()
+            );
+            mine(&mut child, sender, genesis_root, vec![], &chain.params, None, DefaultHash::new()).await;
+            assert!(chain.verify_block(&child).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nonce_overflow_rejected() {
+        use crate::accounting::account::AccountDelta;
+
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        // a parent whose state leaves the sender one nonce from the maximum
+        let advanced = [(sender, AccountDelta { credit: 0, debit: 0, nonce: u64::MAX - 1 })];
+        let state_root = chain.state_manager.apply_updates(chain.get_state_root().unwrap(), &advanced).unwrap();
+        let mut trans = Transaction::new([5; 32], [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        trans.sign(&mut DefaultSigner::generate_random());
+        let parent = Block::new(
+            chain.deepest_hash, 0, 1, vec![trans], Some([5; 32]),
+            BlockTail::default().stamps, 1, Some(MIN_DIFFICULTY), Some(state_root), &mut DefaultHash::new()
+        );
+        let parent_hash = parent.hash.unwrap();
+        chain.headers.insert(parent_hash, parent.header);
+        chain.blocks.insert(parent_hash, parent);
+        let child = async |chain: &mut Chain, nonces: &[u64], signing_key: &mut DefaultSigner| {
+            let transactions = nonces.iter().map(|nonce| {
+                let mut trans = Transaction::new(sender, [1; 32], 0, 0, *nonce, &mut DefaultHash::new());
+                trans.sign(signing_key);
+                trans
+            }).collect();
+            let mut block = Block::new(
+                parent_hash, 0, 2, transactions, Some(sender),
+                BlockTail::default().stamps, 2, None, None, &mut DefaultHash::new()
+            );
+            mine(&mut block, sender, state_root, vec![], &chain.params, None, DefaultHash::new()).await;
+            block
+        };
+
+        // the last nonce is fine
+        let last = child(&mut chain, &[u64::MAX - 1], &mut signing_key).await;
+        assert!(chain.verify_block(&last).is_ok());
+        // but nonces that are contiguous from the account would advance it past the maximum
+        let past = child(&mut chain, &[u64::MAX - 1, u64::MAX], &mut signing_key).await;
+        assert!(matches!(chain.verify_block(&past), Err(BlockValidationError::InvalidTransaction(reason)) if reason == "Nonce overflows"));
+    }
+
+    #[tokio::test]
+    async fn test_future_block_held_then_accepted() {
+        let mut chain = Chain::new_with_genesis();
+        chain.params.future_hold_window = 600;
+        let start = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        chain.clock = Clock::mock(start);
+        let trans = signed_transaction(0);
+        let sender = trans.header.sender;
+        // a minute past the allowed drift
+        let timestamp = start + chain.params.max_future_drift + 60;
+        let block = mined_block_at(&mut chain, vec![trans], sender, timestamp).await;
+
+        assert!(matches!(chain.add_new_block(block.clone()), Err(BlockValidationError::HeldTimestamp(t)) if t == timestamp));
+        // held only once
+        assert!(chain.add_new_block(block.clone()).is_err());
+        assert_eq!(chain.held_blocks.len(), 1);
+        assert_eq!(chain.depth, 0);
+        // not yet
+        chain.clock.advance(30);
+        assert!(chain.release_held_blocks().is_empty());
+        assert_eq!(chain.held_blocks.len(), 1);
+        // now valid
+        chain.clock.advance(30);
+        let released = chain.release_held_blocks();
+        assert_eq!(released, vec![block.clone()]);
+        assert!(chain.held_blocks.is_empty());
+        assert_eq!(chain.depth, 0);
+        assert!(chain.add_new_block(released[0].clone()).is_ok());
+        assert_eq!(chain.depth, 1);
+        assert_eq!(chain.deepest_hash, block.hash.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_held_blocks_bounded() {
+        let mut chain = Chain::new_with_genesis();
+        chain.params.future_hold_window = 600;
+        chain.max_held_blocks = 3;
+        let start = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        chain.clock = Clock::mock(start);
+        let trans = signed_transaction(0);
+        let sender = trans.header.sender;
+        let mut held = vec![];
+        for i in 0..5 {
+            let timestamp = start + chain.params.max_future_drift + 60 + i;
+            let block = mined_block_at(&mut chain, vec![trans], sender, timestamp).await;
+            assert!(matches!(chain.add_new_block(block.clone()), Err(BlockValidationError::HeldTimestamp(_))));
+            assert!(chain.held_blocks.len() <= 3);
+            held.push(block.hash);
+        }
+        // the oldest were dropped to make room
+        assert_eq!(chain.held_blocks.iter().map(|block| block.hash).collect::<Vec<_>>(), held[2..]);
+    }
+
+    #[tokio::test]
+    async fn test_future_block_rejected_outside_hold_window() {
+        let mut chain = Chain::new_with_genesis();
+        let start = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        chain.clock = Clock::mock(start);
+        let trans = signed_transaction(0);
+        let sender = trans.header.sender;
+        let timestamp = start + chain.params.max_future_drift + 60;
+        let block = mined_block_at(&mut chain, vec![trans], sender, timestamp).await;
+        // holding is disabled by default
+        assert!(matches!(chain.add_new_block(block.clone()), Err(BlockValidationError::FutureTimestamp(_))));
+        // past the end of the hold window
+        chain.params.future_hold_window = 59;
+        assert!(matches!(chain.add_new_block(block), Err(BlockValidationError::FutureTimestamp(_))));
+        assert!(chain.held_blocks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_difficulty_grace_window() {
+        let mut chain = Chain::new_with_genesis();
+        chain.params.retarget_window = 2;
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut transaction = Transaction::new(sender, [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        let block = mined_block_at(&mut chain, vec![transaction], sender, 1).await;
+        chain.add_new_block(block).unwrap();
+
+        // depth 2 starts a new retarget window, but the miner still expects the previous difficulty
+        let stale_params = ChainParams { retarget_window: 3, ..chain.params };
+        let mut transaction = Transaction::new(sender, [1; 32], 0, 0, 1, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        let mut block = Block::new(
+            chain.deepest_hash, 0, 2, vec![transaction], Some(sender),
+            BlockTail::default().stamps, 2, None, None, &mut DefaultHash::new()
+        );
+        let prev_header = chain.headers[&chain.deepest_hash];
+        let state_root = chain.state_manager.branch_from_block(&block, &prev_header);
+        mine(&mut block, sender, state_root, vec![], &stale_params, None, DefaultHash::new()).await;
+        assert_eq!(block.header.difficulty_target, Some(chain.params.bootstrap_difficulty));
+        assert_ne!(block.header.difficulty_target, Some(get_difficulty_from_depth(2, &chain.params)));
+
+        // strict by default
+        assert_eq!(chain.params.difficulty_grace_window, 0);
+        assert!(matches!(chain.verify_block(&block), Err(BlockValidationError::MalformedBlock(_))));
+        chain.params.difficulty_grace_window = 1;
+        assert!(chain.add_new_block(block).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_all_tips() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+        let only = chain.all_tips();
+        assert_eq!(only.len(), 1);
+        assert_eq!(only[0], chain.get_tip());
+
+        // a chain two blocks long, and a competing fork one block long
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [1; 32], now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        let a2 = mined_block_on(&mut chain, a1.hash.unwrap(), vec![signed_transaction(0)], [1; 32], now + 1).await;
+        chain.add_new_block(a2.clone()).unwrap();
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [2; 32], now + 2).await;
+        chain.add_new_block(b1.clone()).unwrap();
+
+        let tips = chain.all_tips();
+        assert_eq!(tips.iter().map(|tip| tip.hash).collect::<Vec<_>>(), vec![a2.hash.unwrap(), b1.hash.unwrap()]);
+        assert_eq!(tips[0], chain.get_tip());
+        assert_eq!(tips[1].header.depth, 1);
+        assert_eq!(Some(tips[1].cumulative_work), chain.get_cumulative_work(&b1.hash.unwrap()));
+        assert!(tips[0].cumulative_work > tips[1].cumulative_work);
+
+        // the fork overtakes, and is listed first
+        let b2 = mined_block_on(&mut chain, b1.hash.unwrap(), vec![signed_transaction(0)], [2; 32], now + 3).await;
+        chain.add_new_block(b2.clone()).unwrap();
+        let b3 = mined_block_on(&mut chain, b2.hash.unwrap(), vec![signed_transaction(0)], [2; 32], now + 4).await;
+        chain.add_new_block(b3.clone()).unwrap();
+        let tips = chain.all_tips();
+        assert_eq!(tips[0].hash, b3.hash.unwrap());
+        assert_eq!(tips[0].header.depth, 3);
+        assert!(tips.iter().all(|tip| chain.leaves.contains(&tip.hash)));
+        assert!(tips.windows(2).all(|pair| pair[0].cumulative_work >= pair[1].cumulative_work));
+    }
+
+    #[tokio::test]
+    async fn test_work_delta() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+        // three blocks on one chain, one on the other - every block at the minimum difficulty
+        let mut tip_a = genesis_hash;
+        for i in 0..3 {
+            let block = mined_block_on(&mut chain, tip_a, vec![signed_transaction(0)], [1; 32], now + i).await;
+            tip_a = block.hash.unwrap();
+            chain.add_new_block(block).unwrap();
+        }
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [2; 32], now + 3).await;
+        let tip_b = b1.hash.unwrap();
+        chain.add_new_block(b1).unwrap();
+
+        let block_work = get_work_from_difficulty(MIN_DIFFICULTY) as i128;
+        assert_eq!(chain.work_delta(&tip_a, &tip_b), Some(2 * block_work));
+        assert_eq!(chain.work_delta(&tip_b, &tip_a), Some(-2 * block_work));
+        assert_eq!(chain.work_delta(&tip_a, &genesis_hash), Some(3 * block_work));
+        assert_eq!(chain.work_delta(&tip_a, &tip_a), Some(0));
+        assert_eq!(chain.work_delta(&tip_a, &[9; 32]), None);
+    }
+
+    #[tokio::test]
+    async fn test_canonical_block_at() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let (miner_a, miner_b) = ([1; 32], [2; 32]);
+        let now = chain.clock.now();
+
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], miner_a, now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], miner_b, now + 1).await;
+        chain.add_new_block(b1.clone()).unwrap();
+        // two blocks at depth 1, and the first one is on the deepest chain
+        assert_ne!(a1.hash, b1.hash);
+        assert_eq!(chain.canonical_block_at(0).unwrap().hash, Some(genesis_hash));
+        assert_eq!(chain.canonical_block_at(1).unwrap().hash, a1.hash);
+        assert!(chain.canonical_block_at(2).is_none());
+
+        // the other fork overtakes it
+        let b2 = mined_block_on(&mut chain, b1.hash.unwrap(), vec![signed_transaction(0)], miner_b, now + 2).await;
+        chain.add_new_block(b2.clone()).unwrap();
+        assert_eq!(chain.canonical_block_at(1).unwrap().hash, b1.hash);
+        assert_eq!(chain.canonical_block_at(2).unwrap().hash, b2.hash);
+        assert_eq!(chain.canonical_block_at(0).unwrap().hash, Some(genesis_hash));
+    }
+
+    #[tokio::test]
+    async fn test_reorg_event_carries_unconfirmed_transactions() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let events = chain.subscribe();
+        let now = chain.clock.now();
+        let (shared, orphaned, replacement) = (signed_transaction(0), signed_transaction(0), signed_transaction(0));
+
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![shared, orphaned], [1; 32], now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        assert_eq!(events.try_recv(), Ok(ChainEvent::NewTip(a1.hash.unwrap())));
+
+        // a competing block at the same depth does not move the tip
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![shared], [2; 32], now + 1).await;
+        chain.add_new_block(b1.clone()).unwrap();
+        assert!(events.try_recv().is_err());
+
+        // once it is extended, the shared transaction is confirmed again and only the other one is lost
+        let b2 = mined_block_on(&mut chain, b1.hash.unwrap(), vec![replacement], [2; 32], now + 2).await;
+        chain.add_new_block(b2.clone()).unwrap();
+        assert_eq!(events.try_recv(), Ok(ChainEvent::Reorg {
+            old_tip: a1.hash.unwrap(),
+            new_tip: b2.hash.unwrap(),
+            unconfirmed: vec![orphaned],
+        }));
+        assert!(events.try_recv().is_err());
+
+        // dropped subscribers are forgotten
+        drop(events);
+        let b3 = mined_block_on(&mut chain, b2.hash.unwrap(), vec![signed_transaction(0)], [2; 32], now + 3).await;
+        chain.add_new_block(b3).unwrap();
+        assert!(chain.subscribers.is_empty());
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        // (connected, block hash) in the order seen
+        events: std::sync::Mutex<Vec<(bool, StdByteArray)>>,
+    }
+
+    impl BlockObserver for RecordingObserver {
+        fn on_connect(&self, block: &Block) {
+            self.events.lock().unwrap().push((true, block.hash.unwrap()));
+        }
+
+        fn on_disconnect(&self, block: &Block) {
+            self.events.lock().unwrap().push((false, block.hash.unwrap()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block_observer() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let observer = Arc::new(RecordingObserver::default());
+        chain.add_observer(observer.clone());
+        let now = chain.clock.now();
+
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [1; 32], now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        let a2 = mined_block_on(&mut chain, a1.hash.unwrap(), vec![signed_transaction(0)], [1; 32], now + 1).await;
+        chain.add_new_block(a2.clone()).unwrap();
+        // a fork that does not move the tip is not observed
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [2; 32], now + 2).await;
+        chain.add_new_block(b1.clone()).unwrap();
+        let b2 = mined_block_on(&mut chain, b1.hash.unwrap(), vec![signed_transaction(0)], [2; 32], now + 3).await;
+        chain.add_new_block(b2.clone()).unwrap();
+        assert_eq!(observer.events.lock().unwrap().len(), 2);
+        // the fork overtakes - the old branch disconnects tip first, then the new branch connects in order
+        let b3 = mined_block_on(&mut chain, b2.hash.unwrap(), vec![signed_transaction(0)], [2; 32], now + 4).await;
+        chain.add_new_block(b3.clone()).unwrap();
+
+        let hashes = |blocks: &[&Block]| blocks.iter().map(|block| block.hash.unwrap()).collect::<Vec<_>>();
+        let expected = [
+            hashes(&[&a1, &a2]).into_iter().map(|hash| (true, hash)).collect::<Vec<_>>(),
+            hashes(&[&a2, &a1]).into_iter().map(|hash| (false, hash)).collect(),
+            hashes(&[&b1, &b2, &b3]).into_iter().map(|hash| (true, hash)).collect(),
+        ].concat();
+        assert_eq!(*observer.events.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_index() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+        let (abandoned, moved, forked) = (signed_transaction(0), signed_transaction(0), signed_transaction(0));
+
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![abandoned], [1; 32], now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        // off until enabled
+        assert_eq!(chain.block_containing(&abandoned.hash), None);
+        // enabling indexes the chain so far
+        chain.enable_transaction_index();
+        assert_eq!(chain.block_containing(&abandoned.hash), a1.hash);
+
+        let a2 = mined_block_on(&mut chain, a1.hash.unwrap(), vec![moved], [1; 32], now + 1).await;
+        chain.add_new_block(a2.clone()).unwrap();
+        assert_eq!(chain.block_containing(&moved.hash), a2.hash);
+        // blocks off the deepest chain are not indexed
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![forked], [2; 32], now + 2).await;
+        chain.add_new_block(b1.clone()).unwrap();
+        let b2 = mined_block_on(&mut chain, b1.hash.unwrap(), vec![moved], [2; 32], now + 3).await;
+        chain.add_new_block(b2.clone()).unwrap();
+        assert_eq!(chain.block_containing(&forked.hash), None);
+        assert_eq!(chain.block_containing(&moved.hash), a2.hash);
+
+        // the fork overtakes - moved transactions are re-pointed, abandoned ones dropped
+        let b3 = mined_block_on(&mut chain, b2.hash.unwrap(), vec![signed_transaction(0)], [2; 32], now + 4).await;
+        chain.add_new_block(b3.clone()).unwrap();
+        assert_eq!(chain.deepest_hash, b3.hash.unwrap());
+        assert_eq!(chain.block_containing(&moved.hash), b2.hash);
+        assert_eq!(chain.block_containing(&forked.hash), b1.hash);
+        assert_eq!(chain.block_containing(&abandoned.hash), None);
+        assert_eq!(chain.block_containing(&b3.transactions[0].hash), b3.hash);
+        assert_eq!(chain.block_containing(&[42; 32]), None);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_reorg() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let observer = Arc::new(RecordingObserver::default());
+        chain.add_observer(observer.clone());
+        let events = chain.subscribe();
+        let now = chain.clock.now();
+        let shared = signed_transaction(0);
+
+        let mut a = vec![];
+        for (i, transactions) in [vec![shared], vec![signed_transaction(0)], vec![signed_transaction(0)]].into_iter().enumerate() {
+            let parent = a.last().map_or(genesis_hash, |block: &Block| block.hash.unwrap());
+            let block = mined_block_on(&mut chain, parent, transactions, [1; 32], now + i as u64).await;
+            chain.add_new_block(block.clone()).unwrap();
+            a.push(block);
+        }
+        // a side chain as deep as the tip, which does not move it
+        let mut b = vec![];
+        for (i, transactions) in [vec![signed_transaction(0)], vec![shared], vec![signed_transaction(0)]].into_iter().enumerate() {
+            let parent = b.last().map_or(genesis_hash, |block: &Block| block.hash.unwrap());
+            let block = mined_block_on(&mut chain, parent, transactions, [2; 32], now + 3 + i as u64).await;
+            chain.add_new_block(block.clone()).unwrap();
+            b.push(block);
+        }
+        let old_tip = chain.deepest_hash;
+        assert_eq!(old_tip, a[2].hash.unwrap());
+        let old_root = chain.get_state_root().unwrap();
+        let new_tip = b[2].hash.unwrap();
+
+        let plan = chain.simulate_reorg(new_tip).unwrap();
+        // nothing changed
+        assert_eq!(chain.deepest_hash, old_tip);
+        assert_eq!(chain.get_state_root(), Some(old_root));
+        assert!(chain.simulate_reorg([42; 32]).is_none());
+        let hashes = |blocks: &[Block]| blocks.iter().map(|block| block.hash.unwrap()).collect::<Vec<_>>();
+        assert_eq!(plan.orphaned, hashes(&a).into_iter().rev().collect::<Vec<_>>());
+        assert_eq!(plan.connected, hashes(&b));
+        // the shared transaction is mined on both branches, so it stays confirmed
+        assert_eq!(plan.unconfirmed, vec![a[1].transactions[0], a[2].transactions[0]]);
+        // each miner's rewards move to the other
+        let changed = plan.state_changes.iter().map(|diff| diff.address).collect::<HashSet<_>>();
+        assert!(changed.contains(&[1; 32]) && changed.contains(&[2; 32]));
+
+        // extending the side chain carries the reorg out, with the planned effect
+        observer.events.lock().unwrap().clear();
+        let b4 = mined_block_on(&mut chain, new_tip, vec![signed_transaction(0)], [2; 32], now + 6).await;
+        chain.add_new_block(b4.clone()).unwrap();
+        let observed = observer.events.lock().unwrap().clone();
+        let orphaned = observed.iter().filter(|(connected, _)| !connected).map(|(_, hash)| *hash).collect::<Vec<_>>();
+        let connected = observed.iter().filter(|(connected, _)| *connected).map(|(_, hash)| *hash).collect::<Vec<_>>();
+        assert_eq!(orphaned, plan.orphaned);
+        assert_eq!(connected, [plan.connected.clone(), vec![b4.hash.unwrap()]].concat());
+        let unconfirmed = events.drain().find_map(|event| match event {
+            ChainEvent::Reorg { unconfirmed, .. } => Some(unconfirmed),
+            _ => None,
+        }).unwrap();
+        assert_eq!(unconfirmed, plan.unconfirmed);
+        let at = |root| chain.state_manager.snapshot(root, &mut DefaultHash::new()).unwrap();
+        assert_eq!(diff_states(&at(old_root), &at(chain.headers[&new_tip].state_root.unwrap())), plan.state_changes);
+    }
+
+    #[tokio::test]
+    async fn test_required_block() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+        let anchored = |required: StdByteArray| {
+            let mut signing_key = DefaultSigner::generate_random();
+            let transaction = Transaction::new(signing_key.get_verifying_function().to_bytes(), [3; 32], 0, 0, 0, &mut DefaultHash::new());
+            let mut anchored = transaction.with_required_block(required, &mut DefaultHash::new());
+            assert_ne!(anchored.hash, transaction.hash);
+            anchored.sign(&mut signing_key);
+            anchored
+        };
+
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![anchored(genesis_hash)], [1; 32], now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![anchored(genesis_hash)], [2; 32], now + 1).await;
+        chain.add_new_block(b1.clone()).unwrap();
+        assert!(chain.is_ancestor(genesis_hash, a1.hash.unwrap()));
+        assert!(!chain.is_ancestor(b1.hash.unwrap(), a1.hash.unwrap()));
+
+        // anchored to an ancestor
+        let transaction = anchored(a1.hash.unwrap());
+        let state_root = chain.get_state_root().unwrap();
+        assert!(chain.validate_transaction(&transaction, state_root).is_ok());
+        let a2 = mined_block_on(&mut chain, a1.hash.unwrap(), vec![transaction], [1; 32], now + 2).await;
+        chain.add_new_block(a2.clone()).unwrap();
+        assert_eq!(chain.deepest_hash, a2.hash.unwrap());
+
+        // anchored to a block on another fork, or to no known block
+        for required in [b1.hash.unwrap(), [9; 32]] {
+            let transaction = anchored(required);
+            let state_root = chain.get_state_root().unwrap();
+            assert!(matches!(chain.validate_transaction(&transaction, state_root), Err(BlockValidationError::MissingRequiredBlock(hash)) if hash == required));
+            let block = mined_block_on(&mut chain, a2.hash.unwrap(), vec![transaction], [1; 32], now + 3).await;
+            assert!(matches!(chain.add_new_block(block), Err(BlockValidationError::MissingRequiredBlock(hash)) if hash == required));
+        }
+        assert_eq!(chain.deepest_hash, a2.hash.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_replay_chain() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis = chain.blocks[&chain.deepest_hash].clone();
+        let now = chain.clock.now();
+        let miner = [1; 32];
+        let mut blocks = vec![genesis];
+        for i in 0..3 {
+            let block = mined_block_at(&mut chain, vec![signed_transaction(0)], miner, now + i).await;
+            chain.add_new_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+
+        let replayed = replay_chain(&blocks).unwrap();
+        assert_eq!(replayed.get_state_root(), chain.get_state_root());
+        let balance = |chain: &Chain| chain.state_manager.get_account(&miner, chain.get_state_root().unwrap()).unwrap().balance;
+        assert_eq!(balance(&replayed), balance(&chain));
+
+        // a tampered transaction no longer matches the merkle root
+        let mut tampered = blocks.clone();
+        tampered[2].transactions[0].header.amount = 5;
+        assert!(matches!(replay_chain(&tampered), Err((2, _))));
+        // a gap in the chain
+        let gap = [blocks[0].clone(), blocks[2].clone()];
+        assert!(matches!(replay_chain(&gap), Err((1, BlockValidationError::HashMismatch(_, _)))));
+        // not our genesis
+        assert!(matches!(replay_chain(&blocks[1..]), Err((0, BlockValidationError::HashMismatch(_, _)))));
+        assert!(replay_chain(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_step() {
+        let mut chain = Chain::new_with_genesis();
+        let now = chain.clock.now();
+        let mut blocks = vec![chain.blocks[&chain.deepest_hash].clone()];
+        for i in 0..4 {
+            let block = mined_block_at(&mut chain, vec![signed_transaction(0)], [1; 32], now + i).await;
+            chain.add_new_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+        let params = ChainParams::default();
+        let verify = |blocks: &[Block], mut cursor: VerificationCursor, state_manager: &mut StateManager| {
+            for block in blocks {
+                cursor = verify_step(cursor, block, state_manager, &params, now + 10).unwrap();
+            }
+            cursor
+        };
+
+        // two sittings, resuming from the saved cursor, match the replayed chain
+        let fresh = Chain::new_with_genesis();
+        let mut state_manager = fresh.state_manager.clone();
+        let paused = verify(&blocks[1..3], VerificationCursor::new(fresh.get_tip()), &mut state_manager);
+        assert_eq!(paused.tip.hash, blocks[2].hash.unwrap());
+        let resumed = verify(&blocks[3..], paused, &mut state_manager);
+        let replayed = replay_chain(&blocks).unwrap();
+        assert_eq!(resumed.tip, replayed.get_tip());
+        assert_eq!(resumed.tip, chain.get_tip());
+        assert_eq!(resumed.tip.header.state_root, replayed.get_state_root());
+
+        // a tampered block stops the cursor at the block before it, where replaying fails too
+        let mut tampered = blocks.clone();
+        tampered[3].transactions[0].header.amount = 5;
+        assert!(matches!(replay_chain(&tampered), Err((3, _))));
+        let fresh = Chain::new_with_genesis();
+        let mut state_manager = fresh.state_manager.clone();
+        let mut cursor = VerificationCursor::new(fresh.get_tip());
+        let mut failed_at = None;
+        for (index, block) in tampered.iter().enumerate().skip(1) {
+            match verify_step(cursor.clone(), block, &mut state_manager, &params, now + 10) {
+                Ok(next) => cursor = next,
+                Err(_) => {
+                    failed_at = Some(index);
+                    break;
+                }
+            }
+        }
+        assert_eq!(failed_at, Some(3));
+        assert_eq!(cursor.tip.hash, blocks[2].hash.unwrap());
+        // a block that does not follow the cursor
+        assert!(verify_step(cursor, &blocks[4], &mut state_manager, &params, now + 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_step_checks_like_chain() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis = chain.get_tip();
+        let now = chain.clock.now();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut first = Transaction::new(sender, [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        first.sign(&mut signing_key);
+        let block = mined_block_at(&mut chain, vec![first], [1; 32], now).await;
+        chain.add_new_block(block.clone()).unwrap();
+        // a transaction requiring the genesis block, two blocks down
+        let mut second = Transaction::new(sender, [1; 32], 0, 0, 1, &mut DefaultHash::new()).with_required_block(genesis.hash, &mut DefaultHash::new());
+        second.sign(&mut signing_key);
+        let child = mined_block_at(&mut chain, vec![second], [1; 32], now + 1).await;
+        chain.add_new_block(child.clone()).unwrap();
+
+        let fresh = Chain::new_with_genesis();
+        let mut state_manager = fresh.state_manager.clone();
+        let cursor = verify_step(VerificationCursor::new(genesis), &block, &mut state_manager, &fresh.params, now + 10).unwrap();
+        assert!(verify_step(cursor.clone(), &child, &mut state_manager, &fresh.params, now + 10).is_ok());
+        // the required block is checked against the ancestors the cursor carries
+        let mut stranger = Transaction::new(sender, [1; 32], 0, 0, 1, &mut DefaultHash::new()).with_required_block([9; 32], &mut DefaultHash::new());
+        stranger.sign(&mut signing_key);
+        let orphaned = mined_block_on(&mut chain, block.hash.unwrap(), vec![stranger], [2; 32], now + 1).await;
+        assert!(matches!(chain.add_new_block(orphaned.clone()), Err(BlockValidationError::MissingRequiredBlock(_))));
+        assert!(matches!(
+            verify_step(cursor.clone(), &orphaned, &mut state_manager, &fresh.params, now + 10),
+            Err(BlockValidationError::MissingRequiredBlock(_))
+        ));
+        // and so is the checkpoint
+        let params = ChainParams { checkpoint: Some(Checkpoint { depth: 2, hash: [9; 32] }), ..fresh.params };
+        assert!(matches!(verify_step(cursor, &child, &mut state_manager, &params, now + 10), Err(BlockValidationError::CheckpointMismatch(2))));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_hash() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+        let (mined, forked) = (signed_transaction(0), signed_transaction(0));
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![mined], [1; 32], now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        let a2 = mined_block_on(&mut chain, a1.hash.unwrap(), vec![signed_transaction(0)], [1; 32], now + 1).await;
+        chain.add_new_block(a2.clone()).unwrap();
+        let b1 = mined_block_on(&mut chain, genesis_hash, vec![forked], [2; 32], now + 2).await;
+        chain.add_new_block(b1.clone()).unwrap();
+
+        assert_eq!(chain.lookup_hash(&a1.hash.unwrap()), HashLookup::Block(Box::new(a1.clone())));
+        assert_eq!(chain.lookup_hash(&genesis_hash), HashLookup::Block(Box::new(chain.blocks[&genesis_hash].clone())));
+        assert_eq!(chain.lookup_hash(&mined.hash), HashLookup::Transaction(Box::new(mined), a1.hash.unwrap()));
+        // off the deepest chain
+        assert_eq!(chain.lookup_hash(&forked.hash), HashLookup::Transaction(Box::new(forked), b1.hash.unwrap()));
+        assert_eq!(chain.lookup_hash(&[42; 32]), HashLookup::Unknown);
+        // pooled but unmined transactions are unknown to the chain
+        assert_eq!(chain.lookup_hash(&signed_transaction(1).hash), HashLookup::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_tie_break() {
+        for tie_break in [TieBreak::FirstSeen, TieBreak::LowestHash] {
+            // the same competing blocks, arriving in either order
+            for reversed in [false, true] {
+                let mut chain = Chain::new_with_genesis();
+                chain.tie_break = tie_break;
+                let genesis_hash = chain.deepest_hash;
+                let now = chain.clock.now();
+                let a1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [1; 32], now).await;
+                let b1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [2; 32], now).await;
+                let (first, second) = if reversed { (b1, a1) } else { (a1, b1) };
+                chain.add_new_block(first.clone()).unwrap();
+                chain.add_new_block(second.clone()).unwrap();
+                let (first, second) = (first.hash.unwrap(), second.hash.unwrap());
+                let expected = match tie_break {
+                    TieBreak::FirstSeen => first,
+                    TieBreak::LowestHash => first.min(second),
+                };
+                assert_eq!(chain.deepest_hash, expected);
+                // the other block is still tracked
+                assert!(chain.leaves.contains(&first) && chain.leaves.contains(&second));
+
+                // a deeper chain takes over, whichever block it builds on
+                let other = if expected == first { second } else { first };
+                let extension = mined_block_on(&mut chain, other, vec![signed_transaction(0)], [2; 32], now + 1).await;
+                chain.add_new_block(extension.clone()).unwrap();
+                assert_eq!(chain.deepest_hash, extension.hash.unwrap());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_blocks_deduplicated() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut first = Transaction::new(sender, [3; 32], 0, 0, 0, &mut DefaultHash::new());
+        first.sign(&mut signing_key);
+        let mut second = Transaction::new(sender, [3; 32], 0, 0, 1, &mut DefaultHash::new());
+        second.sign(&mut signing_key);
+
+        let original = mined_block_on(&mut chain, genesis_hash, vec![first], [1; 32], now).await;
+        chain.add_new_block(original.clone()).unwrap();
+        // a competitor with the same transactions, from another miner, is processed
+        let competitor = mined_block_on(&mut chain, genesis_hash, vec![first], [2; 32], now).await;
+        assert_eq!(competitor.header.merkle_root, original.header.merkle_root);
+        assert_ne!(competitor.hash, original.hash);
+        chain.add_new_block(competitor.clone()).unwrap();
+        assert!(chain.get_block(&competitor.hash.unwrap()).is_some());
+
+        // an exact duplicate is not validated again - it would fail now, as its timestamp looks too far ahead
+        chain.clock = Clock::mock(now - chain.params.max_future_drift - 1);
+        assert!(chain.verify_block(&original).is_err());
+        assert!(chain.add_new_block(original.clone()).is_ok());
+        chain.clock = Clock::mock(now);
+
+        // a rejected block is remembered - the nonce was used by its parent
+        let rejected = mined_block_on(&mut chain, original.hash.unwrap(), vec![first], [1; 32], now + 1).await;
+        assert!(matches!(chain.add_new_block(rejected.clone()), Err(BlockValidationError::TransactionNonceMismatch(1, 0))));
+        let hash = rejected.hash.unwrap();
+        assert!(matches!(chain.add_new_block(rejected), Err(BlockValidationError::AlreadyRejected(h)) if h == hash));
+
+        // a block that only claims the hash of a valid one does not get the valid one rejected
+        let valid = mined_block_on(&mut chain, original.hash.unwrap(), vec![second], [1; 32], now + 1).await;
+        let mut forged = valid.clone();
+        forged.header.timestamp += 1;
+        assert!(chain.add_new_block(forged).is_err());
+        // nor does the valid header with a bad signature
+        let mut forged = valid.clone();
+        forged.transactions[0].signature = Some([1; 64]);
+        assert!(matches!(chain.add_new_block(forged.clone()), Err(BlockValidationError::TransactionInvalidSignature)));
+        assert!(matches!(chain.add_new_block(forged), Err(BlockValidationError::AlreadyRejected(_))));
+        // nor a transaction changed under its original signature
+        let mut forged = valid.clone();
+        forged.transactions[0].header.amount += 1;
+        assert!(chain.add_new_block(forged).is_err());
+        chain.add_new_block(valid.clone()).unwrap();
+        assert_eq!(chain.deepest_hash, valid.hash.unwrap());
+
+        // once the block is known, resubmitting it is a no-op
+        let known = chain.blocks.len();
+        chain.add_new_block(valid.clone()).unwrap();
+        assert_eq!(chain.blocks.len(), known);
+        // but different contents under its hash are rejected, and the known block is kept
+        let mut collision = valid.clone();
+        collision.transactions[0].signature = Some([2; 64]);
+        let hash = valid.hash.unwrap();
+        assert!(matches!(chain.add_new_block(collision), Err(BlockValidationError::HashCollision(h)) if h == hash));
+        assert_eq!(chain.get_block(&hash), Some(&valid));
+        assert_eq!(chain.blocks.len(), known);
+    }
+
+    #[tokio::test]
+    async fn test_side_chains_capped() {
+        let mut chain = Chain::new_with_genesis();
+        chain.max_side_chains = 2;
+        chain.finality_depth = 2;
+        let genesis_hash = chain.deepest_hash;
+        let now = chain.clock.now();
+
+        let a1 = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [1; 32], now).await;
+        chain.add_new_block(a1.clone()).unwrap();
+        let a2 = mined_block_on(&mut chain, a1.hash.unwrap(), vec![signed_transaction(0)], [1; 32], now + 1).await;
+        chain.add_new_block(a2.clone()).unwrap();
+        let a3 = mined_block_on(&mut chain, a2.hash.unwrap(), vec![signed_transaction(0)], [1; 32], now + 2).await;
+        chain.add_new_block(a3.clone()).unwrap();
+        // a fork one block behind, and a competitor tied with the tip
+        let b2 = mined_block_on(&mut chain, a1.hash.unwrap(), vec![signed_transaction(0)], [2; 32], now + 3).await;
+        chain.add_new_block(b2.clone()).unwrap();
+        let c3 = mined_block_on(&mut chain, a2.hash.unwrap(), vec![signed_transaction(0)], [3; 32], now + 4).await;
+        chain.add_new_block(c3.clone()).unwrap();
+        // many weak forks off genesis
+        let mut weak = vec![];
+        for i in 0..5 {
+            let block = mined_block_on(&mut chain, genesis_hash, vec![signed_transaction(0)], [4; 32], now + 5 + i).await;
+            chain.add_new_block(block.clone()).unwrap();
+            weak.push(block.hash.unwrap());
+        }
+
+        assert_eq!(chain.deepest_hash, a3.hash.unwrap());
+        let leaves = [a3.hash.unwrap(), b2.hash.unwrap(), c3.hash.unwrap()];
+        assert_eq!(chain.leaves, HashSet::from(leaves));
+        assert!(weak.iter().all(|hash| !chain.blocks.contains_key(hash)));
+        assert!(leaves.iter().all(|hash| chain.blocks.contains_key(hash)));
+
+        // nothing is pruned while every fork is above finality
+        assert_eq!(chain.prune_side_chains(&a3.hash.unwrap(), 0, FINALITY_DEPTH), 0);
+        // the competitor forked above finality, so it survives even without room
+        assert_eq!(chain.prune_side_chains(&a3.hash.unwrap(), 0, 2), 1);
+        assert_eq!(chain.leaves, HashSet::from([a3.hash.unwrap(), c3.hash.unwrap()]));
+        assert!(!chain.blocks.contains_key(&b2.hash.unwrap()));
+        // blocks shared with the best chain stay
+        assert!([genesis_hash, a1.hash.unwrap(), a2.hash.unwrap()].iter().all(|hash| chain.blocks.contains_key(hash)));
+        // and so does their state, even as pruning is repeated
+        assert_eq!(chain.prune_side_chains(&a3.hash.unwrap(), 0, 2), 0);
+        chain.remove_header(&b2.hash.unwrap());
+        for hash in [a1.hash.unwrap(), a2.hash.unwrap(), a3.hash.unwrap(), c3.hash.unwrap()] {
+            assert!(chain.state_manager.get_account(&[1; 32], chain.headers[&hash].state_root.unwrap()).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transactions_with_namespace() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut tagged = vec![];
+        for (nonce, namespace) in [Some(*b"app1"), None, Some(*b"app2"), Some(*b"app1")].into_iter().enumerate() {
+            let mut transaction = Transaction::new(sender, [1; 32], 0, 0, nonce as u64, &mut DefaultHash::new());
+            if let Some(namespace) = namespace {
+                transaction = transaction.with_namespace(namespace, &mut DefaultHash::new());
+            }
+            transaction.sign(&mut signing_key);
+            tagged.push(transaction.hash);
+            let block = mined_block_at(&mut chain, vec![transaction], [2; 32], nonce as u64 + 1).await;
+            chain.add_new_block(block).unwrap();
+        }
+        let hashes = |namespace: &Namespace, depths: Range<u64>| chain.transactions_with_namespace(namespace, depths)
+            .iter().map(|transaction| transaction.hash).collect::<Vec<_>>();
+        assert_eq!(hashes(b"app1", 0..10), vec![tagged[0], tagged[3]]);
+        assert_eq!(hashes(b"app2", 0..10), vec![tagged[2]]);
+        // blocks 1 to 3, so the last app1 transaction, at depth 4, is out of range
+        assert_eq!(hashes(b"app1", 1..4), vec![tagged[0]]);
+        assert!(hashes(b"app1", 2..4).is_empty());
+        assert!(hashes(b"none", 0..10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocks_by_miner() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let mut key_a = DefaultSigner::generate_random();
+        let miner_a = key_a.get_verifying_function().to_bytes();
+        let miner_b = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+        let mut mined = vec![];
+        for (nonce, miner) in [miner_a, miner_b, miner_a].into_iter().enumerate() {
+            let mut transaction = Transaction::new(miner_a, [1; 32], 0, 0, nonce as u64, &mut DefaultHash::new());
+            transaction.sign(&mut key_a);
+            let block = mined_block_at(&mut chain, vec![transaction], miner, nonce as u64 + 1).await;
+            mined.push(block.hash.unwrap());
+            chain.add_new_block(block).unwrap();
+        }
+        assert_eq!(chain.blocks_by_miner(&miner_a), vec![mined[0], mined[2]]);
+        assert_eq!(chain.blocks_by_miner(&miner_b), vec![mined[1]]);
+        assert_eq!(chain.blocks_by_miner(&[0; 32]), vec![genesis_hash]);
+        assert!(chain.blocks_by_miner(&[7; 32]).is_empty());
+
+        // the index follows blocks removed from the chain
+        chain.remove_header(&mined[2]);
+        assert_eq!(chain.blocks_by_miner(&miner_a), vec![mined[0]]);
+        chain.remove_header(&mined[1]);
+        assert!(chain.blocks_by_miner(&miner_b).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reward_policy_enforced() {
+        let mut chain = Chain::new_with_genesis();
+        chain.state_manager.reward_policy = Arc::new(TreasuryRewardPolicy { treasury: [9; 32], treasury_percent: 10 });
+        let mut signing_key = DefaultSigner::generate_random();
+        let miner = signing_key.get_verifying_function().to_bytes();
+        let mut stamper = DefaultSigner::generate_random();
+        let mut transaction = Transaction::new(miner, [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        let mut block = Block::new(
+            chain.deepest_hash, 
+            0, 
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            vec![transaction],
+            Some(miner),
+            BlockTail::default().stamps,
+            1,
+            None,
+            None,
+            &mut DefaultHash::new()
+        );
+        // a stamp, so the block pays a reward
+        let signature = stamper.sign(&block.header);
+        block.header.tail.stamp(Stamp { address: stamper.get_verifying_function().to_bytes(), signature }).unwrap();
+        let prev_header = chain.headers[&block.header.previous_hash];
+
+        // state computed under a different policy is rejected
+        let mut miner_only = chain.state_manager.clone();
+        miner_only.reward_policy = Arc::new(MinerRewardPolicy);
+        let mut wrong = block.clone();
+        let wrong_root = miner_only.branch_from_block(&wrong, &prev_header);
+        mine(&mut wrong, miner, wrong_root, vec![], &chain.params, None, DefaultHash::new()).await;
+        assert!(chain.add_new_block(wrong).is_err());
+
+        let state_root = chain.state_manager.branch_from_block(&block, &prev_header);
+        mine(&mut block, miner, state_root, vec![], &chain.params, None, DefaultHash::new()).await;
+        chain.add_new_block(block).unwrap();
+        let reward = get_reward_from_depth_and_stampers(1, 1);
+        assert!(reward >= 10);
+        let state_root = chain.get_state_root().unwrap();
+        assert_eq!(chain.state_manager.get_account(&[9; 32], state_root).unwrap().balance, reward / 10);
+        assert_eq!(chain.state_manager.get_account(&miner, state_root).unwrap().balance, reward - reward / 10);
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let mut chain = Chain::new_with_genesis();
+        let mut old_key = DefaultSigner::generate_random();
+        let mut new_key = DefaultSigner::generate_random();
+        let address = old_key.get_verifying_function().to_bytes();
+        let rotated_to = new_key.get_verifying_function().to_bytes();
+
+        // signed by the current key
+        let mut rotation = Transaction::new_key_rotation(address, rotated_to, 0, 0, &mut DefaultHash::new());
+        rotation.sign(&mut old_key);
+        let block = mined_block(&mut chain, vec![rotation], address).await;
+        chain.add_new_block(block).unwrap();
+        let account = chain.state_manager.get_account(&address, chain.get_state_root().unwrap()).unwrap();
+        assert_eq!(account.authorized_key, Some(rotated_to));
+        assert_eq!(account.nonce, 1);
+
+        // the old key no longer signs for the account
+        let mut old_spend = Transaction::new(address, [1; 32], 0, 0, 1, &mut DefaultHash::new());
+        old_spend.sign(&mut old_key);
+        let block = mined_block(&mut chain, vec![old_spend], address).await;
+        assert!(matches!(chain.add_new_block(block), Err(BlockValidationError::TransactionInvalidSignature)));
+
+        let mut new_spend = Transaction::new(address, [1; 32], 0, 0, 1, &mut DefaultHash::new());
+        new_spend.sign(&mut new_key);
+        let block = mined_block(&mut chain, vec![new_spend], address).await;
+        assert!(chain.add_new_block(block).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_confirmations() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let miner = signing_key.get_verifying_function().to_bytes();
+        let mut transactions = vec![];
+        for nonce in 0..12 {
+            let mut transaction = Transaction::new(miner, [1; 32], 0, 0, nonce, &mut DefaultHash::new());
+            transaction.sign(&mut signing_key);
+            transactions.push(transaction);
+            let block = mined_block(&mut chain, vec![transaction], miner).await;
+            chain.add_new_block(block).unwrap();
+        }
+        assert_eq!(chain.confirmations(transactions[11].hash), 1);
+        assert_eq!(chain.confirmations(transactions[9].hash), 3);
+        assert_eq!(chain.confirmations(transactions[0].hash), 12);
+        assert_eq!(chain.confirmations([7; 32]), 0);
+        // settling blocks into the history of the miner does not lose them from the chain
+        let account = chain.state_manager.get_account(&miner, chain.get_state_root().unwrap()).unwrap();
+        assert_eq!(account.history.unwrap().n_blocks_mined(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_payment_proof() {
+        let mut chain = Chain::new_with_genesis();
+        let mut transactions = vec![];
+        for _ in 0..4 {
+            let transaction = signed_transaction(0);
+            transactions.push(transaction);
+        }
+        let first = mined_block(&mut chain, transactions[..2].to_vec(), [2; 32]).await;
+        chain.add_new_block(first.clone()).unwrap();
+        let second = mined_block(&mut chain, transactions[2..].to_vec(), [2; 32]).await;
+        chain.add_new_block(second.clone()).unwrap();
+
+        let payment_proof = chain.payment_proof(transactions[1].hash).unwrap();
+        assert_eq!(payment_proof.block_hash, first.hash.unwrap());
+        assert!(verify_payment_proof(&payment_proof).is_ok());
+        let payment_proof = chain.payment_proof(transactions[3].hash).unwrap();
+        assert_eq!(payment_proof.block_hash, second.hash.unwrap());
+        assert!(verify_payment_proof(&payment_proof).is_ok());
+        assert!(chain.payment_proof([9; 32]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_same_block_reward_not_spendable() {
+        let mut chain = Chain::new_with_genesis();
+        let mut miner_key = DefaultSigner::generate_random();
+        let mut stamper = DefaultSigner::generate_random();
+        let miner = miner_key.get_verifying_function().to_bytes();
+        let reward = get_reward_from_depth_and_stampers(1, 1);
+        assert!(reward > 0);
+        let mut spend = Transaction::new(miner, [1; 32], reward, 0, 0, &mut DefaultHash::new());
+        spend.sign(&mut miner_key);
+        let mut other_key = DefaultSigner::generate_random();
+        let mut other = Transaction::new(other_key.get_verifying_function().to_bytes(), [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        other.sign(&mut other_key);
+        // a stamp, so the block pays a reward
+        let genesis = chain.headers[&chain.deepest_hash];
+        let mut stamped = |transactions: Vec<Transaction>| {
+            let mut block = Block::new(
+                chain.deepest_hash, 0, genesis.timestamp + 1, transactions, Some(miner),
+                BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+            );
+            let signature = stamper.sign(&block.header);
+            block.header.tail.stamp(Stamp { address: stamper.get_verifying_function().to_bytes(), signature }).unwrap();
+            block
+        };
+
+        // the reward is only credited after the transactions of its block, so they cannot spend it.
+        // Its state cannot be built, so the block commits to the state of its parent
+        let mut circular = stamped(vec![other, spend]);
+        mine(&mut circular, miner, genesis.state_root.unwrap(), vec![], &chain.params, None, DefaultHash::new()).await;
+        let mut normal = stamped(vec![other]);
+        assert!(matches!(chain.add_new_block(circular), Err(BlockValidationError::TransactionInsufficientBalance(0))));
+        assert_eq!(chain.depth, 0);
+
+        // a normal block is accepted, and pays its reward
+        let state_root = chain.state_manager.branch_from_block(&normal, &genesis);
+        mine(&mut normal, miner, state_root, vec![], &chain.params, None, DefaultHash::new()).await;
+        chain.add_new_block(normal).unwrap();
+        assert_eq!(chain.state_manager.get_account(&miner, chain.get_state_root().unwrap()).unwrap().balance, reward);
+        // from the next block on, the reward is spendable
+        let block = mined_block(&mut chain, vec![spend], [2; 32]).await;
+        chain.add_new_block(block).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_sender_rejected() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut first = Transaction::new(sender, [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        first.sign(&mut signing_key);
+        // by default a never seen sender is an empty account
+        assert!(chain.validate_transaction(&first, chain.get_state_root().unwrap()).is_ok());
+
+        chain.params.soft_forks = SoftForks::default().activate(Rule::RejectUnknownSenders, 0);
+        let result = chain.validate_transaction(&first, chain.get_state_root().unwrap());
+        assert!(matches!(result, Err(BlockValidationError::TransactionUnknownSender(s)) if s == sender));
+        let block = mined_block(&mut chain, vec![first], sender).await;
+        assert!(matches!(chain.add_new_block(block), Err(BlockValidationError::TransactionUnknownSender(s)) if s == sender));
+        // the reason is the missing account, whatever the amount
+        let mut spend = Transaction::new(sender, [1; 32], 10, 0, 0, &mut DefaultHash::new());
+        spend.sign(&mut signing_key);
+        let result = chain.validate_transaction(&spend, chain.get_state_root().unwrap());
+        assert!(matches!(result, Err(BlockValidationError::TransactionUnknownSender(_))));
+
+        // once the account exists, its transactions are checked as usual
+        chain.params.soft_forks = SoftForks::default();
+        let block = mined_block(&mut chain, vec![first], [2; 32]).await;
+        chain.add_new_block(block).unwrap();
+        chain.params.soft_forks = SoftForks::default().activate(Rule::RejectUnknownSenders, 0);
+        let mut second = Transaction::new(sender, [1; 32], 0, 0, 1, &mut DefaultHash::new());
+        second.sign(&mut signing_key);
+        assert!(chain.validate_transaction(&second, chain.get_state_root().unwrap()).is_ok());
+        let result = chain.validate_transaction(&spend, chain.get_state_root().unwrap());
+        assert!(matches!(result, Err(BlockValidationError::TransactionInsufficientBalance(0))));
+    }
+
+    #[tokio::test]
+    async fn test_vrf_proof_required_by_chain() {
+        let mut chain = Chain::new_with_genesis();
+        chain.params.soft_forks = SoftForks::default().activate(Rule::RequireVrfProof, 0);
+        let mut signing_key = DefaultSigner::generate_random();
+        let miner = DefaultSigner::generate_random();
+        let miner_address = miner.get_verifying_function().to_bytes();
+        let mut transaction = Transaction::new(signing_key.get_verifying_function().to_bytes(), [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+
+        let missing = mined_block(&mut chain, vec![transaction], miner_address).await;
+        assert!(matches!(chain.add_new_block(missing), Err(BlockValidationError::MalformedBlock(reason)) if reason == "VRF proof is missing"));
+
+        let parent = chain.deepest_hash;
+        let mut block = Block::new(
+            parent, 0, chain.clock.now(), vec![transaction], Some(miner_address),
+            BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+        );
+        block.set_vrf_proof(&miner.to_bytes());
+        let state_root = chain.state_manager.branch_from_block(&block, &chain.headers[&parent]);
+        mine(&mut block, miner_address, state_root, vec![], &chain.params, None, DefaultHash::new()).await;
+        // a proof by another key is rejected even where proofs are not required
+        let mut forged = block.clone();
+        forged.header.vrf_proof = Some(VrfProof::new(&DefaultSigner::generate_random().to_bytes(), &forged.header.vrf_seed()));
+        mine(&mut forged, miner_address, state_root, vec![], &chain.params, None, DefaultHash::new()).await;
+        chain.params.soft_forks = SoftForks::default();
+        assert!(matches!(chain.add_new_block(forged), Err(BlockValidationError::MalformedBlock(reason)) if reason == "VRF proof is invalid"));
+        chain.params.soft_forks = SoftForks::default().activate(Rule::RequireVrfProof, 0);
+        chain.add_new_block(block.clone()).unwrap();
+        assert_eq!(chain.deepest_hash, block.hash.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_chain_checks_merkle_root() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut first = Transaction::new(sender, [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        first.sign(&mut signing_key);
+        let block = mined_block(&mut chain, vec![first], [2; 32]).await;
+        // a copy carrying another valid transaction than the one its header commits to
+        let mut swapped = block.clone();
+        let mut other = Transaction::new(sender, [3; 32], 0, 0, 0, &mut DefaultHash::new());
+        other.sign(&mut signing_key);
+        swapped.transactions[0] = other;
+        assert!(matches!(chain.add_new_block(swapped), Err(BlockValidationError::MalformedBlock(_))));
+        chain.add_new_block(block.clone()).unwrap();
+        assert_eq!(chain.deepest_hash, block.hash.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_miner_address_checked_by_chain() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let mut transaction = Transaction::new(signing_key.get_verifying_function().to_bytes(), [1; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        let invalid_address = (0..=u8::MAX).map(|b| [b; 32]).find(|address| !DefaultVerifier::is_valid_key(address)).unwrap();
+        chain.params.soft_forks = SoftForks::default().activate(Rule::RequireValidMinerAddress, 2);
+
+        // not enforced below the activation depth of the soft fork
+        let before = mined_block(&mut chain, vec![transaction], invalid_address).await;
+        chain.add_new_block(before).unwrap();
+        // enforced from it
+        let mut next = Transaction::new(transaction.header.sender, [1; 32], 0, 0, 1, &mut DefaultHash::new());
+        next.sign(&mut signing_key);
+        let invalid = mined_block(&mut chain, vec![next], invalid_address).await;
+        assert!(matches!(
+            chain.add_new_block(invalid),
+            Err(BlockValidationError::InvalidMinerAddress(address)) if address == invalid_address
+        ));
+        let valid = mined_block(&mut chain, vec![next], DefaultSigner::generate_random().get_verifying_function().to_bytes()).await;
+        chain.add_new_block(valid).unwrap();
+        assert_eq!(chain.depth, 2);
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation_malformed() {
+        let chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let address = signing_key.get_verifying_function().to_bytes();
+        let new_key = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+        let state_root = chain.get_state_root().unwrap();
+
+        // signed by a key that does not control the account
+        let mut rotation = Transaction::new_key_rotation(address, new_key, 0, 0, &mut DefaultHash::new());
+        rotation.sign(&mut DefaultSigner::generate_random());
+        assert!(matches!(chain.validate_transaction(&rotation, state_root), Err(BlockValidationError::TransactionInvalidSignature)));
+
+        // rotations cannot move funds
+        let mut header = TransactionHeader::new(address, [1; 32], 0, 0, 0);
+        header.rotate_key = Some(new_key);
+        let mut rotation = Transaction { header, hash: header.hash(&mut DefaultHash::new()), signature: None };
+        rotation.sign(&mut signing_key);
+        assert!(matches!(chain.validate_transaction(&rotation, state_root), Err(BlockValidationError::InvalidTransaction(_))));
+
+        // nor lock the account behind an unusable key
+        let mut rotation = Transaction::new_key_rotation(address, [4; 32], 0, 0, &mut DefaultHash::new());
+        rotation.sign(&mut signing_key);
+        assert!(matches!(chain.validate_transaction(&rotation, state_root), Err(BlockValidationError::InvalidTransaction(_))));
+    }
+
     #[tokio::test]
     async fn test_chain_invalid_block() {
         let mut chain = Chain::new_with_genesis();
@@ -466,7 +2383,7 @@ mod tests {
             let prev_header = chain.headers.get(&block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             parent_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -491,7 +2408,7 @@ mod tests {
         let prev_header = chain.headers.get(&fork_block.header.previous_hash)
             .expect("Previous block header not found");
         let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-        mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+        mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
         chain.add_new_block(fork_block.clone()).unwrap();
 
         assert!(chain.blocks.contains_key(&fork_block.hash.unwrap()));
@@ -533,7 +2450,7 @@ mod tests {
             let prev_header = chain.headers.get(&block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             parent_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -556,7 +2473,7 @@ mod tests {
         let prev_header = chain.headers.get(&fork_block.header.previous_hash)
             .expect("Previous block header not found");
         let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-        mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+        mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
         let fork_hash = fork_block.hash.unwrap();
         chain.add_new_block(fork_block).unwrap();
 
@@ -594,7 +2511,7 @@ mod tests {
             let prev_header = chain.headers.get(&block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             main_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -619,7 +2536,7 @@ mod tests {
             let prev_header = chain.headers.get(&fork_block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);   
-            mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             let hash = fork_block.hash.unwrap();
             fork_hashes.push(hash);
             chain.add_new_block(fork_block).unwrap();
@@ -681,7 +2598,7 @@ mod tests {
             let prev_header = chain.headers.get(&block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             main_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -708,7 +2625,7 @@ mod tests {
                 let prev_header = chain.headers.get(&fork_block.header.previous_hash)
                     .expect("Previous block header not found");
                 let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-                mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+                mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
                 parent_hash = fork_block.hash.unwrap();
                 if depth == fork_length {
                     fork_hashes.push(parent_hash);
@@ -765,7 +2682,7 @@ mod tests {
             let prev_header = chain.headers.get(&block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             main_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -790,7 +2707,7 @@ mod tests {
             let prev_header = chain.headers.get(&fork_block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-            mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             fork_hash = fork_block.hash.unwrap();
             chain.add_new_block(fork_block).unwrap();
         }
@@ -835,7 +2752,7 @@ mod tests {
             let prev_header = chain.headers.get(&block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             main_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -860,7 +2777,7 @@ mod tests {
             let prev_header = chain.headers.get(&fork_block.header.previous_hash)
                 .expect("Previous block header not found");
             let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-            mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             fork_hash = fork_block.hash.unwrap();
             chain.add_new_block(fork_block).unwrap();
         }
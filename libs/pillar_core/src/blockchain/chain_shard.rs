@@ -3,9 +3,9 @@ use std::collections::{HashMap, HashSet};
 use pillar_crypto::{hashing::DefaultHash, types::StdByteArray};
 use serde::{Deserialize, Serialize};
 
-use crate::{accounting::{account::Account, state::StateManager}, primitives::{block::BlockHeader, errors::BlockValidationError}, protocol::chain::get_genesis_block};
+use crate::{accounting::{account::Account, state::StateManager}, primitives::{block::BlockHeader, errors::BlockValidationError}, protocol::{chain::get_genesis_block, params::ChainParams, pow::is_committed_difficulty_valid}};
 
-use super::{chain::Chain, TrimmableChain};
+use super::{chain::{Chain, ChainTip}, TrimmableChain};
 
 /// chain shard is used to build up a chain given a list of block headers
 /// It is responsible for the validation and construction of the chain from a new node.
@@ -19,6 +19,12 @@ pub struct ChainShard {
 impl ChainShard{
     /// ensures the hashs are good, and the depths work
     pub fn validate(&self) -> Result<(), BlockValidationError>{
+        self.validate_with_params(&ChainParams::default())
+    }
+
+    /// ensures the hashs are good, the depths work, and the committed difficulties match the retarget
+    /// With a checkpoint in `params`, any header at its depth must be the checkpoint
+    pub fn validate_with_params(&self, params: &ChainParams) -> Result<(), BlockValidationError>{
         let mut genesis_found = false;
         let state_manager = StateManager::new();
         let state_root = state_manager.state_trie
@@ -43,6 +49,16 @@ impl ChainShard{
                 *declared_hash,
                 &mut DefaultHash::new() 
             )?;
+            // a chain that disagrees with the checkpoint is not the chain to sync
+            if let Some(checkpoint) = params.checkpoint
+                && checkpoint.depth == header.depth
+                && checkpoint.hash != *declared_hash {
+                return Err(BlockValidationError::CheckpointMismatch(checkpoint.depth));
+            }
+            // the proof of work is checked against the committed difficulty, which must match the retarget
+            if header.depth != 0 && !is_committed_difficulty_valid(header, params) {
+                return Err(BlockValidationError::MalformedShard("Difficulty target does not match".into()));
+            }
 
             // check the previous hashes exists
             let previous_hash = header.previous_hash;
@@ -50,10 +66,10 @@ impl ChainShard{
 
             match previous_block {
                 Some(last_block) => {
-                    if last_block.depth + 1 != header.depth {
+                    if last_block.depth.checked_add(1) != Some(header.depth) {
                         return Err(BlockValidationError::MalformedShard("Depth does not match previous block".into()));
-                    } else if header.timestamp < last_block.timestamp {
-                        return Err(BlockValidationError::MalformedShard("Timestamp is before previous block".into()));
+                    } else if !params.is_timestamp_ordered(last_block.timestamp, header.timestamp) {
+                        return Err(BlockValidationError::MalformedShard("Timestamp is not after previous block".into()));
                     } else {
                         Ok(())
                     }
@@ -78,6 +94,24 @@ impl ChainShard{
         self.headers.get(hash).cloned()
     }
 
+    /// Checks a peer's claimed tip against the headers it sent, by recomputing the work up to the tip.
+    /// A peer overstating its work is rejected, rather than trusted when choosing who to sync from
+    pub fn verify_claimed_work(&self, claim: &ChainTip) -> Result<(), BlockValidationError> {
+        match self.headers.get(&claim.hash) {
+            Some(header) if *header == claim.header => {},
+            _ => return Err(BlockValidationError::MalformedShard("Claimed tip is not in the shard".into())),
+        }
+        let work = self.get_cumulative_work(&claim.hash).ok_or(
+            BlockValidationError::MalformedShard("Claimed tip does not lead to genesis".into())
+        )?;
+        if work != claim.cumulative_work {
+            return Err(BlockValidationError::MalformedShard(
+                format!("Claimed work {} does not match the headers' work {}", claim.cumulative_work, work)
+            ));
+        }
+        Ok(())
+    }
+
 }
 
 impl From<Chain> for ChainShard{
@@ -114,12 +148,163 @@ impl TrimmableChain for ChainShard {
 mod tests {
     use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction, Signable};
 
+    use pillar_crypto::hashing::Hashable;
+
     use super::*;
     
     use crate::primitives::block::{Block, BlockTail};
     use crate::primitives::transaction::Transaction;
+    use crate::protocol::params::{ChainParams, Checkpoint};
     use crate::protocol::pow::mine;
 
+    #[test]
+    fn test_validate_depth_overflow() {
+        let chain = Chain::new_with_genesis();
+        let mut shard: ChainShard = chain.clone().into();
+        // a header claiming the maximum depth, and a child of it
+        let overflow = BlockHeader::new(
+            chain.deepest_hash, [1; 32], Some([2; 32]), 0, 1, Some([3; 32]),
+            BlockTail::default(), u64::MAX, Some(0)
+        );
+        let overflow_hash = overflow.hash(&mut DefaultHash::new()).unwrap();
+        let child = BlockHeader::new(
+            overflow_hash, [1; 32], Some([2; 32]), 0, 2, Some([3; 32]),
+            BlockTail::default(), 5, Some(0)
+        );
+        let child_hash = child.hash(&mut DefaultHash::new()).unwrap();
+        shard.headers.insert(overflow_hash, overflow);
+        shard.headers.insert(child_hash, child);
+        shard.leaves = HashSet::from([child_hash]);
+        assert!(shard.validate().is_err());
+        // the child is rejected on its own as well
+        shard.headers.remove(&overflow_hash);
+        assert!(shard.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_committed_difficulty() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut transaction = Transaction::new(sender, [2; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        let mut block = Block::new(
+            chain.deepest_hash,
+            0,
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+            vec![transaction],
+            Some(sender),
+            BlockTail::default().stamps,
+            1,
+            None,
+            None,
+            &mut DefaultHash::new(),
+        );
+        let prev_header = chain.headers[&chain.deepest_hash];
+        let state_root = chain.state_manager.branch_from_block(&block, &prev_header);
+        let mut mismatched = block.clone();
+        mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
+        chain.add_new_block(block).unwrap();
+        let shard: ChainShard = chain.clone().into();
+        assert!(shard.validate().is_ok());
+
+        // a valid proof of work, but against a committed difficulty the retarget does not allow
+        let params = ChainParams { bootstrap_difficulty: ChainParams::default().bootstrap_difficulty + 1, ..Default::default() };
+        mine(&mut mismatched, sender, state_root, vec![], &params, None, DefaultHash::new()).await;
+        let mismatched_hash = mismatched.hash.unwrap();
+        assert!(mismatched.header.validate(mismatched_hash, &mut DefaultHash::new()).is_ok());
+        let mut shard: ChainShard = chain.into();
+        shard.headers.insert(mismatched_hash, mismatched.header);
+        shard.leaves.insert(mismatched_hash);
+        assert!(matches!(shard.validate(), Err(BlockValidationError::MalformedShard(_))));
+        assert!(shard.validate_with_params(&params).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_claimed_work() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        for depth in 1..=2 {
+            let mut transaction = Transaction::new(sender, [2; 32], 0, 0, depth - 1, &mut DefaultHash::new());
+            transaction.sign(&mut signing_key);
+            let mut block = Block::new(
+                chain.deepest_hash,
+                0,
+                std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + depth,
+                vec![transaction],
+                Some(sender),
+                BlockTail::default().stamps,
+                depth,
+                None,
+                None,
+                &mut DefaultHash::new(),
+            );
+            let prev_header = chain.headers[&chain.deepest_hash];
+            let state_root = chain.state_manager.branch_from_block(&block, &prev_header);
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
+            chain.add_new_block(block).unwrap();
+        }
+        let honest = chain.get_tip();
+        let shard: ChainShard = chain.into();
+        assert!(shard.verify_claimed_work(&honest).is_ok());
+
+        let inflated = ChainTip { cumulative_work: honest.cumulative_work + 1, ..honest };
+        assert!(matches!(shard.verify_claimed_work(&inflated), Err(BlockValidationError::MalformedShard(_))));
+        // a tip the headers do not contain
+        let unknown = ChainTip { hash: [9; 32], ..honest };
+        assert!(shard.verify_claimed_work(&unknown).is_err());
+        // the headers are missing the tip's ancestors
+        let mut broken = shard.clone();
+        broken.headers.remove(&honest.header.previous_hash);
+        assert!(broken.verify_claimed_work(&honest).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_checkpoint() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut mined = vec![];
+        for depth in 1..=2 {
+            let mut transaction = Transaction::new(sender, [2; 32], 0, 0, depth - 1, &mut DefaultHash::new());
+            transaction.sign(&mut signing_key);
+            let mut block = Block::new(
+                chain.deepest_hash,
+                0,
+                std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + depth,
+                vec![transaction],
+                Some(sender),
+                BlockTail::default().stamps,
+                depth,
+                None,
+                None,
+                &mut DefaultHash::new(),
+            );
+            let prev_header = chain.headers[&chain.deepest_hash];
+            let state_root = chain.state_manager.branch_from_block(&block, &prev_header);
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
+            mined.push(block.hash.unwrap());
+            chain.add_new_block(block).unwrap();
+        }
+        let shard: ChainShard = chain.into();
+        let with_checkpoint = |depth, hash| ChainParams { checkpoint: Some(Checkpoint { depth, hash }), ..Default::default() };
+
+        // consistent checkpoints, including one past the shard's tip
+        assert!(shard.validate_with_params(&with_checkpoint(1, mined[0])).is_ok());
+        assert!(shard.validate_with_params(&with_checkpoint(2, mined[1])).is_ok());
+        assert!(shard.validate_with_params(&with_checkpoint(10, [9; 32])).is_ok());
+        // a chain through another block at the checkpoint depth
+        assert!(matches!(
+            shard.validate_with_params(&with_checkpoint(1, [9; 32])),
+            Err(BlockValidationError::CheckpointMismatch(1))
+        ));
+        assert!(matches!(
+            shard.validate_with_params(&with_checkpoint(2, mined[0])),
+            Err(BlockValidationError::CheckpointMismatch(2))
+        ));
+    }
+
     #[tokio::test]
     async fn test_trim_removes_short_fork() {
         let mut chain = Chain::new_with_genesis();
@@ -148,7 +333,7 @@ mod tests {
             );
             let prev_header = chain.headers.get(&parent_hash).expect("Parent hash must exist");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             parent_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -172,7 +357,7 @@ mod tests {
         );
         let prev_header = chain.headers.get(&genesis_hash).expect("Genesis hash must exist");
         let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-        mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+        mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
         chain.add_new_block(fork_block.clone()).unwrap();
 
         assert!(chain.blocks.contains_key(&fork_block.hash.unwrap()));
@@ -214,7 +399,7 @@ mod tests {
             );
             let prev_header = chain.headers.get(&parent_hash).expect("Parent hash must exist");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             parent_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -236,7 +421,7 @@ mod tests {
         );
         let prev_header = chain.headers.get(&genesis_hash).expect("Genesis hash must exist");
         let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-        mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+        mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
         let fork_hash = fork_block.hash.unwrap();
         chain.add_new_block(fork_block).unwrap();
 
@@ -274,7 +459,7 @@ mod tests {
             );
             let prev_header = chain.headers.get(&main_hash).expect("Parent hash must exist");
             let state_root = chain.state_manager.branch_from_block(&block, prev_header);
-            mine(&mut block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             main_hash = block.hash.unwrap();
             chain.add_new_block(block).unwrap();
         }
@@ -298,7 +483,7 @@ mod tests {
             );
             let prev_header = chain.headers.get(&genesis_hash).expect("Genesis hash must exist");
             let state_root = chain.state_manager.branch_from_block(&fork_block, prev_header);
-            mine(&mut fork_block, sender, state_root, vec![], None, DefaultHash::new()).await;
+            mine(&mut fork_block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
             let hash = fork_block.hash.unwrap();
             fork_hashes.push(hash);
             chain.add_new_block(fork_block).unwrap();
@@ -1,17 +1,177 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::{HashMap, HashSet}, fmt::Debug};
 
 use pillar_crypto::{hashing::{DefaultHash, Hashable}, types::StdByteArray};
 
-use crate::primitives::block::BlockHeader;
+use crate::{primitives::block::{Ancestry, Block, BlockHeader}, protocol::{params::Checkpoint, pow::get_work_from_difficulty}};
 
 pub mod chain;
 pub mod chain_shard;
+pub mod transaction_index;
+
+/// How many blocks below the tip a fork must have split off before it is treated as settled, and may be pruned
+pub const FINALITY_DEPTH: u64 = 10;
+
+/// How strictly a chain validates blocks during acceptance.
+/// Reduced levels only apply at or below the active checkpoint - 
+/// everything above the checkpoint (or everything, without one) gets full validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValidationLevel {
+    /// every check on every block
+    #[default]
+    Full,
+    /// trust transaction signatures in checkpointed history
+    Checkpointed,
+    /// trust transactions in checkpointed history - only headers and state are checked
+    HeadersOnly,
+}
+
+/// How a node picks its tip between blocks at the same depth.
+/// Either way a deeper chain always becomes the tip - this only settles ties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TieBreak {
+    /// keep the block seen first, to avoid churning the tip while a tie lasts
+    #[default]
+    FirstSeen,
+    /// prefer the lowest hash, so every node picks the same tip whatever order blocks arrive in
+    LowestHash,
+}
+
+impl TieBreak {
+    /// Whether `candidate` should replace `tip` as the tip, given both are at the same depth
+    pub fn prefers(&self, candidate: &StdByteArray, tip: &StdByteArray) -> bool {
+        match self {
+            TieBreak::FirstSeen => false,
+            TieBreak::LowestHash => candidate < tip,
+        }
+    }
+}
+
+/// Application logic run as blocks join and leave the deepest chain, such as an index for an explorer.
+/// Callbacks run while the chain is locked, so they should be quick
+pub trait BlockObserver: Debug + Send + Sync {
+    /// `block` joined the deepest chain. Blocks connect parent first
+    fn on_connect(&self, block: &Block);
+    /// `block` left the deepest chain in a reorg. Blocks disconnect tip first, before the new branch connects
+    fn on_disconnect(&self, block: &Block);
+}
+
+/// The subset of checks to run on a single block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationChecks {
+    /// transaction signatures and hashes
+    pub transactions: bool,
+    /// nonces are contiguous from the account nonce
+    pub nonces: bool,
+}
+
+impl ValidationLevel {
+    /// Get the checks to run on the block with `hash`.
+    /// Checks are only reduced for a block `ancestry` shows the checkpoint builds on - being below it is not enough.
+    /// Headers, proof of work, linkage, balances, and the state root are always checked.
+    pub fn checks_for(&self, hash: StdByteArray, checkpoint: Option<Checkpoint>, ancestry: &impl Ancestry) -> ValidationChecks {
+        let checkpointed = *self != ValidationLevel::Full
+            && checkpoint.is_some_and(|checkpoint| ancestry.is_ancestor(hash, checkpoint.hash));
+        match self {
+            ValidationLevel::Checkpointed if checkpointed => ValidationChecks { transactions: false, nonces: true },
+            ValidationLevel::HeadersOnly if checkpointed => ValidationChecks { transactions: false, nonces: false },
+            _ => ValidationChecks { transactions: true, nonces: true },
+        }
+    }
+}
 
 pub trait TrimmableChain {
     fn get_headers(&self) -> &HashMap<StdByteArray, BlockHeader>;
     fn get_leaves_mut(&mut self) -> &mut HashSet<StdByteArray>;
     fn remove_header(&mut self, hash: &StdByteArray);
 
+    /// Sums the work of every block from genesis up to the block with the given hash
+    /// Returns None if the block, or any ancestor, is not known
+    fn get_cumulative_work(&self, hash: &StdByteArray) -> Option<u128> {
+        let headers = self.get_headers();
+        let mut header = headers.get(hash)?;
+        let mut work: u128 = 0;
+        loop {
+            work = work.saturating_add(get_work_from_difficulty(header.difficulty_target.unwrap_or(0)));
+            if header.depth == 0 {
+                return Some(work);
+            }
+            header = headers.get(&header.previous_hash)?;
+        }
+    }
+
+    /// How much more cumulative work the chain up to `a` has than the chain up to `b` - negative if `b` has more.
+    /// Differences past the range of an i128 saturate. Returns None if either block, or any ancestor, is not known
+    fn work_delta(&self, a: &StdByteArray, b: &StdByteArray) -> Option<i128> {
+        let (a, b) = (self.get_cumulative_work(a)?, self.get_cumulative_work(b)?);
+        let magnitude = i128::try_from(a.abs_diff(b)).unwrap_or(i128::MAX);
+        Some(if a >= b { magnitude } else { -magnitude })
+    }
+
+    /// Prunes side chains - leaves other than `best` - down to at most `max_side_chains`, dropping the least cumulative work first.
+    /// Only side chains that forked from the chain of `best` at least `finality_depth` blocks below it, and have less work, are pruned -
+    /// any other could still win, so it is kept whatever the cap. Only the blocks that no kept leaf builds on are removed.
+    ///
+    /// # Returns
+    ///
+    /// * The number of side chains pruned
+    fn prune_side_chains(&mut self, best: &StdByteArray, max_side_chains: usize, finality_depth: u64) -> usize {
+        let leaves: Vec<_> = self.get_leaves_mut().iter().filter(|leaf| *leaf != best).cloned().collect();
+        let (Some(best_depth), Some(best_work)) = (self.get_headers().get(best).map(|header| header.depth), self.get_cumulative_work(best)) else {
+            return 0;
+        };
+        let Some(final_depth) = best_depth.checked_sub(finality_depth) else {
+            return 0;
+        };
+        let headers = self.get_headers();
+        let mut best_chain = HashSet::<StdByteArray>::new();
+        let mut hash = *best;
+        while best_chain.insert(hash) && let Some(header) = headers.get(&hash) && header.depth > 0 {
+            hash = header.previous_hash;
+        }
+        // the depth at which a leaf left the chain of `best`
+        let fork_depth = |leaf: &StdByteArray| {
+            let mut hash = *leaf;
+            while !best_chain.contains(&hash) {
+                hash = headers.get(&hash)?.previous_hash;
+            }
+            headers.get(&hash).map(|header| header.depth)
+        };
+        let (mut settled, open): (Vec<_>, Vec<_>) = leaves.into_iter()
+            .map(|leaf| (leaf, self.get_cumulative_work(&leaf).unwrap_or(0)))
+            .partition(|(leaf, work)| *work < best_work && fork_depth(leaf).is_some_and(|depth| depth <= final_depth));
+        let room = max_side_chains.saturating_sub(open.len());
+        if settled.len() <= room {
+            return 0;
+        }
+        // most work first, the hash settles ties so every node prunes the same chains
+        settled.sort_by_key(|(leaf, work)| (std::cmp::Reverse(*work), *leaf));
+        let pruned = settled.split_off(room);
+
+        // everything a kept leaf builds on stays
+        let mut kept = best_chain.clone();
+        for (leaf, _) in open.iter().chain(settled.iter()) {
+            let mut hash = *leaf;
+            while kept.insert(hash) && let Some(header) = headers.get(&hash) {
+                hash = header.previous_hash;
+            }
+        }
+        let mut nodes_to_remove = HashSet::<StdByteArray>::new();
+        for (leaf, _) in &pruned {
+            let mut hash = *leaf;
+            while !kept.contains(&hash) && nodes_to_remove.insert(hash) && let Some(header) = headers.get(&hash) {
+                hash = header.previous_hash;
+            }
+        }
+        let nodes_to_remove: Vec<_> = nodes_to_remove.into_iter().filter(|hash| headers.contains_key(hash)).collect();
+        for (leaf, _) in &pruned {
+            self.get_leaves_mut().remove(leaf);
+        }
+        for hash in nodes_to_remove {
+            self.remove_header(&hash);
+        }
+        pruned.len()
+    }
+
     fn trim(&mut self) {
         let headers = self.get_headers().clone();
         let mut seen = HashMap::<StdByteArray, StdByteArray>::new(); // node: leaf leading there
@@ -0,0 +1,42 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use pillar_crypto::types::StdByteArray;
+
+use crate::primitives::block::Block;
+
+use super::BlockObserver;
+
+/// The block on the deepest chain holding each transaction, by transaction hash.
+/// Follows the deepest chain as a block observer, so reorgs re-point moved transactions and drop abandoned ones.
+/// Holds an entry per mined transaction, so chains only keep one when enabled
+#[derive(Debug, Default)]
+pub struct TransactionIndex {
+    blocks: Mutex<HashMap<StdByteArray, StdByteArray>>,
+}
+
+impl TransactionIndex {
+    /// The hash of the block on the deepest chain holding `transaction`
+    pub fn block_containing(&self, transaction: &StdByteArray) -> Option<StdByteArray> {
+        self.blocks.lock().unwrap().get(transaction).copied()
+    }
+}
+
+impl BlockObserver for TransactionIndex {
+    fn on_connect(&self, block: &Block) {
+        let hash = block.hash.unwrap();
+        let mut blocks = self.blocks.lock().unwrap();
+        for transaction in &block.transactions {
+            blocks.insert(transaction.hash, hash);
+        }
+    }
+
+    fn on_disconnect(&self, block: &Block) {
+        let hash = block.hash.unwrap();
+        let mut blocks = self.blocks.lock().unwrap();
+        for transaction in &block.transactions {
+            if blocks.get(&transaction.hash) == Some(&hash) {
+                blocks.remove(&transaction.hash);
+            }
+        }
+    }
+}
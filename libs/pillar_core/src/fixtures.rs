@@ -0,0 +1,72 @@
+//! Mining helpers shared by the tests of every module
+
+use pillar_crypto::hashing::DefaultHash;
+use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction, Signable};
+use pillar_crypto::types::StdByteArray;
+
+use crate::blockchain::chain::Chain;
+use crate::primitives::block::{Block, BlockTail, Stamp};
+use crate::primitives::transaction::Transaction;
+use crate::protocol::pow::mine;
+
+/// A transaction of `amount` from a fresh account, signed by it
+pub fn signed_transaction(amount: u64) -> Transaction {
+    let mut signing_key = DefaultSigner::generate_random();
+    let mut transaction = Transaction::new(signing_key.get_verifying_function().to_bytes(), [3; 32], amount, 0, 0, &mut DefaultHash::new());
+    transaction.sign(&mut signing_key);
+    transaction
+}
+
+/// Stamps `block` with a fresh stamper, so it pays a reward
+pub fn stamp(block: &mut Block) {
+    let mut stamper = DefaultSigner::generate_random();
+    let signature = stamper.sign(&block.header);
+    block.header.tail.stamp(Stamp { address: stamper.get_verifying_function().to_bytes(), signature }).unwrap();
+}
+
+/// Builds an unmined child of `parent` with the given timestamp
+pub fn child_of(chain: &Chain, parent: StdByteArray, transactions: Vec<Transaction>, miner: StdByteArray, timestamp: u64) -> Block {
+    Block::new(
+        parent,
+        0,
+        timestamp,
+        transactions,
+        Some(miner),
+        BlockTail::default().stamps,
+        chain.headers[&parent].depth + 1,
+        None,
+        None,
+        &mut DefaultHash::new()
+    )
+}
+
+/// Mines `block` for `miner`, committing to the state it leads to from its parent in `chain`
+pub async fn mine_on_chain(chain: &mut Chain, block: &mut Block, miner: StdByteArray) {
+    let prev_header = chain.headers[&block.header.previous_hash];
+    let state_root = chain.state_manager.branch_from_block(block, &prev_header);
+    mine(block, miner, state_root, vec![], &chain.params, None, DefaultHash::new()).await;
+}
+
+/// Builds and mines a block with the given timestamp on `parent`
+pub async fn mined_block_on(chain: &mut Chain, parent: StdByteArray, transactions: Vec<Transaction>, miner: StdByteArray, timestamp: u64) -> Block {
+    let mut block = child_of(chain, parent, transactions, miner, timestamp);
+    mine_on_chain(chain, &mut block, miner).await;
+    block
+}
+
+/// Builds and mines a block with the given timestamp on the deepest leaf of the chain
+pub async fn mined_block_at(chain: &mut Chain, transactions: Vec<Transaction>, miner: StdByteArray, timestamp: u64) -> Block {
+    let parent = chain.deepest_hash;
+    mined_block_on(chain, parent, transactions, miner, timestamp).await
+}
+
+/// Builds and mines a block on the deepest leaf of the chain
+pub async fn mined_block(chain: &mut Chain, transactions: Vec<Transaction>, miner: StdByteArray) -> Block {
+    // never before the parent allows, like a miner
+    let earliest = chain.params.earliest_timestamp_after(chain.headers[&chain.deepest_hash].timestamp);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    mined_block_at(chain, transactions, miner, timestamp.max(earliest)).await
+}
@@ -1,7 +1,12 @@
+// nothing is exported yet, so only the test build can tell what is unused
+#![cfg_attr(not(test), allow(dead_code))]
+
 mod blockchain;
 mod nodes;
 mod primitives;
 mod protocol;
 mod accounting;
 mod reputation;
-mod persistence;
\ No newline at end of file
+mod persistence;
+#[cfg(test)]
+mod fixtures;
\ No newline at end of file
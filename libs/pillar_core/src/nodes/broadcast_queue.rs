@@ -0,0 +1,171 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use tokio::sync::Notify;
+
+use crate::primitives::messages::Message;
+
+/// messages waiting to be broadcast, by default
+pub const BROADCAST_QUEUE_CAPACITY: usize = 1024;
+
+/// What to do with a new message when the broadcast queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// drop the oldest waiting message to make room
+    #[default]
+    DropOldest,
+    /// wait until the broadcast loop makes room - this slows whoever produces the message
+    BlockProducer,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    messages: VecDeque<Message>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: u64,
+}
+
+/// The outbound queue of the broadcast loop.
+/// Holds at most `capacity` messages, so a message storm cannot grow it without bound.
+#[derive(Debug)]
+pub struct BroadcastQueue {
+    state: Mutex<QueueState>,
+    /// signalled whenever a message leaves the queue
+    space: Notify,
+}
+
+impl Default for BroadcastQueue {
+    fn default() -> Self {
+        BroadcastQueue::new(BROADCAST_QUEUE_CAPACITY, OverflowPolicy::default())
+    }
+}
+
+impl BroadcastQueue {
+    /// A queue of at most `capacity` messages. A capacity of 0 is treated as 1
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        BroadcastQueue {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::new(),
+                capacity: capacity.max(1),
+                policy,
+                dropped: 0,
+            }),
+            space: Notify::new(),
+        }
+    }
+
+    /// Changes the capacity and overflow policy.
+    /// Waiting messages over the new capacity are dropped, oldest first
+    pub fn set_limit(&self, capacity: usize, policy: OverflowPolicy) {
+        let mut state = self.state.lock().unwrap();
+        state.capacity = capacity.max(1);
+        state.policy = policy;
+        let excess = state.messages.len().saturating_sub(state.capacity);
+        state.messages.drain(..excess);
+        state.dropped += excess as u64;
+    }
+
+    /// Queues `message` for broadcast. When the queue is full, follows the overflow policy
+    pub async fn enqueue(&self, message: Message) {
+        loop {
+            // registered before checking, so a dequeue in between is not missed
+            let space = self.space.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.messages.len() < state.capacity {
+                    state.messages.push_back(message);
+                    return;
+                }
+                if state.policy == OverflowPolicy::DropOldest {
+                    state.messages.pop_front();
+                    state.dropped += 1;
+                    state.messages.push_back(message);
+                    return;
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// The oldest waiting message
+    pub fn dequeue(&self) -> Option<Message> {
+        let message = self.state.lock().unwrap().messages.pop_front();
+        if message.is_some() {
+            self.space.notify_waiters();
+        }
+        message
+    }
+
+    /// The number of messages waiting
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    /// The number of messages dropped to make room
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::primitives::messages::Message;
+
+    use super::{BroadcastQueue, OverflowPolicy};
+
+    fn request(i: u8) -> Message {
+        Message::BlockRequest([i; 32])
+    }
+
+    fn requested(message: Option<Message>) -> u8 {
+        match message {
+            Some(Message::BlockRequest(hash)) => hash[0],
+            other => panic!("expected a block request, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let queue = BroadcastQueue::new(3, OverflowPolicy::DropOldest);
+        for i in 0..10 {
+            queue.enqueue(request(i)).await;
+            assert!(queue.len() <= 3);
+        }
+        assert_eq!(queue.dropped(), 7);
+        assert_eq!((0..3).map(|_| requested(queue.dequeue())).collect::<Vec<_>>(), vec![7, 8, 9]);
+        assert!(queue.dequeue().is_none());
+
+        // shrinking drops the oldest waiting messages
+        for i in 0..3 {
+            queue.enqueue(request(i)).await;
+        }
+        queue.set_limit(1, OverflowPolicy::DropOldest);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(requested(queue.dequeue()), 2);
+    }
+
+    #[tokio::test]
+    async fn test_block_producer() {
+        let queue = Arc::new(BroadcastQueue::new(2, OverflowPolicy::BlockProducer));
+        queue.enqueue(request(0)).await;
+        queue.enqueue(request(1)).await;
+
+        let producer = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.enqueue(request(2)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the producer waits, rather than growing the queue or dropping a message
+        assert!(!producer.is_finished());
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(requested(queue.dequeue()), 0);
+        tokio::time::timeout(Duration::from_secs(1), producer).await.unwrap().unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(requested(queue.dequeue()), 1);
+        assert_eq!(requested(queue.dequeue()), 2);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{primitives::messages::Message, protocol::clock::Clock};
+
+/// verification cost each address may spend per window, by default
+pub const MAX_VERIFICATION_COST: u64 = 1024;
+/// the default window length, in seconds
+pub const VERIFICATION_COST_WINDOW: u64 = 10;
+/// addresses tracked at once, by default
+pub const MAX_TRACKED_BUDGETS: usize = 1024;
+
+/// cost of building a transaction proof
+pub const PROOF_COST: u64 = 16;
+/// cost of walking or cloning the whole chain, or a range of the state that may be all of it
+pub const CHAIN_COST: u64 = 64;
+/// cost of comparing against the mempool, or ranking peers over the chain
+pub const SCAN_COST: u64 = 4;
+
+/// The work serving `message` takes, in rough units of a cheap lookup.
+/// Requests that cost far more to serve than to send are priced - everything else is free, and never throttled
+pub fn verification_cost(message: &Message) -> u64 {
+    match message {
+        Message::TransactionProofRequest(_) => PROOF_COST,
+        Message::StateRangeRequest(..)
+        | Message::ChainRequest
+        | Message::ChainShardRequest
+        | Message::ChainSyncRequest(_) => CHAIN_COST,
+        Message::MempoolSyncRequest(_)
+        | Message::PercentileFilteredPeerRequest(..) => SCAN_COST,
+        _ => 0,
+    }
+}
+
+/// Limits the verification cost each address may spend in a fixed window, across all expensive requests.
+/// This keeps a peer from making the node do far more work than it spent asking.
+/// A request that would go over the budget is refused, and costs nothing.
+/// Budgets are kept by the address a connection comes from, since a declared key costs nothing to claim.
+/// At most `max_tracked` addresses are kept; ended windows, then the stalest one, make room
+#[derive(Debug, Clone)]
+pub struct CostBudget {
+    /// cost allowed per address in each window
+    pub budget: u64,
+    /// the window length, in seconds
+    pub window: u64,
+    /// the most addresses to keep budgets for
+    pub max_tracked: usize,
+    /// the time source for windows
+    pub clock: Clock,
+    /// per address - (window start, cost spent in the window)
+    spent: HashMap<IpAddr, (u64, u64)>,
+}
+
+impl Default for CostBudget {
+    fn default() -> Self {
+        CostBudget::new(MAX_VERIFICATION_COST, VERIFICATION_COST_WINDOW)
+    }
+}
+
+impl CostBudget {
+    pub fn new(budget: u64, window: u64) -> Self {
+        CostBudget {
+            budget,
+            window,
+            max_tracked: MAX_TRACKED_BUDGETS,
+            clock: Clock::default(),
+            spent: HashMap::new(),
+        }
+    }
+
+    /// Charges `cost` to `address`
+    ///
+    /// # Returns
+    ///
+    /// * `true` - if the request may be served
+    /// * `false` - if it would take the address over its budget
+    pub fn charge(&mut self, address: &IpAddr, cost: u64) -> bool {
+        if cost == 0 {
+            return true;
+        }
+        let now = self.clock.now();
+        if !self.spent.contains_key(address) && self.spent.len() >= self.max_tracked {
+            let window = self.window;
+            self.spent.retain(|_, (start, _)| now.saturating_sub(*start) < window);
+            if self.spent.len() >= self.max_tracked
+                && let Some(stalest) = self.spent.iter().min_by_key(|(_, (start, _))| *start).map(|(address, _)| *address) {
+                self.spent.remove(&stalest);
+            }
+        }
+        let (start, spent) = self.spent.entry(*address).or_insert((now, 0));
+        if now.saturating_sub(*start) >= self.window {
+            *start = now;
+            *spent = 0;
+        }
+        if spent.saturating_add(cost) > self.budget {
+            return false;
+        }
+        *spent += cost;
+        true
+    }
+
+    /// The cost `address` has left in the current window
+    pub fn remaining(&self, address: &IpAddr) -> u64 {
+        match self.spent.get(address) {
+            Some((start, spent)) if self.clock.now().saturating_sub(*start) < self.window => self.budget.saturating_sub(*spent),
+            _ => self.budget,
+        }
+    }
+
+    /// The number of addresses with a budget kept
+    pub fn tracked(&self) -> usize {
+        self.spent.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::{primitives::messages::Message, protocol::clock::Clock};
+
+    use super::{verification_cost, CostBudget, PROOF_COST};
+
+    fn address(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    #[test]
+    fn test_budget_exhausted_then_window_resets() {
+        let mut budget = CostBudget::new(3 * PROOF_COST, 10);
+        budget.clock = Clock::mock(1000);
+        let (peer, other) = (address(1), address(2));
+        assert!((0..3).all(|_| budget.charge(&peer, PROOF_COST)));
+        assert!(!budget.charge(&peer, PROOF_COST));
+        assert_eq!(budget.remaining(&peer), 0);
+        // free messages always pass
+        assert!(budget.charge(&peer, verification_cost(&Message::Ping)));
+        // budgets are per address
+        assert!(budget.charge(&other, PROOF_COST));
+        assert_eq!(budget.remaining(&other), 2 * PROOF_COST);
+
+        budget.clock.advance(9);
+        assert!(!budget.charge(&peer, 1));
+        budget.clock.advance(1);
+        assert_eq!(budget.remaining(&peer), 3 * PROOF_COST);
+        assert!(budget.charge(&peer, PROOF_COST));
+    }
+
+    #[test]
+    fn test_tracked_budgets_bounded() {
+        let mut budget = CostBudget::new(PROOF_COST, 10);
+        budget.clock = Clock::mock(0);
+        budget.max_tracked = 2;
+        let spent = address(1);
+        assert!(budget.charge(&spent, PROOF_COST));
+        budget.clock.advance(1);
+        assert!(budget.charge(&address(2), PROOF_COST));
+        // a new address pushes out the stalest window rather than growing the map
+        assert!(budget.charge(&address(3), PROOF_COST));
+        assert_eq!(budget.tracked(), 2);
+        assert_eq!(budget.remaining(&spent), PROOF_COST);
+        // ended windows go first
+        budget.clock.advance(10);
+        (4..40).for_each(|n| assert!(budget.charge(&address(n), PROOF_COST)));
+        assert_eq!(budget.tracked(), 2);
+    }
+}
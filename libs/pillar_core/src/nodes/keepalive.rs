@@ -0,0 +1,109 @@
+use std::{collections::HashMap, time::Duration};
+
+use pillar_crypto::types::StdByteArray;
+
+/// the default time allowed to connect and send a message
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// the default time allowed for a peer to respond
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// the default time allowed for a connecting peer to send its request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// the default time between keepalive pings
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// consecutive missed pings after which a peer is dropped, by default
+pub const MAX_MISSED_PINGS: u32 = 3;
+
+/// Timeouts for a single exchange with a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionTimeouts {
+    /// the time allowed to connect and send the message
+    pub write: Duration,
+    /// the time allowed to read the response
+    pub read: Duration,
+    /// when serving, the time allowed for a connecting peer to send its declaration, and then its message
+    pub request: Duration,
+}
+
+impl Default for ConnectionTimeouts {
+    fn default() -> Self {
+        ConnectionTimeouts { write: WRITE_TIMEOUT, read: READ_TIMEOUT, request: REQUEST_TIMEOUT }
+    }
+}
+
+/// Tracks keepalive pings to each peer.
+/// A peer that misses `max_missed` pings in a row is considered dead, any reply resets the count.
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    /// the time between pings
+    pub interval: Duration,
+    /// consecutive missed pings at which a peer is dropped
+    pub max_missed: u32,
+    /// per peer - consecutive missed pings
+    missed: HashMap<StdByteArray, u32>,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive::new(KEEPALIVE_INTERVAL, MAX_MISSED_PINGS)
+    }
+}
+
+impl KeepAlive {
+    pub fn new(interval: Duration, max_missed: u32) -> Self {
+        KeepAlive {
+            interval,
+            max_missed,
+            missed: HashMap::new(),
+        }
+    }
+
+    /// Records a reply from `peer`
+    pub fn record_reply(&mut self, peer: &StdByteArray) {
+        self.missed.remove(peer);
+    }
+
+    /// Records a ping `peer` did not answer
+    ///
+    /// # Returns
+    ///
+    /// * `true` - if the peer has missed too many pings, and should be dropped. The peer is forgotten
+    /// * `false` - otherwise
+    pub fn record_miss(&mut self, peer: &StdByteArray) -> bool {
+        let missed = self.missed.entry(*peer).or_default();
+        *missed += 1;
+        if *missed >= self.max_missed {
+            self.missed.remove(peer);
+            return true;
+        }
+        false
+    }
+
+    /// The number of consecutive pings `peer` has missed
+    pub fn missed(&self, peer: &StdByteArray) -> u32 {
+        self.missed.get(peer).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::KeepAlive;
+
+    #[test]
+    fn test_missed_pings() {
+        let mut keepalive = KeepAlive::new(Duration::from_secs(1), 3);
+        let (peer, other) = ([1; 32], [2; 32]);
+        assert!(!keepalive.record_miss(&peer));
+        assert!(!keepalive.record_miss(&peer));
+        // a reply resets the count
+        keepalive.record_reply(&peer);
+        assert_eq!(keepalive.missed(&peer), 0);
+        assert!(!keepalive.record_miss(&peer));
+        assert!(!keepalive.record_miss(&other));
+        assert!(!keepalive.record_miss(&peer));
+        assert_eq!(keepalive.missed(&other), 1);
+        assert!(keepalive.record_miss(&peer));
+        assert_eq!(keepalive.missed(&peer), 0);
+    }
+}
@@ -1,16 +1,89 @@
-use pillar_crypto::hashing::DefaultHash;
+use pillar_crypto::{hashing::{DefaultHash, HashFunction}, types::StdByteArray};
 use tracing::instrument;
 
-use crate::{primitives::{block::{Block, BlockTail}, messages::Message}, protocol::{pow::mine, reputation::get_current_reputations_for_stampers}};
+use crate::{blockchain::chain::Chain, primitives::{block::{Block, BlockTail}, messages::Message, pool::MinerPool, transaction::Transaction}, protocol::{clock::valid_timestamp_range, difficulty::get_difficulty_from_depth, pow::{mine_as_worker, MiningWorker}, reputation::get_current_reputations_for_stampers}};
 
 use super::{node::{Broadcaster, Node}};
 
 pub const MAX_TRANSACTION_WAIT_TIME: u64 = 5; // seconds
 pub const MAX_BLOCK_TRANSACTION_SIZE: usize = 10; // number of transactions to mine at once
 
+/// Everything an external miner needs to build and mine the next block on the deepest chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTemplate {
+    /// the hash of the current tip
+    pub previous_hash: StdByteArray,
+    /// the depth of the block to mine
+    pub depth: u64,
+    /// the earliest valid timestamp, or now if later
+    pub timestamp: u64,
+    /// the pooled transactions to include
+    pub transactions: Vec<Transaction>,
+    /// the difficulty target, without stamps
+    pub difficulty_target: u64,
+}
+
+impl BlockTemplate {
+    /// Identifies the template. Changes whenever the tip or the chosen transactions change,
+    /// so miners can poll and compare to learn when to rebuild their work.
+    pub fn id(&self) -> StdByteArray {
+        let mut hasher = DefaultHash::new();
+        hasher.update(self.previous_hash);
+        hasher.update(self.depth.to_le_bytes());
+        for transaction in &self.transactions {
+            hasher.update(transaction.hash);
+        }
+        hasher.digest().unwrap()
+    }
+
+    /// The unmined block described by the template
+    pub fn to_block(&self) -> Block {
+        Block::new(
+            self.previous_hash,
+            0,
+            self.timestamp,
+            self.transactions.clone(),
+            None,
+            BlockTail::default().stamps,
+            self.depth,
+            Some(self.difficulty_target),
+            None,
+            &mut DefaultHash::new()
+        )
+    }
+}
+
+/// Builds a template on the tip of `chain`, with the pooled transactions that are valid there.
+/// At most `MAX_BLOCK_TRANSACTION_SIZE` transactions are taken, in pool order. The pool is not changed.
+/// `None` if none of the pooled transactions are valid on the tip - a block needs at least one transaction
+/// for its merkle tree, so empty blocks cannot be built.
+/// Compare `BlockTemplate::id` between calls to see if the work is stale.
+pub fn get_block_template(chain: &Chain, pool: &MinerPool) -> Option<BlockTemplate> {
+    let tip = chain.get_top_block()?;
+    let state_root = chain.get_state_root()?;
+    let (earliest, _) = valid_timestamp_range(&tip.header, &chain.params, &chain.clock);
+    let transactions = pool.pending_transactions().into_iter()
+        .filter(|transaction| chain.validate_transaction(transaction, state_root).is_ok())
+        .take(MAX_BLOCK_TRANSACTION_SIZE)
+        .collect::<Vec<_>>();
+    if transactions.is_empty() {
+        return None;
+    }
+    let depth = tip.header.depth + 1;
+    Some(BlockTemplate {
+        previous_hash: tip.hash?,
+        depth,
+        timestamp: chain.clock.now().max(earliest),
+        transactions,
+        difficulty_target: get_difficulty_from_depth(depth, &chain.params),
+    })
+}
+
 #[derive(Clone)]
 pub struct Miner {
-    pub node: Node
+    pub node: Node,
+    /// the nonces this miner grinds - miners sharing an address should each use their own worker index
+    pub worker: MiningWorker,
 }
 
 impl Miner{
@@ -18,10 +91,14 @@ impl Miner{
     /// Creates a new miner instance
     /// Takes ownership of the node
     pub fn new(node: Node) -> Result<Self, std::io::Error> {
+        if node.read_only {
+            return Err(std::io::Error::other("read-only nodes cannot mine"));
+        }
         let miner_pool = &node.miner_pool;
         if miner_pool.is_some(){
             Ok(Miner {
                 node,
+                worker: MiningWorker::default(),
             })
         }else{
             Err(std::io::Error::other(
@@ -41,7 +118,6 @@ impl Miner{
         tokio::spawn(monitor_transaction_pool(self.clone()));
         tokio::spawn(monitor_block_pool(self.clone()));
     }
-
 }
 
 /// monitors the nodes transaction pool
@@ -84,10 +160,12 @@ async fn monitor_transaction_pool(miner: Miner) {
             // mine
             let chain_lock = miner.node.inner.chain.lock().await;
             let chain = chain_lock.as_ref().unwrap();
+            // never build a block older than its parent
+            let (earliest, _) = valid_timestamp_range(&chain.get_top_block().unwrap().header, &chain.params, &chain.clock);
             let block = Block::new(
                 chain.get_top_block().unwrap().hash.unwrap(), // if it crahses, there is bug
                 0, // undefined nonce
-                now,
+                now.max(earliest),
                 transactions,
                 None, // because this is a proposition on an unmined node
                 BlockTail::default().stamps,
@@ -112,7 +190,7 @@ async fn monitor_block_pool(miner: Miner) {
     loop {
         // check if there is a block to mine
         if let Some(mut block) = miner.node.miner_pool.as_ref().unwrap().pop_mine_ready_block(){
-            block.header.miner_address = Some(miner.node.inner.public_key);
+            block.set_miner(miner.node.inner.public_key);
             block.header.tail.clean(&block.header.clone()); // removes broken signatures
             let mut chain_lock = miner.node.inner.chain.lock().await;
             let chain = chain_lock.as_mut().unwrap();
@@ -128,14 +206,16 @@ async fn monitor_block_pool(miner: Miner) {
                 chain, 
                 &block.header
             ).values().cloned().collect::<Vec<f64>>();
+            let chain_params = chain.params;
             drop(chain_lock); // drop the lock before mining
-            mine(
+            mine_as_worker(
                 &mut block, 
                 miner.node.inner.public_key,
                 state_root,
                 reputations,
+                &chain_params,
                 Some(miner.node.miner_pool.as_ref().unwrap().mine_abort_receiver.clone()),
-                DefaultHash::new()
+                miner.worker,
             ).await;
             // after mining the block, just transmit
             // TODO this doesnt fully belong here - also handle broadcast error
@@ -155,10 +235,115 @@ mod test{
 
     use pillar_crypto::hashing::DefaultHash;
 
-    use crate::{persistence::database::GenesisDatastore, primitives::{block::{Block, BlockTail}, pool::MinerPool, transaction::Transaction}, protocol::{difficulty::MIN_DIFFICULTY, pow::mine}};
-    use crate::nodes::miner::Miner;
+    use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction, Signable};
+
+    use pillar_crypto::types::StdByteArray;
+
+    use crate::{accounting::wallet::Wallet, blockchain::chain::Chain, fixtures::mine_on_chain, persistence::database::GenesisDatastore, primitives::{block::{Block, BlockTail, Stamp}, pool::MinerPool, transaction::Transaction}, protocol::{clock::Clock, difficulty::{get_difficulty_from_depth, MIN_DIFFICULTY}, params::ChainParams, pow::mine}};
+    use crate::nodes::miner::{get_block_template, Miner};
     use super::Node;
 
+    #[tokio::test]
+    async fn test_block_template() {
+        let mut chain = Chain::new_with_genesis();
+        let pool = MinerPool::new();
+        // a block needs a transaction, so an empty pool gives no template
+        assert!(get_block_template(&chain, &pool).is_none());
+
+        // nor does a pool of only invalid transactions
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut transaction = Transaction::new(sender, [2; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        pool.add_transaction(Transaction::new(sender, [2; 32], 0, 0, 1, &mut DefaultHash::new()));
+        assert!(get_block_template(&chain, &pool).is_none());
+        // a valid pooled transaction gives a template with only it
+        pool.add_transaction(transaction);
+        let template = get_block_template(&chain, &pool).unwrap();
+        assert_eq!(template.previous_hash, chain.deepest_hash);
+        assert_eq!(template.depth, 1);
+        assert_eq!(template.transactions, vec![transaction]);
+        // the same inputs give the same template
+        assert_eq!(get_block_template(&chain, &pool).unwrap().id(), template.id());
+        // building a template does not take from the pool
+        assert_eq!(pool.pending_transactions().len(), 2);
+
+        // mining the template gives its target, and a block the chain accepts
+        assert_eq!(template.difficulty_target, get_difficulty_from_depth(1, &chain.params));
+        let mut block = template.to_block();
+        block.set_miner(sender);
+        let state_root = chain.state_manager.branch_from_block(&block, &chain.headers[&chain.deepest_hash]);
+        mine(&mut block, sender, state_root, vec![], &chain.params, None, DefaultHash::new()).await;
+        assert_eq!(block.header.difficulty_target, Some(template.difficulty_target));
+        chain.add_new_block(block.clone()).unwrap();
+
+        // a new tip changes the template
+        let mut next_transaction = Transaction::new(sender, [3; 32], 0, 0, 1, &mut DefaultHash::new());
+        next_transaction.sign(&mut signing_key);
+        pool.add_transaction(next_transaction);
+        let next = get_block_template(&chain, &pool).unwrap();
+        assert_eq!(next.previous_hash, block.hash.unwrap());
+        assert_eq!(next.depth, 2);
+        assert_ne!(next.id(), template.id());
+    }
+
+    /// Seeds accounts, pools a known set of transactions, then assembles, mines and applies a block.
+    /// Keys, timestamps and the clock are all fixed, so the outcome is too - the golden values
+    /// only change if the pipeline, the encoding or the reward schedule does
+    #[tokio::test]
+    async fn test_pool_to_block_fixture() {
+        const NOW: u64 = 1_700_000_000;
+        let mut chain = Chain::new_with_genesis();
+        chain.clock = Clock::mock(NOW);
+        let pool = MinerPool::new();
+        let [mut alice, mut bob, carol, miner, mut stamper] = [0, 1, 2, 3, 4].map(|index| Wallet::derive(b"pool fixture", index));
+        let transfer = |wallet: &mut Wallet, receiver: &Wallet, amount: u64, nonce: u64| {
+            let mut transaction = Transaction::new(wallet.address, receiver.address, amount, NOW - 60, nonce, &mut DefaultHash::new());
+            transaction.sign(wallet);
+            transaction
+        };
+        let mine_template = async |chain: &mut Chain, pool: &MinerPool, miner: &Wallet, stamper: Option<&mut Wallet>| {
+            let mut block = get_block_template(chain, pool).unwrap().to_block();
+            block.set_miner(miner.address);
+            if let Some(stamper) = stamper {
+                let signature = stamper.sign(&block.header);
+                block.header.tail.stamp(Stamp { address: stamper.address, signature }).unwrap();
+            }
+            mine_on_chain(chain, &mut block, miner.address).await;
+            chain.add_new_block(block.clone()).unwrap();
+            pool.prune(chain);
+            block
+        };
+
+        // seed alice with a stamped block's reward
+        pool.add_transaction(transfer(&mut alice, &bob, 0, 0));
+        mine_template(&mut chain, &pool, &alice, Some(&mut stamper)).await;
+        let balance = |chain: &Chain, wallet: &Wallet| chain.state_manager.get_account_or_default(&wallet.address, chain.get_state_root().unwrap()).balance;
+        let seeded = balance(&chain, &alice);
+        assert_eq!(seeded, 1000);
+
+        let transactions = vec![
+            transfer(&mut alice, &bob, 10, 1),
+            transfer(&mut alice, &carol, 5, 2),
+            transfer(&mut bob, &carol, 0, 0),
+        ];
+        for transaction in &transactions {
+            pool.add_transaction(*transaction);
+        }
+        let block = mine_template(&mut chain, &pool, &miner, None).await;
+        assert_eq!(block.transactions, transactions);
+        assert!(pool.pending_transactions().is_empty());
+
+        let to_hex = |bytes: StdByteArray| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(
+            [balance(&chain, &alice), balance(&chain, &bob), balance(&chain, &carol), balance(&chain, &miner)],
+            // no stamps on the second block, so no reward for its miner
+            [985, 10, 5, 0]
+        );
+        assert_eq!(to_hex(block.header.merkle_root), "2d7cdb10922106da8e3bbc5e524b4722cb85ff1ab282a98a380b6b634cd8d063");
+        assert_eq!(to_hex(chain.get_state_root().unwrap()), "9c6921528b51b0df2babe080bc72c7d094accd8a8d453074f39ca5516a94d039");
+    }
+
     #[tokio::test]
     async fn test_miner(){
         let public_key = [1u8; 32];
@@ -192,7 +377,7 @@ mod test{
             1, None, None, &mut hasher);
 
         // mine the block
-        mine(&mut block, miner.node.inner.public_key, [8; 32], vec![], None, hasher).await;
+        mine(&mut block, miner.node.inner.public_key, [8; 32], vec![], &ChainParams::default(), None, hasher).await;
         
         assert!(block.header.nonce > 0);
         assert!(block.header.miner_address.is_some());
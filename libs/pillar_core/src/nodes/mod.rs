@@ -1,6 +1,13 @@
+pub mod broadcast_queue;
+pub mod cost_budget;
+pub mod keepalive;
 pub mod miner;
 pub mod node;
 pub mod peer;
+pub mod proof_cache;
+pub mod proof_queue;
+pub mod rate_limit;
+pub mod retry;
 
 #[cfg(test)]
 mod tests {
@@ -17,9 +24,9 @@ mod tests {
     };
 
     use crate::{
-        accounting::wallet::Wallet, nodes::{
-            miner::{Miner, MAX_TRANSACTION_WAIT_TIME}, node::NodeState, peer::Peer
-        }, persistence::database::GenesisDatastore, primitives::{messages::Message, pool::MinerPool, transaction::Transaction}, protocol::{difficulty::get_reward_from_depth_and_stampers, peers::discover_peers, transactions::{get_transaction_proof, submit_transaction}}
+        accounting::{account::{AccountDelta, TransactionStub}, state::verify_account_range, wallet::Wallet}, blockchain::BlockObserver, nodes::{
+            miner::{Miner, MAX_TRANSACTION_WAIT_TIME}, node::NodeState, peer::Peer, cost_budget::{CostBudget, PROOF_COST}, proof_queue::ProofQueue, rate_limit::ProofRateLimiter, retry::RetryPolicy
+        }, fixtures::mined_block, persistence::database::GenesisDatastore, primitives::{block::Block, errors::QueryError, messages::Message, pool::MinerPool, transaction::Transaction}, protocol::{chain::{block_settle_consumer, dicover_chain, get_genesis_block, query_tip_from_peer, request_header_at_depth}, clock::Clock, difficulty::get_reward_from_depth_and_stampers, params::Checkpoint, peers::{check_tip_agreement, discover_peers, TipAgreement}, transactions::{get_transaction_proof, reconcile_mempool, submit_transaction}, communication::serve_peers}
     };
 
     use super::node::Node;
@@ -1208,15 +1215,6 @@ mod tests {
         // check states
         assert!(node_a.inner.state.lock().await.clone() == NodeState::Serving);
         assert!(node_b.inner.state.lock().await.clone() == NodeState::Serving);
-        let state_manager = node_a
-            .inner
-            .chain
-            .lock()
-            .await
-            .as_mut()
-            .unwrap()
-            .state_manager
-            .clone();
         println!("Submitting multiple transactions from A to B");
         submit_transaction(
             &mut node_a,
@@ -1628,4 +1626,517 @@ mod tests {
         drop(already_a);
     }
 
+    #[tokio::test]
+    async fn test_tip_exchange(){
+        let ip_address_a = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 16));
+        let port_a = 8100;
+        let ip_address_b = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 17));
+        let port_b = 8101;
+        let (mut node_a, mut wallet_a) = create_empty_node_genisis(
+            ip_address_a,
+            port_a,
+            vec![],
+            true,
+            None,
+        )
+        .await;
+        let (mut node_b, _) = create_empty_node_genisis(
+            ip_address_b,
+            port_b,
+            vec![],
+            true,
+            None,
+        )
+        .await;
+        // give a one block ahead of b
+        let block = mined_block_for(&node_a, &mut wallet_a).await;
+        node_a.inner.chain.lock().await.as_mut().unwrap().add_new_block(block).unwrap();
+
+        node_a.serve().await;
+        node_b.serve().await;
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await; // wait for the nodes to start
+
+        let peer_a: Peer = node_a.clone().into();
+        let peer_b: Peer = node_b.clone().into();
+        // b is behind a
+        let tip_a = query_tip_from_peer(&mut peer_a.clone(), &peer_b).await.unwrap();
+        assert_eq!(tip_a.header.depth, 1);
+        let local_b = node_b.inner.chain.lock().await.as_ref().unwrap().get_tip();
+        assert!(local_b.is_behind(&tip_a));
+        // a is not behind b
+        let tip_b = query_tip_from_peer(&mut peer_b.clone(), &peer_a).await.unwrap();
+        assert_eq!(tip_b.header.depth, 0);
+        let local_a = node_a.inner.chain.lock().await.as_ref().unwrap().get_tip();
+        assert!(!local_a.is_behind(&tip_b));
+        node_a.stop().await;
+        node_b.stop().await;
+    }
+
+    /// Builds a valid mined block on top of the node's chain, without adding it
+    async fn mined_block_for(node: &Node, wallet: &mut Wallet) -> Block {
+        let mut transaction = Transaction::new(wallet.address, [2; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(wallet);
+        let mut chain_lock = node.inner.chain.lock().await;
+        mined_block(chain_lock.as_mut().unwrap(), vec![transaction], wallet.address).await
+    }
+
+    fn relayed_blocks(node: &Node) -> Vec<Block> {
+        let mut relayed = vec![];
+        while let Some(message) = node.inner.broadcast_queue.dequeue() {
+            if let Message::BlockTransmission(block) = message {
+                relayed.push(block);
+            }
+        }
+        relayed
+    }
+
+    #[tokio::test]
+    async fn test_relay_after_validation(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 18));
+        let (mut node, mut wallet) = create_empty_node_genisis(ip_address, 8102, vec![], true, None).await;
+        assert!(node.relay_validated_only);
+        *node.inner.state.lock().await = NodeState::Serving;
+        let valid = mined_block_for(&node, &mut wallet).await;
+        let mut invalid = valid.clone();
+        invalid.header.timestamp += 1;
+        let peer: Peer = node.clone().into();
+
+        node.serve_request(&Message::BlockTransmission(invalid), peer.ip_address, peer.clone()).await.unwrap();
+        node.serve_request(&Message::BlockTransmission(valid.clone()), peer.ip_address, peer).await.unwrap();
+        // nothing is relayed on receipt
+        assert!(relayed_blocks(&node).is_empty());
+
+        let (stop, stop_signal) = flume::bounded(1);
+        let settle = tokio::spawn(block_settle_consumer(node.clone(), Some(stop_signal)));
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        stop.send(()).unwrap();
+        settle.await.unwrap();
+
+        // only the valid block is relayed, once settled
+        let relayed = relayed_blocks(&node);
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].hash, valid.hash);
+        assert_eq!(node.inner.chain.lock().await.as_ref().unwrap().depth, 1);
+    }
+
+    #[tokio::test]
+    async fn test_relay_without_chain_checks_header(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 19));
+        let (mut node, mut wallet) = create_empty_node_genisis(ip_address, 8103, vec![], true, None).await;
+        // not consuming, so blocks cannot be connected to the chain
+        assert!(!node.inner.state.lock().await.is_consume());
+        let valid = mined_block_for(&node, &mut wallet).await;
+        let mut invalid = valid.clone();
+        invalid.header.timestamp += 1;
+        let peer: Peer = node.clone().into();
+
+        node.serve_request(&Message::BlockTransmission(invalid.clone()), peer.ip_address, peer.clone()).await.unwrap();
+        assert!(relayed_blocks(&node).is_empty());
+        node.serve_request(&Message::BlockTransmission(valid.clone()), peer.ip_address, peer.clone()).await.unwrap();
+        let relayed = relayed_blocks(&node);
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].hash, valid.hash);
+
+        // eager relay forwards on receipt, without validating
+        node.relay_validated_only = false;
+        node.serve_request(&Message::BlockTransmission(invalid), peer.ip_address, peer).await.unwrap();
+        assert_eq!(relayed_blocks(&node).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_proof_requests_throttled(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 20));
+        let spammer = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 21)), 8105);
+        let honest = Peer::new([4; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 50)), 8135);
+        let (mut node, _) = create_empty_node_genisis(ip_address, 8104, vec![spammer.clone(), honest.clone()], true, None).await;
+        *node.inner.state.lock().await = NodeState::Serving;
+        let mut limiter = ProofRateLimiter::new(2, 10);
+        limiter.clock = Clock::mock(0);
+        limiter.max_penalty = 3;
+        *node.inner.proof_limiter.lock().await = limiter;
+
+        let genesis = get_genesis_block(None);
+        let request = Message::TransactionProofRequest(TransactionStub {
+            block_hash: node.inner.chain.lock().await.as_ref().unwrap().deepest_hash,
+            transaction_hash: genesis.transactions[0].hash,
+        });
+        let mut responses = vec![];
+        for _ in 0..4 {
+            responses.push(node.serve_request(&request, spammer.ip_address, spammer.clone()).await.unwrap());
+        }
+        assert!(responses[..2].iter().all(|r| matches!(r, Message::TransactionProofResponse(_))));
+        assert!(responses[2..].iter().all(|r| matches!(r, Message::Error(_))));
+        assert_eq!(node.inner.proof_limiter.lock().await.penalty(&spammer.ip_address), 2);
+        assert!(node.inner.peers.lock().await.contains_key(&spammer.public_key));
+
+        // other requests are not limited
+        assert!(matches!(node.serve_request(&Message::TipRequest, spammer.ip_address, spammer.clone()).await.unwrap(), Message::TipResponse(_)));
+        // the limit follows the address, whatever key the spammer declares
+        let response = node.serve_request(&request, spammer.ip_address, honest.clone()).await.unwrap();
+        assert!(matches!(response, Message::Error(_)));
+        assert_eq!(node.inner.proof_limiter.lock().await.penalty(&honest.ip_address), 0);
+        // an address past the penalty limit is dropped, and the peer it posed as is kept
+        assert!(node.inner.proof_limiter.lock().await.is_banned(&spammer.ip_address));
+        assert!(!node.inner.peers.lock().await.contains_key(&spammer.public_key));
+        assert!(node.inner.peers.lock().await.contains_key(&honest.public_key));
+        assert!(matches!(node.serve_request(&request, honest.ip_address, honest.clone()).await.unwrap(), Message::TransactionProofResponse(_)));
+
+        // the limit resets with the window
+        node.inner.proof_limiter.lock().await.clock.advance(10);
+        assert!(matches!(node.serve_request(&request, spammer.ip_address, spammer).await.unwrap(), Message::TransactionProofResponse(_)));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_node(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 29));
+        let (mut node, mut wallet) = create_empty_node_genisis(ip_address, 8114, vec![], true, Some(MinerPool::new())).await;
+        node.read_only = true;
+        *node.inner.state.lock().await = NodeState::Serving;
+
+        assert!(Miner::new(node.clone()).is_err());
+        let submitted = submit_transaction(&mut node, &mut wallet, [1; 32], 0, false, None).await;
+        assert!(matches!(submitted, Err(QueryError::ReadOnly)));
+        assert_eq!(wallet.nonce, 0);
+
+        // queries are still served
+        let peer = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 30)), 8115);
+        let tip = match node.serve_request(&Message::TipRequest, peer.ip_address, peer.clone()).await.unwrap() {
+            Message::TipResponse(tip) => tip,
+            other => panic!("Expected a tip, got {other:?}"),
+        };
+        let response = node.serve_request(&Message::BlockRequest(tip.hash), peer.ip_address, peer).await.unwrap();
+        assert!(matches!(response, Message::BlockResponse(Some(block)) if block.hash == Some(tip.hash)));
+    }
+
+    #[tokio::test]
+    async fn test_proof_served_from_cache(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 31));
+        let peer = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 32)), 8117);
+        let (mut node, _) = create_empty_node_genisis(ip_address, 8116, vec![], true, None).await;
+        *node.inner.state.lock().await = NodeState::Serving;
+        let block = {
+            let chain = node.inner.chain.lock().await;
+            let chain = chain.as_ref().unwrap();
+            chain.blocks[&chain.deepest_hash].clone()
+        };
+        let stub = TransactionStub { block_hash: block.hash.unwrap(), transaction_hash: block.transactions[0].hash };
+        let fresh = block.get_proof_for_transaction(stub.transaction_hash).unwrap();
+
+        let mut responses = vec![];
+        for _ in 0..2 {
+            match node.serve_request(&Message::TransactionProofRequest(stub.clone()), peer.ip_address, peer.clone()).await.unwrap() {
+                Message::TransactionProofResponse(proof) => responses.push(proof),
+                other => panic!("Expected a proof, got {other:?}"),
+            }
+        }
+        assert_eq!(responses, vec![fresh.clone(), fresh]);
+        let cache = node.inner.proof_cache.lock().await;
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.len(), 1);
+        drop(cache);
+
+        // a transaction that is not in the block is refused, and nothing is cached
+        let missing = TransactionStub { transaction_hash: [9; 32], ..stub };
+        let response = node.serve_request(&Message::TransactionProofRequest(missing), peer.ip_address, peer).await.unwrap();
+        assert!(matches!(response, Message::Error(_)));
+        assert_eq!(node.inner.proof_cache.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_queued_proofs_served(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 45));
+        let peer = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 46)), 8131);
+        let (node, _) = create_empty_node_genisis(ip_address, 8130, vec![], true, None).await;
+        *node.inner.state.lock().await = NodeState::Serving;
+        *node.inner.proof_queue.lock().await = ProofQueue::new(1);
+        // skip the cache so every request generates a proof
+        node.inner.proof_cache.lock().await.capacity = 0;
+        let block = {
+            let chain = node.inner.chain.lock().await;
+            let chain = chain.as_ref().unwrap();
+            chain.blocks[&chain.deepest_hash].clone()
+        };
+        let stub = TransactionStub { block_hash: block.hash.unwrap(), transaction_hash: block.transactions[0].hash };
+        let fresh = block.get_proof_for_transaction(stub.transaction_hash).unwrap();
+
+        let handles = (0..8).map(|_| {
+            let (mut node, stub, peer) = (node.clone(), stub.clone(), peer.clone());
+            tokio::spawn(async move {
+                node.serve_request(&Message::TransactionProofRequest(stub), peer.ip_address, peer).await.unwrap()
+            })
+        }).collect::<Vec<_>>();
+        for handle in handles {
+            match handle.await.unwrap() {
+                Message::TransactionProofResponse(proof) => assert_eq!(proof, fresh),
+                other => panic!("Expected a proof, got {other:?}"),
+            }
+        }
+        assert_eq!(node.inner.proof_queue.lock().await.active(), 0);
+
+        // with the slot busy and no room to wait, every kind of proof request is refused
+        node.inner.proof_queue.lock().await.max_waiting = 0;
+        let busy = node.inner.proof_queue.lock().await.clone();
+        let running = tokio::spawn(async move { busy.run(|| std::thread::sleep(std::time::Duration::from_millis(200))).await });
+        tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+        let state_root = node.inner.chain.lock().await.as_ref().unwrap().get_state_root().unwrap();
+        for request in [Message::TransactionProofRequest(stub), Message::StateRangeRequest(state_root, [0; 32], [0xff; 32])] {
+            let mut node = node.clone();
+            assert!(matches!(node.serve_request(&request, peer.ip_address, peer.clone()).await.unwrap(), Message::Error(e) if e == "Proof queue is full"));
+        }
+        assert!(running.await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_verification_cost_budget(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 47));
+        let spammer = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 48)), 8133);
+        let other = Peer::new([4; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 49)), 8134);
+        let (mut node, _) = create_empty_node_genisis(ip_address, 8132, vec![], true, None).await;
+        *node.inner.state.lock().await = NodeState::Serving;
+        let mut budget = CostBudget::new(3 * PROOF_COST, 10);
+        budget.clock = Clock::mock(0);
+        *node.inner.cost_budget.lock().await = budget;
+
+        let genesis = get_genesis_block(None);
+        let request = Message::TransactionProofRequest(TransactionStub {
+            block_hash: node.inner.chain.lock().await.as_ref().unwrap().deepest_hash,
+            transaction_hash: genesis.transactions[0].hash,
+        });
+        let mut responses = vec![];
+        for _ in 0..5 {
+            responses.push(node.serve_request(&request, spammer.ip_address, spammer.clone()).await.unwrap());
+        }
+        assert!(responses[..3].iter().all(|r| matches!(r, Message::TransactionProofResponse(_))));
+        assert!(responses[3..].iter().all(|r| matches!(r, Message::Error(_))));
+        // whole chain requests are throttled by the same budget
+        assert!(matches!(node.serve_request(&Message::ChainShardRequest, spammer.ip_address, spammer.clone()).await.unwrap(), Message::Error(_)));
+        // cheap messages still proceed, from anyone
+        assert!(matches!(node.serve_request(&Message::TipRequest, spammer.ip_address, spammer.clone()).await.unwrap(), Message::TipResponse(_)));
+        assert!(matches!(node.serve_request(&Message::TipRequest, other.ip_address, other.clone()).await.unwrap(), Message::TipResponse(_)));
+        // declaring another key does not buy a fresh budget
+        assert!(matches!(node.serve_request(&request, spammer.ip_address, other.clone()).await.unwrap(), Message::Error(_)));
+        // and the spending does not land on the peer it posed as
+        assert!(matches!(node.serve_request(&request, other.ip_address, other).await.unwrap(), Message::TransactionProofResponse(_)));
+
+        // the budget resets with the window
+        node.inner.cost_budget.lock().await.clock.advance(10);
+        assert!(matches!(node.serve_request(&request, spammer.ip_address, spammer).await.unwrap(), Message::TransactionProofResponse(_)));
+    }
+
+    #[tokio::test]
+    async fn test_header_request_retried(){
+        let (ip_a, ip_b) = (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 51)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 52)));
+        let unreachable = Peer::new([5; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53)), 8138);
+        let (node_b, _) = create_empty_node_genisis(ip_b, 8137, vec![], true, None).await;
+        let (mut node_a, _) = create_empty_node_genisis(ip_a, 8136, vec![unreachable, (&node_b).into()], true, None).await;
+        node_a.retry_policy = RetryPolicy { max_attempts: 3, base_delay: std::time::Duration::from_millis(10), jitter: std::time::Duration::ZERO };
+        *node_b.inner.state.lock().await = NodeState::Serving;
+        tokio::spawn(serve_peers(node_b.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        // whichever peer is asked first, the serving one answers
+        let genesis = node_b.inner.chain.lock().await.as_ref().unwrap().canonical_block_at(0).unwrap().header;
+        assert_eq!(request_header_at_depth(&node_a, 0).await.unwrap(), Some(genesis));
+        assert_eq!(request_header_at_depth(&node_a, 5).await.unwrap(), None);
+        // with only the unreachable peer left, the request fails once the attempts run out
+        node_a.inner.peers.lock().await.remove(&node_b.inner.public_key);
+        assert!(request_header_at_depth(&node_a, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_state_range_sync(){
+        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 37));
+        let peer = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::new(127, 0, 0, 38)), 8123);
+        let (mut node, _) = create_empty_node_genisis(ip_address, 8122, vec![], true, None).await;
+        *node.inner.state.lock().await = NodeState::Serving;
+        // spread accounts over the address space
+        let state_root = {
+            let mut chain = node.inner.chain.lock().await;
+            let chain = chain.as_mut().unwrap();
+            let updates = (1..=16u8).map(|i| ([i.wrapping_mul(37); 32], AccountDelta { credit: i as u64, debit: 0, nonce: 0 })).collect::<Vec<_>>();
+            chain.state_manager.apply_updates(chain.get_state_root().unwrap(), &updates).unwrap()
+        };
+
+        // pages cut short by the node's cap are followed to the end of each half
+        node.max_accounts_per_range = 3;
+        let mut fetched = vec![];
+        for (mut start, end) in [([0; 32], [0x7f; 32]), ([0x80; 32], [0xff; 32])] {
+            loop {
+                let range = match node.serve_request(&Message::StateRangeRequest(state_root, start, end), peer.ip_address, peer.clone()).await.unwrap() {
+                    Message::StateRangeResponse(range) => range,
+                    other => panic!("Expected a state range, got {other:?}"),
+                };
+                assert!(range.within(start, end).count() <= 3);
+                assert!(verify_account_range(&range, start, end, state_root));
+                fetched.extend(range.within(start, end).cloned());
+                match range.next {
+                    Some(next) => start = next,
+                    None => break,
+                }
+            }
+        }
+        fetched.sort_by_key(|account| account.address);
+        let chain = node.inner.chain.lock().await;
+        let snapshot = chain.as_ref().unwrap().state_manager.snapshot(state_root, &mut DefaultHash::new()).unwrap();
+        assert_eq!(fetched, snapshot.accounts);
+        assert_eq!(node.inner.proof_queue.lock().await.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_discovery_checkpoint(){
+        let (ip_a, ip_b) = (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 39)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 40)));
+        let (node_b, _) = create_empty_node_genisis(ip_b, 8125, vec![], true, None).await;
+        let (mut node_a, _) = create_empty_node_genisis(ip_a, 8124, vec![(&node_b).into()], false, None).await;
+        *node_b.inner.state.lock().await = NodeState::Serving;
+        tokio::spawn(serve_peers(node_b.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let genesis_hash = node_b.inner.chain.lock().await.as_ref().unwrap().deepest_hash;
+        // an observer registered before there is a chain
+        #[derive(Debug, Default)]
+        struct Connected(std::sync::Mutex<Vec<StdByteArray>>);
+        impl BlockObserver for Connected {
+            fn on_connect(&self, block: &Block) {
+                self.0.lock().unwrap().push(block.hash.unwrap());
+            }
+            fn on_disconnect(&self, _: &Block) {}
+        }
+        let observer = Arc::new(Connected::default());
+        node_a.register_block_observer(observer.clone()).await;
+
+        // the only peer disagrees with the checkpoint, so there is nothing to sync from
+        node_a.checkpoint = Some(Checkpoint { depth: 0, hash: [9; 32] });
+        assert!(matches!(dicover_chain(node_a.clone()).await, Err(QueryError::InsufficientInfo(_))));
+        assert!(node_a.inner.chain.lock().await.is_none());
+
+        // a consistent checkpoint is synced, and kept for the blocks that follow
+        node_a.checkpoint = Some(Checkpoint { depth: 0, hash: genesis_hash });
+        dicover_chain(node_a.clone()).await.unwrap();
+        let chain = node_a.inner.chain.lock().await;
+        assert_eq!(chain.as_ref().unwrap().deepest_hash, genesis_hash);
+        assert_eq!(chain.as_ref().unwrap().params.checkpoint, node_a.checkpoint);
+        // the synced chain connects the observer from genesis
+        assert_eq!(*observer.0.lock().unwrap(), vec![genesis_hash]);
+    }
+
+    #[tokio::test]
+    async fn test_tip_agreement(){
+        let ip = |last: u8| IpAddr::V4(Ipv4Addr::new(127, 0, 0, last));
+        let mut peers = vec![];
+        for (last, port) in [(42, 8127), (43, 8128), (44, 8129)] {
+            let (peer, _) = create_empty_node_genisis(ip(last), port, vec![], true, None).await;
+            *peer.inner.state.lock().await = NodeState::Serving;
+            tokio::spawn(serve_peers(peer.clone(), None));
+            peers.push(peer);
+        }
+        let (node, _) = create_empty_node_genisis(ip(41), 8126, peers.iter().map(|peer| peer.into()).collect(), true, None).await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        // gives a peer another block at the tip depth
+        let fork = |peer: &Node| {
+            let peer = peer.clone();
+            async move {
+                let mut lock = peer.inner.chain.lock().await;
+                let chain = lock.as_mut().unwrap();
+                let mut genesis = chain.blocks.remove(&chain.deepest_hash).unwrap();
+                chain.headers.remove(&chain.deepest_hash);
+                genesis.header.timestamp += 1;
+                let hash = genesis.finalize(&mut DefaultHash::new()).unwrap();
+                chain.headers.insert(hash, genesis.header);
+                chain.blocks.insert(hash, genesis);
+                chain.deepest_hash = hash;
+            }
+        };
+
+        let agreement = check_tip_agreement(&node, 3).await.unwrap();
+        assert_eq!(agreement, TipAgreement { agreeing: 3, disagreeing: 0, unanswered: 0 });
+        // a minority disagrees
+        fork(&peers[0]).await;
+        let agreement = check_tip_agreement(&node, 3).await.unwrap();
+        assert_eq!(agreement, TipAgreement { agreeing: 2, disagreeing: 1, unanswered: 0 });
+        assert!(!agreement.is_alert());
+        // a majority disagrees
+        fork(&peers[1]).await;
+        let agreement = check_tip_agreement(&node, 3).await.unwrap();
+        assert_eq!(agreement, TipAgreement { agreeing: 1, disagreeing: 2, unanswered: 0 });
+        assert!(agreement.is_alert());
+        // a peer without the chain does not count either way
+        *peers[2].inner.state.lock().await = NodeState::ICD;
+        let agreement = check_tip_agreement(&node, 3).await.unwrap();
+        assert_eq!(agreement, TipAgreement { agreeing: 0, disagreeing: 2, unanswered: 1 });
+        // a smaller sample only asks that many peers
+        let sampled = check_tip_agreement(&node, 1).await.unwrap();
+        assert_eq!(sampled.agreeing + sampled.disagreeing + sampled.unanswered, 1);
+    }
+
+    #[tokio::test]
+    async fn test_mempool_reconciliation(){
+        let (ip_a, ip_b) = (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 25)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 26)));
+        let (node_b, _) = create_empty_node_genisis(ip_b, 8111, vec![], true, Some(MinerPool::new())).await;
+        let (node_a, _) = create_empty_node_genisis(ip_a, 8110, vec![(&node_b).into()], true, Some(MinerPool::new())).await;
+        *node_b.inner.state.lock().await = NodeState::Serving;
+        tokio::spawn(serve_peers(node_b.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut wallet = Wallet::generate_random();
+        let mut transaction = |nonce: u64| {
+            let mut transaction = Transaction::new(wallet.address, [9; 32], 0, 0, nonce, &mut DefaultHash::new());
+            transaction.sign(&mut wallet);
+            transaction
+        };
+        let (pool_a, pool_b) = (node_a.miner_pool.as_ref().unwrap(), node_b.miner_pool.as_ref().unwrap());
+        for nonce in 0..4 {
+            pool_a.add_transaction(transaction(nonce));
+        }
+        for nonce in 2..7 {
+            pool_b.add_transaction(transaction(nonce));
+        }
+        // a forged transaction in the peer's pool is not taken in
+        let forged = Transaction::new([1; 32], [9; 32], 0, 0, 0, &mut DefaultHash::new());
+        pool_b.add_transaction(forged);
+        let mut peer: Peer = (&node_b).into();
+        assert_eq!(reconcile_mempool(&node_a, &mut peer).await.unwrap(), (3, 2));
+        assert!(!pool_a.transaction_ids().contains(&forged.hash));
+        assert_eq!(pool_a.transaction_ids().len(), 7);
+        assert_eq!(pool_b.transaction_ids().len(), 8);
+        // nothing left to exchange
+        assert_eq!(reconcile_mempool(&node_a, &mut peer).await.unwrap(), (0, 0));
+        assert_eq!(pool_b.pending_transactions().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_mempool_sync_paginated(){
+        let (ip_a, ip_b) = (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 35)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 36)));
+        let (mut node_b, _) = create_empty_node_genisis(ip_b, 8121, vec![], true, Some(MinerPool::new())).await;
+        let (node_a, _) = create_empty_node_genisis(ip_a, 8120, vec![(&node_b).into()], true, Some(MinerPool::new())).await;
+        node_b.max_mempool_tx_per_response = 3;
+        *node_b.inner.state.lock().await = NodeState::Serving;
+        tokio::spawn(serve_peers(node_b.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut wallet = Wallet::generate_random();
+        let transactions = (0..9).map(|nonce| {
+            let mut transaction = Transaction::new(wallet.address, [9; 32], 0, 0, nonce, &mut DefaultHash::new());
+            transaction.sign(&mut wallet);
+            transaction
+        }).collect::<Vec<_>>();
+        let transaction = |nonce: u64| transactions[nonce as usize];
+        let (pool_a, pool_b) = (node_a.miner_pool.as_ref().unwrap(), node_b.miner_pool.as_ref().unwrap());
+        for nonce in 0..8 {
+            pool_b.add_transaction(transaction(nonce));
+        }
+        pool_a.add_transaction(transaction(8));
+
+        // a single response is truncated to the cap, in pool order
+        let mut peer: Peer = (&node_b).into();
+        let response = peer.communicate(&Message::MempoolSyncRequest(pool_a.transaction_ids()), &(&node_a).into()).await.unwrap();
+        let Message::MempoolSyncResponse(missing, requested) = response else {
+            panic!("Expected a mempool sync response");
+        };
+        assert_eq!(missing, (0..3).map(transaction).collect::<Vec<_>>());
+        assert_eq!(requested.len(), 1);
+
+        // reconciling continues past the cap until nothing is missing
+        assert_eq!(reconcile_mempool(&node_a, &mut peer).await.unwrap(), (8, 1));
+        assert_eq!(pool_a.transaction_ids(), pool_b.transaction_ids());
+        assert_eq!(pool_a.transaction_ids().len(), 9);
+    }
 }
@@ -1,4 +1,4 @@
-use super::peer::Peer;
+use super::{broadcast_queue::BroadcastQueue, cost_budget::{verification_cost, CostBudget}, keepalive::{ConnectionTimeouts, KeepAlive}, peer::Peer, proof_cache::ProofCache, proof_queue::ProofQueue, rate_limit::ProofRateLimiter, retry::{RequestFailures, RetryPolicy}};
 use flume::{Receiver, Sender};
 use pillar_crypto::{hashing::{DefaultHash, Hashable}, signing::{DefaultSigner, SigFunction, Signable}, types::StdByteArray};
 use tracing::instrument;
@@ -6,15 +6,17 @@ use std::{any::Any, collections::{HashMap, HashSet}, net::IpAddr, sync::Arc};
 use tokio::sync::Mutex;
 
 use crate::{
-    blockchain::chain::Chain,
-    persistence::database::{Datastore, EmptyDatastore},
-    primitives::{block::{Block, BlockHeader, Stamp}, messages::Message, pool::MinerPool, transaction::{FilterMatch, TransactionFilter}},
-    protocol::{chain::{block_settle_consumer, dicover_chain, service_sync, sync_chain},
-    communication::{broadcast_knowledge, serve_peers},
-    reputation::{nth_percentile_peer, N_TRANSMISSION_SIGNATURES}},
+    accounting::state::MAX_ACCOUNTS_PER_RANGE,
+    blockchain::{chain::Chain, BlockObserver},
+    persistence::database::{verify_blocks_integrity, Datastore, EmptyDatastore},
+    primitives::{block::{Block, BlockHeader, Stamp}, messages::{Message, WireFormat}, pool::MinerPool, transaction::{FilterMatch, TransactionFilter}},
+    protocol::{chain::{block_settle_consumer, dicover_chain, service_sync, sync_chain, MAX_BLOCK_DOWNLOADS},
+    communication::{broadcast_knowledge, keep_alive, serve_peers}, params::Checkpoint,
+    reputation::{nth_percentile_peer, N_TRANSMISSION_SIGNATURES}, transactions::MAX_MEMPOOL_TX_PER_RESPONSE},
 };
  
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum NodeState{
     ICD,
     ChainOutdated,
@@ -63,9 +65,14 @@ fn get_initial_state(datastore: &dyn Datastore) -> (NodeState, Option<Chain>) {
     if datastore.latest_chain().is_some() {
         // if it does, load the chain
         match datastore.load_chain() {
-            Ok(chain) => {
+            Ok(chain) => match verify_blocks_integrity(&chain.blocks) {
                 // assign the chain to the node
-                (NodeState::ChainOutdated, Some(chain))
+                Ok(()) => (NodeState::ChainOutdated, Some(chain)),
+                Err(e) => {
+                    // a corrupted chain cannot be trusted - discover it again
+                    tracing::warn!("Stored chain failed its integrity check: {}", e);
+                    (NodeState::ICD, None)
+                }
             },
             Err(_) => {
                 // if it fails, we are in discovery mode
@@ -86,8 +93,8 @@ pub struct NodeInner {
     pub peers: Mutex<HashMap<StdByteArray, Peer>>,
     // the blockchain
     pub chain: Mutex<Option<Chain>>,
-    /// transactions to be broadcasted
-    pub broadcast_queue: lfqueue::UnboundedQueue<Message>,
+    /// messages to be broadcasted - bounded, with a configurable overflow policy
+    pub broadcast_queue: BroadcastQueue,
     // a collection of things already broadcasted
     pub broadcasted_already: Mutex<HashSet<StdByteArray>>,
     // transaction filter queue
@@ -100,6 +107,20 @@ pub struct NodeInner {
     pub state: Mutex<NodeState>,
     /// registered filters for the local node - producer will be this node, and consumer will be some backgroung thread that polls
     pub filter_callbacks: Mutex<HashMap<TransactionFilter, Sender<BlockHeader>>>,
+    /// per peer limits on proof requests
+    pub proof_limiter: Mutex<ProofRateLimiter>,
+    /// per peer budgets on the cost of serving expensive requests
+    pub cost_budget: Mutex<CostBudget>,
+    /// transaction proofs already served
+    pub proof_cache: Mutex<ProofCache>,
+    /// bounds how many proofs are generated at once
+    pub proof_queue: Mutex<ProofQueue>,
+    /// missed keepalive pings per peer
+    pub keepalive: Mutex<KeepAlive>,
+    /// consecutive failed requests per peer
+    pub request_failures: Mutex<RequestFailures>,
+    /// observers of the deepest chain - attached to every chain the node syncs
+    pub block_observers: Mutex<Vec<Arc<dyn BlockObserver>>>,
 }
 
 #[derive(Clone)]
@@ -111,10 +132,32 @@ pub struct Node {
     pub port: u16,
     /// transactions to be serviced
     pub miner_pool: Option<MinerPool>,
+    /// only relay mined blocks once they are validated, rather than on receipt
+    pub relay_validated_only: bool,
+    /// how many blocks may be downloaded at once while syncing
+    pub max_block_downloads: usize,
+    /// the least cumulative work a peer's chain needs to be synced from - for example, the work up to a trusted checkpoint
+    pub min_total_work: u128,
+    /// a trusted checkpoint given at startup - peers whose chains disagree with it are not synced from,
+    /// and blocks that disagree with it are rejected
+    pub checkpoint: Option<Checkpoint>,
+    /// how many transactions to serve in one mempool sync response - the requester asks again for the rest
+    pub max_mempool_tx_per_response: usize,
+    /// how many accounts to serve in one state range response - the requester continues from where it was cut short
+    pub max_accounts_per_range: usize,
+    /// timeouts for every exchange with a peer, as client and as server
+    pub connection_timeouts: ConnectionTimeouts,
+    /// how failed block requests during sync are retried
+    pub retry_policy: RetryPolicy,
+    /// observe only - validate, sync, and answer queries, but never mine or submit transactions
+    pub read_only: bool,
+    /// the format of messages this node sends, and accepts - peers speaking another format are turned away at the handshake
+    pub wire_format: WireFormat,
     /// kill handles
     kill_broadcast: Option<flume::Sender<()>>,
     kill_serve: Option<flume::Sender<()>>,
     kill_settle: Option<flume::Sender<()>>,
+    kill_keepalive: Option<flume::Sender<()>>,
 }
 
 
@@ -143,17 +186,26 @@ impl Node {
             tracing::warn!("Node created without a database. This will not persist the chain or transactions.");
             database = Some(Arc::new(EmptyDatastore::new()));
         }
-        let broadcast_queue = lfqueue::UnboundedQueue::new();
+        let broadcast_queue = BroadcastQueue::default();
         let late_settle_queue = lfqueue::UnboundedQueue::new();
         let transaction_filters = Mutex::new(Vec::new());
         let broadcasted_already = Mutex::new(HashSet::new());
         let peer_map = peers
             .iter()
+            // a node is never its own peer
+            .filter(|peer| peer.public_key != public_key)
             .map(|peer| (peer.public_key, peer.clone()))
             .collect::<HashMap<_, _>>();
         tracing::info!("Node created with {} initial peers", peer_map.len());
         let (state, maybe_chain) = get_initial_state(&**database.as_ref().unwrap());
         tracing::debug!("Node initial state: {:?}", state);
+        // reload transactions that were pending when the node last stopped
+        if let (Some(pool), Some(chain)) = (&transaction_pool, &maybe_chain) {
+            match pool.restore(&**database.as_ref().unwrap(), chain) {
+                Ok(n) => tracing::info!("Restored {} pending transactions", n),
+                Err(e) => tracing::warn!("Failed to restore pending transactions: {}", e),
+            }
+        }
         Node {
             inner: NodeInner {
             public_key,
@@ -167,13 +219,31 @@ impl Node {
             state: Mutex::new(state), // initially in discovery mode
             late_settle_queue,
             datastore: database,
+            proof_limiter: Mutex::new(ProofRateLimiter::default()),
+            cost_budget: Mutex::new(CostBudget::default()),
+            proof_cache: Mutex::new(ProofCache::default()),
+            proof_queue: Mutex::new(ProofQueue::default()),
+            keepalive: Mutex::new(KeepAlive::default()),
+            request_failures: Mutex::new(RequestFailures::default()),
+            block_observers: Mutex::new(Vec::new()),
             }.into(),
             ip_address,
             port,
             miner_pool: transaction_pool,
+            relay_validated_only: true,
+            max_block_downloads: MAX_BLOCK_DOWNLOADS,
+            min_total_work: 0,
+            checkpoint: None,
+            max_mempool_tx_per_response: MAX_MEMPOOL_TX_PER_RESPONSE,
+            max_accounts_per_range: MAX_ACCOUNTS_PER_RANGE,
+            connection_timeouts: ConnectionTimeouts::default(),
+            retry_policy: RetryPolicy::default(),
+            read_only: false,
+            wire_format: WireFormat::default(),
             kill_broadcast: None,
             kill_serve: None,
             kill_settle: None,
+            kill_keepalive: None,
         }
     }
     
@@ -190,6 +260,7 @@ impl Node {
         let broadcast_killer = flume::bounded(1);
         let serve_killer = flume::bounded(1);
         let settle_killer = flume::bounded(1);
+        let keepalive_killer = flume::bounded(1);
     
         let state = self.inner.state.lock().await.clone();
         let handle = match state {
@@ -227,12 +298,14 @@ impl Node {
             });
         }
         tracing::trace!("Node is now in state: {:?}", self.inner.state.lock().await);
-        let _ = tokio::spawn(serve_peers(self.clone(), Some(serve_killer.1.clone())));
-        let _ = tokio::spawn(broadcast_knowledge(self.clone(), Some(broadcast_killer.1.clone())));
-        let _ = tokio::spawn(block_settle_consumer(self.clone(), Some(settle_killer.1.clone())));
+        tokio::spawn(serve_peers(self.clone(), Some(serve_killer.1.clone())));
+        tokio::spawn(broadcast_knowledge(self.clone(), Some(broadcast_killer.1.clone())));
+        tokio::spawn(block_settle_consumer(self.clone(), Some(settle_killer.1.clone())));
+        tokio::spawn(keep_alive(self.clone(), Some(keepalive_killer.1.clone())));
         self.kill_broadcast = Some(broadcast_killer.0);
         self.kill_serve = Some(serve_killer.0);
         self.kill_settle = Some(settle_killer.0);
+        self.kill_keepalive = Some(keepalive_killer.0);
         tracing::info!("Node processes finished launching. Broadcasting and serving threads are now running.");
     }
 
@@ -246,11 +319,30 @@ impl Node {
         let _ = self.kill_broadcast.as_ref().unwrap().send(());
         let _ = self.kill_serve.as_ref().unwrap().send(());
         let _ = self.kill_settle.as_ref().unwrap().send(());
+        let _ = self.kill_keepalive.as_ref().unwrap().send(());
         tracing::debug!("Kill signals sent.");
+        // save pending transactions so they survive a restart
+        if let (Some(pool), Some(datastore)) = (&self.miner_pool, &self.inner.datastore) {
+            match pool.persist(&**datastore) {
+                Ok(n) => tracing::info!("Persisted {} pending transactions", n),
+                Err(e) => tracing::warn!("Failed to persist pending transactions: {}", e),
+            }
+        }
         *self.inner.state.lock().await = NodeState::ChainOutdated;
         tracing::info!("Node stopping.");
     }
 
+    /// Register an observer of blocks joining and leaving the deepest chain.
+    /// The observer follows the current chain, if any, and is attached to every chain the node syncs later -
+    /// a synced chain connects to it from genesis
+    pub async fn register_block_observer(&self, observer: Arc<dyn BlockObserver>) {
+        let mut chain = self.inner.chain.lock().await;
+        if let Some(chain) = chain.as_mut() {
+            chain.add_observer(observer.clone());
+        }
+        self.inner.block_observers.lock().await.push(observer);
+    }
+
     /// Register a transaction filter callback - adds the callback channel and adds it to the transaction filter queue
     /// Sends a broadcast to request peers to also watch for the block - if a peer catches it, it will be sent back
     #[instrument(name = "Node::register_transaction_callback", skip(self, filter), fields(
@@ -266,20 +358,26 @@ impl Node {
         self.inner.transaction_filters.lock().await.push((filter.clone(), self.clone().into()));
         tracing::info!("Transaction filter registered, starting brodcast");
         // broadcast the filter to all peers
-        self.inner.broadcast_queue.enqueue(Message::TransactionFilterRequest(filter, self.clone().into()));
+        self.inner.broadcast_queue.enqueue(Message::TransactionFilterRequest(filter, self.clone().into())).await;
         // return the receiver
         receiver
     }
 
-    /// Derive the response to a request from a peer
-    #[instrument(name = "Node::serve_request", skip(self, message, _declared_peer), fields(
+    /// Derive the response to a request from a peer.
+    /// `source` is the address the connection came from - limits are kept by it, as the declared peer is only a claim
+    #[instrument(name = "Node::serve_request", skip(self, message, declared_peer), fields(
         public_key = ?self.inner.public_key,
-        peer = ?_declared_peer.public_key,
+        peer = ?declared_peer.public_key,
         message = ?message.type_id()
     ))]
-    pub async fn serve_request(&mut self, message: &Message, _declared_peer: Peer) -> Result<Message, std::io::Error> {
+    pub async fn serve_request(&mut self, message: &Message, source: IpAddr, declared_peer: Peer) -> Result<Message, std::io::Error> {
         let state = self.inner.state.lock().await.clone();
+        if !self.inner.cost_budget.lock().await.charge(&source, verification_cost(message)) {
+            tracing::warn!("Address {} is over its verification cost budget", source);
+            return Ok(Message::Error("Verification cost budget exceeded".into()));
+        }
         match message {
+            Message::Ping => Ok(Message::Ping),
             Message::PeerRequest => {
                 // send all peers
                 let response = Message::PeerResponse(self.inner.peers.lock().await.values().cloned().collect());
@@ -297,16 +395,15 @@ impl Node {
             },
             Message::TransactionBroadcast(transaction) => {
                 // add the transaction to the pool
-                if let Some(ref pool) = self.miner_pool{
-                    if state.is_consume() {
-                        tracing::info!("Adding transaction to mining pool.");
-                        pool.add_transaction(*transaction);
-                    }
+                if let Some(ref pool) = self.miner_pool
+                    && state.is_consume() {
+                    tracing::info!("Adding transaction to mining pool.");
+                    pool.add_transaction(*transaction);
                 }
                 // to be broadcasted
                 if state.is_forward(){
                     tracing::info!("Broadcasting transaction");
-                    self.inner.broadcast_queue.enqueue(Message::TransactionBroadcast(transaction.to_owned()));
+                    self.inner.broadcast_queue.enqueue(Message::TransactionBroadcast(transaction.to_owned())).await;
                 }
                 Ok(Message::TransactionAck)
             }
@@ -326,14 +423,21 @@ impl Node {
                     self.handle_callbacks(&block).await;
                 }
 
-                if state.is_forward(){
+                // mined blocks are relayed once validated - after settling when consuming, otherwise once the header checks out
+                let relay_now = match block.hash {
+                    Some(hash) if self.relay_validated_only && block.header.miner_address.is_some() => {
+                        !state.is_consume() && block.header.validate(hash, &mut DefaultHash::new()).is_ok()
+                    },
+                    _ => true,
+                };
+                if state.is_forward() && relay_now{
                     // TODO handle is_track instead
                     // if we do not have the chain, just forward the block if there is room in the stamps
                     if block.header.tail.n_stamps() < N_TRANSMISSION_SIGNATURES && !state.is_consume(){
                         tracing::info!("Stamping and broadcasting only because not ");
                         let _ = self.stamp_block(&mut block.clone());
                     }
-                    self.inner.broadcast_queue.enqueue(Message::BlockTransmission(block.clone())); // forward
+                    self.inner.broadcast_queue.enqueue(Message::BlockTransmission(block.clone())).await; // forward
                 }
                 Ok(Message::BlockAck)
             },
@@ -348,6 +452,25 @@ impl Node {
                     Ok(Message::Error("Chain not downloaded for peer".into()))
                 }
             },
+            Message::TipRequest => {
+                // send the tip of the deepest chain
+                if state.is_consume(){
+                    let lock = self.inner.chain.lock().await;
+                    Ok(Message::TipResponse(lock.as_ref().unwrap().get_tip()))
+                }else{
+                    Ok(Message::Error("Chain not downloaded for peer".into()))
+                }
+            },
+            Message::HeaderRequest(depth) => {
+                // send the header at the depth of the deepest chain
+                if state.is_consume(){
+                    let lock = self.inner.chain.lock().await;
+                    let header = lock.as_ref().unwrap().canonical_block_at(*depth).map(|block| block.header);
+                    Ok(Message::HeaderResponse(header))
+                }else{
+                    Ok(Message::Error("Chain not downloaded for peer".into()))
+                }
+            },
             Message::ChainShardRequest => {
                 // send the block headers to the peer
                 if state.is_consume(){
@@ -359,15 +482,26 @@ impl Node {
                 }
             },
             Message::TransactionProofRequest(stub) => {
+                if !self.allow_proof_request(source).await {
+                    return Ok(Message::Error("Proof request rate exceeded".into()));
+                }
                 if state.is_consume(){
-                    let lock = self.inner.chain.lock().await;
-                    let chain = lock.as_ref().unwrap().clone();
+                    if let Some(proof) = self.inner.proof_cache.lock().await.get(&stub.block_hash, &stub.transaction_hash) {
+                        return Ok(Message::TransactionProofResponse(proof));
+                    }
+                    let block = self.inner.chain.lock().await.as_ref().unwrap().get_block(&stub.block_hash).cloned();
 
-                    let block = chain.get_block(&stub.block_hash);
-                    
                     if let Some(block) = block{
-                        let proof = block.get_proof_for_transaction(stub.transaction_hash);
-                        Ok(Message::TransactionProofResponse(proof.unwrap()))
+                        let transaction_hash = stub.transaction_hash;
+                        let queue = self.inner.proof_queue.lock().await.clone();
+                        match queue.run(move || block.get_proof_for_transaction(transaction_hash)).await {
+                            Some(Some(proof)) => {
+                                self.inner.proof_cache.lock().await.insert(stub.block_hash, stub.transaction_hash, proof.clone());
+                                Ok(Message::TransactionProofResponse(proof))
+                            },
+                            Some(None) => Ok(Message::Error("Transaction is not in the block".into())),
+                            None => Ok(Message::Error("Proof queue is full".into())),
+                        }
                     }else{
                         Ok(Message::Error("Block does not exist".into()))
                     }                 
@@ -379,7 +513,7 @@ impl Node {
                 // place into the transaction filter queue - if it is not already there
                 let mut transaction_filters = self.inner.transaction_filters.lock().await;
                 if transaction_filters.iter().any(|(f, p)| f == filter && p == peer) {
-                    self.inner.broadcast_queue.enqueue(Message::TransactionFilterRequest(filter.to_owned(), peer.to_owned()));
+                    self.inner.broadcast_queue.enqueue(Message::TransactionFilterRequest(filter.to_owned(), peer.to_owned())).await;
                     transaction_filters.push((filter.to_owned(), peer.to_owned()));
                 }
                 // to be broadcasted
@@ -412,6 +546,41 @@ impl Node {
                     Ok(Message::PercentileFilteredPeerResponse(vec![])) // just say nothing - info not up to date
                 }
             },
+            Message::StateRangeRequest(state_root, start, end) => {
+                // a range can be the whole state, so it counts against the same limit as other proofs
+                if !self.allow_proof_request(source).await {
+                    return Ok(Message::Error("Proof request rate exceeded".into()));
+                }
+                if state.is_consume(){
+                    let state_manager = self.inner.chain.lock().await.as_ref().unwrap().state_manager.clone();
+                    let (start, end, state_root, limit) = (*start, *end, *state_root, self.max_accounts_per_range);
+                    let queue = self.inner.proof_queue.lock().await.clone();
+                    match queue.run(move || state_manager.account_range(start, end, state_root, limit)).await {
+                        Some(range) => Ok(Message::StateRangeResponse(range)),
+                        None => Ok(Message::Error("Proof queue is full".into())),
+                    }
+                }else{
+                    Ok(Message::Error("Chain not downloaded for peer".into()))
+                }
+            },
+            Message::MempoolSyncRequest(transactions) => {
+                match &self.miner_pool {
+                    Some(pool) if state.is_consume() => {
+                        let mut difference = pool.difference(transactions);
+                        difference.to_send.truncate(self.max_mempool_tx_per_response);
+                        Ok(Message::MempoolSyncResponse(difference.to_send, difference.to_request))
+                    },
+                    _ => Ok(Message::MempoolSyncResponse(vec![], HashSet::new())), // nothing to offer or take
+                }
+            },
+            Message::MempoolTransactions(transactions) => {
+                if let Some(ref pool) = self.miner_pool && state.is_consume()
+                    && let Some(chain) = self.inner.chain.lock().await.as_ref() {
+                    let (added, rejected) = pool.add_applicable(transactions.clone(), chain);
+                    tracing::debug!("Pooled {} reconciled transactions, rejected {}", added, rejected.len());
+                }
+                Ok(Message::TransactionAck)
+            },
             Message::ChainSyncRequest(leaves) => {
                 if state.is_consume() {
                     let chains = service_sync(self.clone(), leaves).await?;
@@ -427,6 +596,20 @@ impl Node {
         }
     }
 
+    /// Counts a proof request from `source` against its rate limit.
+    /// An address that keeps going over the limit has its peers dropped from the peer list
+    async fn allow_proof_request(&self, source: IpAddr) -> bool {
+        let mut limiter = self.inner.proof_limiter.lock().await;
+        if limiter.allow(&source) {
+            return true;
+        }
+        tracing::warn!("Address {} is over the proof request limit", source);
+        if limiter.is_banned(&source) {
+            self.inner.peers.lock().await.retain(|_, peer| peer.ip_address != source);
+        }
+        false
+    }
+
     /// After receiving a block - settle it to the chain
     /// Includes the tracking of reputation, braodcasting, and responding to callbacks 
     #[instrument(name = "Node::settle_unmined_block", skip(self, block), fields(
@@ -452,10 +635,11 @@ impl Node {
             n_stamps += 1; // we have stamped the block
         }
 
-        if (already_broadcasted || n_stamps == N_TRANSMISSION_SIGNATURES) && self.miner_pool.is_some(){
+        if (already_broadcasted || n_stamps == N_TRANSMISSION_SIGNATURES) && !self.read_only
+            && let Some(ref pool) = self.miner_pool {
             // add the block to the pool
             tracing::info!("Adding block to miner pool.");
-            self.miner_pool.as_ref().unwrap().add_mine_ready_block(block.clone());
+            pool.add_mine_ready_block(block.clone());
         }
         Ok(())
     }
@@ -531,13 +715,13 @@ impl Node {
 
 impl From<&Node> for Peer {
     fn from(node: &Node) -> Self {
-        Peer::new(node.inner.public_key, node.ip_address, node.port)
+        Peer::new(node.inner.public_key, node.ip_address, node.port).with_wire_format(node.wire_format)
     }
 }
 
 impl From<Node> for Peer {
     fn from(node: Node) -> Self {
-        Peer::new(node.inner.public_key, node.ip_address, node.port)
+        Peer::new(node.inner.public_key, node.ip_address, node.port).with_wire_format(node.wire_format)
     }
 }
 
@@ -556,7 +740,7 @@ impl Broadcaster for Node {
         let mut responses = Vec::new();
         let mut peers = self.inner.peers.lock().await.clone(); // do not hold lock
         for (_, peer) in peers.iter_mut(){
-            let response = peer.communicate(message, &self.into()).await;
+            let response = peer.communicate_within(message, &self.into(), self.connection_timeouts).await;
             if let Err(e) = response {
                 tracing::error!("Failed to communicate with peer {:?}: {:?}", peer.public_key, e);
                 continue; // skip this peer
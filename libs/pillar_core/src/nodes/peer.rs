@@ -1,13 +1,13 @@
-use std::{net::IpAddr, time::Duration};
+use std::{hash::{Hash, Hasher}, net::IpAddr};
 
 use pillar_crypto::serialization::PillarSerialize;
 use serde::{Serialize, Deserialize};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, time::timeout};
 use tracing::instrument;
 
-use crate::{primitives::messages::Message};
+use crate::{nodes::keepalive::ConnectionTimeouts, primitives::messages::{Message, WireFormat}};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Peer{
     /// The public key of the peer
     pub public_key: [u8; 32],
@@ -15,6 +15,10 @@ pub struct Peer{
     pub ip_address: IpAddr,
     /// The port of the peer
     pub port: u16,
+    /// The format the peer speaks when it starts a connection.
+    /// Only known for the local node - it is never sent, and does not affect equality
+    #[serde(skip)]
+    pub wire_format: WireFormat,
 }
 
 impl Clone for Peer {
@@ -22,29 +26,55 @@ impl Clone for Peer {
         Peer {
             public_key: self.public_key,
             ip_address: self.ip_address,
-            port: self.port
+            port: self.port,
+            wire_format: self.wire_format,
         }
     }
 }
 
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key == other.public_key && self.ip_address == other.ip_address && self.port == other.port
+    }
+}
+
+impl Eq for Peer {}
+
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.public_key.hash(state);
+        self.ip_address.hash(state);
+        self.port.hash(state);
+    }
+}
+
 impl Peer{
     /// Create a new peer
     pub fn new(public_key: [u8; 32], ip_address: IpAddr, port: u16) -> Self {
         Peer {
             public_key,
             ip_address,
-            port
+            port,
+            wire_format: WireFormat::default(),
         }
     }
 
+    /// The same peer, speaking `wire_format`
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
+    }
+
     /// Send a message to the peer
     /// Initializaes a new connection to the peer
+    /// The message is encoded in the wire format of the initializing peer
     #[instrument(skip(self, message, initializing_peer))]
     async fn send_initial(&mut self, message: &Message, initializing_peer: &Peer) -> Result<TcpStream, std::io::Error> {
         let mut stream = tokio::net::TcpStream::connect(format!("{}:{}", self.ip_address, self.port)).await?;
-        let serialized_message = message.serialize_pillar();
+        let format = initializing_peer.wire_format;
+        let serialized_message = format.encode(message);
         // always send a "peer" object of the initializing node first, and length of the message in bytes
-        let declaration = Message::Declaration(initializing_peer.clone(), serialized_message.as_ref().unwrap().len() as u32);
+        let declaration = format.declaration(initializing_peer.clone(), serialized_message.as_ref().unwrap().len() as u32);
         // serialize with bincode
         let bytes = declaration.serialize_pillar().map_err(
             std::io::Error::other
@@ -62,7 +92,7 @@ impl Peer{
 
     /// Get a response from the peer
     /// This function will block until a response is received
-    async fn read_response(&self, mut stream: TcpStream) -> Result<Message, std::io::Error> {
+    async fn read_response(&self, mut stream: TcpStream, format: WireFormat) -> Result<Message, std::io::Error> {
         // read the message
         let mut buffer = [0; 4];
         // read the size (u32)
@@ -71,17 +101,18 @@ impl Peer{
         let size = u32::from_le_bytes(buffer);
         let mut buffer = vec![0; size as usize];
         let n = stream.read_exact(&mut buffer).await?;
-        // deserialize with bincode
-        let message: Message = PillarSerialize::deserialize_pillar(&buffer[..n]).map_err(
-            std::io::Error::other
-        )?;
+        let message = format.decode(&buffer[..n])?;
         Ok(message)
     }
 
     pub async fn communicate(&mut self, message: &Message, initializing_peer: &Peer) -> Result<Message, std::io::Error> {
-        
-        let stream = timeout(Duration::from_secs(1), self.send_initial(message, initializing_peer)).await??;
-        let response = self.read_response(stream).await?;
+        self.communicate_within(message, initializing_peer, ConnectionTimeouts::default()).await
+    }
+
+    /// Send a message to the peer and read the response, failing with `TimedOut` if either takes too long
+    pub async fn communicate_within(&mut self, message: &Message, initializing_peer: &Peer, timeouts: ConnectionTimeouts) -> Result<Message, std::io::Error> {
+        let stream = timeout(timeouts.write, self.send_initial(message, initializing_peer)).await??;
+        let response = timeout(timeouts.read, self.read_response(stream, initializing_peer.wire_format)).await??;
         Ok(response)
     }
 }
@@ -193,7 +224,7 @@ mod tests{
         let message = Message::Ping;
         let stream = peer.send_initial(&message, &initializing_peer).await.unwrap(); // send to peer
         // read the response
-        let response = peer.read_response(stream).await.unwrap();
+        let response = peer.read_response(stream, initializing_peer.wire_format).await.unwrap();
         match response{
             Message::Ping => {},
             _ => panic!("Expected a ping message")
@@ -0,0 +1,98 @@
+use std::collections::{HashMap, VecDeque};
+
+use pillar_crypto::{proofs::MerkleProof, types::StdByteArray};
+
+/// proofs kept by default
+pub const PROOF_CACHE_CAPACITY: usize = 1024;
+
+/// Transaction proofs already served, by (block hash, transaction hash).
+/// The block hash commits to the merkle root, so a proof for the same key never changes -
+/// entries stay valid even if the block later leaves the deepest chain.
+/// When full, the oldest proof is dropped. A capacity of 0 disables the cache
+#[derive(Debug, Clone)]
+pub struct ProofCache {
+    /// proofs kept at once
+    pub capacity: usize,
+    proofs: HashMap<(StdByteArray, StdByteArray), MerkleProof>,
+    /// keys, oldest first
+    order: VecDeque<(StdByteArray, StdByteArray)>,
+    /// requests served from the cache so far
+    hits: u64,
+}
+
+impl Default for ProofCache {
+    fn default() -> Self {
+        ProofCache::new(PROOF_CACHE_CAPACITY)
+    }
+}
+
+impl ProofCache {
+    pub fn new(capacity: usize) -> Self {
+        ProofCache {
+            capacity,
+            proofs: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+        }
+    }
+
+    /// The cached proof that `transaction` is in the block `block_hash`
+    pub fn get(&mut self, block_hash: &StdByteArray, transaction: &StdByteArray) -> Option<MerkleProof> {
+        let proof = self.proofs.get(&(*block_hash, *transaction)).cloned();
+        if proof.is_some() {
+            self.hits += 1;
+        }
+        proof
+    }
+
+    /// Caches the proof that `transaction` is in the block `block_hash`
+    pub fn insert(&mut self, block_hash: StdByteArray, transaction: StdByteArray, proof: MerkleProof) {
+        if self.capacity == 0 || self.proofs.contains_key(&(block_hash, transaction)) {
+            return;
+        }
+        while self.proofs.len() >= self.capacity && let Some(oldest) = self.order.pop_front() {
+            self.proofs.remove(&oldest);
+        }
+        self.proofs.insert((block_hash, transaction), proof);
+        self.order.push_back((block_hash, transaction));
+    }
+
+    /// The number of requests served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of proofs cached
+    pub fn len(&self) -> usize {
+        self.proofs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proofs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::chain::get_genesis_block;
+
+    use super::ProofCache;
+
+    #[test]
+    fn test_oldest_proof_evicted() {
+        let genesis = get_genesis_block(None);
+        let proof = genesis.get_proof_for_transaction(genesis.transactions[0].hash).unwrap();
+        let mut cache = ProofCache::new(2);
+        cache.insert([1; 32], [1; 32], proof.clone());
+        cache.insert([2; 32], [1; 32], proof.clone());
+        cache.insert([3; 32], [1; 32], proof.clone());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&[1; 32], &[1; 32]), None);
+        assert_eq!(cache.get(&[3; 32], &[1; 32]), Some(proof.clone()));
+        assert_eq!(cache.hits(), 1);
+
+        let mut disabled = ProofCache::new(0);
+        disabled.insert([1; 32], [1; 32], proof);
+        assert!(disabled.is_empty());
+    }
+}
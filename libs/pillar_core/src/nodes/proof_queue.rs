@@ -0,0 +1,161 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+use tokio::sync::Semaphore;
+
+/// proofs generated at once by default
+pub const MAX_CONCURRENT_PROOFS: usize = 4;
+/// requests waiting for a slot at once by default
+pub const MAX_WAITING_PROOFS: usize = 64;
+
+/// Bounds how many proofs are generated at once.
+/// Requests over the limit wait in line, first come first served, instead of being refused -
+/// this protects the node's CPU under bursts, where the rate limiter keeps any one peer in check.
+/// The line itself is bounded by `max_waiting`; past that, requests are refused.
+/// Clones share the same slots and line
+#[derive(Debug, Clone)]
+pub struct ProofQueue {
+    /// proofs generated at once
+    limit: usize,
+    /// requests that may wait for a slot at once
+    pub max_waiting: usize,
+    slots: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl Default for ProofQueue {
+    fn default() -> Self {
+        ProofQueue::new(MAX_CONCURRENT_PROOFS)
+    }
+}
+
+/// Holds a place in line, giving it up when dropped - even if the waiting request is cancelled
+struct Place<'a>(&'a AtomicUsize);
+
+impl Drop for Place<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ProofQueue {
+    /// A limit of 0 is treated as 1, so proofs are still served
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        ProofQueue {
+            limit,
+            max_waiting: MAX_WAITING_PROOFS,
+            slots: Arc::new(Semaphore::new(limit)),
+            waiting: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// proofs generated at once
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// proofs being generated right now
+    pub fn active(&self) -> usize {
+        self.limit - self.slots.available_permits()
+    }
+
+    /// requests waiting for a slot right now
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Runs `generate` on a blocking thread once a slot is free, waiting in line until then
+    ///
+    /// # Returns
+    ///
+    /// * `Some(T)` - the result of `generate`
+    /// * `None` - if the line was full. `generate` is not run
+    pub async fn run<T, F>(&self, generate: F) -> Option<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _slot = match self.slots.try_acquire() {
+            Ok(slot) => slot,
+            Err(_) => {
+                if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.max_waiting {
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    return None;
+                }
+                let _place = Place(&self.waiting);
+                self.slots.acquire().await.expect("proof queue is never closed")
+            },
+        };
+        Some(tokio::task::spawn_blocking(generate).await.expect("proof generation panicked"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+    use super::ProofQueue;
+
+    #[tokio::test]
+    async fn test_concurrency_bounded() {
+        let queue = ProofQueue::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let handles = (0..10).map(|i| {
+            let (queue, running, peak) = (queue.clone(), running.clone(), peak.clone());
+            tokio::spawn(async move {
+                queue.run(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    i * 2
+                }).await.unwrap()
+            })
+        }).collect::<Vec<_>>();
+
+        let mut results = vec![];
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        // every queued request is served, with its own result
+        assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(queue.active(), 0);
+        // a limit of 0 still serves proofs, one at a time
+        assert_eq!(ProofQueue::new(0).limit(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_bounded() {
+        let mut queue = ProofQueue::new(1);
+        queue.max_waiting = 2;
+        let busy = queue.clone();
+        let running = tokio::spawn(async move { busy.run(|| std::thread::sleep(Duration::from_millis(200))).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let waiting = (0..2).map(|i| {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.run(move || i).await })
+        }).collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(queue.waiting(), 2);
+        // the line is full, so the next request is refused at once
+        assert_eq!(queue.run(|| 9).await, None);
+
+        assert!(running.await.unwrap().is_some());
+        let mut results = vec![];
+        for handle in waiting {
+            results.push(handle.await.unwrap());
+        }
+        assert_eq!(results, vec![Some(0), Some(1)]);
+        assert_eq!(queue.waiting(), 0);
+        // a cancelled request gives up its place
+        let busy = queue.clone();
+        let running = tokio::spawn(async move { busy.run(|| std::thread::sleep(Duration::from_millis(100))).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let cancelled = queue.clone();
+        assert!(tokio::time::timeout(Duration::from_millis(10), cancelled.run(|| 0)).await.is_err());
+        assert_eq!(queue.waiting(), 0);
+        running.await.unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::protocol::clock::Clock;
+
+/// proof requests each address may make per window, by default
+pub const MAX_PROOF_REQUESTS: u32 = 32;
+/// the default window length, in seconds
+pub const PROOF_REQUEST_WINDOW: u64 = 10;
+/// penalty after which an address is dropped, by default
+pub const MAX_PROOF_PENALTY: u64 = 16;
+/// addresses tracked at once, by default
+pub const MAX_TRACKED_ADDRESSES: usize = 1024;
+
+/// Limits how many proof requests each address may make in a fixed window.
+/// Proofs are expensive to build, so this is kept apart from any other message handling.
+/// Every request over the limit is refused and adds to the address's penalty.
+/// Limits are kept by the address a connection comes from, not the key a peer declares - anyone can declare any key.
+/// At most `max_tracked` addresses are kept; past that, the stalest window and the lightest penalty make room
+#[derive(Debug, Clone)]
+pub struct ProofRateLimiter {
+    /// requests allowed per address in each window
+    pub max_requests: u32,
+    /// the window length, in seconds
+    pub window: u64,
+    /// penalty at which the node should stop talking to an address
+    pub max_penalty: u64,
+    /// the most addresses to keep windows and penalties for
+    pub max_tracked: usize,
+    /// the time source for windows
+    pub clock: Clock,
+    /// per address - (window start, requests in the window)
+    requests: HashMap<IpAddr, (u64, u32)>,
+    /// per address - throttled requests so far
+    penalties: HashMap<IpAddr, u64>,
+}
+
+impl Default for ProofRateLimiter {
+    fn default() -> Self {
+        ProofRateLimiter::new(MAX_PROOF_REQUESTS, PROOF_REQUEST_WINDOW)
+    }
+}
+
+impl ProofRateLimiter {
+    pub fn new(max_requests: u32, window: u64) -> Self {
+        ProofRateLimiter {
+            max_requests,
+            window,
+            max_penalty: MAX_PROOF_PENALTY,
+            max_tracked: MAX_TRACKED_ADDRESSES,
+            clock: Clock::default(),
+            requests: HashMap::new(),
+            penalties: HashMap::new(),
+        }
+    }
+
+    /// Records a proof request from `address`
+    ///
+    /// # Returns
+    ///
+    /// * `true` - if the request may be served
+    /// * `false` - if the address is over its limit. The address is penalized
+    pub fn allow(&mut self, address: &IpAddr) -> bool {
+        let now = self.clock.now();
+        if !self.requests.contains_key(address) && self.requests.len() >= self.max_tracked {
+            self.make_room(now);
+        }
+        let (start, count) = self.requests.entry(*address).or_insert((now, 0));
+        if now.saturating_sub(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count < self.max_requests {
+            *count += 1;
+            return true;
+        }
+        if !self.penalties.contains_key(address) && self.penalties.len() >= self.max_tracked
+            && let Some(lightest) = self.penalties.iter().min_by_key(|(_, penalty)| **penalty).map(|(address, _)| *address) {
+            self.penalties.remove(&lightest);
+        }
+        *self.penalties.entry(*address).or_default() += 1;
+        false
+    }
+
+    /// Forgets windows that have ended, or the stalest one if none have
+    fn make_room(&mut self, now: u64) {
+        let window = self.window;
+        self.requests.retain(|_, (start, _)| now.saturating_sub(*start) < window);
+        if self.requests.len() >= self.max_tracked
+            && let Some(stalest) = self.requests.iter().min_by_key(|(_, (start, _))| *start).map(|(address, _)| *address) {
+            self.requests.remove(&stalest);
+        }
+    }
+
+    /// The number of throttled requests from `address`
+    pub fn penalty(&self, address: &IpAddr) -> u64 {
+        self.penalties.get(address).copied().unwrap_or(0)
+    }
+
+    /// Whether `address` has been throttled too often to keep
+    pub fn is_banned(&self, address: &IpAddr) -> bool {
+        self.penalty(address) >= self.max_penalty
+    }
+
+    /// The number of addresses with a window or a penalty
+    pub fn tracked(&self) -> usize {
+        self.requests.keys().chain(self.penalties.keys()).collect::<std::collections::HashSet<_>>().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::protocol::clock::Clock;
+
+    use super::ProofRateLimiter;
+
+    fn address(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    #[test]
+    fn test_burst_throttled_then_window_resets() {
+        let mut limiter = ProofRateLimiter::new(3, 10);
+        limiter.clock = Clock::mock(1000);
+        let (peer, other) = (address(1), address(2));
+        assert!((0..3).all(|_| limiter.allow(&peer)));
+        assert!(!limiter.allow(&peer));
+        assert!(!limiter.allow(&peer));
+        assert_eq!(limiter.penalty(&peer), 2);
+        // limits are per address
+        assert!(limiter.allow(&other));
+        assert_eq!(limiter.penalty(&other), 0);
+
+        limiter.clock.advance(9);
+        assert!(!limiter.allow(&peer));
+        limiter.clock.advance(1);
+        assert!(limiter.allow(&peer));
+        // penalties outlive the window
+        assert_eq!(limiter.penalty(&peer), 3);
+    }
+
+    #[test]
+    fn test_ban_after_max_penalty() {
+        let mut limiter = ProofRateLimiter::new(1, 10);
+        limiter.clock = Clock::mock(0);
+        limiter.max_penalty = 2;
+        let peer = address(1);
+        assert!(limiter.allow(&peer));
+        assert!(!limiter.allow(&peer));
+        assert!(!limiter.is_banned(&peer));
+        assert!(!limiter.allow(&peer));
+        assert!(limiter.is_banned(&peer));
+    }
+
+    #[test]
+    fn test_tracked_addresses_bounded() {
+        let mut limiter = ProofRateLimiter::new(1, 10);
+        limiter.clock = Clock::mock(0);
+        limiter.max_tracked = 2;
+        let banned = address(1);
+        assert!(limiter.allow(&banned));
+        assert!(!limiter.allow(&banned));
+        assert!(!limiter.allow(&banned));
+        // a stream of new addresses cannot grow the limiter, or wash out a heavy penalty
+        for n in 2..50 {
+            limiter.clock.advance(1);
+            assert!(limiter.allow(&address(n)));
+            assert!(!limiter.allow(&address(n)));
+            assert!(limiter.tracked() <= 4);
+        }
+        assert_eq!(limiter.penalty(&banned), 2);
+        // the window of the banned address was the stalest, so it starts again
+        assert!(limiter.allow(&banned));
+    }
+}
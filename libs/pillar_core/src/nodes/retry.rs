@@ -0,0 +1,126 @@
+use std::{collections::HashMap, time::Duration};
+
+use pillar_crypto::types::StdByteArray;
+use rand::{rng, Rng};
+
+/// attempts at a request before giving up, by default
+pub const MAX_REQUEST_ATTEMPTS: u32 = 4;
+/// the default wait before the first retry - each later retry waits twice as long
+pub const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+/// the default most random time added to each wait
+pub const RETRY_JITTER: Duration = Duration::from_millis(50);
+/// consecutive failed requests after which a peer is dropped, by default
+pub const MAX_REQUEST_FAILURES: u32 = 8;
+
+/// How a failed request to a peer is retried.
+/// Each retry goes to a peer not yet tried if there is one, after an exponentially growing wait,
+/// so a flaky peer is not hammered and many nodes retrying do not line up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// attempts before giving up, including the first. 0 is treated as 1
+    pub max_attempts: u32,
+    /// the wait before the first retry
+    pub base_delay: Duration,
+    /// the most random time added to each wait
+    pub jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { max_attempts: MAX_REQUEST_ATTEMPTS, base_delay: RETRY_BASE_DELAY, jitter: RETRY_JITTER }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retry number `retry`, counting from 1 - `base_delay * 2^(retry - 1)`, plus up to `jitter`
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        let jitter = rng().random_range(0..=self.jitter.as_millis() as u64);
+        self.base_delay.saturating_mul(factor).saturating_add(Duration::from_millis(jitter))
+    }
+}
+
+/// Tracks failed requests to each peer.
+/// A peer that fails `max_failures` requests in a row should be dropped, any success resets the count.
+#[derive(Debug, Clone)]
+pub struct RequestFailures {
+    /// consecutive failures at which a peer is dropped
+    pub max_failures: u32,
+    /// per peer - consecutive failed requests
+    failures: HashMap<StdByteArray, u32>,
+}
+
+impl Default for RequestFailures {
+    fn default() -> Self {
+        RequestFailures::new(MAX_REQUEST_FAILURES)
+    }
+}
+
+impl RequestFailures {
+    pub fn new(max_failures: u32) -> Self {
+        RequestFailures {
+            max_failures,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Records a request `peer` answered
+    pub fn record_success(&mut self, peer: &StdByteArray) {
+        self.failures.remove(peer);
+    }
+
+    /// Records a request `peer` failed
+    ///
+    /// # Returns
+    ///
+    /// * `true` - if the peer has failed too many requests, and should be dropped. The peer is forgotten
+    /// * `false` - otherwise
+    pub fn record_failure(&mut self, peer: &StdByteArray) -> bool {
+        let failures = self.failures.entry(*peer).or_default();
+        *failures += 1;
+        if *failures >= self.max_failures {
+            self.failures.remove(peer);
+            return true;
+        }
+        false
+    }
+
+    /// The number of consecutive requests `peer` has failed
+    pub fn failures(&self, peer: &StdByteArray) -> u32 {
+        self.failures.get(peer).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RequestFailures, RetryPolicy};
+
+    #[test]
+    fn test_exponential_delay() {
+        let policy = RetryPolicy { max_attempts: 4, base_delay: Duration::from_millis(10), jitter: Duration::ZERO };
+        let delays = (1..=4).map(|retry| policy.delay(retry).as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![10, 20, 40, 80]);
+        // far out retries saturate rather than overflow
+        assert!(policy.delay(100) >= policy.delay(4));
+
+        let jittered = RetryPolicy { jitter: Duration::from_millis(5), ..policy };
+        for _ in 0..20 {
+            let delay = jittered.delay(2);
+            assert!(delay >= Duration::from_millis(20) && delay <= Duration::from_millis(25));
+        }
+    }
+
+    #[test]
+    fn test_failures_reset_on_success() {
+        let mut failures = RequestFailures::new(2);
+        let peer = [1; 32];
+        assert!(!failures.record_failure(&peer));
+        failures.record_success(&peer);
+        assert_eq!(failures.failures(&peer), 0);
+        assert!(!failures.record_failure(&peer));
+        assert!(failures.record_failure(&peer));
+        assert_eq!(failures.failures(&peer), 0);
+    }
+}
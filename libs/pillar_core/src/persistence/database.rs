@@ -1,9 +1,9 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::{Arc, Mutex}};
 
 
-use pillar_crypto::types::StdByteArray;
+use pillar_crypto::{hashing::{DefaultHash, Hashable}, types::StdByteArray};
 
-use crate::{blockchain::chain::Chain, primitives::block::Block};
+use crate::{blockchain::chain::Chain, primitives::{block::Block, errors::IntegrityError, transaction::Transaction}, protocol::{params::ChainParams, pow::{is_committed_difficulty_valid, is_valid_hash}}};
 
 pub trait Datastore: Send + Sync {
     /// If a chain exists on disk.
@@ -31,18 +31,72 @@ pub trait Datastore: Send + Sync {
     /// This will write/remove as needed to ensure the on disk state matches the chain.
     fn sync_chain(&self, chain: Chain) -> Result<(), std::io::Error>;
 
+    /// Saves the pending transactions, replacing any previously saved.
+    fn save_transactions(&self, transactions: &[Transaction]) -> Result<(), std::io::Error>;
+
+    /// Loads the saved pending transactions.
+    /// 
+    /// Returns an empty list if none were saved.
+    fn load_transactions(&self) -> Result<Vec<Transaction>, std::io::Error>;
+
+    /// Checks the stored chain before it is trusted, such as after a crash.
+    /// 
+    /// Returns the first inconsistency from genesis, if there is one.
+    fn verify_store_integrity(&self) -> Result<(), IntegrityError> {
+        let chain = self.load_chain().map_err(IntegrityError::Unreadable)?;
+        verify_blocks_integrity(&chain.blocks)
+    }
+
+}
+
+/// Checks that every block is keyed by the hash of its header, meets its own difficulty target - one the retarget allows,
+/// as the stored target is only a claim - and links to a stored parent one block shallower. There is one genesis block.
+/// Blocks are checked shallowest first.
+pub fn verify_blocks_integrity(blocks: &HashMap<StdByteArray, Block>) -> Result<(), IntegrityError> {
+    let params = ChainParams::default();
+    let mut ordered = blocks.iter().collect::<Vec<_>>();
+    ordered.sort_by_key(|(hash, block)| (block.header.depth, **hash));
+    if ordered.first().is_none_or(|(_, block)| block.header.depth != 0) {
+        return Err(IntegrityError::NoGenesis);
+    }
+    if let Some((hash, _)) = ordered.get(1).filter(|(_, block)| block.header.depth == 0) {
+        return Err(IntegrityError::ExtraGenesis(**hash));
+    }
+    for (hash, block) in ordered {
+        let header_hash = block.header.hash(&mut DefaultHash::new())
+            .map_err(|_| IntegrityError::Unhashable(*hash))?;
+        if header_hash != *hash || block.hash != Some(*hash) {
+            return Err(IntegrityError::HashMismatch(*hash, header_hash));
+        }
+        if !is_committed_difficulty_valid(&block.header, &params)
+            || !block.header.difficulty_target.is_some_and(|target| is_valid_hash(target, hash)) {
+            return Err(IntegrityError::InsufficientWork(*hash));
+        }
+        if block.header.depth == 0 {
+            continue; // genesis has no parent
+        }
+        let Some(parent) = blocks.get(&block.header.previous_hash) else {
+            return Err(IntegrityError::MissingParent(*hash, block.header.previous_hash));
+        };
+        if parent.header.depth + 1 != block.header.depth {
+            return Err(IntegrityError::DepthMismatch(*hash));
+        }
+    }
+    Ok(())
 }
 
 /// The most basic datastore that is essentially memory based without any persistence.
 #[derive(Clone)]
 pub struct GenesisDatastore{
-    chain: Chain
+    chain: Chain,
+    transactions: Arc<Mutex<Vec<Transaction>>>,
 }
 
 impl GenesisDatastore {
     pub fn new() -> Self {
         GenesisDatastore {
             chain: Chain::new_with_genesis(),
+            transactions: Arc::new(Mutex::new(Vec::new())),
         }
     }
 }
@@ -72,17 +126,28 @@ impl Datastore for GenesisDatastore {
     fn sync_chain(&self, _chain: Chain) -> Result<(), std::io::Error> {
         unimplemented!()
     }
+
+    fn save_transactions(&self, transactions: &[Transaction]) -> Result<(), std::io::Error> {
+        *self.transactions.lock().map_err(|e| std::io::Error::other(e.to_string()))? = transactions.to_vec();
+        Ok(())
+    }
+
+    fn load_transactions(&self) -> Result<Vec<Transaction>, std::io::Error> {
+        Ok(self.transactions.lock().map_err(|e| std::io::Error::other(e.to_string()))?.clone())
+    }
 }
 
 /// This datastore never provides any chain, but it can store.
 pub struct EmptyDatastore{
-    chain: Option<Chain>
+    chain: Option<Chain>,
+    transactions: Mutex<Vec<Transaction>>,
 }
 
 impl EmptyDatastore {
     pub fn new() -> Self {
         EmptyDatastore {
             chain: None,
+            transactions: Mutex::new(Vec::new()),
         }
     }
 }
@@ -118,17 +183,28 @@ impl Datastore for EmptyDatastore {
     fn sync_chain(&self, _chain: Chain) -> Result<(), std::io::Error> {
         unimplemented!()
     }
+
+    fn save_transactions(&self, transactions: &[Transaction]) -> Result<(), std::io::Error> {
+        *self.transactions.lock().map_err(|e| std::io::Error::other(e.to_string()))? = transactions.to_vec();
+        Ok(())
+    }
+
+    fn load_transactions(&self) -> Result<Vec<Transaction>, std::io::Error> {
+        Ok(self.transactions.lock().map_err(|e| std::io::Error::other(e.to_string()))?.clone())
+    }
 }
 
 
 
 
 
+#[allow(dead_code)] // not wired in until the stubs below are written
 pub struct SledDatastore {
     data: sled::Db,
 }
 
 impl SledDatastore {
+    #[allow(dead_code)]
     pub fn new(address: String) -> Self {
         SledDatastore { 
             data: sled::open(address).expect("Failed to open sled database"),
@@ -150,6 +226,7 @@ impl Datastore for SledDatastore {
         }
     }
 
+    #[allow(unused_variables, unused_mut)]
     fn load_chain(&self) -> Result<Chain, std::io::Error> {
         let leaf_hashes = self.data.get("leaf_hashes")
             .map_err(std::io::Error::other)?;
@@ -160,19 +237,138 @@ impl Datastore for SledDatastore {
         
     }
 
+    #[allow(unused_variables)]
     fn save_chain(&mut self, chain: Chain) -> Result<(), std::io::Error> {
         todo!()
     }
 
+    #[allow(unused_variables)]
     fn save_block(&self, block: Block) -> Result<(), std::io::Error> {
         todo!()
     }
 
+    #[allow(unused_variables)]
     fn load_block(&self, block_hash: &str) -> Result<Block, std::io::Error> {
         todo!()
     }
 
+    #[allow(unused_variables)]
     fn sync_chain(&self, chain: Chain) -> Result<(), std::io::Error> {
         todo!()
     }
-}
\ No newline at end of file
+
+    fn save_transactions(&self, transactions: &[Transaction]) -> Result<(), std::io::Error> {
+        let serialized = bincode::serialize(transactions).map_err(std::io::Error::other)?;
+        self.data.insert("pending_transactions", serialized).map_err(std::io::Error::other)?;
+        self.data.flush().map_err(std::io::Error::other)?;
+        Ok(())
+    }
+
+    fn load_transactions(&self) -> Result<Vec<Transaction>, std::io::Error> {
+        match self.data.get("pending_transactions").map_err(std::io::Error::other)? {
+            Some(value) => bincode::deserialize(&value).map_err(std::io::Error::other),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use pillar_crypto::hashing::DefaultHash;
+
+    use crate::blockchain::chain::Chain;
+    use crate::fixtures::{mined_block_at, signed_transaction};
+    use crate::primitives::block::Block;
+    use crate::primitives::errors::IntegrityError;
+    use crate::protocol::pow::is_valid_hash;
+
+    use super::{Datastore, GenesisDatastore};
+
+    /// A store holding genesis and three mined blocks, with the blocks shallowest first
+    async fn mined_store() -> (GenesisDatastore, Vec<Block>) {
+        let mut chain = Chain::new_with_genesis();
+        let now = chain.clock.now();
+        let mut blocks = vec![];
+        for i in 0..3 {
+            let block = mined_block_at(&mut chain, vec![signed_transaction(0)], [1; 32], now + i).await;
+            chain.add_new_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+        let mut store = GenesisDatastore::new();
+        store.save_chain(chain).unwrap();
+        (store, blocks)
+    }
+
+    /// Applies `corrupt` to the chain held by the store
+    fn corrupted(store: &GenesisDatastore, corrupt: impl FnOnce(&mut Chain)) -> GenesisDatastore {
+        let mut store = store.clone();
+        let mut chain = store.load_chain().unwrap();
+        corrupt(&mut chain);
+        store.save_chain(chain).unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn test_verify_store_integrity() {
+        let (store, blocks) = mined_store().await;
+        assert!(store.verify_store_integrity().is_ok());
+        assert!(GenesisDatastore::new().verify_store_integrity().is_ok());
+        let hash = |i: usize| blocks[i].hash.unwrap();
+
+        // a missing block breaks the link from its child
+        let missing = corrupted(&store, |chain| { chain.blocks.remove(&hash(1)); });
+        assert!(matches!(missing.verify_store_integrity(), Err(IntegrityError::MissingParent(child, parent)) if child == hash(2) && parent == hash(1)));
+
+        // a corrupted header no longer hashes to its key - reported before the deeper missing block
+        let tampered = corrupted(&store, |chain| {
+            chain.blocks.get_mut(&hash(0)).unwrap().header.timestamp += 1;
+            chain.blocks.remove(&hash(1));
+        });
+        assert!(matches!(tampered.verify_store_integrity(), Err(IntegrityError::HashMismatch(stored, _)) if stored == hash(0)));
+
+        // a block mined again at the wrong depth
+        let mut deeper = blocks[2].clone();
+        deeper.header.depth += 1;
+        let deeper_hash = loop {
+            let hash = deeper.finalize(&mut DefaultHash::new()).unwrap();
+            if is_valid_hash(deeper.header.difficulty_target.unwrap(), &hash) {
+                break hash;
+            }
+            deeper.header.nonce += 1;
+        };
+        let misplaced = corrupted(&store, |chain| {
+            chain.blocks.remove(&hash(2));
+            chain.blocks.insert(deeper_hash, deeper);
+        });
+        assert!(matches!(misplaced.verify_store_integrity(), Err(IntegrityError::DepthMismatch(hash)) if hash == deeper_hash));
+
+        // a block rehashed under a target its hash does not meet
+        let mut harder = blocks[2].clone();
+        harder.header.difficulty_target = Some(255);
+        let harder_hash = harder.finalize(&mut DefaultHash::new()).unwrap();
+        let unworked = corrupted(&store, |chain| {
+            chain.blocks.remove(&hash(2));
+            chain.blocks.insert(harder_hash, harder);
+        });
+        assert!(matches!(unworked.verify_store_integrity(), Err(IntegrityError::InsufficientWork(hash)) if hash == harder_hash));
+
+        // a block rehashed under a target any hash meets, easier than the retarget allows
+        let mut easy = blocks[2].clone();
+        easy.header.difficulty_target = Some(0);
+        let easy_hash = easy.finalize(&mut DefaultHash::new()).unwrap();
+        let cheap = corrupted(&store, |chain| {
+            chain.blocks.remove(&hash(2));
+            chain.blocks.insert(easy_hash, easy);
+        });
+        assert!(matches!(cheap.verify_store_integrity(), Err(IntegrityError::InsufficientWork(hash)) if hash == easy_hash));
+
+        // a second genesis block, even a well formed one
+        let mut other = store.load_chain().unwrap().blocks[&blocks[0].header.previous_hash].clone();
+        other.header.timestamp += 1;
+        let other_hash = other.finalize(&mut DefaultHash::new()).unwrap();
+        let forked = corrupted(&store, |chain| { chain.blocks.insert(other_hash, other); });
+        assert!(matches!(forked.verify_store_integrity(), Err(IntegrityError::ExtraGenesis(_))));
+
+        let empty = corrupted(&store, |chain| chain.blocks.clear());
+        assert!(matches!(empty.verify_store_integrity(), Err(IntegrityError::NoGenesis)));
+    }
+}
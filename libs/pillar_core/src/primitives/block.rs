@@ -1,17 +1,64 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use pillar_crypto::hashing::{DefaultHash, HashFunction, Hashable};
-use pillar_crypto::merkle::{generate_tree, MerkleTree};
-use pillar_crypto::proofs::{generate_proof_of_inclusion, verify_proof_of_inclusion, MerkleProof};
-use pillar_crypto::signing::{DefaultVerifier, SigFunction, SigVerFunction, Signable};
+use pillar_crypto::merkle::{generate_tree, merkle_root_of, MerkleTree};
+use pillar_crypto::proofs::{generate_proof_of_inclusion, proof_leaf_index, verify_indexed_proof_of_inclusion, verify_proof_of_inclusion, MerkleProof, TrieMerkleProof};
+use pillar_crypto::signing::{verify_in_batches, DefaultVerifier, SigFunction, SigVerFunction, Signable};
 use pillar_crypto::types::StdByteArray;
+use pillar_crypto::vrf::VrfProof;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_with::{serde_as, Bytes};
 
+use crate::accounting::account::{Account, AccountDelta};
+use crate::blockchain::chain::SIGNATURE_BATCH_SIZE;
+use crate::blockchain::ValidationChecks;
+use crate::accounting::state::{verify_account_proof, AccountDiff, StateManager, StateSnapshot};
 use crate::primitives::errors::BlockValidationError;
-use crate::protocol::pow::is_valid_hash;
-use crate::protocol::reputation::N_TRANSMISSION_SIGNATURES;
-use super::transaction::Transaction;
+use crate::protocol::params::{ChainParams, Rule};
+use crate::protocol::pow::{is_committed_difficulty_valid, is_difficulty_accepted, is_valid_hash};
+use crate::protocol::reputation::{get_current_reputations_for_stampers_from_state, N_TRANSMISSION_SIGNATURES};
+use super::pool::MinerPool;
+use super::transaction::{short_id, Transaction, TransactionHeader};
+
+/// the furthest into the future a block timestamp may be, in seconds
+pub const MAX_FUTURE_DRIFT: u64 = 60 * 60;
+
+/// What validating a block needs to know of the chain below its parent
+pub trait Ancestry {
+    /// Whether the block with hash `ancestor` is `descendant` or one of its ancestors
+    fn is_ancestor(&self, ancestor: StdByteArray, descendant: StdByteArray) -> bool;
+}
+
+/// Knows nothing of the chain but the parent itself
+struct ParentOnly;
+
+impl Ancestry for ParentOnly {
+    fn is_ancestor(&self, ancestor: StdByteArray, descendant: StdByteArray) -> bool {
+        ancestor == descendant
+    }
+}
+
+/// How thoroughly `Block::validate_against` checks a block, beyond the consensus parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationOptions {
+    /// the current time, in seconds
+    pub now: u64,
+    /// which of the per transaction and nonce checks to run
+    pub checks: ValidationChecks,
+    /// how many signatures are verified together
+    pub signature_batch_size: usize,
+}
+
+impl ValidationOptions {
+    /// Every check, at time `now`
+    pub fn full(now: u64) -> Self {
+        ValidationOptions {
+            now,
+            checks: ValidationChecks { transactions: true, nonces: true },
+            signature_batch_size: SIGNATURE_BATCH_SIZE,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Clone, PartialEq, Eq)]
 pub struct Block{
@@ -40,8 +87,17 @@ impl<'de> Deserialize<'de> for Block {
         }
 
         let helper = PartialBlock::deserialize(deserializer)?;
+        // reject the whole block if any transaction is malformed
+        if helper.transactions.is_empty() {
+            return Err(serde::de::Error::custom("Block has no transactions"));
+        }
+        for (i, transaction) in helper.transactions.iter().enumerate() {
+            transaction.sanity_check(&mut DefaultHash::new()).map_err(
+                |e| serde::de::Error::custom(format!("Transaction {i} is malformed: {e}"))
+            )?;
+        }
 
-        Ok(Block::new(
+        let mut block = Block::new(
             helper.header.previous_hash,
             helper.header.nonce,
             helper.header.timestamp,
@@ -52,7 +108,13 @@ impl<'de> Deserialize<'de> for Block {
             helper.header.difficulty_target,
             helper.header.state_root,
             &mut DefaultHash::new()
-        ))
+        );
+        // every other header field is taken as sent - only the merkle root is rebuilt from the transactions
+        let merkle_root = block.header.merkle_root;
+        block.header = helper.header;
+        block.header.merkle_root = merkle_root;
+        let _ = block.finalize(&mut DefaultHash::new());
+        Ok(block)
     }
 }
 
@@ -76,6 +138,183 @@ impl Default for Stamp {
 }
 
 
+/// A self contained proof that a transaction was mined, for the payee to keep.
+/// It can be checked without the chain, with `verify_payment_proof`.
+#[derive(Debug, PartialEq, Clone, Eq, Serialize, Deserialize)]
+pub struct PaymentProof {
+    // the hash of the transaction
+    pub transaction: StdByteArray,
+    // the header of the block the transaction is in
+    pub header: BlockHeader,
+    // the proof of inclusion of the transaction under the header merkle root
+    pub proof: MerkleProof,
+    // the hash of the block
+    pub block_hash: StdByteArray,
+}
+
+/// Verifies a payment proof on its own.
+/// Checks the transaction is under the header merkle root, the header hashes to the block hash,
+/// and the hash meets the header difficulty target. The target is declared by the prover, so it must also
+/// be one the retarget allows - see `is_committed_difficulty_valid`.
+/// This does not show the block is on the canonical chain, nor how deep it is.
+pub fn verify_payment_proof(payment_proof: &PaymentProof) -> Result<(), BlockValidationError> {
+    verify_payment_proof_with_params(payment_proof, &ChainParams::default())
+}
+
+/// Verifies a payment proof like `verify_payment_proof`, under the retarget of `params`
+pub fn verify_payment_proof_with_params(payment_proof: &PaymentProof, params: &ChainParams) -> Result<(), BlockValidationError> {
+    let mut hasher = DefaultHash::new();
+    let header = &payment_proof.header;
+    if !verify_proof_of_inclusion(payment_proof.transaction, &payment_proof.proof, header.merkle_root, &mut hasher) {
+        return Err(BlockValidationError::MalformedBlock("Transaction is not under the merkle root".into()));
+    }
+    let hash = header.hash(&mut hasher).map_err(|e| BlockValidationError::MalformedBlock(e.to_string()))?;
+    if hash != payment_proof.block_hash {
+        return Err(BlockValidationError::HashMismatch(payment_proof.block_hash, hash));
+    }
+    let Some(difficulty_target) = header.difficulty_target else {
+        return Err(BlockValidationError::MalformedBlock("Header has no difficulty target".into()));
+    };
+    if !is_valid_hash(difficulty_target, &hash) || !is_committed_difficulty_valid(header, params) {
+        return Err(BlockValidationError::DifficultyMismatch(difficulty_target, Box::new(*header)));
+    }
+    Ok(())
+}
+
+/// A compact proof that a mined block is invalid, for a full node to convince a light client
+/// without sending the whole block. It can be checked on its own, with `verify_fraud_proof`.
+/// Every part of a proof is bound to the accused header, so it cannot be made against an honest block.
+#[derive(Debug, PartialEq, Clone, Eq, Serialize, Deserialize)]
+pub enum FraudProof {
+    /// An account spends more in the block than it holds in the parent state.
+    /// Funds the account receives in the same block do not count, just as when connecting the block
+    OverSpend {
+        /// the header of the block
+        header: BlockHeader,
+        /// the header of its parent, which commits to the state the block spends from
+        parent: BlockHeader,
+        /// the spending account, as in the parent state
+        account: Account,
+        /// the proof the account is in the parent state
+        account_proof: TrieMerkleProof,
+        /// transactions from the account, each with its index in the block and its proof of inclusion at that index
+        transactions: Vec<(u64, TransactionHeader, MerkleProof)>,
+    },
+}
+
+/// Verifies a fraud proof against the hash of the block it accuses.
+/// Checks the header hashes to `block_hash` and meets its difficulty target, so the proof is about a real mined block,
+/// then checks the fault itself against data the header commits to.
+///
+/// # Returns
+///
+/// * `Ok(())` if the proof shows the block is invalid
+/// * `Err(BlockValidationError)` if the proof is for another block, or does not show a fault
+pub fn verify_fraud_proof(fraud_proof: &FraudProof, block_hash: StdByteArray) -> Result<(), BlockValidationError> {
+    let mut hasher = DefaultHash::new();
+    match fraud_proof {
+        FraudProof::OverSpend { header, parent, account, account_proof, transactions } => {
+            let hash = header.hash(&mut hasher).map_err(|e| BlockValidationError::MalformedBlock(e.to_string()))?;
+            if hash != block_hash {
+                return Err(BlockValidationError::HashMismatch(block_hash, hash));
+            }
+            let Some(difficulty_target) = header.difficulty_target else {
+                return Err(BlockValidationError::MalformedBlock("Header has no difficulty target".into()));
+            };
+            if !is_valid_hash(difficulty_target, &hash) {
+                return Err(BlockValidationError::DifficultyMismatch(difficulty_target, Box::new(*header)));
+            }
+            let parent_hash = parent.hash(&mut hasher).map_err(|e| BlockValidationError::MalformedBlock(e.to_string()))?;
+            if parent_hash != header.previous_hash {
+                return Err(BlockValidationError::HashMismatch(header.previous_hash, parent_hash));
+            }
+            let Some(parent_root) = parent.state_root else {
+                return Err(BlockValidationError::NoStateRoot(Box::new(*parent)));
+            };
+            if !verify_account_proof(account, account_proof, parent_root) {
+                return Err(BlockValidationError::MalformedBlock("The account is not in the parent state".into()));
+            }
+            let mut indices = HashSet::new();
+            let mut total: u64 = 0;
+            for (index, transaction, proof) in transactions {
+                if transaction.sender != account.address {
+                    return Err(BlockValidationError::MalformedBlock("A transaction is not from the account".into()));
+                }
+                // each transaction counts once
+                if !indices.insert(*index) {
+                    return Err(BlockValidationError::MalformedBlock("A transaction is counted twice".into()));
+                }
+                let transaction_hash = transaction.hash(&mut hasher);
+                if !header.verify_indexed_inclusion(transaction_hash, proof, *index) {
+                    return Err(BlockValidationError::MalformedBlock("A transaction is not in the block".into()));
+                }
+                total = total.saturating_add(transaction.amount);
+            }
+            if total <= account.balance {
+                return Err(BlockValidationError::MalformedBlock("The account can afford the transactions".into()));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Verifies many transaction inclusion proofs, possibly from different blocks.
+/// An item is valid if its transaction is under the header merkle root, and the header has valid proof of work
+/// against a target the retarget allows. A header without a target is invalid.
+/// The work of a header that appears in several items is only checked once.
+///
+/// # Returns
+///
+/// * The verdict for each item, in order
+pub fn verify_many(items: &[(StdByteArray, MerkleProof, BlockHeader)]) -> Vec<bool> {
+    verify_many_with_params(items, &ChainParams::default())
+}
+
+/// Verifies many transaction inclusion proofs like `verify_many`, under the retarget of `params`
+pub fn verify_many_with_params(items: &[(StdByteArray, MerkleProof, BlockHeader)], params: &ChainParams) -> Vec<bool> {
+    let mut hasher = DefaultHash::new();
+    let mut worked: HashMap<&BlockHeader, bool> = HashMap::new();
+    items.iter().map(|(transaction, proof, header)| {
+        let has_work = *worked.entry(header).or_insert_with(|| {
+            let Some(difficulty_target) = header.difficulty_target else {
+                return false;
+            };
+            is_committed_difficulty_valid(header, params)
+                && header.hash(&mut hasher).is_ok_and(|hash| is_valid_hash(difficulty_target, &hash))
+        });
+        has_work && verify_proof_of_inclusion(*transaction, proof, header.merkle_root, &mut hasher)
+    }).collect()
+}
+
+/// Verifies that `headers` extend `trusted` in order, such as from a checkpoint - the core of a light client following the chain.
+/// Each header must link to the one before it, be one deeper, meet its committed difficulty,
+/// and commit to a difficulty the retarget of the main chain allows.
+///
+/// # Returns
+///
+/// * `Ok(())` if every header extends the chain
+/// * `Err(index)` with the index of the first header that does not
+pub fn verify_extension(trusted: &BlockHeader, headers: &[BlockHeader]) -> Result<(), usize> {
+    verify_extension_with_params(trusted, headers, &ChainParams::default())
+}
+
+/// Verifies that `headers` extend `trusted` like `verify_extension`, under the retarget of `params`
+pub fn verify_extension_with_params(trusted: &BlockHeader, headers: &[BlockHeader], params: &ChainParams) -> Result<(), usize> {
+    let mut hasher = DefaultHash::new();
+    let mut parent = (trusted.depth, trusted.hash(&mut hasher).ok());
+    for (index, header) in headers.iter().enumerate() {
+        let linked = parent.1 == Some(header.previous_hash) && parent.0.checked_add(1) == Some(header.depth);
+        let hash = header.hash(&mut hasher).ok();
+        let worked = hash.is_some_and(|hash| header.validate(hash, &mut hasher).is_ok())
+            && is_committed_difficulty_valid(header, params);
+        if !linked || !worked {
+            return Err(index);
+        }
+        parent = (header.depth, hash);
+    }
+    Ok(())
+}
+
 /// A block tail tracks the signatures of people who have broadcasted the block
 /// This is used for immutibility of participation reputation
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Eq, Default, Hash)]
@@ -166,7 +405,12 @@ impl BlockTail {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Eq, Default)]
+/// domain separation for the extranonce in the header hash
+pub const EXTRANONCE_TAG: &[u8] = b"pillar/extranonce";
+/// domain separation for the transaction count in the header hash
+pub const TRANSACTION_COUNT_TAG: &[u8] = b"pillar/transaction-count";
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Eq, Default, Hash)]
 pub struct BlockHeader{
     // previous_hash is the sha3_356 hash of the previous block in the chain
     pub previous_hash: StdByteArray,
@@ -186,9 +430,16 @@ pub struct BlockHeader{
     pub difficulty_target: Option<u64>,
     // tail is the tail of the block which can contain stamps
     pub tail: BlockTail,
+    // the miner's VRF proof over the seed of the previous block, for leader election
+    pub vrf_proof: Option<VrfProof>,
+    // extra search space beyond the nonce - a pool hands each of its miners a different one
+    pub extranonce: Option<u64>,
+    // the number of transactions in the block, so a proof can show a transaction is one of exactly this many
+    pub transaction_count: Option<u64>,
 }
 
 impl BlockHeader {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         previous_hash: StdByteArray, 
         merkle_root: StdByteArray, 
@@ -209,7 +460,61 @@ impl BlockHeader {
             depth,
             tail,
             difficulty_target,
+            vrf_proof: None,
+            extranonce: None,
+            transaction_count: None,
+        }
+    }
+
+    /// The VRF input of the block - fixed by the previous block, so a miner cannot grind it
+    pub fn vrf_seed(&self) -> StdByteArray {
+        let mut hasher = DefaultHash::new();
+        hasher.update(self.previous_hash);
+        hasher.update(self.depth.to_le_bytes());
+        hasher.digest().unwrap()
+    }
+
+    /// Checks the rules the chain parameters turn on for the header at its depth.
+    /// Both `Block::connect_to_header` and the chain run these, so the rules hold on every validation path.
+    /// * The miner address is a valid public key, if `Rule::RequireValidMinerAddress` applies
+    /// * The VRF proof is valid, if there is one or `Rule::RequireVrfProof` applies
+    pub fn validate_rules(&self, params: &ChainParams) -> Result<(), BlockValidationError> {
+        // miner address
+        if params.enforces(Rule::RequireValidMinerAddress, self.depth)
+            && let Some(miner_address) = self.miner_address
+            && !DefaultVerifier::is_valid_key(&miner_address) {
+            return Err(BlockValidationError::InvalidMinerAddress(miner_address));
+        }
+        // eligibility
+        if params.enforces(Rule::RequireVrfProof, self.depth) || self.vrf_proof.is_some() {
+            self.verify_vrf_proof()?;
         }
+        Ok(())
+    }
+
+    /// Verifies the VRF proof against the miner's key and the seed of the previous block
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(output)` - the VRF output of the miner
+    /// * `Err(BlockValidationError::MalformedBlock)` - if the proof is missing or invalid
+    pub fn verify_vrf_proof(&self) -> Result<StdByteArray, BlockValidationError> {
+        let Some(proof) = self.vrf_proof else {
+            return Err(BlockValidationError::MalformedBlock("VRF proof is missing".into()));
+        };
+        let Some(miner_address) = self.miner_address else {
+            return Err(BlockValidationError::NoMinerAddress(Box::new(*self)));
+        };
+        proof.verify(&miner_address, &self.vrf_seed())
+            .ok_or(BlockValidationError::MalformedBlock("VRF proof is invalid".into()))
+    }
+
+    /// Verifies that `transaction` is transaction `index` of the block, out of the committed transaction count.
+    /// False if the header commits to no count, as the proof could then point past the last transaction
+    pub fn verify_indexed_inclusion(&self, transaction: StdByteArray, proof: &MerkleProof, index: u64) -> bool {
+        self.transaction_count.is_some_and(|count| {
+            verify_indexed_proof_of_inclusion(transaction, proof, self.merkle_root, index, count, &mut DefaultHash::new())
+        })
     }
 
     /// Validate header of the block
@@ -227,19 +532,39 @@ impl BlockHeader {
         &self, 
         expected_hash: StdByteArray,
         hasher: &mut impl HashFunction
+    ) -> Result<(), BlockValidationError> {
+        let current_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.validate_at(expected_hash, current_time.saturating_add(MAX_FUTURE_DRIFT), hasher)
+    }
+
+    /// Validates the header against an explicit latest allowed timestamp
+    /// 
+    /// # Arguments
+    /// 
+    /// * `expected_hash` - The expected hash of the block
+    /// * `max_timestamp` - The latest timestamp the block may have
+    /// * `hasher` - A mutable instance of a type implementing the HashFunction trait
+    pub fn validate_at(
+        &self, 
+        expected_hash: StdByteArray,
+        max_timestamp: u64,
+        hasher: &mut impl HashFunction
     ) -> Result<(), BlockValidationError> {
         // check the miner is declared
         if self.miner_address.is_none() {
-            return Err(BlockValidationError::NoMinerAddress(*self));
+            return Err(BlockValidationError::NoMinerAddress(Box::new(*self)));
         }
         if self.state_root.is_none() {
-            return Err(BlockValidationError::NoStateRoot(*self));
+            return Err(BlockValidationError::NoStateRoot(Box::new(*self)));
         }
         if expected_hash != self.hash(hasher).unwrap() {
             return Err(BlockValidationError::HashMismatch(expected_hash, self.hash(hasher).unwrap()));
         }
         if !is_valid_hash(self.difficulty_target.unwrap(), &self.hash(hasher).unwrap()) {
-            return Err(BlockValidationError::DifficultyMismatch(self.difficulty_target.unwrap(), *self));
+            return Err(BlockValidationError::DifficultyMismatch(self.difficulty_target.unwrap(), Box::new(*self)));
         }
         // check that all the signatures work in the tail
         let tail = &mut self.tail.clone();
@@ -254,12 +579,7 @@ impl BlockHeader {
 
 
         // check the time is not too far in the future
-        let current_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        if self.timestamp > current_time + 60 * 60 {
-            // one hour margin
+        if self.timestamp > max_timestamp {
             return Err(BlockValidationError::FutureTimestamp(self.timestamp));
         }
         Ok(())
@@ -282,6 +602,13 @@ impl BlockHeader {
 impl Hashable for BlockHeader {
     /// Hash the block header using SHA3-256
     /// 
+    /// The encoding is part of consensus, and must not change. Fields are hashed in order:
+    /// previous hash, merkle root, miner address, state root, then nonce, timestamp, depth, and
+    /// difficulty target as 8 byte little endian integers, then the signature and address of every stamp,
+    /// then, only if there is one, the gamma, challenge, and response of the VRF proof,
+    /// then, only if there is one, `EXTRANONCE_TAG` and the extranonce as an 8 byte little endian integer,
+    /// then, only if there is one, `TRANSACTION_COUNT_TAG` and the transaction count as an 8 byte little endian integer.
+    /// 
     /// # Returns
     /// 
     /// * The SHA3-256 hash of the block header
@@ -317,6 +644,19 @@ impl Hashable for BlockHeader {
             hash_function.update(self.tail.stamps[i].signature);
             hash_function.update(self.tail.stamps[i].address);
         }
+        if let Some(proof) = self.vrf_proof {
+            hash_function.update(proof.gamma);
+            hash_function.update(proof.challenge);
+            hash_function.update(proof.response);
+        }
+        if let Some(extranonce) = self.extranonce {
+            hash_function.update(EXTRANONCE_TAG);
+            hash_function.update(extranonce.to_le_bytes());
+        }
+        if let Some(transaction_count) = self.transaction_count {
+            hash_function.update(TRANSACTION_COUNT_TAG);
+            hash_function.update(transaction_count.to_le_bytes());
+        }
         Ok(hash_function.digest().unwrap())
     }
 }
@@ -340,7 +680,7 @@ impl Block {
         let tail = BlockTail {
             stamps
         };
-        let header = BlockHeader::new(
+        let mut header = BlockHeader::new(
             previous_hash, 
             merkle_tree.nodes.get(merkle_tree.root.unwrap()).unwrap().hash,
             state_root, // State root is not set in this context
@@ -351,6 +691,7 @@ impl Block {
             depth,
             difficulty_target
         );
+        header.transaction_count = Some(transactions.len() as u64);
         let hash = header.hash(hasher);
         Block {
             header,
@@ -360,6 +701,268 @@ impl Block {
         }
     }
 
+    /// Recomputes the hash from the current header and stores it, so the stored hash always matches the header.
+    /// Call after changing the header - such as when mining settles on a nonce.
+    /// If the header cannot be hashed, the stored hash is cleared
+    pub fn finalize(&mut self, hasher: &mut impl HashFunction) -> Result<StdByteArray, std::io::Error> {
+        let hash = self.header.hash(hasher);
+        self.hash = hash.as_ref().ok().copied();
+        hash
+    }
+
+    /// A single value binding the header and the transactions the block actually carries, for comparing whole blocks.
+    /// The header hash only covers the claimed merkle root - this recomputes the root from the transactions,
+    /// so two blocks with the same header but different transactions have different commitments.
+    /// The block hash remains the block's identity on the chain
+    pub fn commitment(&self, hasher: &mut impl HashFunction) -> Result<StdByteArray, std::io::Error> {
+        let header_hash = self.header.hash(hasher)?;
+        let merkle_root = merkle_root_of(&self.transactions, hasher)?;
+        hasher.update(header_hash);
+        hasher.update(merkle_root);
+        hasher.digest()
+    }
+
+    /// Sets the miner address on an unmined block
+    /// The cached hash is cleared, as it no longer matches the header until the block is (re)mined
+    pub fn set_miner(&mut self, address: StdByteArray) {
+        self.header.miner_address = Some(address);
+        self.hash = None;
+    }
+
+    /// Proves the miner's eligibility with a VRF proof over the seed of the previous block.
+    /// The miner address must be the public key of `private_key`.
+    /// The cached hash is cleared, as it no longer matches the header until the block is (re)mined
+    pub fn set_vrf_proof(&mut self, private_key: &StdByteArray) {
+        self.header.vrf_proof = Some(VrfProof::new(private_key, &self.header.vrf_seed()));
+        self.hash = None;
+    }
+
+    /// Verifies the block as the child of `parent`, returning the first failed check.
+    /// Runs every check of `validate_against`, with nothing known of the chain but the parent,
+    /// then checks that applying the block to the parent state gives the declared state root.
+    ///
+    /// On success, the state after the block is branched in the state manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `parent` - The block this block is built on
+    /// * `state_manager` - The state manager holding the parent state
+    /// * `params` - The chain parameters
+    /// * `now` - The current time, in seconds
+    pub fn connect_to_parent(
+        &self,
+        parent: &Block,
+        state_manager: &mut StateManager,
+        params: &ChainParams,
+        now: u64,
+    ) -> Result<(), BlockValidationError> {
+        self.connect_to_header(parent.hash, &parent.header, state_manager, params, now)
+    }
+
+    /// Verifies the block as the child of the block with `parent_hash` and header `parent`, as in `connect_to_parent`.
+    /// Only the parent's header is needed - its transactions are already reflected in its state root.
+    /// As nothing older than the parent is known, a transaction requiring any other block fails
+    pub fn connect_to_header(
+        &self,
+        parent_hash: Option<StdByteArray>,
+        parent: &BlockHeader,
+        state_manager: &mut StateManager,
+        params: &ChainParams,
+        now: u64,
+    ) -> Result<(), BlockValidationError> {
+        let Some(parent_hash) = parent_hash else {
+            return Err(BlockValidationError::MalformedBlock("Previous hash does not match parent".into()));
+        };
+        self.validate_against(parent_hash, parent, &ParentOnly, state_manager, params, ValidationOptions::full(now))?;
+        self.verify_state_transition(parent, state_manager)
+    }
+
+    /// Validates the block as the child of the block with `parent_hash` and header `parent`, without applying it.
+    /// These are the consensus checks - the chain, `connect_to_header` and `verify_step` all run them, so every path
+    /// accepts the same blocks. Checks, in order:
+    /// * The block links to the parent hash
+    /// * The depth is one past the parent
+    /// * The difficulty target matches the expected target, or lags it within the grace window
+    /// * The timestamp is not before the parent, nor past the allowed drift
+    /// * The header hash, proof of work, and stamps are valid
+    /// * The rules the chain parameters turn on at the depth, such as the VRF proof
+    /// * A block at the depth of the checkpoint, or of the assumed valid block, is that block
+    /// * The merkle root commits to the transactions
+    /// * Every block a transaction requires is an ancestor, according to `ancestry`
+    /// * Every transaction is well formed and signed by its sender, if `options` checks transactions
+    ///   and the block is not assumed valid
+    /// * Senders have accounts, if the chain parameters require it
+    /// * Senders can afford their transactions, and nonces continue from the account nonce, if `options` checks nonces
+    ///
+    /// The state transition is not checked - that branches the state, so it is left to the caller.
+    pub fn validate_against(
+        &self,
+        parent_hash: StdByteArray,
+        parent: &BlockHeader,
+        ancestry: &impl Ancestry,
+        state_manager: &StateManager,
+        params: &ChainParams,
+        options: ValidationOptions,
+    ) -> Result<(), BlockValidationError> {
+        let mut hasher = DefaultHash::new();
+        let Some(hash) = self.hash else {
+            return Err(BlockValidationError::MalformedBlock("Hash is not specified".into()));
+        };
+        // linkage
+        if self.header.previous_hash != parent_hash {
+            return Err(BlockValidationError::MalformedBlock("Previous hash does not match parent".into()));
+        }
+        // depth
+        if parent.depth.checked_add(1) != Some(self.header.depth) {
+            return Err(BlockValidationError::MalformedBlock("Depth does not match previous block".into()));
+        }
+        let Some(parent_root) = parent.state_root else {
+            return Err(BlockValidationError::NoStateRoot(Box::new(*parent)));
+        };
+        // difficulty
+        let reputations = get_current_reputations_for_stampers_from_state(state_manager, parent, &self.header)
+            .values().cloned().collect::<Vec<f64>>();
+        if !is_difficulty_accepted(&self.header, &reputations, params) {
+            return Err(BlockValidationError::MalformedBlock("Difficulty target does not match".into()));
+        }
+        // timestamp
+        if !params.is_timestamp_ordered(parent.timestamp, self.header.timestamp) {
+            return Err(BlockValidationError::MalformedBlock("Timestamp is not after previous block".into()));
+        }
+        let max_timestamp = options.now.saturating_add(params.max_future_drift);
+        if self.header.timestamp > max_timestamp {
+            return Err(BlockValidationError::FutureTimestamp(self.header.timestamp));
+        }
+        // proof of work
+        self.header.validate_at(hash, max_timestamp, &mut hasher)?;
+        // miner address and eligibility
+        self.header.validate_rules(params)?;
+        // trusted blocks
+        for trusted in [params.checkpoint, params.assume_valid].into_iter().flatten() {
+            if trusted.depth == self.header.depth && trusted.hash != hash {
+                return Err(BlockValidationError::CheckpointMismatch(trusted.depth));
+            }
+        }
+        // merkle root
+        self.verify_merkle_root(&mut hasher)?;
+        // transactions requiring a block are only valid on top of it
+        for transaction in &self.transactions {
+            if let Some(required) = transaction.header.required_block_hash
+                && !ancestry.is_ancestor(required, parent_hash) {
+                return Err(BlockValidationError::MissingRequiredBlock(required));
+            }
+        }
+        // signatures, against the keys controlling the accounts in the parent state
+        if options.checks.transactions && !params.is_assumed_valid(hash, ancestry) {
+            let mut keys: HashMap<StdByteArray, StdByteArray> = HashMap::new();
+            let mut batch = Vec::with_capacity(self.transactions.len());
+            for transaction in &self.transactions {
+                transaction.sanity_check(&mut hasher)?;
+                let Some(signature) = transaction.signature else {
+                    return Err(BlockValidationError::TransactionInvalidSignature);
+                };
+                let sender = transaction.header.sender;
+                let key = *keys.entry(sender).or_insert_with(|| state_manager.get_account_or_default(&sender, parent_root).verifying_key());
+                batch.push((DefaultVerifier::from_bytes(&key), signature, transaction));
+            }
+            if !verify_in_batches(&batch, options.signature_batch_size) {
+                return Err(BlockValidationError::TransactionInvalidSignature);
+            }
+        }
+        // balances and nonces, against the parent state
+        let mut per_sender: BTreeMap<StdByteArray, (u64, Vec<u64>)> = BTreeMap::new();
+        for transaction in &self.transactions {
+            let (total, nonces) = per_sender.entry(transaction.header.sender).or_default();
+            *total = total.checked_add(transaction.header.amount)
+                .ok_or(BlockValidationError::InvalidTransaction("Amount overflows".into()))?;
+            nonces.push(transaction.header.nonce);
+        }
+        for (sender, (total, mut nonces)) in per_sender {
+            let account = match state_manager.get_account(&sender, parent_root) {
+                Some(account) => account,
+                None if params.enforces(Rule::RejectUnknownSenders, self.header.depth) => {
+                    return Err(BlockValidationError::TransactionUnknownSender(sender));
+                },
+                None => Account::new(sender, 0),
+            };
+            if account.balance < total {
+                return Err(BlockValidationError::TransactionInsufficientBalance(account.balance));
+            }
+            // the account nonce is advanced once per transaction when the state is applied
+            if account.nonce.checked_add(nonces.len() as u64).is_none() {
+                return Err(BlockValidationError::InvalidTransaction("Nonce overflows".into()));
+            }
+            if !options.checks.nonces {
+                continue;
+            }
+            nonces.sort();
+            // within range, as the account nonce plus the transaction count was checked above
+            for (nonce, expected_nonce) in nonces.into_iter().zip(account.nonce..) {
+                if nonce != expected_nonce {
+                    return Err(BlockValidationError::TransactionNonceMismatch(expected_nonce, nonce));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the transactions and rewards of the block to the state under `parent`, and checks that
+    /// the result is the committed state root. The parent's state is never changed - the new state is a branch off it,
+    /// kept for the block to settle on if it matches and removed if not.
+    /// The transactions must already be valid against the parent state, as checked by `connect_to_header`
+    pub fn verify_state_transition(&self, parent: &BlockHeader, state_manager: &mut StateManager) -> Result<(), BlockValidationError> {
+        if parent.state_root.is_none() {
+            return Err(BlockValidationError::NoStateRoot(Box::new(*parent)));
+        }
+        let state_root = state_manager.try_branch_from_block(self, parent)?;
+        if self.header.state_root != Some(state_root) {
+            state_manager.remove_branch(state_root);
+            return Err(BlockValidationError::MalformedBlock("State root does not match".into()));
+        }
+        Ok(())
+    }
+
+    /// The accounts the transactions of the block would change, applied in order to `pre_state` without committing anything, in address order.
+    /// The reward and history the block gives its miner and stampers are not included - they depend on the chain, not just the block.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(diffs)` - each changed account, as in `pre_state` and after the transactions
+    /// * `Err(std::io::Error)` - if a transaction overdraws its sender, or overflows an account
+    pub fn state_changes(&self, pre_state: &StateSnapshot) -> Result<Vec<AccountDiff>, std::io::Error> {
+        let before: HashMap<StdByteArray, &Account> = pre_state.accounts.iter().map(|account| (account.address, account)).collect();
+        let mut after: BTreeMap<StdByteArray, Account> = BTreeMap::new();
+        let pre = |address: StdByteArray| before.get(&address).map_or(Account::new(address, 0), |account| (*account).clone());
+        for transaction in &self.transactions {
+            let header = &transaction.header;
+            let sender = after.entry(header.sender).or_insert_with(|| pre(header.sender));
+            AccountDelta { credit: 0, debit: header.amount, nonce: 1 }.apply(sender)?;
+            if let Some(key) = header.rotate_key {
+                sender.authorized_key = Some(key);
+            }
+            let receiver = after.entry(header.receiver).or_insert_with(|| pre(header.receiver));
+            AccountDelta { credit: header.amount, debit: 0, nonce: 0 }.apply(receiver)?;
+        }
+        Ok(after.into_iter().filter_map(|(address, account)| {
+            let previous = before.get(&address).map(|account| (*account).clone());
+            (previous.as_ref() != Some(&account)).then_some(AccountDiff { address, before: previous, after: Some(account) })
+        }).collect())
+    }
+
+    /// Recomputes the merkle root from the transactions, and checks it against the header.
+    /// If the header commits to a transaction count, the block must carry exactly that many
+    pub fn verify_merkle_root(&self, hasher: &mut impl HashFunction) -> Result<(), BlockValidationError> {
+        let merkle_root = merkle_root_of(&self.transactions, hasher)
+            .map_err(|e| BlockValidationError::MalformedBlock(e.to_string()))?;
+        if merkle_root != self.header.merkle_root {
+            return Err(BlockValidationError::MalformedBlock("Merkle root does not match".into()));
+        }
+        if self.header.transaction_count.is_some_and(|count| count != self.transactions.len() as u64) {
+            return Err(BlockValidationError::MalformedBlock("Transaction count does not match".into()));
+        }
+        Ok(())
+    }
+
     /// Creates the proof of inclusion for a transaction in the block
     pub fn get_proof_for_transaction<T: Into<StdByteArray>>(&self, transaction: T) -> Option<MerkleProof> {
         generate_proof_of_inclusion(
@@ -369,6 +972,49 @@ impl Block {
         )
     }
 
+    /// A fraud proof showing this mined block is invalid, against the state of `parent`.
+    /// `None` if the block is not mined, or has no fault that can be proven compactly -
+    /// an over-spend is only proven for an account that exists in the parent state
+    pub fn fraud_proof(&self, parent: &BlockHeader, state_manager: &StateManager) -> Option<FraudProof> {
+        self.hash?;
+        let parent_root = parent.state_root?;
+        let mut per_sender: BTreeMap<StdByteArray, u64> = BTreeMap::new();
+        for transaction in &self.transactions {
+            let total = per_sender.entry(transaction.header.sender).or_default();
+            *total = total.saturating_add(transaction.header.amount);
+        }
+        let (account_proof, account) = per_sender.into_iter().find_map(|(sender, total)| {
+            state_manager.account_proof(&sender, parent_root).filter(|(_, account)| account.balance < total)
+        })?;
+        let mut indices = HashSet::new();
+        let mut transactions = vec![];
+        for transaction in self.transactions.iter().filter(|transaction| transaction.header.sender == account.address) {
+            let proof = self.get_proof_for_transaction(transaction.hash)?;
+            let index = proof_leaf_index(&proof)?;
+            // a repeated transaction proves as its first copy
+            if indices.insert(index) {
+                transactions.push((index, transaction.header, proof));
+            }
+        }
+        Some(FraudProof::OverSpend {
+            header: self.header,
+            parent: *parent,
+            account,
+            account_proof,
+            transactions,
+        })
+    }
+
+    /// Bundles the proof that a transaction is in this mined block
+    pub fn payment_proof(&self, transaction: StdByteArray) -> Option<PaymentProof> {
+        Some(PaymentProof {
+            transaction,
+            header: self.header,
+            proof: self.get_proof_for_transaction(transaction)?,
+            block_hash: self.hash?,
+        })
+    }
+
     /// Veerifies a transaction is in the block
     pub fn validate_transaction<T: Into<StdByteArray> + Clone>(&self, transaction: T) -> bool{
         let proof = self.get_proof_for_transaction(transaction.clone());
@@ -383,6 +1029,23 @@ impl Block {
             false
         }
     }
+
+    /// The short ids of the transactions, in block order, salted with the block hash - see `short_id`.
+    /// `None` if the block is not mined
+    pub fn short_ids(&self) -> Option<Vec<u64>> {
+        let salt = self.hash?;
+        Some(self.transactions.iter().map(|t| short_id(&t.hash, &salt)).collect())
+    }
+
+    /// The hashes of the transactions in the block that are not in the mempool, in block order.
+    /// Unknown transactions were never broadcast to this node - they may be private, or a sign of censorship.
+    pub fn unknown_transactions(&self, mempool: &MinerPool) -> Vec<StdByteArray> {
+        let known: HashSet<StdByteArray> = mempool.pending_transactions().iter().map(|t| t.hash).collect();
+        self.transactions.iter()
+            .map(|t| t.hash)
+            .filter(|hash| !known.contains(hash))
+            .collect()
+    }
 }
 
 impl Signable<64> for BlockHeader {
@@ -407,6 +1070,768 @@ mod tests {
     use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction};
 
     use super::*;
+    use crate::protocol::params::SoftForks;
+
+    fn unmined_block() -> Block {
+        let transaction = Transaction::new([1; 32], [2; 32], 1, 1, 0, &mut DefaultHash::new());
+        Block::new(
+            [0; 32], 0, 1, vec![transaction], Some([1; 32]),
+            BlockTail::default().stamps, 1,
+            Some(crate::protocol::difficulty::MIN_DIFFICULTY),
+            Some([2; 32]), &mut DefaultHash::new()
+        )
+    }
+
+    fn genesis() -> (StateManager, Block) {
+        let state_manager = StateManager::new();
+        let state_root = state_manager.state_trie.lock().unwrap()
+            .create_genesis([0; 32], crate::accounting::account::Account::default())
+            .unwrap();
+        (state_manager, crate::protocol::chain::get_genesis_block(Some(state_root)))
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn signed_transaction(amount: u64, nonce: u64) -> (Transaction, StdByteArray) {
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let mut transaction = Transaction::new(sender, [1; 32], amount, 0, nonce, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        (transaction, sender)
+    }
+
+    /// Mines a child of `parent` for `miner`, committing to `state_root` or the real post state if None.
+    /// `edit` changes the block before it is mined
+    async fn child(
+        parent: &Block, state_manager: &mut StateManager, transactions: Vec<Transaction>, state_root: Option<StdByteArray>,
+        miner: StdByteArray, edit: Option<&dyn Fn(&mut Block)>
+    ) -> Block {
+        let mut block = Block::new(
+            parent.hash.unwrap(), 0, now(), transactions, Some(miner),
+            BlockTail::default().stamps, parent.header.depth + 1, None, None, &mut DefaultHash::new()
+        );
+        if let Some(edit) = edit {
+            edit(&mut block);
+        }
+        let state_root = state_root.unwrap_or_else(|| state_manager.branch_from_block(&block, &parent.header));
+        crate::protocol::pow::mine(&mut block, miner, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
+        block
+    }
+
+    fn malformed_reason(result: Result<(), BlockValidationError>) -> String {
+        match result {
+            Err(BlockValidationError::MalformedBlock(reason)) => reason,
+            other => panic!("expected a malformed block, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_transactions() {
+        let transactions = (0..4).map(
+            |i| Transaction::new([1; 32], [2; 32], 1, 1, i, &mut DefaultHash::new())
+        ).collect::<Vec<_>>();
+        let block = Block::new(
+            [0; 32], 0, 1, transactions.clone(), Some([1; 32]),
+            BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+        );
+        let mempool = MinerPool::new();
+        assert_eq!(block.unknown_transactions(&mempool), transactions.iter().map(|t| t.hash).collect::<Vec<_>>());
+
+        // a mix of seen and unseen, and pooled transactions that are not in the block
+        mempool.add_transaction(transactions[2]);
+        mempool.add_transaction(transactions[0]);
+        mempool.add_transaction(Transaction::new([3; 32], [2; 32], 1, 1, 0, &mut DefaultHash::new()));
+        assert_eq!(block.unknown_transactions(&mempool), vec![transactions[1].hash, transactions[3].hash]);
+        // checking does not consume the mempool
+        assert_eq!(mempool.pending_transactions().len(), 3);
+
+        for transaction in &transactions {
+            mempool.add_transaction(*transaction);
+        }
+        assert!(block.unknown_transactions(&mempool).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_finalize() {
+        let (mut state_manager, parent) = genesis();
+        let mut block = child(&parent, &mut state_manager, vec![signed_transaction(0, 0).0], None, [3; 32], None).await;
+        let mined = block.hash.unwrap();
+        // mining leaves the stored hash matching the header
+        assert_eq!(mined, block.header.hash(&mut DefaultHash::new()).unwrap());
+        assert!(block.header.validate(mined, &mut DefaultHash::new()).is_ok());
+
+        // a changed header no longer matches, until finalized
+        block.header.nonce += 1;
+        assert_ne!(block.hash.unwrap(), block.header.hash(&mut DefaultHash::new()).unwrap());
+        let hash = block.finalize(&mut DefaultHash::new()).unwrap();
+        assert_eq!(block.hash, Some(hash));
+        assert_eq!(hash, block.header.hash(&mut DefaultHash::new()).unwrap());
+        assert_ne!(hash, mined);
+
+        // finalizing the mined header again restores the mined hash, which validates
+        block.header.nonce -= 1;
+        assert_eq!(block.finalize(&mut DefaultHash::new()).unwrap(), mined);
+        assert!(block.header.validate(block.hash.unwrap(), &mut DefaultHash::new()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_commitment() {
+        let (mut state_manager, parent) = genesis();
+        let block = child(&parent, &mut state_manager, vec![signed_transaction(0, 0).0], None, [3; 32], None).await;
+        let commitment = block.commitment(&mut DefaultHash::new()).unwrap();
+        // identical blocks share it
+        assert_eq!(block.clone().commitment(&mut DefaultHash::new()).unwrap(), commitment);
+        assert_ne!(commitment, block.hash.unwrap());
+
+        // changing the header changes it
+        let mut changed = block.clone();
+        changed.header.nonce += 1;
+        assert_ne!(changed.commitment(&mut DefaultHash::new()).unwrap(), commitment);
+        let mut changed = block.clone();
+        changed.header.state_root = Some([7; 32]);
+        assert_ne!(changed.commitment(&mut DefaultHash::new()).unwrap(), commitment);
+        // and the transactions, even when the header still claims the old merkle root
+        let mut changed = block.clone();
+        changed.transactions[0] = signed_transaction(0, 0).0;
+        assert_eq!(changed.header.hash(&mut DefaultHash::new()).unwrap(), block.hash.unwrap());
+        assert_ne!(changed.commitment(&mut DefaultHash::new()).unwrap(), commitment);
+        let mut changed = block.clone();
+        changed.transactions.push(signed_transaction(0, 1).0);
+        assert_ne!(changed.commitment(&mut DefaultHash::new()).unwrap(), commitment);
+    }
+
+    #[tokio::test]
+    async fn test_fraud_proof_over_spend() {
+        use crate::accounting::account::AccountDelta;
+
+        let (mut state_manager, genesis) = genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let funding = [(sender, AccountDelta { credit: 10, debit: 0, nonce: 0 })];
+        let mut parent = genesis.clone();
+        parent.header.state_root = Some(state_manager.apply_updates(genesis.header.state_root.unwrap(), &funding).unwrap());
+        parent.hash = Some(parent.header.hash(&mut DefaultHash::new()).unwrap());
+        let mut spend = |amount: u64, nonce: u64| {
+            let mut transaction = Transaction::new(sender, [1; 32], amount, 0, nonce, &mut DefaultHash::new());
+            transaction.sign(&mut signing_key);
+            transaction
+        };
+        let (first, second, third) = (spend(4, 0), spend(6, 1), spend(6, 1));
+        let other = signed_transaction(0, 0).0;
+
+        // spending exactly the balance is no fault
+        let honest = child(&parent, &mut state_manager, vec![first, other, second], None, [3; 32], None).await;
+        assert!(honest.fraud_proof(&parent.header, &state_manager).is_none());
+
+        // the post state cannot be built for an over-spend, so the block commits to any root
+        let block = child(&parent, &mut state_manager, vec![first, other, third, spend(1, 2)], Some([9; 32]), [3; 32], None).await;
+        let fraud_proof = block.fraud_proof(&parent.header, &state_manager).unwrap();
+        let decoded: FraudProof = bincode::deserialize(&bincode::serialize(&fraud_proof).unwrap()).unwrap();
+        assert_eq!(decoded, fraud_proof);
+        assert!(verify_fraud_proof(&fraud_proof, block.hash.unwrap()).is_ok());
+        // not about this block
+        assert!(matches!(verify_fraud_proof(&fraud_proof, parent.hash.unwrap()), Err(BlockValidationError::HashMismatch(_, _))));
+
+        let FraudProof::OverSpend { header, parent: parent_header, account, account_proof, transactions } = fraud_proof;
+        assert_eq!(transactions.iter().map(|(index, ..)| *index).collect::<Vec<_>>(), vec![0, 2, 3]);
+        let with = |account: Account, transactions: Vec<(u64, TransactionHeader, MerkleProof)>| FraudProof::OverSpend {
+            header, parent: parent_header, account, account_proof: account_proof.clone(), transactions,
+        };
+        // leaving a transaction out leaves the account able to pay
+        assert_eq!(malformed_reason(verify_fraud_proof(&with(account.clone(), transactions[..2].to_vec()), block.hash.unwrap())), "The account can afford the transactions");
+        // nor can one transaction be counted twice
+        let twice = vec![transactions[1].clone(), transactions[1].clone()];
+        assert_eq!(malformed_reason(verify_fraud_proof(&with(account.clone(), twice), block.hash.unwrap())), "A transaction is counted twice");
+        // or placed somewhere else in the block
+        let mut moved = transactions.clone();
+        moved[0].0 = 1;
+        assert_eq!(malformed_reason(verify_fraud_proof(&with(account.clone(), moved), block.hash.unwrap())), "A transaction is not in the block");
+        // the balance is the one in the parent state
+        let mut poorer = account.clone();
+        poorer.balance = 1;
+        assert_eq!(malformed_reason(verify_fraud_proof(&with(poorer, transactions.clone()), block.hash.unwrap())), "The account is not in the parent state");
+
+        // the transactions of an honest block prove nothing
+        let honest_transactions = [0, 2].into_iter().map(|index| {
+            let transaction = honest.transactions[index as usize];
+            (index, transaction.header, honest.get_proof_for_transaction(transaction.hash).unwrap())
+        }).collect();
+        let honest_proof = FraudProof::OverSpend {
+            header: honest.header, parent: parent.header, account, account_proof, transactions: honest_transactions,
+        };
+        assert_eq!(malformed_reason(verify_fraud_proof(&honest_proof, honest.hash.unwrap())), "The account can afford the transactions");
+    }
+
+    #[tokio::test]
+    async fn test_indexed_inclusion() {
+        use pillar_crypto::proofs::HashDirection;
+
+        let (mut state_manager, parent) = genesis();
+        let transactions = (0..3).map(|_| signed_transaction(0, 0).0).collect::<Vec<_>>();
+        let block = child(&parent, &mut state_manager, transactions.clone(), None, [3; 32], None).await;
+        assert_eq!(block.header.transaction_count, Some(3));
+
+        for (i, transaction) in transactions.iter().enumerate() {
+            let proof = block.get_proof_for_transaction(transaction.hash).unwrap();
+            assert!(block.header.verify_indexed_inclusion(transaction.hash, &proof, i as u64));
+        }
+        // the last transaction also proves as the copy that pads its level - one past the committed count
+        let last = transactions[2].hash;
+        let mut padding = block.get_proof_for_transaction(last).unwrap();
+        padding.directions[0] = HashDirection::Left;
+        assert!(verify_proof_of_inclusion(last, &padding, block.header.merkle_root, &mut DefaultHash::new()));
+        assert!(!block.header.verify_indexed_inclusion(last, &padding, 3));
+        // without a committed count, no index can be proven
+        let mut uncommitted = block.header;
+        uncommitted.transaction_count = None;
+        let proof = block.get_proof_for_transaction(last).unwrap();
+        assert!(!uncommitted.verify_indexed_inclusion(last, &proof, 2));
+
+        // a copy padded with the last transaction keeps the merkle root, but not the count
+        let mut padded = block.clone();
+        padded.transactions.push(transactions[2]);
+        assert_eq!(merkle_root_of(&padded.transactions, &mut DefaultHash::new()).unwrap(), block.header.merkle_root);
+        assert_eq!(malformed_reason(padded.verify_merkle_root(&mut DefaultHash::new())), "Transaction count does not match");
+    }
+
+    #[tokio::test]
+    async fn test_payment_proof() {
+        let (mut state_manager, parent) = genesis();
+        let transactions = (0..3).map(|_| signed_transaction(0, 0).0).collect::<Vec<_>>();
+        let block = child(&parent, &mut state_manager, transactions.clone(), None, [3; 32], None).await;
+        let payment_proof = block.payment_proof(transactions[1].hash).unwrap();
+        assert!(verify_payment_proof(&payment_proof).is_ok());
+        let decoded: PaymentProof = bincode::deserialize(&bincode::serialize(&payment_proof).unwrap()).unwrap();
+        assert_eq!(decoded, payment_proof);
+        assert!(block.payment_proof([9; 32]).is_none());
+
+        // a proof for another transaction
+        let mut other = payment_proof.clone();
+        other.transaction = transactions[2].hash;
+        assert!(matches!(verify_payment_proof(&other), Err(BlockValidationError::MalformedBlock(_))));
+        // a header with the wrong root
+        let mut wrong_root = payment_proof.clone();
+        wrong_root.header.merkle_root = [9; 32];
+        assert!(matches!(verify_payment_proof(&wrong_root), Err(BlockValidationError::MalformedBlock(_))));
+        // a header that does not hash to the block hash
+        let mut wrong_hash = payment_proof.clone();
+        wrong_hash.header.timestamp += 1;
+        assert!(matches!(verify_payment_proof(&wrong_hash), Err(BlockValidationError::HashMismatch(_, _))));
+        // a consistent bundle without the work
+        let mut unworked = payment_proof.clone();
+        while is_valid_hash(unworked.header.difficulty_target.unwrap(), &unworked.header.hash(&mut DefaultHash::new()).unwrap()) {
+            unworked.header.nonce += 1;
+        }
+        unworked.block_hash = unworked.header.hash(&mut DefaultHash::new()).unwrap();
+        assert!(matches!(verify_payment_proof(&unworked), Err(BlockValidationError::DifficultyMismatch(_, _))));
+        // a header declaring a target easier than the retarget allows, which its hash meets
+        let mut easy = payment_proof.clone();
+        easy.header.difficulty_target = Some(0);
+        easy.block_hash = easy.header.hash(&mut DefaultHash::new()).unwrap();
+        assert!(matches!(verify_payment_proof(&easy), Err(BlockValidationError::DifficultyMismatch(0, _))));
+        // or declaring none at all
+        let mut undeclared = payment_proof.clone();
+        undeclared.header.difficulty_target = None;
+        assert!(matches!(verify_payment_proof(&undeclared), Err(BlockValidationError::MalformedBlock(_))));
+    }
+
+    #[tokio::test]
+    async fn test_verify_many() {
+        let (mut state_manager, parent) = genesis();
+        let first_transactions = (0..3).map(|_| signed_transaction(0, 0).0).collect::<Vec<_>>();
+        let first = child(&parent, &mut state_manager, first_transactions.clone(), None, [3; 32], None).await;
+        let second_transactions = (0..2).map(|_| signed_transaction(0, 0).0).collect::<Vec<_>>();
+        let second = child(&first, &mut state_manager, second_transactions.clone(), None, [3; 32], None).await;
+        let item = |block: &Block, transaction: &Transaction| {
+            (transaction.hash, block.get_proof_for_transaction(transaction.hash).unwrap(), block.header)
+        };
+        let mut unworked = item(&second, &second_transactions[1]);
+        while is_valid_hash(unworked.2.difficulty_target.unwrap(), &unworked.2.hash(&mut DefaultHash::new()).unwrap()) {
+            unworked.2.nonce += 1;
+        }
+        let mut misplaced = item(&first, &first_transactions[0]);
+        misplaced.2 = second.header;
+        // a header declaring an easy target it meets, and one declaring none
+        let mut easy = item(&first, &first_transactions[1]);
+        easy.2.difficulty_target = Some(0);
+        let mut undeclared = item(&second, &second_transactions[0]);
+        undeclared.2.difficulty_target = None;
+
+        let items = vec![
+            item(&first, &first_transactions[0]),
+            item(&second, &second_transactions[0]),
+            misplaced,
+            item(&first, &first_transactions[2]),
+            unworked,
+            item(&second, &second_transactions[1]),
+            item(&first, &first_transactions[1]),
+            easy,
+            undeclared,
+        ];
+        assert_eq!(verify_many(&items), vec![true, true, false, true, false, true, true, false, false]);
+        assert!(verify_many(&[]).is_empty());
+        // a chain bootstrapping at another difficulty allows none of the targets
+        let other = ChainParams { bootstrap_difficulty: ChainParams::default().bootstrap_difficulty + 1, ..ChainParams::default() };
+        assert_eq!(verify_many_with_params(&items, &other), vec![false; items.len()]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_extension() {
+        let (mut state_manager, genesis) = genesis();
+        let mut blocks = vec![genesis];
+        for _ in 0..4 {
+            let block = child(blocks.last().unwrap(), &mut state_manager, vec![signed_transaction(0, 0).0], None, [3; 32], None).await;
+            blocks.push(block);
+        }
+        let headers = blocks.iter().map(|block| block.header).collect::<Vec<_>>();
+        assert_eq!(verify_extension(&headers[0], &headers[1..]), Ok(()));
+        assert_eq!(verify_extension(&headers[2], &headers[3..]), Ok(()));
+        assert_eq!(verify_extension(&headers[4], &[]), Ok(()));
+
+        // a sibling of the second block is well formed, but does not link to the first
+        let sibling = child(&blocks[1], &mut state_manager, vec![signed_transaction(0, 0).0], None, [3; 32], None).await;
+        let mut broken = headers[1..].to_vec();
+        broken[2] = child(&sibling, &mut state_manager, vec![signed_transaction(0, 0).0], None, [3; 32], None).await.header;
+        assert_eq!(verify_extension(&headers[0], &broken), Err(2));
+        // skipping a block breaks depth continuity
+        assert_eq!(verify_extension(&headers[0], &[headers[1], headers[3]]), Err(1));
+        // headers without the work are rejected
+        let mut unworked = headers[1..].to_vec();
+        while is_valid_hash(unworked[3].difficulty_target.unwrap(), &unworked[3].hash(&mut DefaultHash::new()).unwrap()) {
+            unworked[3].nonce += 1;
+        }
+        assert_eq!(verify_extension(&headers[0], &unworked), Err(3));
+        // as are headers committing to an easier difficulty than the retarget allows
+        let mut easy = headers[1..].to_vec();
+        easy[0].difficulty_target = Some(0);
+        assert_eq!(verify_extension(&headers[0], &easy), Err(0));
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_parent_valid() {
+        let (mut state_manager, parent) = genesis();
+        let (transaction, sender) = signed_transaction(0, 0);
+        let block = child(&parent, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+        assert!(block.connect_to_parent(&parent, &mut state_manager, &ChainParams::default(), now()).is_ok());
+        let account = state_manager.get_account(&sender, block.header.state_root.unwrap()).unwrap();
+        assert_eq!(account.nonce, 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_parent_header_failures() {
+        let params = ChainParams::default();
+        let (mut state_manager, parent) = genesis();
+        let (transaction, _) = signed_transaction(0, 0);
+        let block = child(&parent, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+
+        let mut other_parent = parent.clone();
+        other_parent.hash = Some([7; 32]);
+        let result = block.connect_to_parent(&other_parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "Previous hash does not match parent");
+
+        let mut deep = block.clone();
+        deep.header.depth = 2;
+        let result = deep.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "Depth does not match previous block");
+
+        let mut harder = block.clone();
+        harder.header.difficulty_target = Some(harder.header.difficulty_target.unwrap() + 1);
+        let result = harder.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "Difficulty target does not match");
+
+        let mut later_parent = parent.clone();
+        later_parent.header.timestamp = block.header.timestamp + 1;
+        let result = block.connect_to_parent(&later_parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "Timestamp is not after previous block");
+
+        let early = block.header.timestamp - params.max_future_drift - 1;
+        let result = block.connect_to_parent(&parent, &mut state_manager, &params, early);
+        assert!(matches!(result, Err(BlockValidationError::FutureTimestamp(_))));
+
+        let mut unworked = block.clone();
+        unworked.header.nonce += 1;
+        let result = unworked.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert!(matches!(result, Err(BlockValidationError::HashMismatch(_, _))));
+    }
+
+    #[tokio::test]
+    async fn test_equal_timestamp() {
+        let (mut state_manager, parent) = genesis();
+        let (transaction, _) = signed_transaction(0, 0);
+        let block = child(&parent, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+        let mut same_time_parent = parent.clone();
+        same_time_parent.header.timestamp = block.header.timestamp;
+
+        // strictly later by default
+        let strict = ChainParams::default();
+        let result = block.connect_to_parent(&same_time_parent, &mut state_manager, &strict, now());
+        assert_eq!(malformed_reason(result), "Timestamp is not after previous block");
+
+        let equal = ChainParams { allow_equal_timestamps: true, ..strict };
+        assert!(block.connect_to_parent(&same_time_parent, &mut state_manager, &equal, now()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_parent_body_failures() {
+        let params = ChainParams::default();
+        let (mut state_manager, parent) = genesis();
+
+        let (transaction, _) = signed_transaction(0, 0);
+        let mut swapped = child(&parent, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+        swapped.transactions = vec![signed_transaction(0, 0).0];
+        let result = swapped.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "Merkle root does not match");
+
+        let (mut unsigned, _) = signed_transaction(0, 0);
+        unsigned.signature = None;
+        let block = child(&parent, &mut state_manager, vec![unsigned], None, [3; 32], None).await;
+        let result = block.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert!(matches!(result, Err(BlockValidationError::TransactionInvalidSignature)));
+
+        let (transaction, _) = signed_transaction(5, 0);
+        let block = child(&parent, &mut state_manager, vec![transaction], Some([9; 32]), [3; 32], None).await;
+        let result = block.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert!(matches!(result, Err(BlockValidationError::TransactionInsufficientBalance(0))));
+
+        let (transaction, _) = signed_transaction(0, 1);
+        let block = child(&parent, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+        let result = block.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert!(matches!(result, Err(BlockValidationError::TransactionNonceMismatch(0, 1))));
+
+        let (transaction, sender) = signed_transaction(0, 0);
+        let block = child(&parent, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+        let strict = ChainParams { soft_forks: SoftForks::default().activate(Rule::RejectUnknownSenders, 0), ..params };
+        let result = block.connect_to_parent(&parent, &mut state_manager, &strict, now());
+        assert!(matches!(result, Err(BlockValidationError::TransactionUnknownSender(s)) if s == sender));
+
+        let (transaction, _) = signed_transaction(0, 0);
+        let block = child(&parent, &mut state_manager, vec![transaction], Some([9; 32]), [3; 32], None).await;
+        let result = block.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "State root does not match");
+    }
+
+    #[tokio::test]
+    async fn test_verify_state_transition() {
+        use crate::accounting::account::AccountDelta;
+
+        let (mut state_manager, genesis) = genesis();
+        let (transaction, sender) = signed_transaction(5, 0);
+        let funded = |state_manager: &mut StateManager, balance: u64| {
+            let delta = AccountDelta { credit: balance, debit: 0, nonce: 0 };
+            state_manager.apply_updates(genesis.header.state_root.unwrap(), &[(sender, delta)]).unwrap()
+        };
+        let mut parent = genesis.clone();
+        parent.header.state_root = Some(funded(&mut state_manager, 10));
+
+        // the block commits to the state its transactions produce
+        let block = child(&parent, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+        assert!(block.verify_state_transition(&parent.header, &mut state_manager).is_ok());
+        let after = block.header.state_root.unwrap();
+        assert_eq!(state_manager.get_account(&sender, after).unwrap().balance, 5);
+
+        // committed to a state where the sender held more than it did
+        let mut tampered = genesis.clone();
+        tampered.header.state_root = Some(funded(&mut state_manager, 11));
+        let block = child(&tampered, &mut state_manager, vec![transaction], None, [3; 32], None).await;
+        let result = block.verify_state_transition(&parent.header, &mut state_manager);
+        assert_eq!(malformed_reason(result), "State root does not match");
+        // the parent state is untouched
+        assert_eq!(state_manager.get_account(&sender, parent.header.state_root.unwrap()).unwrap().balance, 10);
+        // and the rejected block reached the state of the accepted one, which is kept
+        assert_eq!(state_manager.get_account(&sender, after).unwrap().balance, 5);
+
+        let mut rootless = parent.header;
+        rootless.state_root = None;
+        assert!(matches!(block.verify_state_transition(&rootless, &mut state_manager), Err(BlockValidationError::NoStateRoot(_))));
+    }
+
+    #[tokio::test]
+    async fn test_state_changes() {
+        use crate::accounting::account::AccountDelta;
+        use crate::accounting::state::diff_states;
+
+        let (mut state_manager, genesis) = genesis();
+        let (mut key_a, mut key_b) = (DefaultSigner::generate_random(), DefaultSigner::generate_random());
+        let (a, b) = (key_a.get_verifying_function().to_bytes(), key_b.get_verifying_function().to_bytes());
+        let funding = [(a, AccountDelta { credit: 10, debit: 0, nonce: 0 }), (b, AccountDelta { credit: 20, debit: 0, nonce: 0 })];
+        let mut parent = genesis.clone();
+        parent.header.state_root = Some(state_manager.apply_updates(genesis.header.state_root.unwrap(), &funding).unwrap());
+        let transfer = |key: &mut DefaultSigner, sender: StdByteArray, receiver: StdByteArray, amount: u64, nonce: u64| {
+            let mut transaction = Transaction::new(sender, receiver, amount, 0, nonce, &mut DefaultHash::new());
+            transaction.sign(key);
+            transaction
+        };
+        // a spends some of what b sends it in the same block
+        let transactions = vec![
+            transfer(&mut key_a, a, [1; 32], 3, 0),
+            transfer(&mut key_b, b, a, 5, 0),
+            transfer(&mut key_a, a, b, 12, 1),
+        ];
+        let pre_state = state_manager.snapshot(parent.header.state_root.unwrap(), &mut DefaultHash::new()).unwrap();
+        let block = child(&parent, &mut state_manager, transactions, None, [3; 32], None).await;
+        let diffs = block.state_changes(&pre_state).unwrap();
+        assert_eq!(diffs.iter().map(|diff| diff.address).collect::<Vec<_>>(), {
+            let mut addresses = vec![a, b, [1; 32]];
+            addresses.sort();
+            addresses
+        });
+        let changes = |address| diffs.iter().find(|diff| diff.address == address).map(|diff| (diff.balances(), diff.nonces())).unwrap();
+        assert_eq!(changes(a), ((10, 0), (0, 2)));
+        assert_eq!(changes(b), ((20, 27), (0, 1)));
+        assert_eq!(changes([1; 32]), ((0, 3), (0, 0)));
+        // nothing was committed
+        assert_eq!(state_manager.get_account(&a, parent.header.state_root.unwrap()).unwrap().balance, 10);
+
+        // the diffs match the state the block commits to, but for the history of the miner
+        let post_state = state_manager.snapshot(block.header.state_root.unwrap(), &mut DefaultHash::new()).unwrap();
+        let applied = diff_states(&pre_state, &post_state).into_iter()
+            .filter(|diff| diff.address != block.header.miner_address.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(diffs, applied);
+
+        // a block overdrawing an account has no changes to preview
+        let mut overdrawn = block.clone();
+        overdrawn.transactions = vec![transfer(&mut key_a, a, b, 11, 0)];
+        assert!(overdrawn.state_changes(&pre_state).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_parent_required_block() {
+        let (mut state_manager, parent) = genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let anchored = |required: StdByteArray, signing_key: &mut DefaultSigner| {
+            let mut transaction = Transaction::new(sender, [1; 32], 0, 0, 0, &mut DefaultHash::new())
+                .with_required_block(required, &mut DefaultHash::new());
+            transaction.sign(signing_key);
+            transaction
+        };
+        // the parent is known
+        let block = child(&parent, &mut state_manager, vec![anchored(parent.hash.unwrap(), &mut signing_key)], None, [3; 32], None).await;
+        assert!(block.connect_to_parent(&parent, &mut state_manager, &ChainParams::default(), now()).is_ok());
+        // nothing older is, so any other block is missing
+        let block = child(&parent, &mut state_manager, vec![anchored([9; 32], &mut signing_key)], None, [3; 32], None).await;
+        let result = block.connect_to_parent(&parent, &mut state_manager, &ChainParams::default(), now());
+        assert!(matches!(result, Err(BlockValidationError::MissingRequiredBlock(hash)) if hash == [9; 32]));
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_parent_vrf_proof() {
+        let params = ChainParams { soft_forks: SoftForks::default().activate(Rule::RequireVrfProof, 0), ..ChainParams::default() };
+        let (mut state_manager, parent) = genesis();
+        let miner = DefaultSigner::generate_random();
+        let miner_address = miner.get_verifying_function().to_bytes();
+        let mine_with_proof = async |state_manager: &mut StateManager, private_key: Option<StdByteArray>| {
+            let set_proof = |block: &mut Block| if let Some(private_key) = private_key {
+                block.set_vrf_proof(&private_key);
+            };
+            child(&parent, state_manager, vec![signed_transaction(0, 0).0], None, miner_address, Some(&set_proof)).await
+        };
+
+        let block = mine_with_proof(&mut state_manager, Some(miner.to_bytes())).await;
+        assert!(block.connect_to_parent(&parent, &mut state_manager, &params, now()).is_ok());
+        assert!(block.header.verify_vrf_proof().is_ok());
+        // the proof survives serialization
+        let decoded: Block = bincode::deserialize(&bincode::serialize(&block).unwrap()).unwrap();
+        assert_eq!(decoded, block);
+
+        // a proof by another key
+        let forged = mine_with_proof(&mut state_manager, Some(DefaultSigner::generate_random().to_bytes())).await;
+        let result = forged.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "VRF proof is invalid");
+        // still checked when not required
+        let result = forged.connect_to_parent(&parent, &mut state_manager, &ChainParams::default(), now());
+        assert_eq!(malformed_reason(result), "VRF proof is invalid");
+
+        let missing = mine_with_proof(&mut state_manager, None).await;
+        let result = missing.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert_eq!(malformed_reason(result), "VRF proof is missing");
+        assert!(missing.connect_to_parent(&parent, &mut state_manager, &ChainParams::default(), now()).is_ok());
+
+        // the proof is committed to by the hash
+        let mut swapped = block.clone();
+        swapped.header.vrf_proof = forged.header.vrf_proof;
+        let result = swapped.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert!(matches!(result, Err(BlockValidationError::HashMismatch(_, _))));
+    }
+
+    #[tokio::test]
+    async fn test_soft_fork_activation() {
+        let (mut state_manager, parent) = genesis();
+        let block = child(&parent, &mut state_manager, vec![signed_transaction(0, 0).0], None, [7; 32], None).await;
+        assert_eq!(block.header.depth, 1);
+
+        // not active yet - the block follows the old rules
+        let scheduled = ChainParams { soft_forks: SoftForks::default().activate(Rule::RequireVrfProof, 2), ..ChainParams::default() };
+        assert!(!scheduled.enforces(Rule::RequireVrfProof, 1));
+        assert!(block.connect_to_parent(&parent, &mut state_manager, &scheduled, now()).is_ok());
+
+        // active from the depth of the block onward
+        for activation in [0, 1] {
+            let active = ChainParams { soft_forks: SoftForks::default().activate(Rule::RequireVrfProof, activation), ..ChainParams::default() };
+            let result = block.connect_to_parent(&parent, &mut state_manager, &active, now());
+            assert_eq!(malformed_reason(result), "VRF proof is missing");
+        }
+        // the other rules are untouched
+        assert!(!scheduled.enforces(Rule::RejectUnknownSenders, 5));
+        // a rule activated at genesis holds for the whole chain
+        assert!(ChainParams { soft_forks: scheduled.soft_forks.activate(Rule::RejectUnknownSenders, 0), ..scheduled }.enforces(Rule::RejectUnknownSenders, 0));
+    }
+
+    #[tokio::test]
+    async fn test_miner_address_format() {
+        let (mut state_manager, parent) = genesis();
+        let mined_by = async |miner_address: StdByteArray, state_manager: &mut StateManager| {
+            child(&parent, state_manager, vec![signed_transaction(0, 0).0], None, miner_address, None).await
+        };
+        let strict = ChainParams { soft_forks: SoftForks::default().activate(Rule::RequireValidMinerAddress, 0), ..ChainParams::default() };
+
+        let valid = mined_by(DefaultSigner::generate_random().get_verifying_function().to_bytes(), &mut state_manager).await;
+        assert!(valid.connect_to_parent(&parent, &mut state_manager, &strict, now()).is_ok());
+
+        let invalid_address = (0..=u8::MAX).map(|b| [b; 32]).find(|address| !DefaultVerifier::is_valid_key(address)).unwrap();
+        let invalid = mined_by(invalid_address, &mut state_manager).await;
+        assert!(matches!(
+            invalid.connect_to_parent(&parent, &mut state_manager, &strict, now()),
+            Err(BlockValidationError::InvalidMinerAddress(address)) if address == invalid_address
+        ));
+        // off by default
+        assert!(invalid.connect_to_parent(&parent, &mut state_manager, &ChainParams::default(), now()).is_ok());
+        // or from a soft fork
+        let forked = ChainParams { soft_forks: SoftForks::default().activate(Rule::RequireValidMinerAddress, 1), ..ChainParams::default() };
+        assert!(invalid.connect_to_parent(&parent, &mut state_manager, &forked, now()).is_err());
+    }
+
+    fn to_hex(bytes: StdByteArray) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn golden_header(fields: [u8; 4], nonce: u64, timestamp: u64, depth: u64, difficulty: u64) -> BlockHeader {
+        BlockHeader::new(
+            [fields[0]; 32], [fields[1]; 32], Some([fields[3]; 32]), nonce, timestamp,
+            Some([fields[2]; 32]), BlockTail::default(), depth, Some(difficulty)
+        )
+    }
+
+    #[test]
+    fn test_header_hash_golden_vectors() {
+        let max = u64::MAX;
+        let vectors = [
+            (golden_header([0; 4], 0, 0, 0, 0), "9171181454a1df5f57fb3fd98566fc2286ecbc8df4bd792ff0deccd637ce5b76"),
+            (golden_header([1, 2, 3, 4], max, max, max, max), "05ee31e6c01508334bf1e4eb94b39625179a2c48c35d69b642aadf81acd8a957"),
+            (golden_header([0; 4], max, 0, 0, 0), "2e778a6118138c3a6630c2e5fb4cfd8783033866001fdfbe06a422c24c805cdf"),
+            (golden_header([0; 4], 0, max, 0, 0), "333ea7ca2e7c730411e9596e7efee3d166c88404bfdee6e569507372349c1f91"),
+            (golden_header([0; 4], 0, 0, max, 0), "d067b88b5aa27d88f9a8a0d2fda80f468891c24575ebd0836b76f9dd772eee87"),
+            (golden_header([0; 4], 0, 0, 0, max), "db15034435d49ba4291005c8a08ffaae2b04e19a2224e57570f6098271a470c7"),
+            // the byte order of integers is little endian
+            (golden_header([0; 4], 1, 0, 0, 0), "e495bbda0107315470f2ba186de88002a18da2515b82929f14502c2d27898360"),
+            (golden_header([0; 4], 1 << 56, 0, 0, 0), "53707e466a4040c5ad968d6e72f31a9bf71c06e6809e82693db57304cd30766f"),
+        ];
+        for (header, expected) in vectors {
+            assert_eq!(to_hex(header.hash(&mut DefaultHash::new()).unwrap()), expected, "{header:?}");
+        }
+    }
+
+    #[test]
+    fn test_header_hash_golden_vector_with_stamp() {
+        let mut header = golden_header(
+            [1, 2, 3, 4], 0x0102030405060708, 0x1112131415161718, 0x2122232425262728, 0x3132333435363738
+        );
+        header.tail.stamps[0] = Stamp { signature: [5; 64], address: [6; 32] };
+        assert_eq!(
+            to_hex(header.hash(&mut DefaultHash::new()).unwrap()),
+            "8f7b493c62cea8c98976cc721c6a774eec464bf7d8f8f7dd4290006ea0a92f0d"
+        );
+    }
+
+    #[test]
+    fn test_extranonce_hash() {
+        let header = golden_header([1, 2, 3, 4], 7, 8, 9, 10);
+        let hash_with = |extranonce: Option<u64>| {
+            let mut header = header;
+            header.extranonce = extranonce;
+            header.hash(&mut DefaultHash::new()).unwrap()
+        };
+        let hashes = [hash_with(None), hash_with(Some(0)), hash_with(Some(1)), hash_with(Some(u64::MAX))];
+        let distinct = hashes.iter().collect::<HashSet<_>>();
+        assert_eq!(distinct.len(), hashes.len());
+        // without an extranonce, the hash is unchanged
+        assert_eq!(hashes[0], header.hash(&mut DefaultHash::new()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pool_extranonce_validates() {
+        let params = ChainParams::default();
+        let (mut state_manager, parent) = genesis();
+        // assigned by the pool before the miner grinds the nonce
+        let assign_extranonce = |block: &mut Block| block.header.extranonce = Some(42);
+        let block = child(&parent, &mut state_manager, vec![signed_transaction(0, 0).0], None, [3; 32], Some(&assign_extranonce)).await;
+        assert_eq!(block.header.extranonce, Some(42));
+        assert!(block.connect_to_parent(&parent, &mut state_manager, &params, now()).is_ok());
+        // the whole header survives the wire, so the block can be relayed
+        let decoded: Block = bincode::deserialize(&bincode::serialize(&block).unwrap()).unwrap();
+        assert_eq!(decoded, block);
+        assert_eq!(decoded.header.transaction_count, Some(1));
+        assert!(decoded.connect_to_parent(&parent, &mut state_manager, &params, now()).is_ok());
+
+        // the extranonce is committed to by the hash
+        let mut swapped = block.clone();
+        swapped.header.extranonce = Some(43);
+        let result = swapped.connect_to_parent(&parent, &mut state_manager, &params, now());
+        assert!(matches!(result, Err(BlockValidationError::HashMismatch(_, _))));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_malformed_transaction() {
+        let transactions = (0..4).map(
+            |i| Transaction::new([1; 32], [2; 32], i, 1, i, &mut DefaultHash::new())
+        ).collect();
+        let mut block = Block::new(
+            [0; 32], 0, 1, transactions, Some([1; 32]),
+            BlockTail::default().stamps, 1, Some(4), Some([2; 32]), &mut DefaultHash::new()
+        );
+        let bytes = bincode::serialize(&block).unwrap();
+        let decoded: Block = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded, block);
+
+        // one transaction declares a hash that is not its own
+        block.transactions[2].hash = [7; 32];
+        let bytes = bincode::serialize(&block).unwrap();
+        let error = bincode::deserialize::<Block>(&bytes).unwrap_err();
+        assert!(error.to_string().contains("Transaction 2 is malformed"));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_empty_block() {
+        let mut block = unmined_block();
+        block.transactions.clear();
+        let bytes = bincode::serialize(&block).unwrap();
+        assert!(bincode::deserialize::<Block>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_set_miner_clears_hash() {
+        let mut block = unmined_block();
+        assert!(block.hash.is_some());
+        block.set_miner([9; 32]);
+        assert_eq!(block.header.miner_address, Some([9; 32]));
+        assert!(block.hash.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_miner_then_mine() {
+        let mut block = unmined_block();
+        block.set_miner([9; 32]);
+        crate::protocol::pow::mine(&mut block, [9; 32], [2; 32], vec![], &crate::protocol::params::ChainParams::default(), None, DefaultHash::new()).await;
+        let hash = block.hash.expect("block should be mined");
+        assert_eq!(block.header.miner_address, Some([9; 32]));
+        assert!(block.header.validate(hash, &mut DefaultHash::new()).is_ok());
+    }
 
     #[test]
     fn test_tail() {
@@ -11,15 +11,19 @@ pub enum BlockValidationError {
     /// malformed shard
     MalformedShard(String),
     /// The block is invalid because it has no miner address
-    NoMinerAddress(BlockHeader),
+    NoMinerAddress(Box<BlockHeader>),
+    /// The block is invalid because its miner address is not a valid public key
+    InvalidMinerAddress(StdByteArray),
     /// The block is invalid because it has no state root
-    NoStateRoot(BlockHeader),
+    NoStateRoot(Box<BlockHeader>),
     /// The block is invalid because the hash does not match the header
     HashMismatch(StdByteArray, StdByteArray),
     /// The block is invalid because the difficulty does not match the header
-    DifficultyMismatch(u64, BlockHeader),
+    DifficultyMismatch(u64, Box<BlockHeader>),
     /// The block is invalid because the timestamp is in the future
     FutureTimestamp(u64),
+    /// The block timestamp is past the allowed drift, but close enough to be held until it is valid
+    HeldTimestamp(u64),
     /// The block is invalid because a signature in the tail is invalid
     InvalidStampSignature(StdByteArray),
     /// The block is invalid because it contains an invalid transaction
@@ -36,8 +40,20 @@ pub enum BlockValidationError {
     TransactionSignatureMismatch,
     /// The transaction is invalid because the sender does not have enough balance
     TransactionInsufficientBalance(u64),
+    /// The transaction is invalid because the sender account does not exist
+    TransactionUnknownSender(StdByteArray),
     // invalid transaction signature
     TransactionInvalidSignature,
+    /// The block is at the checkpoint depth but does not have the checkpoint hash
+    CheckpointMismatch(u64),
+    /// A block with the same header was already rejected
+    AlreadyRejected(StdByteArray),
+    /// A different block with the same hash is already in the chain
+    HashCollision(StdByteArray),
+    /// The transaction requires a block with this hash, which is not in the chain it would join
+    MissingRequiredBlock(StdByteArray),
+    /// Applying the block would leave the account with more than the total supply - a consensus bug
+    SupplyExceeded(StdByteArray, u64),
     // other
     Other(String),
 }
@@ -51,6 +67,9 @@ impl Display for BlockValidationError {
             BlockValidationError::NoMinerAddress(header) => {
                 write!(f, "Block has no miner address: {header:?}")
             }
+            BlockValidationError::InvalidMinerAddress(address) => {
+                write!(f, "Block miner address is not a valid public key: {address:?}")
+            }
             BlockValidationError::NoStateRoot(header) => {
                 write!(f, "Block has no state root: {header:?}")
             }
@@ -63,9 +82,27 @@ impl Display for BlockValidationError {
             BlockValidationError::FutureTimestamp(timestamp) => {
                 write!(f, "Block timestamp is in the future: {timestamp}")
             }
+            BlockValidationError::HeldTimestamp(timestamp) => {
+                write!(f, "Block timestamp is in the future, holding until valid: {timestamp}")
+            }
             BlockValidationError::InvalidStampSignature(address) => {
                 write!(f, "Invalid tail signature from address: {address:?}")
             }
+            BlockValidationError::CheckpointMismatch(depth) => {
+                write!(f, "Block does not match the checkpoint at depth {depth}")
+            }
+            BlockValidationError::AlreadyRejected(hash) => {
+                write!(f, "Block was already rejected: {hash:?}")
+            }
+            BlockValidationError::HashCollision(hash) => {
+                write!(f, "A different block with the same hash is already known: {hash:?}")
+            }
+            BlockValidationError::MissingRequiredBlock(hash) => {
+                write!(f, "Transaction requires block {hash:?}, which is not an ancestor")
+            }
+            BlockValidationError::SupplyExceeded(address, supply) => {
+                write!(f, "Account {address:?} would exceed the total supply of {supply}")
+            }
             BlockValidationError::InvalidTransaction(reason) => {
                 write!(f, "Block contains an invalid transaction: {reason}")
             }
@@ -87,6 +124,9 @@ impl Display for BlockValidationError {
             BlockValidationError::TransactionInsufficientBalance(balance) => {
                 write!(f, "Transaction has insufficient balance: {balance}")
             }
+            BlockValidationError::TransactionUnknownSender(sender) => {
+                write!(f, "Transaction sender account does not exist: {sender:?}")
+            }
             BlockValidationError::TransactionInvalidSignature => {
                 write!(f, "Transaction has an invalid signature")
             },
@@ -112,6 +152,8 @@ pub enum QueryError{
     IOError(std::io::Error),
     /// insufficient info
     InsufficientInfo(String),
+    /// the node is read-only, and cannot submit
+    ReadOnly,
 }
 
 impl Display for QueryError {
@@ -122,6 +164,46 @@ impl Display for QueryError {
             QueryError::NoReply => write!(f, "No reply received"),
             QueryError::IOError(err) => write!(f, "IO error: {err}"),
             QueryError::InsufficientInfo(info) => write!(f, "Insufficient info: {info}"),
+            QueryError::ReadOnly => write!(f, "Node is read-only"),
+        }
+    }
+}
+/// The first inconsistency in a stored chain
+#[derive(Debug)]
+pub enum IntegrityError {
+    /// The chain could not be loaded from the store
+    Unreadable(std::io::Error),
+    /// The store has no genesis block
+    NoGenesis,
+    /// The block header is missing a field needed to hash it
+    Unhashable(StdByteArray),
+    /// The block is stored under a hash that its header does not hash to - (stored hash, header hash)
+    HashMismatch(StdByteArray, StdByteArray),
+    /// The block hash does not meet its difficulty target
+    InsufficientWork(StdByteArray),
+    /// The parent of the block is not stored - (block hash, parent hash)
+    MissingParent(StdByteArray, StdByteArray),
+    /// The block is not one deeper than its parent
+    DepthMismatch(StdByteArray),
+    /// The block is a second genesis block
+    ExtraGenesis(StdByteArray),
+}
+
+impl Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityError::Unreadable(err) => write!(f, "Stored chain cannot be read: {err}"),
+            IntegrityError::NoGenesis => write!(f, "Stored chain has no genesis block"),
+            IntegrityError::Unhashable(hash) => write!(f, "Stored block {hash:?} cannot be hashed"),
+            IntegrityError::HashMismatch(stored, computed) => {
+                write!(f, "Stored block {stored:?} hashes to {computed:?}")
+            }
+            IntegrityError::InsufficientWork(hash) => write!(f, "Stored block {hash:?} does not meet its difficulty target"),
+            IntegrityError::MissingParent(hash, parent) => {
+                write!(f, "Parent {parent:?} of stored block {hash:?} is missing")
+            }
+            IntegrityError::DepthMismatch(hash) => write!(f, "Stored block {hash:?} is not one deeper than its parent"),
+            IntegrityError::ExtraGenesis(hash) => write!(f, "Stored block {hash:?} is a second genesis block"),
         }
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,7 @@
 use std::collections::HashSet;
 
-use crate::{accounting::account::TransactionStub, blockchain::{chain::Chain, chain_shard::ChainShard}, nodes::peer::Peer, primitives::{block::{Block, BlockHeader}, transaction::{Transaction, TransactionFilter}}};
-use pillar_crypto::{hashing::{HashFunction, Hashable}, proofs::MerkleProof, serialization::PillarSerialize, types::StdByteArray};
+use crate::{accounting::{account::TransactionStub, state::AccountRange}, blockchain::{chain::{Chain, ChainTip}, chain_shard::ChainShard}, nodes::peer::Peer, primitives::{block::{Block, BlockHeader}, transaction::{Transaction, TransactionFilter}}};
+use pillar_crypto::{hashing::{HashFunction, Hashable}, proofs::MerkleProof, serialization::{deserialize_exact, PillarSerialize}, types::StdByteArray};
 use serde::{Serialize, Deserialize};
 
 
@@ -54,13 +54,33 @@ pub enum Message {
     // response with peers filtered between a lower percentile and an upper percentile based on reputation
     PercentileFilteredPeerResponse(Vec<Peer>),
     // error message
-    Error(String)
+    Error(String),
+    // request for the tip of the peers deepest chain
+    TipRequest,
+    // response with the tip of the deepest chain
+    TipResponse(ChainTip),
+    // mempool reconciliation request - the hashes of the sender's pooled transactions
+    MempoolSyncRequest(HashSet<StdByteArray>),
+    // mempool reconciliation response - the transactions the requester is missing, and the hashes the responder is missing
+    MempoolSyncResponse(Vec<Transaction>, HashSet<StdByteArray>),
+    // transactions requested during mempool reconciliation
+    MempoolTransactions(Vec<Transaction>),
+    // a declaration for a peer speaking json - the message and response that follow are json encoded
+    JsonDeclaration(Peer, u32),
+    // request for the accounts with trie paths from a start to an end, inclusive, under a state root - (state root, start, end)
+    StateRangeRequest(StdByteArray, StdByteArray, StdByteArray),
+    // response with the accounts in the range and its neighbours, in path order, each with its proof under the state root
+    StateRangeResponse(AccountRange),
+    // request for the header at a depth on the deepest chain
+    HeaderRequest(u64),
+    // response with the header at the depth - none if the chain is not that deep
+    HeaderResponse(Option<BlockHeader>),
 }
 
 impl PillarSerialize for Message {
     fn serialize_pillar(&self) -> Result<Vec<u8>, std::io::Error> {
         match self {
-            Message::Declaration(_, _) => {
+            Message::Declaration(_, _) | Message::JsonDeclaration(_, _) => {
                 bincode::serialize(&self).map_err(std::io::Error::other)
             },
             _ => {
@@ -76,11 +96,11 @@ impl PillarSerialize for Message {
         let decompressed = lz4_flex::decompress_size_prepended(data).map_err(std::io::Error::other);
         match decompressed {
             Ok(decompressed) => {
-                bincode::deserialize::<Self>(&decompressed).map_err(std::io::Error::other)
+                deserialize_exact(&decompressed)
             },
             Err(_) => {
                 // if the decompression fails, try to deserialize without decompression
-                bincode::deserialize::<Self>(data).map_err(std::io::Error::other)
+                deserialize_exact(data)
             }
         }
     }
@@ -95,6 +115,42 @@ impl Hashable for Message{
     }
 }
 
+/// The encoding of the messages that follow a declaration.
+/// The declaration itself is always bincode, so that it has a fixed length - its variant says which format follows.
+/// Hashes never depend on the wire format, they use the canonical pillar serialization.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    /// Human readable, for debugging and tooling.
+    /// Chains cannot be sent as json, as their maps are keyed by hashes
+    Json,
+}
+
+impl WireFormat {
+    pub fn encode(&self, message: &Message) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            WireFormat::Bincode => message.serialize_pillar(),
+            WireFormat::Json => serde_json::to_vec(message).map_err(std::io::Error::other),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Message, std::io::Error> {
+        match self {
+            WireFormat::Bincode => Message::deserialize_pillar(data),
+            WireFormat::Json => serde_json::from_slice(data).map_err(std::io::Error::other),
+        }
+    }
+
+    /// The declaration announcing `peer`, and a message of `length` bytes in this format
+    pub fn declaration(&self, peer: Peer, length: u32) -> Message {
+        match self {
+            WireFormat::Bincode => Message::Declaration(peer, length),
+            WireFormat::Json => Message::JsonDeclaration(peer, length),
+        }
+    }
+}
+
 pub enum Versions{
     V1V4 = 1,
     #[allow(dead_code)]
@@ -109,11 +165,12 @@ pub const fn get_declaration_length(version: Versions) -> u64 {
     }
 }
 
+#[cfg(test)]
 mod tests{
 
-    use pillar_crypto::serialization::PillarSerialize;
+    use pillar_crypto::{hashing::{DefaultHash, Hashable}, serialization::PillarSerialize};
 
-    use crate::{nodes::peer::Peer, primitives::messages::{get_declaration_length, Message, Versions}};
+    use crate::{nodes::peer::Peer, primitives::{messages::{get_declaration_length, Message, Versions, WireFormat}, transaction::Transaction}};
 
 
     #[test]
@@ -133,5 +190,53 @@ mod tests{
         );
         assert_eq!(get_declaration_length(Versions::V1V4), declaration.serialize_pillar().unwrap().len() as u64);
         assert_eq!(get_declaration_length(Versions::V1V6), declarationv1v6.serialize_pillar().unwrap().len() as u64);
+        let peer = Peer::new([0; 32], std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 8000);
+        let json_declaration = WireFormat::Json.declaration(peer, 1);
+        assert_eq!(get_declaration_length(Versions::V1V4), json_declaration.serialize_pillar().unwrap().len() as u64);
+    }
+
+    #[test]
+    fn test_wire_format_round_trip() {
+        let transaction = Transaction::new([1; 32], [2; 32], 10, 0, 0, &mut DefaultHash::new());
+        let peer = Peer::new([3; 32], std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 8000);
+        let messages = vec![
+            Message::Ping,
+            Message::TransactionBroadcast(transaction),
+            Message::PeerResponse(vec![peer]),
+            Message::BlockRequest([4; 32]),
+            Message::Error("error".into()),
+        ];
+        for message in messages {
+            let hash = message.hash(&mut DefaultHash::new()).unwrap();
+            for format in [WireFormat::Bincode, WireFormat::Json] {
+                let decoded = format.decode(&format.encode(&message).unwrap()).unwrap();
+                // the hash is canonical, whatever the format
+                assert_eq!(decoded.hash(&mut DefaultHash::new()).unwrap(), hash);
+            }
+        }
+        // the formats do not read each other
+        let json = WireFormat::Json.encode(&Message::Ping).unwrap();
+        assert!(WireFormat::Bincode.decode(&json).is_err());
+    }
+
+    #[test]
+    fn test_wrong_length_hash_rejected() {
+        let encoded = bincode::serialize(&Message::BlockRequest([4; 32])).unwrap();
+        // a hash one byte short runs out of data, one byte long leaves a byte over
+        let short = encoded[..encoded.len() - 1].to_vec();
+        let mut long = encoded.clone();
+        long.push(4);
+        for bytes in [short, long] {
+            assert!(Message::deserialize_pillar(&lz4_flex::compress_prepend_size(&bytes)).is_err());
+            assert!(Message::deserialize_pillar(&bytes).is_err());
+        }
+        assert!(Message::deserialize_pillar(&lz4_flex::compress_prepend_size(&encoded)).is_ok());
+
+        for length in [31, 33] {
+            let json = format!("{{\"BlockRequest\":{:?}}}", vec![4u8; length]);
+            assert!(WireFormat::Json.decode(json.as_bytes()).is_err());
+        }
+        let json = format!("{{\"BlockRequest\":{:?}}}", vec![4u8; 32]);
+        assert!(WireFormat::Json.decode(json.as_bytes()).is_ok());
     }
 }
\ No newline at end of file
@@ -6,10 +6,10 @@ pub mod errors;
 
 #[cfg(test)]
 mod tests{
-    use pillar_crypto::{hashing::{DefaultHash, Hashable}, signing::{DefaultSigner, SigFunction, SigVerFunction, Signable}};
+    use pillar_crypto::{hashing::{DefaultHash, HashFunction, Hashable}, signing::{verify_in_batches, DefaultSigner, DefaultVerifier, SigFunction, SigVerFunction, Signable}, types::StdByteArray};
     
 
-    use crate::{primitives::{block::{BlockHeader, BlockTail}, transaction::{Transaction, TransactionHeader}}};
+    use crate::{primitives::{block::{BlockHeader, BlockTail}, errors::BlockValidationError, transaction::{Transaction, TransactionHeader}}, protocol::params::ChainParams};
     
 
     #[test]
@@ -50,6 +50,46 @@ mod tests{
         assert_eq!(hash.len(), 32);
     }
 
+    #[test]
+    fn test_transaction_hash_domain_separated() {
+        let header = TransactionHeader::new([1; 32], [2; 32], 0x0102030405060708, 0x1112131415161718, 0x2122232425262728);
+        let hash = header.hash(&mut DefaultHash::new());
+        let mut naive = DefaultHash::new();
+        naive.update(header.sender);
+        naive.update(header.receiver);
+        naive.update(header.amount.to_le_bytes());
+        naive.update(header.timestamp.to_le_bytes());
+        naive.update(header.nonce.to_le_bytes());
+        assert_ne!(hash, naive.digest().unwrap());
+
+        let to_hex = |bytes: StdByteArray| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(to_hex(hash), "8452674a5dfee9012c2204c37a7bbfc7f0282df628e1c1c99e961bb1bc4798d7");
+        let rotation = TransactionHeader { rotate_key: Some([3; 32]), ..TransactionHeader::new([1; 32], [1; 32], 0, 0, 0) };
+        assert_eq!(to_hex(rotation.hash(&mut DefaultHash::new())), "af4246260a815e74e184fb443377613160127c562c9762a76bd6ac9cd5282fb8");
+    }
+
+    #[test]
+    fn test_namespace_changes_hash() {
+        let mut hash_function = DefaultHash::new();
+        let transaction = Transaction::new([1; 32], [2; 32], 10, 0, 0, &mut hash_function);
+        let tagged = transaction.with_namespace(*b"app1", &mut hash_function);
+        assert_ne!(tagged.hash, transaction.hash);
+        assert_ne!(tagged.hash, transaction.with_namespace(*b"app2", &mut hash_function).hash);
+        assert_eq!(tagged.hash, tagged.header.hash(&mut hash_function));
+        assert!(tagged.sanity_check(&mut hash_function).is_ok());
+
+        // the tag is covered by the hash, so it cannot be changed without breaking the signature
+        let mut signing_key = DefaultSigner::generate_random();
+        let mut signed = Transaction::new(signing_key.get_verifying_function().to_bytes(), [2; 32], 10, 0, 0, &mut hash_function)
+            .with_namespace(*b"app1", &mut hash_function);
+        signed.sign(&mut signing_key);
+        let mut retagged = signed;
+        retagged.header.namespace = Some(*b"app2");
+        assert!(retagged.sanity_check(&mut hash_function).is_err());
+        retagged.hash = retagged.header.hash(&mut hash_function);
+        assert!(!signing_key.get_verifying_function().verify(&signed.signature.unwrap(), &retagged));
+    }
+
     #[test]
     fn test_transaction_sign() {
         let sender = [0u8; 32];
@@ -73,4 +113,106 @@ mod tests{
         let transaction2 = Transaction::new(sender, receiver, amount, timestamp, nonce+1, &mut hash_function);
         assert!(!signing_key.get_verifying_function().verify(&transaction.signature.unwrap(), &transaction2));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_batch_verification_matches_individual() {
+        let mut transactions = vec![];
+        let mut keys = vec![];
+        for i in 0..10 {
+            let mut signing_key = DefaultSigner::generate_random();
+            let sender = signing_key.get_verifying_function().to_bytes();
+            let mut transaction = Transaction::new(sender, [1u8; 32], i, 0, 0, &mut DefaultHash::new());
+            transaction.sign(&mut signing_key);
+            transactions.push(transaction);
+            keys.push(sender);
+        }
+        fn batch<'a>(transactions: &'a [Transaction], keys: &[StdByteArray]) -> Vec<(DefaultVerifier, [u8; 64], &'a Transaction)> {
+            transactions.iter().zip(keys).map(
+                |(transaction, key)| (DefaultVerifier::from_bytes(key), transaction.signature.unwrap(), transaction)
+            ).collect()
+        }
+        let individual = |transactions: &[Transaction]| transactions.iter().zip(&keys).map(
+            |(transaction, key)| DefaultVerifier::from_bytes(key).verify(&transaction.signature.unwrap(), transaction)
+        ).collect::<Vec<_>>();
+
+        assert!(individual(&transactions).iter().all(|valid| *valid));
+        for batch_size in [0, 1, 3, 10, 16] {
+            assert!(verify_in_batches(&batch(&transactions, &keys), batch_size));
+        }
+
+        // a signature from the right key over different data
+        let mut tampered = transactions.clone();
+        tampered[7].header.amount += 1;
+        tampered[7].hash = tampered[7].header.hash(&mut DefaultHash::new());
+        assert_eq!(individual(&tampered).iter().filter(|valid| !**valid).count(), 1);
+        for batch_size in [0, 1, 3, 10, 16] {
+            assert!(!verify_in_batches(&batch(&tampered, &keys), batch_size));
+        }
+        // only the batch holding the bad signature fails
+        let tampered = batch(&tampered, &keys);
+        assert!(DefaultVerifier::verify_batch(&tampered[..5]));
+        assert!(!DefaultVerifier::verify_batch(&tampered[5..]));
+
+        // a malformed signature - the identity commitment has small order
+        let mut malformed = transactions.clone();
+        malformed[2].signature = Some([[1; 32], [0; 32]].concat().try_into().unwrap());
+        assert_eq!(individual(&malformed).iter().filter(|valid| !**valid).count(), 1);
+        for batch_size in [0, 1, 3, 10, 16] {
+            assert!(!verify_in_batches(&batch(&malformed, &keys), batch_size));
+        }
+    }
+
+    #[test]
+    fn test_validate_standalone() {
+        let params = ChainParams::default();
+        let now = 1_000_000;
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let signed = |sender, receiver, timestamp, signing_key: &mut DefaultSigner| {
+            let mut transaction = Transaction::new(sender, receiver, 5, timestamp, 0, &mut DefaultHash::new());
+            transaction.sign(signing_key);
+            transaction
+        };
+
+        let valid = signed(sender, [1; 32], now, &mut signing_key);
+        assert!(valid.validate_standalone(&params, now).is_ok());
+        // up to the drift
+        assert!(signed(sender, [1; 32], now + params.max_future_drift, &mut signing_key).validate_standalone(&params, now).is_ok());
+
+        assert!(matches!(signed([0; 32], [1; 32], now, &mut signing_key).validate_standalone(&params, now), Err(BlockValidationError::TransactionNoSender)));
+        assert!(matches!(signed([4; 32], [1; 32], now, &mut signing_key).validate_standalone(&params, now), Err(BlockValidationError::TransactionNoSender)));
+        assert!(matches!(signed(sender, [0; 32], now, &mut signing_key).validate_standalone(&params, now), Err(BlockValidationError::TransactionNoReceiver)));
+        let unsigned = Transaction::new(sender, [1; 32], 5, now, 0, &mut DefaultHash::new());
+        assert!(matches!(unsigned.validate_standalone(&params, now), Err(BlockValidationError::TransactionInvalidSignature)));
+        let future = signed(sender, [1; 32], now + params.max_future_drift + 1, &mut signing_key);
+        assert!(matches!(future.validate_standalone(&params, now), Err(BlockValidationError::TransactionFutureTimestamp)));
+        let mut tampered = valid;
+        tampered.header.amount += 1;
+        assert!(matches!(tampered.validate_standalone(&params, now), Err(BlockValidationError::HashMismatch(_, _))));
+        let mut rotation = Transaction::new_key_rotation(sender, [4; 32], now, 0, &mut DefaultHash::new());
+        rotation.sign(&mut signing_key);
+        assert!(matches!(rotation.validate_standalone(&params, now), Err(BlockValidationError::InvalidTransaction(_))));
+    }
+
+    #[test]
+    fn test_short_id() {
+        use std::collections::HashSet;
+        use crate::primitives::transaction::short_id;
+
+        let txids = (0..10_000u32).map(|i| {
+            let mut hasher = DefaultHash::new();
+            hasher.update(i.to_le_bytes());
+            hasher.digest().unwrap()
+        }).collect::<Vec<StdByteArray>>();
+        let (salt, other_salt) = ([1; 32], [2; 32]);
+        // the same for the same salt
+        assert_eq!(short_id(&txids[0], &salt), short_id(&txids[0], &salt));
+        // different under another salt
+        let salted = txids.iter().map(|txid| short_id(txid, &salt)).collect::<Vec<_>>();
+        let other = txids.iter().map(|txid| short_id(txid, &other_salt)).collect::<Vec<_>>();
+        assert!(salted.iter().zip(&other).all(|(a, b)| a != b));
+        // no collisions across many transactions
+        assert_eq!(salted.iter().collect::<HashSet<_>>().len(), txids.len());
+        assert_eq!(other.iter().collect::<HashSet<_>>().len(), txids.len());
+    }
+}
@@ -1,14 +1,88 @@
-use std::sync::Arc;
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::{Arc, Mutex}};
 
 use flume::{Receiver, Sender};
 
-use super::{block::Block, transaction::Transaction};
+use pillar_crypto::{serialization::PillarSerialize, types::StdByteArray};
+use serde::{Deserialize, Serialize};
 
+use crate::{blockchain::chain::Chain, persistence::database::Datastore, protocol::clock::Clock};
+
+use super::{block::Block, transaction::{short_id, Transaction}};
+
+/// The default number of seconds a transaction may wait in the pool before it is evicted
+pub const MEMPOOL_EXPIRY: u64 = 24 * 60 * 60;
+
+/// The pooled transactions in pool order, with when each entered the pool.
+/// Both live under one lock, so the pool is changed in place - it is never drained and refilled
+#[derive(Default)]
+struct Pooled {
+    transactions: VecDeque<Transaction>,
+    // when each pooled transaction entered the pool, by hash
+    entry_times: HashMap<StdByteArray, u64>,
+}
+
+impl Pooled {
+    /// Removes every transaction `remove` picks, keeping the rest in pool order
+    fn remove_where(&mut self, mut remove: impl FnMut(&Transaction) -> bool) -> Vec<Transaction> {
+        let Pooled { transactions, entry_times } = self;
+        let mut removed = vec![];
+        transactions.retain(|transaction| {
+            if remove(transaction) {
+                entry_times.remove(&transaction.hash);
+                removed.push(*transaction);
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+}
+
+/// What each sender has left to spend, and the nonces it has taken, after the transactions counted so far
+struct SenderBudgets<'a> {
+    chain: &'a Chain,
+    state_root: StdByteArray,
+    senders: HashMap<StdByteArray, (u64, HashSet<u64>)>,
+}
+
+impl<'a> SenderBudgets<'a> {
+    fn new(chain: &'a Chain, state_root: StdByteArray) -> Self {
+        SenderBudgets { chain, state_root, senders: HashMap::new() }
+    }
+
+    fn budget(&mut self, sender: StdByteArray) -> &mut (u64, HashSet<u64>) {
+        let (chain, state_root) = (self.chain, self.state_root);
+        self.senders.entry(sender)
+            .or_insert_with(|| (chain.state_manager.get_account_or_default(&sender, state_root).balance, HashSet::new()))
+    }
+
+    /// Counts a pooled transaction against its sender, without checking it
+    fn charge(&mut self, transaction: &Transaction) {
+        let (balance, nonces) = self.budget(transaction.header.sender);
+        *balance = balance.saturating_sub(transaction.header.amount);
+        nonces.insert(transaction.header.nonce);
+    }
+
+    /// Counts a transaction against its sender if it could be mined at the top of the chain,
+    /// its nonce is not taken, and the sender can cover it along with every transaction counted before
+    fn admit(&mut self, transaction: &Transaction) -> bool {
+        let (chain, state_root) = (self.chain, self.state_root);
+        let (balance, nonces) = self.budget(transaction.header.sender);
+        if nonces.contains(&transaction.header.nonce) || *balance < transaction.header.amount
+            || !MinerPool::is_applicable(transaction, chain, state_root) {
+            return false;
+        }
+        *balance -= transaction.header.amount;
+        nonces.insert(transaction.header.nonce);
+        true
+    }
+}
 
 #[derive(Clone)]
 pub struct MinerPool {
-    // receiver channel
-    transactions_queue: Arc<lfqueue::UnboundedQueue<Transaction>>,
+    // pooled transactions
+    transactions: Arc<Mutex<Pooled>>,
     // proposition blocks
     block_propositions_queue: Arc<lfqueue::UnboundedQueue<Block>>,
     // ready blocks
@@ -16,36 +90,291 @@ pub struct MinerPool {
     // mine abort signal
     pub mine_abort_sender: Sender<u64>,
     pub mine_abort_receiver: Receiver<u64>,
+    // the time source for expiry
+    pub clock: Clock,
+    // how many seconds a transaction may wait in the pool
+    pub expiry: u64,
+    // what happens to pooled transactions a block extending the chain makes invalid
+    pub connect_policy: ConnectPolicy,
+}
+
+/// What the pool does with its transactions when a block extends the deepest chain.
+/// Reorgs always prune the whole pool - this only covers forward progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectPolicy {
+    /// drop the transactions of the block's senders that it made invalid - used nonces, and spends the sender can no longer cover
+    #[default]
+    Reconcile,
+    /// keep every transaction - stale ones are skipped when a block template is built
+    Keep,
+}
+
+/// What to exchange with a peer so both mempools become the union of the two.
+/// Each transaction crosses the network once - only what one side is missing is sent.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MempoolDifference {
+    /// pooled transactions the peer does not have, in pool order
+    pub to_send: Vec<Transaction>,
+    /// transactions the peer has that are not pooled here
+    pub to_request: HashSet<StdByteArray>,
 }
 
+/// Pending transactions, packed to move a mempool to another node - see `MinerPool::export_mempool`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MempoolBundle {
+    /// the pooled transactions, in pool order
+    pub transactions: Vec<Transaction>,
+}
+
+impl PillarSerialize for MempoolBundle {}
+
 /// Transaction pool for now is just a vector of transactions
 /// In the future, it will be a more complex structure - perhaps a max heap on the transaction fee
 /// Rn, FIFO
 impl MinerPool{
     pub fn new() -> Self {
         let (mine_abort_sender, mine_abort_receiver) = flume::unbounded();
-        let transactions_queue = Arc::new(lfqueue::UnboundedQueue::new());
         let block_propositions_queue = Arc::new(lfqueue::UnboundedQueue::new());
         let mine_ready_blocks_queue = Arc::new(lfqueue::UnboundedQueue::new());
         MinerPool {
-            transactions_queue,
+            transactions: Arc::new(Mutex::new(Pooled::default())),
             block_propositions_queue,
             mine_ready_blocks_queue,
             mine_abort_sender,
             mine_abort_receiver,
+            clock: Clock::default(),
+            expiry: MEMPOOL_EXPIRY,
+            connect_policy: ConnectPolicy::default(),
         }
     }
 
     /// Adds a transaction to the pool
     pub fn add_transaction(&self, transaction: Transaction) {
-        // send the transaction to the receiver
-        self.transactions_queue.enqueue(transaction);
+        let mut pooled = self.transactions.lock().unwrap();
+        pooled.entry_times.entry(transaction.hash).or_insert_with(|| self.clock.now());
+        pooled.transactions.push_back(transaction);
     }
 
     /// Returns the transaction at the front of the pool
     pub fn pop_transaction(&self) -> Option<Transaction> {
-        // receive the transaction from the sender
-        self.transactions_queue.dequeue()
+        let mut pooled = self.transactions.lock().unwrap();
+        let transaction = pooled.transactions.pop_front()?;
+        pooled.entry_times.remove(&transaction.hash);
+        Some(transaction)
+    }
+
+    /// Evicts every transaction that has waited in the pool for `expiry` seconds or more, by the pool clock.
+    /// The rest keep their order.
+    ///
+    /// # Returns
+    ///
+    /// * The evicted transactions, in pool order
+    pub fn evict_expired(&self) -> Vec<Transaction> {
+        let now = self.clock.now();
+        let mut pooled = self.transactions.lock().unwrap();
+        let expired: HashSet<StdByteArray> = pooled.entry_times.iter()
+            .filter(|(_, entered)| now.saturating_sub(**entered) >= self.expiry)
+            .map(|(hash, _)| *hash)
+            .collect();
+        pooled.remove_where(|transaction| expired.contains(&transaction.hash))
+    }
+
+    /// Evicts the transaction from `sender` with `nonce`, along with every pooled
+    /// transaction from the same sender with a higher nonce, as those can no longer be applied.
+    /// Transactions from other senders, and lower nonces from the same sender, keep their order.
+    /// If the transaction is not pooled, nothing is evicted.
+    ///
+    /// # Returns
+    ///
+    /// * The evicted transactions, in pool order
+    pub fn evict_transaction(&self, sender: StdByteArray, nonce: u64) -> Vec<Transaction> {
+        let mut pooled = self.transactions.lock().unwrap();
+        if !pooled.transactions.iter().any(|t| t.header.sender == sender && t.header.nonce == nonce) {
+            return vec![];
+        }
+        pooled.remove_where(|t| t.header.sender == sender && t.header.nonce >= nonce)
+    }
+
+    /// Returns a copy of every pooled transaction, in pool order, leaving the pool unchanged
+    pub fn pending_transactions(&self) -> Vec<Transaction> {
+        self.transactions.lock().unwrap().transactions.iter().copied().collect()
+    }
+
+    /// The hashes of every pooled transaction
+    pub fn transaction_ids(&self) -> HashSet<StdByteArray> {
+        self.transactions.lock().unwrap().transactions.iter().map(|t| t.hash).collect()
+    }
+
+    /// Finds the pooled transactions behind the short ids of a compact block, salted with `salt`, to rebuild its transactions.
+    /// A short id that matches no pooled transaction, or more than one, is `None` - the full transaction must be requested
+    ///
+    /// # Returns
+    ///
+    /// * The transaction for each short id, in the order given
+    pub fn match_short_ids(&self, salt: &StdByteArray, short_ids: &[u64]) -> Vec<Option<Transaction>> {
+        let mut by_short_id: HashMap<u64, Option<Transaction>> = HashMap::new();
+        for transaction in self.pending_transactions() {
+            by_short_id.entry(short_id(&transaction.hash, salt))
+                .and_modify(|found| if found.is_some_and(|found| found.hash != transaction.hash) { *found = None })
+                .or_insert(Some(transaction));
+        }
+        short_ids.iter().map(|id| by_short_id.get(id).copied().flatten()).collect()
+    }
+
+    /// The pooled transactions that double spend against `block` - those with the sender and nonce of a transaction
+    /// in the block, but not the same transaction. The block settles the nonce, so these can never be mined after it
+    ///
+    /// # Returns
+    ///
+    /// * The hashes of the conflicting transactions, in pool order
+    pub fn conflicting_transactions(&self, block: &Block) -> Vec<StdByteArray> {
+        let confirmed: HashMap<(StdByteArray, u64), StdByteArray> = block.transactions.iter()
+            .map(|t| ((t.header.sender, t.header.nonce), t.hash))
+            .collect();
+        self.pending_transactions().into_iter()
+            .filter(|t| confirmed.get(&(t.header.sender, t.header.nonce)).is_some_and(|hash| *hash != t.hash))
+            .map(|t| t.hash)
+            .collect()
+    }
+
+    /// Compares the pool with the transaction hashes of a peer's mempool
+    pub fn difference(&self, peer_transactions: &HashSet<StdByteArray>) -> MempoolDifference {
+        let pending = self.pending_transactions();
+        let local: HashSet<StdByteArray> = pending.iter().map(|t| t.hash).collect();
+        MempoolDifference {
+            to_send: pending.into_iter().filter(|t| !peer_transactions.contains(&t.hash)).collect(),
+            to_request: peer_transactions.difference(&local).copied().collect(),
+        }
+    }
+
+    /// Saves the pooled transactions to the datastore, so they survive a restart
+    /// 
+    /// # Returns
+    /// 
+    /// * The number of transactions saved
+    pub fn persist(&self, datastore: &dyn Datastore) -> Result<usize, std::io::Error> {
+        let pending = self.pending_transactions();
+        datastore.save_transactions(&pending)?;
+        Ok(pending.len())
+    }
+
+    /// Loads the saved transactions from the datastore back into the pool, as in `add_applicable`.
+    /// A transaction is dropped if it is no longer valid, its nonce has already been used,
+    /// or its sender cannot cover it together with the sender's pooled transactions.
+    /// 
+    /// # Returns
+    /// 
+    /// * The number of transactions restored
+    pub fn restore(&self, datastore: &dyn Datastore, chain: &Chain) -> Result<usize, std::io::Error> {
+        let (restored, _) = self.add_applicable(datastore.load_transactions()?, chain);
+        Ok(restored)
+    }
+
+    /// Packs the pooled transactions into a bundle another node can import, leaving the pool unchanged.
+    /// The bundle is in the wire encoding, so it does not depend on the datastore of either node
+    pub fn export_mempool(&self) -> Result<Vec<u8>, std::io::Error> {
+        MempoolBundle { transactions: self.pending_transactions() }.serialize_pillar()
+    }
+
+    /// Adds the transactions of a bundle from `export_mempool` to the pool, in bundle order.
+    /// Like `restore`, each transaction is validated against the state at the top of `chain`,
+    /// and dropped if it is no longer valid. Transactions already in the pool are skipped.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - the number of transactions imported
+    /// * `Err(std::io::Error)` - if the bundle cannot be decoded. Nothing is imported
+    pub fn import_mempool(&self, bundle: &[u8], chain: &Chain) -> Result<usize, std::io::Error> {
+        let bundle = MempoolBundle::deserialize_pillar(bundle)?;
+        let (imported, _) = self.add_applicable(bundle.transactions, chain);
+        Ok(imported)
+    }
+
+    /// Adds the transactions that could be mined at the top of `chain` to the pool, in order.
+    /// Each transaction is checked against what its sender has left after the pooled transactions
+    /// and those added before it - the balance they spend, and the nonces they take.
+    /// Transactions already in the pool are skipped.
+    ///
+    /// # Returns
+    ///
+    /// * The number of transactions added, and the hashes of those rejected as invalid
+    pub fn add_applicable(&self, transactions: Vec<Transaction>, chain: &Chain) -> (usize, Vec<StdByteArray>) {
+        let Some(state_root) = chain.get_state_root() else {
+            return (0, transactions.iter().map(|t| t.hash).collect());
+        };
+        let mut pooled = self.transactions.lock().unwrap();
+        let mut budgets = SenderBudgets::new(chain, state_root);
+        pooled.transactions.iter().for_each(|t| budgets.charge(t));
+        let mut hashes: HashSet<StdByteArray> = pooled.transactions.iter().map(|t| t.hash).collect();
+        let now = self.clock.now();
+        let mut added = 0;
+        let mut rejected = vec![];
+        for transaction in transactions {
+            if hashes.contains(&transaction.hash) {
+                continue;
+            }
+            if !budgets.admit(&transaction) {
+                rejected.push(transaction.hash);
+                continue;
+            }
+            hashes.insert(transaction.hash);
+            pooled.entry_times.entry(transaction.hash).or_insert(now);
+            pooled.transactions.push_back(transaction);
+            added += 1;
+        }
+        (added, rejected)
+    }
+
+    /// Drops every pooled transaction that is no longer valid at the top of the chain.
+    /// Call this after a reorg - transactions funded by blocks that left the deepest chain,
+    /// such as a spend of an orphaned block's reward, can no longer be mined.
+    /// Each sender's pooled transactions must fit its balance and nonces together.
+    ///
+    /// # Returns
+    ///
+    /// * The dropped transactions, in pool order
+    pub fn prune(&self, chain: &Chain) -> Vec<Transaction> {
+        self.retain_applicable(chain, |_| true)
+    }
+
+    /// Drops the pooled transactions that `block`, newly connected at the top of `chain`, made invalid, as the `connect_policy` says.
+    /// A block only debits and advances the nonces of its own senders, so only their transactions are checked.
+    /// One is dropped if its nonce was used, it is no longer valid at the top of the chain,
+    /// or the sender cannot cover it together with the sender's earlier pooled transactions.
+    ///
+    /// # Returns
+    ///
+    /// * The dropped transactions, in pool order
+    pub fn reconcile_connected(&self, chain: &Chain, block: &Block) -> Vec<Transaction> {
+        if self.connect_policy == ConnectPolicy::Keep {
+            return vec![];
+        }
+        let senders = block.transactions.iter().map(|transaction| transaction.header.sender).collect::<HashSet<_>>();
+        self.retain_applicable(chain, |transaction| senders.contains(&transaction.header.sender))
+    }
+
+    /// Keeps the pooled transactions that could be mined together at the top of `chain`, in pool order.
+    /// Only the transactions `checked` selects can be dropped
+    fn retain_applicable(&self, chain: &Chain, checked: impl Fn(&Transaction) -> bool) -> Vec<Transaction> {
+        let Some(state_root) = chain.get_state_root() else {
+            return vec![];
+        };
+        let mut budgets = SenderBudgets::new(chain, state_root);
+        self.transactions.lock().unwrap().remove_where(|transaction| checked(transaction) && !budgets.admit(transaction))
+    }
+
+    /// Whether a transaction could still be mined on the state at `state_root`
+    fn is_applicable(transaction: &Transaction, chain: &Chain, state_root: StdByteArray) -> bool {
+        let account = chain.state_manager.get_account_or_default(&transaction.header.sender, state_root);
+        if transaction.header.nonce < account.nonce {
+            tracing::debug!("Dropping transaction with used nonce {}", transaction.header.nonce);
+            return false;
+        }
+        if let Err(e) = chain.validate_transaction(transaction, state_root) {
+            tracing::debug!("Dropping transaction: {}", e);
+            return false;
+        }
+        true
     }
 
     /// Returns the block at the front of the pool
@@ -69,4 +398,346 @@ impl MinerPool{
         self.mine_ready_blocks_queue.dequeue()
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use pillar_crypto::hashing::DefaultHash;
+    use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction, Signable};
+
+    use crate::blockchain::chain::Chain;
+    use crate::fixtures::{child_of, mine_on_chain, stamp};
+    use crate::persistence::database::GenesisDatastore;
+    use crate::primitives::block::{Block, BlockTail, Stamp};
+    use crate::primitives::transaction::Transaction;
+    use crate::protocol::clock::Clock;
+    use crate::protocol::params::ChainParams;
+    use crate::protocol::pow::mine;
+
+    use super::{ConnectPolicy, MempoolDifference, MinerPool};
+
+    fn transaction(sender: u8, nonce: u64) -> Transaction {
+        Transaction::new([sender; 32], [9; 32], 1, 0, nonce, &mut DefaultHash::new())
+    }
+
+    fn drain(pool: &MinerPool) -> Vec<([u8; 32], u64)> {
+        let mut remaining = vec![];
+        while let Some(t) = pool.pop_transaction() {
+            remaining.push((t.header.sender, t.header.nonce));
+        }
+        remaining
+    }
+
+    #[test]
+    fn test_match_short_ids() {
+        let pool = MinerPool::new();
+        let pooled = (0..4).map(|nonce| transaction(1, nonce)).collect::<Vec<_>>();
+        for t in &pooled {
+            pool.add_transaction(*t);
+        }
+        let missing = transaction(2, 0);
+        let mut block = Block::new(
+            [0; 32], 0, 1, vec![pooled[2], missing, pooled[0]], Some([1; 32]),
+            BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+        );
+        assert_eq!(block.short_ids(), None);
+        block.hash = Some([7; 32]);
+        let short_ids = block.short_ids().unwrap();
+        // the transaction the pool has never seen must be requested in full
+        assert_eq!(pool.match_short_ids(&[7; 32], &short_ids), vec![Some(pooled[2]), None, Some(pooled[0])]);
+        // under another salt, the ids point nowhere
+        assert!(pool.match_short_ids(&[8; 32], &short_ids).iter().all(Option::is_none));
+        assert_eq!(pool.pending_transactions(), pooled);
+    }
+
+    #[tokio::test]
+    async fn test_persist_and_restore() {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let sender = signing_key.get_verifying_function().to_bytes();
+        let signed = |amount: u64, nonce: u64, signing_key: &mut DefaultSigner| {
+            let mut transaction = Transaction::new(sender, [2; 32], amount, 0, nonce, &mut DefaultHash::new());
+            transaction.sign(signing_key);
+            transaction
+        };
+        let settled = signed(0, 0, &mut signing_key);
+        // settle nonce 0 in a block, so a pooled nonce 0 is stale
+        let mut block = Block::new(
+            chain.deepest_hash, 0,
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+            vec![settled], Some(sender), BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+        );
+        // a stamp, so the block pays a reward
+        let mut stamper = DefaultSigner::generate_random();
+        let signature = stamper.sign(&block.header);
+        block.header.tail.stamp(Stamp { address: stamper.get_verifying_function().to_bytes(), signature }).unwrap();
+        let prev_header = chain.headers[&chain.deepest_hash];
+        let state_root = chain.state_manager.branch_from_block(&block, &prev_header);
+        mine(&mut block, sender, state_root, vec![], &ChainParams::default(), None, DefaultHash::new()).await;
+        chain.add_new_block(block).unwrap();
+
+        let valid = signed(0, 1, &mut signing_key);
+        let overdrawn = signed(1_000_000_000, 1, &mut signing_key);
+        // each spend fits the reward alone, but not both together
+        let reward = chain.state_manager.get_account_or_default(&sender, chain.get_state_root().unwrap()).balance;
+        assert!(reward > 1);
+        let (spend, overspend) = (signed(reward / 2 + 1, 2, &mut signing_key), signed(reward / 2 + 1, 3, &mut signing_key));
+        // a different transaction with a nonce already taken
+        let mut reused = Transaction::new(sender, [3; 32], 0, 0, 1, &mut DefaultHash::new());
+        reused.sign(&mut signing_key);
+        let pool = MinerPool::new();
+        for transaction in [settled, valid, overdrawn, spend, overspend, reused] {
+            pool.add_transaction(transaction);
+        }
+
+        let datastore = GenesisDatastore::new();
+        assert_eq!(pool.persist(&datastore).unwrap(), 6);
+        // persisting leaves the pool unchanged
+        assert_eq!(pool.pending_transactions().len(), 6);
+
+        let restarted = MinerPool::new();
+        assert_eq!(restarted.restore(&datastore, &chain).unwrap(), 2);
+        assert_eq!(restarted.pending_transactions(), vec![valid, spend]);
+        // restoring into a pool counts what is already there
+        let partial = MinerPool::new();
+        partial.add_transaction(spend);
+        assert_eq!(partial.restore(&datastore, &chain).unwrap(), 1);
+        assert_eq!(partial.pending_transactions(), vec![spend, valid]);
+    }
+
+    #[test]
+    fn test_conflicting_transactions() {
+        let pool = MinerPool::new();
+        let pooled = transaction(1, 0);
+        let unrelated = transaction(1, 1);
+        let other_sender = transaction(2, 0);
+        for t in [pooled, unrelated, other_sender] {
+            pool.add_transaction(t);
+        }
+        // the block confirms a different transaction from sender 1 at nonce 0, and the very same one from sender 2
+        let replacement = Transaction::new([1; 32], [8; 32], 5, 0, 0, &mut DefaultHash::new());
+        let block = Block::new(
+            [0; 32], 0, 1, vec![replacement, other_sender], Some([1; 32]),
+            BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+        );
+        assert_eq!(pool.conflicting_transactions(&block), vec![pooled.hash]);
+        // the pool is left as it was
+        assert_eq!(pool.pending_transactions(), vec![pooled, unrelated, other_sender]);
+    }
+
+    #[test]
+    fn test_export_and_import_mempool() {
+        let chain = Chain::new_with_genesis();
+        let mut signing_keys = (0..3).map(|_| DefaultSigner::generate_random()).collect::<Vec<_>>();
+        let valid = signing_keys.iter_mut().map(|signing_key| {
+            let sender = signing_key.get_verifying_function().to_bytes();
+            let mut transaction = Transaction::new(sender, [2; 32], 0, 0, 0, &mut DefaultHash::new());
+            transaction.sign(signing_key);
+            transaction
+        }).collect::<Vec<_>>();
+        let mut overdrawn = Transaction::new(valid[0].header.sender, [2; 32], 1_000_000_000, 0, 1, &mut DefaultHash::new());
+        overdrawn.sign(&mut signing_keys[0]);
+        let pool = MinerPool::new();
+        for transaction in [valid[0], overdrawn, valid[1], valid[2]] {
+            pool.add_transaction(transaction);
+        }
+
+        let bundle = pool.export_mempool().unwrap();
+        // exporting leaves the pool unchanged
+        assert_eq!(pool.pending_transactions().len(), 4);
+
+        // a fresh node with the same state takes only what is valid there, in order
+        let fresh = MinerPool::new();
+        assert_eq!(fresh.import_mempool(&bundle, &chain.clone()).unwrap(), 3);
+        assert_eq!(fresh.pending_transactions(), valid);
+        // importing again adds nothing
+        assert_eq!(fresh.import_mempool(&bundle, &chain).unwrap(), 0);
+        assert_eq!(fresh.pending_transactions().len(), 3);
+
+        assert!(fresh.import_mempool(&bundle[..bundle.len() / 2], &chain).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_to_union() {
+        let (local, remote) = (MinerPool::new(), MinerPool::new());
+        for nonce in 0..6 {
+            local.add_transaction(transaction(1, nonce));
+        }
+        for nonce in 3..10 {
+            remote.add_transaction(transaction(1, nonce));
+        }
+        remote.add_transaction(transaction(2, 0));
+
+        // the local node sends its ids, the peer answers with what it has and wants
+        let remote_difference = remote.difference(&local.transaction_ids());
+        let local_difference = local.difference(&remote.transaction_ids());
+        assert_eq!(remote_difference.to_request, local_difference.to_send.iter().map(|t| t.hash).collect());
+        assert_eq!(local_difference.to_request, remote_difference.to_send.iter().map(|t| t.hash).collect());
+        // only the transactions one side is missing cross - 3 one way, 5 the other
+        assert_eq!(local_difference.to_send.len(), 3);
+        assert_eq!(remote_difference.to_send.len(), 5);
+        for transaction in remote_difference.to_send {
+            local.add_transaction(transaction);
+        }
+        for transaction in local_difference.to_send {
+            remote.add_transaction(transaction);
+        }
+        assert_eq!(local.transaction_ids(), remote.transaction_ids());
+        assert_eq!(local.transaction_ids().len(), 11);
+        assert_eq!(local.difference(&remote.transaction_ids()), MempoolDifference::default());
+    }
+
+    #[test]
+    fn test_evict_cascades_to_descendants() {
+        let pool = MinerPool::new();
+        for nonce in 0..4 {
+            pool.add_transaction(transaction(1, nonce));
+        }
+        let evicted = pool.evict_transaction([1; 32], 0);
+        assert_eq!(evicted.iter().map(|t| t.header.nonce).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert!(pool.pop_transaction().is_none());
+    }
+
+    #[test]
+    fn test_evict_keeps_independent_transactions() {
+        let pool = MinerPool::new();
+        pool.add_transaction(transaction(1, 0));
+        pool.add_transaction(transaction(2, 0));
+        pool.add_transaction(transaction(1, 1));
+        pool.add_transaction(transaction(2, 1));
+        pool.add_transaction(transaction(1, 2));
+        let evicted = pool.evict_transaction([1; 32], 1);
+        assert_eq!(evicted.len(), 2);
+        assert_eq!(drain(&pool), vec![([1; 32], 0), ([2; 32], 0), ([2; 32], 1)]);
+    }
+
+    #[test]
+    fn test_evict_expired() {
+        let mut pool = MinerPool::new();
+        pool.clock = Clock::mock(1_000);
+        pool.expiry = 100;
+        pool.add_transaction(transaction(1, 0));
+        pool.clock.advance(60);
+        pool.add_transaction(transaction(2, 0));
+        pool.add_transaction(transaction(3, 0));
+
+        // one second short of the first expiry
+        pool.clock.advance(39);
+        assert!(pool.evict_expired().is_empty());
+        pool.clock.advance(1);
+        assert_eq!(pool.evict_expired(), vec![transaction(1, 0)]);
+        // a transaction that left the pool is forgotten, so it may enter again afresh
+        assert_eq!(pool.pop_transaction(), Some(transaction(2, 0)));
+        pool.add_transaction(transaction(2, 0));
+        pool.clock.advance(60);
+        assert_eq!(pool.evict_expired(), vec![transaction(3, 0)]);
+        assert_eq!(drain(&pool), vec![([2; 32], 0)]);
+    }
+
+    #[test]
+    fn test_evict_missing_transaction() {
+        let pool = MinerPool::new();
+        pool.add_transaction(transaction(1, 1));
+        pool.add_transaction(transaction(1, 2));
+        assert!(pool.evict_transaction([1; 32], 0).is_empty());
+        assert_eq!(drain(&pool), vec![([1; 32], 1), ([1; 32], 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_prune_after_reorg() {
+        let mut chain = Chain::new_with_genesis();
+        let genesis_hash = chain.deepest_hash;
+        let mut miner_key = DefaultSigner::generate_random();
+        let miner = miner_key.get_verifying_function().to_bytes();
+        let mut other_key = DefaultSigner::generate_random();
+        let other = other_key.get_verifying_function().to_bytes();
+        let now = chain.clock.now();
+        let mine_on = async |chain: &mut Chain, parent, miner, signing_key: &mut DefaultSigner, timestamp| {
+            let mut filler = Transaction::new(miner, [2; 32], 0, 0, 0, &mut DefaultHash::new());
+            filler.sign(signing_key);
+            let mut block = child_of(chain, parent, vec![filler], miner, timestamp);
+            // a stamp, so the block pays a reward
+            stamp(&mut block);
+            mine_on_chain(chain, &mut block, miner).await;
+            chain.add_new_block(block.clone()).unwrap();
+            block.hash.unwrap()
+        };
+        // the miner's reward from the first block funds a pooled spend
+        let orphaned = mine_on(&mut chain, genesis_hash, miner, &mut miner_key, now).await;
+        let reward = chain.state_manager.get_account(&miner, chain.get_state_root().unwrap()).unwrap().balance;
+        assert!(reward > 0);
+        let mut spend = Transaction::new(miner, [3; 32], reward, 0, 1, &mut DefaultHash::new());
+        spend.sign(&mut miner_key);
+        let mut unrelated_key = DefaultSigner::generate_random();
+        let mut unrelated = Transaction::new(unrelated_key.get_verifying_function().to_bytes(), [3; 32], 0, 0, 0, &mut DefaultHash::new());
+        unrelated.sign(&mut unrelated_key);
+        let pool = MinerPool::new();
+        pool.add_transaction(spend);
+        pool.add_transaction(unrelated);
+        assert!(pool.prune(&chain).is_empty());
+
+        // a longer fork from genesis orphans the block
+        let fork = mine_on(&mut chain, genesis_hash, other, &mut other_key, now + 1).await;
+        let mut fork_key = DefaultSigner::generate_random();
+        let fork_miner = fork_key.get_verifying_function().to_bytes();
+        mine_on(&mut chain, fork, fork_miner, &mut fork_key, now + 2).await;
+        assert_ne!(chain.canonical_block_at(1).unwrap().hash, Some(orphaned));
+
+        // the spend of the orphaned reward is gone, the rest stays
+        assert_eq!(pool.prune(&chain), vec![spend]);
+        assert_eq!(pool.pending_transactions(), vec![unrelated]);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_after_connect() {
+        let mut chain = Chain::new_with_genesis();
+        let mut miner_key = DefaultSigner::generate_random();
+        let miner = miner_key.get_verifying_function().to_bytes();
+        let now = chain.clock.now();
+        let mine_on_tip = async |chain: &mut Chain, transactions: Vec<Transaction>, stamped: bool, timestamp| {
+            let mut block = child_of(chain, chain.deepest_hash, transactions, miner, timestamp);
+            if stamped {
+                stamp(&mut block);
+            }
+            mine_on_chain(chain, &mut block, miner).await;
+            chain.add_new_block(block.clone()).unwrap();
+            block
+        };
+        let spend = |miner_key: &mut DefaultSigner, receiver: u8, amount: u64, nonce: u64| {
+            let mut transaction = Transaction::new(miner, [receiver; 32], amount, 0, nonce, &mut DefaultHash::new());
+            transaction.sign(miner_key);
+            transaction
+        };
+        // a stamped block funds the miner
+        let filler = spend(&mut miner_key, 2, 0, 0);
+        mine_on_tip(&mut chain, vec![filler], true, now).await;
+        let reward = chain.state_manager.get_account(&miner, chain.get_state_root().unwrap()).unwrap().balance;
+        assert!(reward > 1);
+
+        let mined = spend(&mut miner_key, 2, reward / 2, 1);
+        let same_nonce = spend(&mut miner_key, 3, 1, 1);
+        let next = spend(&mut miner_key, 2, reward - reward / 2, 2);
+        let overspend = spend(&mut miner_key, 3, 1, 3);
+        // the block does not touch this sender, so its spend is left for a reorg or the template to catch
+        let mut unfunded_key = DefaultSigner::generate_random();
+        let mut unfunded = Transaction::new(unfunded_key.get_verifying_function().to_bytes(), [3; 32], 1, 0, 0, &mut DefaultHash::new());
+        unfunded.sign(&mut unfunded_key);
+        let pool = MinerPool::new();
+        for transaction in [mined, same_nonce, next, overspend, unfunded] {
+            pool.add_transaction(transaction);
+        }
+        let mut kept = MinerPool::new();
+        kept.connect_policy = ConnectPolicy::Keep;
+        kept.add_transaction(same_nonce);
+
+        // the block uses nonce 1 - both transactions with it are stale, and the balance left covers only the next spend
+        let block = mine_on_tip(&mut chain, vec![mined], false, now + 1).await;
+        assert_eq!(pool.reconcile_connected(&chain, &block), vec![mined, same_nonce, overspend]);
+        assert_eq!(pool.pending_transactions(), vec![next, unfunded]);
+        assert!(pool.reconcile_connected(&chain, &block).is_empty());
+        assert_eq!(pool.prune(&chain), vec![unfunded]);
+        // the keep policy leaves even the stale transaction
+        assert!(kept.reconcile_connected(&chain, &block).is_empty());
+        assert_eq!(kept.pending_transactions(), vec![same_nonce]);
+    }
+}
@@ -1,9 +1,24 @@
-use pillar_crypto::{hashing::{HashFunction, Hashable}, signing::{SigFunction, Signable}, types::StdByteArray};
+use pillar_crypto::{hashing::{DefaultHash, HashFunction, Hashable}, signing::{DefaultVerifier, SigFunction, Signable}, types::StdByteArray};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, Bytes};
 
-use super::block::Block;
+use crate::protocol::params::ChainParams;
 
+use super::{block::Block, errors::BlockValidationError};
+
+/// Prefixed to every transaction hash, so a transaction hash can never equal a hash of another kind
+/// (a block header, an account, a merkle node) over the same bytes.
+/// The tag is part of consensus - changing it changes every transaction hash.
+pub const TRANSACTION_HASH_TAG: &[u8] = b"pillar/transaction";
+
+/// Hashed before the required block hash of a transaction, so it cannot be mistaken for a rotated key
+pub const REQUIRED_BLOCK_TAG: &[u8] = b"pillar/required-block";
+
+/// Hashed before the salt and transaction hash of a short id
+pub const SHORT_ID_TAG: &[u8] = b"pillar/short-id";
+
+/// An application namespace tag - lets applications sharing the chain find their own transactions
+pub type Namespace = [u8; 4];
 
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Hash, PartialEq, Eq)]
@@ -28,7 +43,13 @@ pub struct TransactionHeader{
     // timestamp is the time the transaction was created
     pub timestamp: u64,
     // the nonce is a random number used to prevent replay attacks
-    pub nonce: u64
+    pub nonce: u64,
+    // if set, the sender's account is controlled by this ed25519 public key from now on
+    pub rotate_key: Option<StdByteArray>,
+    // the application namespace this transaction belongs to, if any
+    pub namespace: Option<Namespace>,
+    // if set, the transaction is only valid in a chain holding the block with this hash
+    pub required_block_hash: Option<StdByteArray>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
@@ -44,6 +65,7 @@ pub struct TransactionFilter {
 }
 
 impl TransactionFilter{
+    #[allow(dead_code)]
     /// Create a new transaction filter
     /// 
     /// # Arguments
@@ -68,20 +90,17 @@ pub trait FilterMatch<T>{
 /// match a transaction to a transaction filter
 impl FilterMatch<Transaction> for TransactionFilter {
     fn matches(&self, other: &Transaction) -> bool {
-        if let Some(sender) = self.sender {
-            if sender != other.header.sender {
-                return false;
-            }
+        if let Some(sender) = self.sender
+            && sender != other.header.sender {
+            return false;
         }
-        if let Some(receiver) = self.receiver {
-            if receiver != other.header.receiver {
-                return false;
-            }
+        if let Some(receiver) = self.receiver
+            && receiver != other.header.receiver {
+            return false;
         }
-        if let Some(amount) = self.amount {
-            if amount != other.header.amount {
-                return false;
-            }
+        if let Some(amount) = self.amount
+            && amount != other.header.amount {
+            return false;
         }
         true
     }
@@ -122,12 +141,19 @@ impl TransactionHeader {
             receiver,
             amount,
             timestamp,
-            nonce
+            nonce,
+            rotate_key: None,
+            namespace: None,
+            required_block_hash: None,
         }
     }
 
     /// Hash the transaction header using the provided HashFunction
     ///
+    /// The hash is domain separated - `TRANSACTION_HASH_TAG` is hashed first, then the sender, receiver,
+    /// amount, timestamp, and nonce, with integers as 8 byte little endian, then the rotated key if there is one,
+    /// then the namespace if there is one, then `REQUIRED_BLOCK_TAG` and the required block hash if there is one.
+    ///
     /// # Arguments
     ///
     /// * `hasher` - A mutable instance of a type implementing the HashFunction trait
@@ -136,11 +162,25 @@ impl TransactionHeader {
     ///
     /// * The hash of the transaction header as a StdByteArray array
     pub fn hash(&self, hasher: &mut impl HashFunction) -> StdByteArray {
+        hasher.update(TRANSACTION_HASH_TAG);
         hasher.update(self.sender);
         hasher.update(self.receiver);
         hasher.update(self.amount.to_le_bytes());
         hasher.update(self.timestamp.to_le_bytes());
         hasher.update(self.nonce.to_le_bytes());
+        // only rotations commit to the key, so transfer hashes are unchanged
+        if let Some(key) = self.rotate_key {
+            hasher.update(key);
+        }
+        // a key is 32 bytes and a namespace 4, so the two cannot be mistaken for one another
+        if let Some(namespace) = self.namespace {
+            hasher.update(namespace);
+        }
+        // tagged, since a block hash is the same length as a key
+        if let Some(block_hash) = self.required_block_hash {
+            hasher.update(REQUIRED_BLOCK_TAG);
+            hasher.update(block_hash);
+        }
         hasher.digest().expect("Hashing failed")
     }
 }
@@ -173,6 +213,98 @@ impl Transaction {
     }
 }
 
+impl Transaction {
+    /// Create a transaction that rotates the key controlling the sender's account
+    /// It must be signed by the current key, and moves no funds
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The account address
+    /// * `new_key` - The ed25519 public key that will control the account
+    /// * `timestamp` - The time the transaction was created
+    /// * `nonce` - The sender's next nonce
+    pub fn new_key_rotation(
+        sender: StdByteArray,
+        new_key: StdByteArray,
+        timestamp: u64,
+        nonce: u64,
+        hash_function: &mut impl HashFunction
+    ) -> Self {
+        let mut header = TransactionHeader::new(sender, sender, 0, timestamp, nonce);
+        header.rotate_key = Some(new_key);
+        let hash = header.hash(hash_function);
+        Transaction {
+            header,
+            hash,
+            signature: None,
+        }
+    }
+
+    /// The same transaction, tagged with an application namespace
+    /// The hash is recomputed, so any signature is dropped - sign after tagging
+    pub fn with_namespace(mut self, namespace: Namespace, hash_function: &mut impl HashFunction) -> Self {
+        self.header.namespace = Some(namespace);
+        self.hash = self.header.hash(hash_function);
+        self.signature = None;
+        self
+    }
+
+    /// The same transaction, only valid in a chain holding the block with `block_hash` - so it cannot be replayed on another fork
+    /// The hash is recomputed, so any signature is dropped - sign after anchoring
+    pub fn with_required_block(mut self, block_hash: StdByteArray, hash_function: &mut impl HashFunction) -> Self {
+        self.header.required_block_hash = Some(block_hash);
+        self.hash = self.header.hash(hash_function);
+        self.signature = None;
+        self
+    }
+
+    /// Structural checks that need no chain state
+    /// The declared hash must be the hash of the header
+    /// A key rotation must be a zero amount transaction to the sender, with a valid key
+    pub fn sanity_check(&self, hash_function: &mut impl HashFunction) -> Result<(), BlockValidationError> {
+        let expected = self.header.hash(hash_function);
+        if expected != self.hash {
+            return Err(BlockValidationError::HashMismatch(self.hash, expected));
+        }
+        if let Some(key) = self.header.rotate_key {
+            if self.header.amount != 0 || self.header.receiver != self.header.sender {
+                return Err(BlockValidationError::InvalidTransaction("Key rotation cannot transfer funds".into()));
+            }
+            if !DefaultVerifier::is_valid_key(&key) {
+                return Err(BlockValidationError::InvalidTransaction("Key rotation to an invalid key".into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    /// The checks that need no account state, as a first pass on submission.
+    /// The signature must be present, but is not verified - the key that must have made it
+    /// is part of the account state, since keys can be rotated.
+    ///
+    /// Checks:
+    /// 1. The sender and receiver are set, and the sender is a usable public key.
+    /// 2. The transaction is signed.
+    /// 3. The timestamp is within the allowed drift of `now`.
+    /// 4. The hash, and the shape of key rotations, as in `sanity_check`.
+    pub fn validate_standalone(&self, params: &ChainParams, now: u64) -> Result<(), BlockValidationError> {
+        if self.header.sender == [0; 32] || !DefaultVerifier::is_valid_key(&self.header.sender) {
+            return Err(BlockValidationError::TransactionNoSender);
+        }
+        if self.header.receiver == [0; 32] {
+            return Err(BlockValidationError::TransactionNoReceiver);
+        }
+        if self.signature.is_none() {
+            return Err(BlockValidationError::TransactionInvalidSignature);
+        }
+        if self.header.timestamp > now.saturating_add(params.max_future_drift) {
+            return Err(BlockValidationError::TransactionFutureTimestamp);
+        }
+        self.sanity_check(&mut DefaultHash::new())
+    }
+}
+
 impl Hashable for Transaction {
     fn hash(&self, hasher: &mut impl HashFunction) -> Result<StdByteArray, std::io::Error> {
         Ok(self.header.hash(hasher))
@@ -199,4 +331,16 @@ impl Signable<64> for Transaction {
         self.signature = Some(signature);
         self.signature.unwrap()
     }
-}
\ No newline at end of file
+}
+
+/// The short id of the transaction `txid` in a compact block - the first 8 bytes of a hash over `salt` and `txid`, little endian.
+/// Compact blocks salt with the block hash, so two transactions that collide in one block almost surely do not in the next,
+/// and a collision cannot be ground for before the block is mined
+pub fn short_id(txid: &StdByteArray, salt: &StdByteArray) -> u64 {
+    let mut hasher = DefaultHash::new();
+    hasher.update(SHORT_ID_TAG);
+    hasher.update(salt);
+    hasher.update(txid);
+    let digest = hasher.digest().expect("Hashing failed");
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
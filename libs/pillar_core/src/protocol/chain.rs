@@ -1,12 +1,17 @@
-use std::{collections::{HashMap, HashSet}};
+use std::{collections::{HashMap, HashSet}, sync::Arc};
 
 use pillar_crypto::{hashing::{DefaultHash, Hashable}, merkle::generate_tree, types::StdByteArray};
-use rand::{rng, seq::{IteratorRandom}};
+use tokio::sync::Semaphore;
 use tracing::{instrument, warn};
 
-use crate::{blockchain::{chain::Chain, chain_shard::ChainShard, TrimmableChain}, nodes::{node::{Broadcaster, Node}, peer::Peer}, primitives::{block::{Block, BlockTail}, errors::{BlockValidationError, QueryError}, messages::Message, transaction::Transaction}};
+use crate::{blockchain::{chain::{Chain, ChainTip}, chain_shard::ChainShard, TrimmableChain}, nodes::{node::{Broadcaster, Node}, peer::Peer}, primitives::{block::{Block, BlockHeader, BlockTail}, errors::{BlockValidationError, QueryError}, messages::Message, transaction::Transaction}};
 
-use super::peers::discover_peers;
+use super::{params::ChainParams, peers::{discover_peers, request_with_retries}};
+
+/// The default number of blocks downloaded at once during sync
+pub const MAX_BLOCK_DOWNLOADS: usize = 16;
+/// How often the settle consumer checks for held blocks whose time has come
+pub const HELD_BLOCK_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
 /// Queries a peer to send a block.
 async fn query_block_from_peer(
@@ -46,47 +51,133 @@ async fn query_block_from_peer(
     Ok(block)
 }
 
-/// Given a shard (validated) uses the node to get the chain
-async fn shard_to_chain(node: &mut Node, shard: ChainShard) -> Result<Chain, QueryError> {
-    let mut threads = Vec::new();
-    // get many blocks simultaneously
-    for (hash, _) in shard.headers{
-        let nodeclone = node.clone();
-        let handle = tokio::spawn(async move{
-            loop{ // keep asking for the node until we pass
-                let mut peer = nodeclone.clone().inner.peers.lock().await.values().choose(&mut rng()).unwrap().clone(); // random peer
-                let block = query_block_from_peer(&mut peer, &nodeclone.clone().into(), hash).await;
-                if let Ok(block) = block {
-                    // we got the block, send it to the channel
-                    return block;
-                }
+/// Queries a peer for the tip of its deepest chain.
+/// The tip header must hash to the declared tip hash.
+pub async fn query_tip_from_peer(
+    peer: &mut Peer,
+    initializing_peer: &Peer,
+) -> Result<ChainTip, QueryError>{
+    let response = peer.communicate(&Message::TipRequest, initializing_peer).await.map_err(
+        QueryError::IOError
+    )?;
+    match response {
+        Message::TipResponse(tip) => {
+            let hash = tip.header.hash(&mut DefaultHash::new()).map_err(
+                |_| QueryError::BadBlock(BlockValidationError::MalformedBlock("Tip header cannot be hashed".to_string()))
+            )?;
+            if hash != tip.hash {
+                return Err(QueryError::BadBlock(BlockValidationError::HashMismatch(tip.hash, hash)));
             }
-        });
-        threads.push(handle);
+            Ok(tip)
+        }
+        _ => Err(QueryError::InvalidResponse)
+    }
+}
+
+/// Queries a peer for the header at `depth` on its deepest chain.
+/// `None` if the peer's chain is not that deep.
+pub async fn query_header_at_depth(
+    peer: &mut Peer,
+    initializing_peer: &Peer,
+    depth: u64,
+) -> Result<Option<BlockHeader>, QueryError>{
+    let response = peer.communicate(&Message::HeaderRequest(depth), initializing_peer).await.map_err(
+        QueryError::IOError
+    )?;
+    match response {
+        Message::HeaderResponse(Some(header)) if header.depth != depth => Err(QueryError::InvalidResponse),
+        Message::HeaderResponse(header) => Ok(header),
+        _ => Err(QueryError::InvalidResponse)
+    }
+}
+
+/// Asks peers for the header at `depth` on their deepest chains, under the node's retry policy.
+/// `None` if the peer that answers has no chain that deep.
+pub async fn request_header_at_depth(node: &Node, depth: u64) -> Result<Option<BlockHeader>, QueryError> {
+    let initializing_peer: Peer = node.into();
+    request_with_retries(node, |mut peer| {
+        let initializing_peer = initializing_peer.clone();
+        async move { query_header_at_depth(&mut peer, &initializing_peer, depth).await }
+    }).await
+}
+
+/// Downloads the block for every hash, with at most `limit` downloads in flight at once.
+/// A download is only started once a permit is free, so the number of blocks
+/// being received at any time - and the memory they take - is bounded by the limit.
+/// Fails with the first failed download, once every download has finished
+async fn download_blocks<F, Fut>(hashes: Vec<StdByteArray>, limit: usize, fetch: F) -> Result<Vec<Block>, QueryError>
+where
+    F: Fn(StdByteArray) -> Fut,
+    Fut: Future<Output = Result<Block, QueryError>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(limit.max(1)));
+    let mut threads = Vec::new();
+    for hash in hashes {
+        // backpressure - wait here instead of queueing more requests
+        let permit = permits.clone().acquire_owned().await.expect("Download semaphore closed");
+        let download = fetch(hash);
+        threads.push(tokio::spawn(async move {
+            let block = download.await;
+            drop(permit);
+            block
+        }));
     }
     // let the threads finish
     let mut blocks: Vec<Block> = Vec::new();
+    let mut failure = None;
     for thread in threads {
-        blocks.push(thread.await.unwrap());
+        match thread.await.unwrap() {
+            Ok(block) => blocks.push(block),
+            Err(e) => failure = failure.or(Some(e)),
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(blocks),
     }
+}
+
+/// Given a shard (validated) uses the node to get the chain.
+/// Each block is requested under the node's retry policy, and a block that fails validation
+/// is downloaded again at most `max_attempts` times before sync gives up
+async fn shard_to_chain(node: &mut Node, shard: ChainShard) -> Result<Chain, QueryError> {
+    // get many blocks simultaneously, up to the node's limit
+    let hashes = shard.headers.keys().cloned().collect();
+    let mut blocks = download_blocks(hashes, node.max_block_downloads, |hash| {
+        let nodeclone = node.clone();
+        async move {
+            let block = request_with_retries(&nodeclone, |mut peer| {
+                let initializing_peer: Peer = (&nodeclone).into();
+                async move { query_block_from_peer(&mut peer, &initializing_peer, hash).await }
+            }).await;
+            if let Err(e) = &block {
+                warn!("Failed to download block {:?}: {}", hash, e);
+            }
+            block
+        }
+    }).await?;
     // we need to work our way up by depth
     // sort by depth
     blocks.sort_by_key(|x| x.header.depth);
     // note: we know that there is exactly one genesis from shard validation
     let mut chain = Chain::new_with_genesis();
+    chain.params.checkpoint = node.checkpoint.or(chain.params.checkpoint);
+    // the shard places each block on its chain before the block arrives
+    chain.learn_headers(shard.headers.values().copied());
     for block in &blocks[1..]{ // skip the first - genesis
         let mut block = block.to_owned();
         let hash = block.hash.unwrap();
-        loop{ // we need to keep going until it passes full validation
-            match chain.add_new_block(block){
-                Err(_) => { // failed validation
-                    let mut peer = node.inner.peers.lock().await.values().choose(&mut rng()).unwrap().clone(); // random peer
-                    block = query_block_from_peer(&mut peer, &node.clone().into(), hash).await?; // one more attempt, then fail.
-                }
-                _ => {
-                    break;
-                }
+        let mut attempts = 0;
+        // we need to keep going until it passes full validation, or the attempts run out
+        while let Err(e) = chain.add_new_block(block) {
+            attempts += 1;
+            if attempts > node.retry_policy.max_attempts {
+                return Err(QueryError::BadBlock(e));
             }
+            block = request_with_retries(node, |mut peer| {
+                let initializing_peer: Peer = (&*node).into();
+                async move { query_block_from_peer(&mut peer, &initializing_peer, hash).await }
+            }).await?;
         }
     }
     Ok(chain)
@@ -100,6 +191,8 @@ pub async fn dicover_chain(mut node: Node) -> Result<(), QueryError> {
         QueryError::IOError
     )?;
     // broadcast the chain shard request to all peers
+    let params = ChainParams { checkpoint: node.checkpoint, ..Default::default() };
+    let mut rejected = 0;
     let mut peers = node.inner.peers.lock().await;
     let mut chain_shards = Vec::new();
     for (_, peer) in peers.iter_mut() {
@@ -108,27 +201,49 @@ pub async fn dicover_chain(mut node: Node) -> Result<(), QueryError> {
             .map_err(QueryError::IOError)?;
         if let Message::ChainShardResponse(shard) = response {
             // add the shard to the chain   
-            shard.validate().map_err(
-                QueryError::BadBlock
-            )?;
-            chain_shards.push(shard);
+            match shard.validate_with_params(&params) {
+                Ok(()) => chain_shards.push(shard),
+                // a chain off the trusted checkpoint is skipped, rather than failing discovery
+                Err(BlockValidationError::CheckpointMismatch(depth)) => {
+                    warn!("Peer {:?} serves a chain that disagrees with the checkpoint at depth {}", peer.public_key, depth);
+                    rejected += 1;
+                }
+                Err(e) => return Err(QueryError::BadBlock(e)),
+            }
             // TODO perhaps blacklist the peer
         }  
 
     }
     drop(peers);
-    // find deepest out of peers
-    let shard = deepest_shard(&chain_shards)?;
+    if chain_shards.is_empty() && rejected > 0 {
+        return Err(QueryError::InsufficientInfo("No peer chain agrees with the checkpoint".into()));
+    }
+    // find deepest out of peers, ignoring chains with too little work to be real
+    let shard = deepest_shard(&chain_shards, node.min_total_work)?;
     // now we have valid shards
-    let chain = shard_to_chain(&mut node, shard.clone()).await?;
-    node.inner.chain.lock().await.replace(chain);
+    let mut chain = shard_to_chain(&mut node, shard.clone()).await?;
+    let mut chain_lock = node.inner.chain.lock().await;
+    for observer in node.inner.block_observers.lock().await.iter() {
+        chain.add_observer_from_genesis(observer.clone());
+    }
+    chain_lock.replace(chain);
     Ok(())
 }
 
 /// Find the deepest chain shard - they shoudl in theory be the same but we want the longest
+/// Shards whose deepest leaf has less than `min_total_work` are ignored, so a trivially mined fake chain is never synced.
 /// TODO: Maybe we should check agreement of hashes and such, but with POW deepest should be accurate
-pub fn deepest_shard(shards: &[ChainShard]) -> Result<ChainShard, QueryError> {
-    let shard = shards.iter().max_by_key(|shard| shard.leaves.iter().max_by_key(|leaf| shard.headers[*leaf].depth).unwrap());
+pub fn deepest_shard(shards: &[ChainShard], min_total_work: u128) -> Result<ChainShard, QueryError> {
+    let deepest_leaf = |shard: &ChainShard| shard.leaves.iter().copied().max_by_key(|leaf| shard.headers[leaf].depth);
+    let mut enough_work = shards.iter().filter(|shard| {
+        deepest_leaf(shard)
+            .and_then(|leaf| shard.get_cumulative_work(&leaf))
+            .is_some_and(|work| work >= min_total_work)
+    }).peekable();
+    if !shards.is_empty() && enough_work.peek().is_none() {
+        return Err(QueryError::InsufficientInfo("No peer chain has the minimum total work".into()));
+    }
+    let shard = enough_work.max_by_key(|shard| deepest_leaf(shard).map(|leaf| shard.headers[&leaf].depth));
     match shard {
         Some(shard) => Ok(shard.clone()),
         None => Err(QueryError::NoReply),   
@@ -170,6 +285,8 @@ pub async fn sync_chain(node: Node) -> Result<(), QueryError> {
         return Err(QueryError::InsufficientInfo("Chain is not initialized".to_string()));
     }
     let chain = chain.as_mut().unwrap();
+    // extensions off the trusted checkpoint are rejected as they are added
+    chain.params.checkpoint = node.checkpoint.or(chain.params.checkpoint);
     let leaves = chain.leaves.clone();
     
     let request = Message::ChainSyncRequest(leaves.clone());
@@ -283,10 +400,10 @@ pub async fn service_sync(node: Node, leaves: &HashSet<StdByteArray>) -> Result<
 /// adding them to the chain
 #[instrument(fields(node = ?node.inner.public_key), skip_all)]
 pub async fn block_settle_consumer(node: Node, stop_signal: Option<flume::Receiver<()>>){
+    let mut next_release = tokio::time::Instant::now();
     loop{
-        if let Some(signal) = &stop_signal {
-            if signal.try_recv().is_ok() {break;}
-        }
+        if let Some(signal) = &stop_signal
+            && signal.try_recv().is_ok() {break;}
         let state = node.inner.state.lock().await.clone();
         if !state.is_consume() {continue;}
         if let Some(block) = node.inner.late_settle_queue.dequeue(){
@@ -299,15 +416,134 @@ pub async fn block_settle_consumer(node: Node, stop_signal: Option<flume::Receiv
             }
             // then we settle the block
             tracing::info!("Settling mined block with miner address: {:?}", block.header.miner_address);
+            let previous_tip = chain.deepest_hash;
             if chain.add_new_block(block.clone()).is_err() {continue;} // failed to add the block
             tracing::info!("Valid block added to chain.");
+            if chain.deepest_hash != previous_tip && let Some(ref pool) = node.miner_pool {
+                if chain.deepest_hash == block.hash.unwrap() && block.header.previous_hash == previous_tip {
+                    let dropped = pool.reconcile_connected(chain, &block);
+                    tracing::debug!("New tip invalidated {} pooled transactions.", dropped.len());
+                } else {
+                    // a reorg, or held blocks released on top of this one - pooled transactions may depend on orphaned blocks,
+                    // or be spent by blocks other than this one
+                    let dropped = pool.prune(chain);
+                    tracing::info!("New tip dropped {} pooled transactions.", dropped.len());
+                }
+                let expired = pool.evict_expired();
+                tracing::debug!("Evicted {} expired pooled transactions.", expired.len());
+            }
             drop(chain_lock); // free lock cause why not
+            if node.relay_validated_only && node.inner.state.lock().await.is_forward() {
+                // the block was held back on receipt until it was validated
+                node.inner.broadcast_queue.enqueue(Message::BlockTransmission(block.clone())).await;
+            }
             if let Some(ref pool) = node.miner_pool{
                 // signal to stop trying to mine the current block
                 let _ = pool.mine_abort_sender.send(block.header.depth);
             }
             // the stamping process is done. do, if there is a miner address, then stamping is done on this block.
             tracing::info!("Reputations recorded for new block.");
+        }else if next_release <= tokio::time::Instant::now(){
+            // nothing new - held blocks whose time has come are settled like any other block
+            next_release = tokio::time::Instant::now() + HELD_BLOCK_CHECK_INTERVAL;
+            let released = node.inner.chain.lock().await.as_mut().map(Chain::release_held_blocks).unwrap_or_default();
+            if !released.is_empty() {
+                tracing::info!("Releasing {} held blocks.", released.len());
+            }
+            for block in released {
+                node.inner.late_settle_queue.enqueue(block);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    use pillar_crypto::types::StdByteArray;
+
+    use crate::{blockchain::chain_shard::ChainShard, primitives::{block::{BlockHeader, BlockTail}, errors::QueryError}};
+
+    use super::{deepest_shard, download_blocks, get_genesis_block};
+
+    /// A shard on top of genesis, with a block for each difficulty
+    fn shard(tag: u8, difficulties: &[u64]) -> ChainShard {
+        let genesis = get_genesis_block(Some([0; 32]));
+        let mut previous = genesis.hash.unwrap();
+        let mut shard = ChainShard { headers: [(previous, genesis.header)].into(), leaves: Default::default() };
+        for (i, difficulty) in difficulties.iter().enumerate() {
+            let hash: StdByteArray = [tag.wrapping_add(i as u8); 32];
+            let header = BlockHeader::new(
+                previous, [0; 32], Some([0; 32]), 0, i as u64, Some([tag; 32]), BlockTail::default(), i as u64 + 1, Some(*difficulty)
+            );
+            shard.headers.insert(hash, header);
+            previous = hash;
+        }
+        shard.leaves.insert(previous);
+        shard
+    }
+
+    #[test]
+    fn test_min_total_work() {
+        // deeper, but trivially mined
+        let cheap = shard(1, &[1, 1, 1, 1]);
+        let heavy = shard(100, &[30]);
+        let shards = [cheap.clone(), heavy.clone()];
+        // without a threshold the deepest wins
+        assert_eq!(deepest_shard(&shards, 0).unwrap(), cheap);
+        // the cheap chain is skipped
+        assert_eq!(deepest_shard(&shards, 1 << 20).unwrap(), heavy);
+        assert_eq!(deepest_shard(&shards, 1 << 30).unwrap(), heavy);
+        // nothing qualifies
+        assert!(matches!(deepest_shard(&shards, 1 << 40), Err(QueryError::InsufficientInfo(_))));
+        assert!(matches!(deepest_shard(&[], 0), Err(QueryError::NoReply)));
+    }
+
+    #[tokio::test]
+    async fn test_download_limit() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let hashes = (0..100u8).map(|i| [i; 32]).collect::<Vec<_>>();
+        let blocks = download_blocks(hashes, 4, |_| {
+            let (in_flight, most_in_flight) = (in_flight.clone(), most_in_flight.clone());
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(get_genesis_block(None))
+            }
+        }).await.unwrap();
+        assert_eq!(blocks.len(), 100);
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 4);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+
+        // a limit of zero still makes progress, one at a time
+        most_in_flight.store(0, Ordering::SeqCst);
+        let blocks = download_blocks(vec![[0; 32]; 10], 0, |_| {
+            let (in_flight, most_in_flight) = (in_flight.clone(), most_in_flight.clone());
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(get_genesis_block(None))
+            }
+        }).await.unwrap();
+        assert_eq!(blocks.len(), 10);
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 1);
+
+        // a failed download fails the whole set, after the rest have finished
+        let finished = Arc::new(AtomicUsize::new(0));
+        let blocks = download_blocks((0..10u8).map(|i| [i; 32]).collect(), 4, |hash| {
+            let finished = finished.clone();
+            async move {
+                finished.fetch_add(1, Ordering::SeqCst);
+                if hash == [3; 32] { Err(QueryError::NoReply) } else { Ok(get_genesis_block(None)) }
+            }
+        }).await;
+        assert!(matches!(blocks, Err(QueryError::NoReply)));
+        assert_eq!(finished.load(Ordering::SeqCst), 10);
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use crate::{primitives::block::BlockHeader, protocol::params::ChainParams};
+
+/// Source of the current unix time, in seconds
+/// Uses the system time unless mocked - a mocked clock only moves when told to
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    mock: Option<Arc<AtomicU64>>,
+}
+
+impl Clock {
+    /// A clock backed by the system time
+    pub fn system() -> Self {
+        Clock { mock: None }
+    }
+
+    /// A clock frozen at `now` - clones share the same time
+    pub fn mock(now: u64) -> Self {
+        Clock { mock: Some(Arc::new(AtomicU64::new(now))) }
+    }
+
+    /// The current unix time in seconds
+    pub fn now(&self) -> u64 {
+        match &self.mock {
+            Some(time) => time.load(Ordering::SeqCst),
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    /// Moves a mocked clock forward. Does nothing to a system clock
+    pub fn advance(&self, seconds: u64) {
+        if let Some(time) = &self.mock {
+            time.fetch_add(seconds, Ordering::SeqCst);
+        }
+    }
+}
+
+/// The timestamps a block built on `parent` may have right now, as an inclusive (lower, upper) range.
+/// A block must be later than its parent (or as late, if the chain allows equal timestamps), and no more than the allowed drift past the clock.
+/// The range is empty (lower > upper) if the parent itself is too far in the future.
+pub fn valid_timestamp_range(parent: &BlockHeader, params: &ChainParams, clock: &Clock) -> (u64, u64) {
+    (params.earliest_timestamp_after(parent.timestamp), clock.now().saturating_add(params.max_future_drift))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{primitives::block::{BlockHeader, BlockTail}, protocol::params::ChainParams};
+
+    use super::{valid_timestamp_range, Clock};
+
+    fn header_at(timestamp: u64) -> BlockHeader {
+        BlockHeader::new([0; 32], [0; 32], None, 0, timestamp, None, BlockTail::default(), 1, None)
+    }
+
+    #[test]
+    fn test_valid_timestamp_range() {
+        let params = ChainParams::default();
+        let clock = Clock::mock(10_000);
+        let (lower, upper) = valid_timestamp_range(&header_at(9_000), &params, &clock);
+        // strictly after the parent
+        assert_eq!(lower, 9_001);
+        assert_eq!(upper, 10_000 + params.max_future_drift);
+
+        // grows as time passes
+        clock.advance(100);
+        assert_eq!(valid_timestamp_range(&header_at(9_000), &params, &clock), (9_001, 10_100 + params.max_future_drift));
+        // and shrinks with a later parent, or a tighter drift
+        assert_eq!(valid_timestamp_range(&header_at(9_500), &params, &clock).0, 9_501);
+        let tight = ChainParams { max_future_drift: 10, ..params };
+        assert_eq!(valid_timestamp_range(&header_at(9_500), &tight, &clock), (9_501, 10_110));
+        // a parent too far ahead leaves nothing valid
+        let (lower, upper) = valid_timestamp_range(&header_at(20_000), &tight, &clock);
+        assert!(lower > upper);
+        // or as late as the parent, if equal timestamps are allowed
+        let equal = ChainParams { allow_equal_timestamps: true, ..params };
+        assert_eq!(valid_timestamp_range(&header_at(9_500), &equal, &clock).0, 9_500);
+    }
+}
@@ -1,25 +1,26 @@
-use pillar_crypto::{hashing::{DefaultHash, Hashable}, serialization::PillarSerialize};
+use pillar_crypto::{hashing::{DefaultHash, Hashable}, serialization::PillarSerialize, types::StdByteArray};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
 };
 
-use tokio::time::{timeout, Duration};
+use tokio::time::timeout;
 use tracing::instrument;
 
 use crate::{
-    nodes::node::{Broadcaster, Node}, primitives::messages::{get_declaration_length, Message, Versions}
+    nodes::node::{Broadcaster, Node}, primitives::messages::{get_declaration_length, Message, Versions, WireFormat}
 };
 
+use super::peers::{check_tip_agreement, ECLIPSE_SAMPLE_SIZE};
+
 /// Background process that consumes mined blocks, and transactions which must be forwarded
 pub async fn broadcast_knowledge(node: Node, stop_signal: Option<flume::Receiver<()>>) -> Result<(), std::io::Error> {
     let mut hasher = DefaultHash::new();
     loop {
         // send a message to all peers
-        if let Some(signal) = &stop_signal {
-            if signal.try_recv().is_ok() {
-                return Ok(());
-            }
+        if let Some(signal) = &stop_signal
+            && signal.try_recv().is_ok() {
+            return Ok(());
         }
         if let Some(pool) = &node.miner_pool {
             while let Some(proposed_block) = pool.pop_block_proposition(){
@@ -64,28 +65,28 @@ pub async fn serve_peers(node: Node, stop_signal: Option<flume::Receiver<()>>) {
         .unwrap();
     loop {
         // handle connection
-        let mut stream = match timeout(tokio::time::Duration::from_secs(3),listener.accept()).await {
-            Ok(Ok((stream, _))) => stream,
+        let (mut stream, source) = match timeout(tokio::time::Duration::from_secs(3),listener.accept()).await {
+            Ok(Ok((stream, address))) => (stream, address.ip()),
             Ok(Err(e)) => {
                 tracing::error!("Error accepting connection: {}", e);
                 continue;
             },
             Err(_) => {
                 // check if we should stop
-                if let Some(signal) = &stop_signal {
-                    if signal.try_recv().is_ok() {
-                        break;
-                    }
+                if let Some(signal) = &stop_signal
+                    && signal.try_recv().is_ok() {
+                    break;
                 }
                 continue; // timeout, try again
             }     
         };
         // spawn a new thread to handle the connection
         let mut self_clone = node.clone();
+        let timeouts = node.connection_timeouts;
         tokio::spawn(async move {
             // first read the peer declaration
             let mut buffer = [0; get_declaration_length(Versions::V1V4) as usize];
-            let result = timeout(Duration::from_secs(1), stream.read_exact(&mut buffer)).await;
+            let result = timeout(timeouts.request, stream.read_exact(&mut buffer)).await;
             let status = match &result {
                 Ok(Ok(_)) => 0,
                 Ok(Err(_)) => 1,
@@ -101,6 +102,7 @@ pub async fn serve_peers(node: Node, stop_signal: Option<flume::Receiver<()>>) {
                         std::io::ErrorKind::InvalidInput,
                         if status == 1 {"Invalid peer declaration"} else {"Declaration timeout."},
                     ),
+                    self_clone.wire_format,
                 ).await;
                 return;
             };
@@ -113,19 +115,13 @@ pub async fn serve_peers(node: Node, stop_signal: Option<flume::Receiver<()>>) {
                         std::io::ErrorKind::InvalidInput,
                         "Invalid peer delaration",
                     ),
+                    self_clone.wire_format,
                 ).await;
                 return;
             }
-            let declaration = declaration.unwrap();
-            let message_length;
-            let declaring_peer = match declaration {
-                Message::Declaration(peer, n) => {
-                    message_length = n;
-                    // add the peer to the list if and only if it is not already in the list
-                    self_clone.maybe_update_peer(peer.clone()).await.unwrap();
-                    // send a response
-                    peer
-                }
+            let (declaring_peer, message_length, format) = match declaration.unwrap() {
+                Message::Declaration(peer, n) => (peer, n, WireFormat::Bincode),
+                Message::JsonDeclaration(peer, n) => (peer, n, WireFormat::Json),
                 _ => {
                     send_error_message(
                         &mut stream,
@@ -133,33 +129,74 @@ pub async fn serve_peers(node: Node, stop_signal: Option<flume::Receiver<()>>) {
                             std::io::ErrorKind::InvalidInput,
                             "Expected peer delaration",
                         ),
+                        self_clone.wire_format,
                     )
                     .await;
                     return;
                 }
             };
+            if format != self_clone.wire_format {
+                // reply in the format the peer speaks, so that it can read why
+                send_error_message(
+                    &mut stream,
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Wire format mismatch: expected {:?}, got {:?}", self_clone.wire_format, format),
+                    ),
+                    format,
+                ).await;
+                return;
+            }
+            if declaring_peer.public_key == self_clone.inner.public_key {
+                // we dialed ourselves - drop it before it takes up a peer slot or any work
+                tracing::debug!("Dropping a connection from our own identity");
+                send_error_message(
+                    &mut stream,
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Connection to self",
+                    ),
+                    format,
+                ).await;
+                return;
+            }
+            // add the peer to the list if and only if it is not already in the list
+            self_clone.maybe_update_peer(declaring_peer.clone()).await.unwrap();
             // read actual the message
             let mut buffer = vec![0; message_length as usize];
-            let _ = stream.read_exact(&mut buffer).await.unwrap();
-            let message: Result<Message, std::io::Error> = PillarSerialize::deserialize_pillar(&buffer);
-            if message.is_err() {
-                // halt
-                send_error_message(&mut stream, message.unwrap_err()).await;
+            if !matches!(timeout(timeouts.request, stream.read_exact(&mut buffer)).await, Ok(Ok(_))) {
+                tracing::warn!("Peer {:?} did not send its message in time", declaring_peer.public_key);
                 return;
             }
-            let message = message.unwrap();
-            let response = self_clone.serve_request(&message, declaring_peer).await;
+            let message = match format.decode(&buffer) {
+                Ok(message) => message,
+                Err(e) => {
+                    // halt
+                    send_error_message(&mut stream, e, format).await;
+                    return;
+                }
+            };
+            let response = self_clone.serve_request(&message, source, declaring_peer.clone()).await;
             match response {
-                Err(e) => send_error_message(&mut stream, e).await,
+                Err(e) => send_error_message(&mut stream, e, format).await,
                 Ok(message) => {
-                    let serialized = message.serialize_pillar().unwrap();
+                    let serialized = match format.encode(&message) {
+                        Ok(serialized) => serialized,
+                        Err(e) => {
+                            send_error_message(&mut stream, e, format).await;
+                            return;
+                        }
+                    };
                     let nbytes = serialized.len() as u32;
-                    // write the size of the message as 4 bytes - 4 bytes because we are using u32
-                    stream.write_all(&nbytes.to_le_bytes()[..4]).await.unwrap();
-                    stream
-                        .write_all(&serialized)
-                        .await
-                        .unwrap();
+                    let written = timeout(timeouts.write, async {
+                        // write the size of the message as 4 bytes - 4 bytes because we are using u32
+                        stream.write_all(&nbytes.to_le_bytes()[..4]).await?;
+                        stream.write_all(&serialized).await
+                    }).await;
+                    if !matches!(written, Ok(Ok(_))) {
+                        tracing::warn!("Failed to send the response to peer {:?} in time", declaring_peer.public_key);
+                        return;
+                    }
                     tracing::debug!("Sent {} bytes to peer", nbytes);
                 }
             };
@@ -167,11 +204,53 @@ pub async fn serve_peers(node: Node, stop_signal: Option<flume::Receiver<()>>) {
     }
 }
 
-/// sends an error response when given a string description
+/// Background process that pings every peer on the keepalive interval.
+/// Peers that miss too many pings in a row are dropped
+pub async fn keep_alive(node: Node, stop_signal: Option<flume::Receiver<()>>) {
+    loop {
+        let interval = node.inner.keepalive.lock().await.interval;
+        match &stop_signal {
+            Some(signal) => if timeout(interval, signal.recv_async()).await.is_ok() {
+                return;
+            },
+            None => tokio::time::sleep(interval).await,
+        }
+        ping_peers(&node).await;
+        // the tip is only worth checking once the chain is held
+        if node.inner.state.lock().await.is_consume() {
+            let _ = check_tip_agreement(&node, ECLIPSE_SAMPLE_SIZE).await;
+        }
+    }
+}
+
+/// Pings every peer once. Any response counts as a reply, and a failure or timeout as a miss.
+///
+/// # Returns
+///
+/// * The peers dropped for missing too many pings
+#[instrument(skip_all, fields(ip = %node.ip_address, port = node.port))]
+pub async fn ping_peers(node: &Node) -> Vec<StdByteArray> {
+    let peers = node.inner.peers.lock().await.clone(); // do not hold lock
+    let mut dropped = vec![];
+    for (public_key, mut peer) in peers {
+        let reply = peer.communicate_within(&Message::Ping, &node.into(), node.connection_timeouts).await;
+        let mut keepalive = node.inner.keepalive.lock().await;
+        if reply.is_ok() {
+            keepalive.record_reply(&public_key);
+        } else if keepalive.record_miss(&public_key) {
+            tracing::warn!("Peer {:?} missed {} pings - disconnecting", public_key, keepalive.max_missed);
+            node.inner.peers.lock().await.remove(&public_key);
+            dropped.push(public_key);
+        }
+    }
+    dropped
+}
+
+/// sends an error response when given a string description, encoded in `format`
 #[instrument(skip_all)]
-async fn send_error_message(stream: &mut TcpStream, e: impl std::error::Error) {
+async fn send_error_message(stream: &mut TcpStream, e: impl std::error::Error, format: WireFormat) {
     // writye message size
-    let serialized = Message::Error(e.to_string()).serialize_pillar().unwrap();
+    let serialized = format.encode(&Message::Error(e.to_string())).unwrap();
     let nbytes = serialized.len() as u32;
     // write the size of the message as 4 bytes - 4 bytes because we are using u32
     stream.write_all(&nbytes.to_le_bytes()[..4]).await.unwrap();
@@ -209,11 +288,7 @@ mod tests {
             .await
             .unwrap();
 
-        let peer = Peer {
-            public_key: [3; 32],
-            ip_address,
-            port: 8085,
-        };
+        let peer = Peer::new([3; 32], ip_address, 8085);
 
         let declaration = Message::Declaration(peer.clone(), 0);
         let serialized = declaration.serialize_pillar().unwrap();
@@ -240,11 +315,7 @@ mod tests {
             .await
             .unwrap();
 
-        let peer = Peer {
-            public_key: [3; 32],
-            ip_address,
-            port: 8081,
-        };
+        let peer = Peer::new([3; 32], ip_address, 8081);
 
         // listen as peer to hear from server
         let listener = TcpListener::bind(format!("{}:{}", ip_address, 8081))
@@ -277,7 +348,7 @@ mod tests {
             _ => panic!("Expected a Declaration message"),
         }
 
-        let mut buffer = vec![0; serialized_message.len() as usize];
+        let mut buffer = vec![0; serialized_message.len()];
         let n = peer_stream.read_exact(&mut buffer).await.unwrap();
         let message: Message = PillarSerialize::deserialize_pillar(&buffer[..n]).unwrap();
         match message {
@@ -374,4 +445,103 @@ mod tests {
         assert!(elapsed.as_secs() < 3, "Server did not stop in time");
     }
 
+    #[tokio::test]
+    async fn test_unresponsive_peer_disconnected(){
+        let ip_address = IpAddr::V4(Ipv4Addr::from_str("127.0.0.22").unwrap());
+        // accepts connections and reads, but never answers
+        let silent = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.23").unwrap()), 8106);
+        let listener = TcpListener::bind(format!("{}:{}", silent.ip_address, silent.port)).await.unwrap();
+        tokio::spawn(async move {
+            let mut open = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                open.push(stream);
+            }
+        });
+        let responsive = Node::new([4; 32], [5; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.24").unwrap()), 8107, vec![], None, None);
+        tokio::spawn(serve_peers(responsive.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut node = Node::new([1; 32], [2; 32], ip_address, 8108, vec![silent.clone(), (&responsive).into()], None, None);
+        node.connection_timeouts.read = std::time::Duration::from_millis(200);
+        for missed in 1..3 {
+            assert!(ping_peers(&node).await.is_empty());
+            assert_eq!(node.inner.keepalive.lock().await.missed(&silent.public_key), missed);
+        }
+        assert_eq!(ping_peers(&node).await, vec![silent.public_key]);
+        let peers = node.inner.peers.lock().await;
+        assert!(!peers.contains_key(&silent.public_key));
+        assert!(peers.contains_key(&[4; 32]));
+        assert_eq!(node.inner.keepalive.lock().await.missed(&[4; 32]), 0);
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_dropped(){
+        let node = Node::new([1; 32], [2; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.54").unwrap()), 8139, vec![], None, None);
+        tokio::spawn(serve_peers(node.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        // connect, and never send a declaration
+        let mut stream = TcpStream::connect(format!("{}:{}", node.ip_address, node.port)).await.unwrap();
+        let now = std::time::Instant::now();
+        let mut buffer = [0; 1];
+        let read = timeout(std::time::Duration::from_secs(5), stream.read(&mut buffer)).await;
+        // the server gives up with an error, rather than waiting on the connection
+        assert!(read.is_ok(), "Server kept waiting on a silent connection");
+        assert!(now.elapsed() < node.connection_timeouts.request * 2);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_stops(){
+        let node = Node::new([1; 32], [2; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.22").unwrap()), 8109, vec![], None, None);
+        let (sender, stop_signal) = flume::bounded(1);
+        let handle = tokio::spawn(keep_alive(node, Some(stop_signal)));
+        let now = std::time::Instant::now();
+        sender.send(()).unwrap();
+        handle.await.unwrap();
+        assert!(now.elapsed().as_millis() < 500, "Keepalive did not stop in time");
+    }
+
+    #[tokio::test]
+    async fn test_wire_format_mismatch(){
+        let mut node = Node::new([1; 32], [2; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.27").unwrap()), 8112, vec![], None, None);
+        node.wire_format = WireFormat::Json;
+        tokio::spawn(serve_peers(node.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut server: Peer = (&node).into();
+        let bincode_client = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.28").unwrap()), 8113);
+        match server.communicate(&Message::Ping, &bincode_client).await.unwrap() {
+            Message::Error(e) => assert!(e.contains("Wire format mismatch"), "{e}"),
+            other => panic!("Expected a mismatch error, got {other:?}"),
+        }
+        // the mismatched peer is not remembered
+        assert!(!node.inner.peers.lock().await.contains_key(&[3; 32]));
+
+        let json_client = bincode_client.with_wire_format(WireFormat::Json);
+        assert!(matches!(server.communicate(&Message::Ping, &json_client).await.unwrap(), Message::Ping));
+        assert!(node.inner.peers.lock().await.contains_key(&[3; 32]));
+    }
+
+    #[tokio::test]
+    async fn test_self_connection_rejected(){
+        let ip_address = IpAddr::V4(Ipv4Addr::from_str("127.0.0.33").unwrap());
+        let itself = Peer::new([1; 32], ip_address, 8118);
+        // a node configured with itself as a peer does not keep it
+        let node = Node::new([1; 32], [2; 32], ip_address, 8118, vec![itself.clone()], None, None);
+        assert!(node.inner.peers.lock().await.is_empty());
+        tokio::spawn(serve_peers(node.clone(), None));
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        let mut server: Peer = (&node).into();
+        match server.communicate(&Message::Ping, &(&node).into()).await.unwrap() {
+            Message::Error(e) => assert_eq!(e, "Connection to self"),
+            other => panic!("Expected a self connection error, got {other:?}"),
+        }
+        assert!(node.inner.peers.lock().await.is_empty());
+
+        // another peer is still served
+        let client = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.34").unwrap()), 8119);
+        assert!(matches!(server.communicate(&Message::Ping, &client).await.unwrap(), Message::Ping));
+        assert_eq!(node.inner.peers.lock().await.len(), 1);
+    }
+
 }
@@ -1,15 +1,21 @@
-use crate::protocol::reputation::N_TRANSMISSION_SIGNATURES;
+use crate::protocol::{params::ChainParams, reputation::N_TRANSMISSION_SIGNATURES};
 
 const INITIAL_BLOCK_REWARD: u64 = 10_000;
 pub const MIN_DIFFICULTY: u64 = 4; // minimum difficulty for the first 500 blocks
+pub const RETARGET_WINDOW: u64 = 500; // blocks between difficulty steps
+pub const DIFFICULTY_STEP: u64 = 2; // difficulty added at each retarget boundary
 
-/// get more difficult after every 500 blocks
-/// the schedule is 4 + 2*(depth // 500)
-pub fn _get_base_difficulty_from_depth(depth: u64) -> u64{
+/// get more difficult after every retarget window
+/// below the first full window the bootstrap difficulty is used,
+/// after that the schedule is min_difficulty + step*(depth // window)
+pub fn get_difficulty_from_depth(depth: u64, params: &ChainParams) -> u64{
     if depth == 0{
         return 0; // genesis block
     }
-    MIN_DIFFICULTY+2*(depth/500)
+    if depth < params.retarget_window{
+        return params.bootstrap_difficulty;
+    }
+    params.min_difficulty.saturating_add(params.difficulty_step.saturating_mul(depth / params.retarget_window))
 }
 
 /// Get the reward to pay to the miner
@@ -29,20 +35,48 @@ pub fn get_reward_from_depth_and_stampers(depth: u64, n_stampers: usize) -> u64{
 
 #[cfg(test)]
 mod test{
-    use crate::protocol::{difficulty::{_get_base_difficulty_from_depth, get_reward_from_depth_and_stampers, INITIAL_BLOCK_REWARD}, reputation::N_TRANSMISSION_SIGNATURES};
+    use crate::protocol::{difficulty::{get_difficulty_from_depth, DIFFICULTY_STEP, MIN_DIFFICULTY, RETARGET_WINDOW, get_reward_from_depth_and_stampers, INITIAL_BLOCK_REWARD}, params::ChainParams, reputation::N_TRANSMISSION_SIGNATURES};
 
     #[test]
     fn test_initial(){
         for i in 1..499{
-            assert!(_get_base_difficulty_from_depth(i) == 4);
+            assert!(get_difficulty_from_depth(i, &ChainParams::default()) == 4);
         }
     }
 
     #[test]
     fn test_second(){
         for i in 500..999{
-            assert!(_get_base_difficulty_from_depth(i) == 6)
+            assert!(get_difficulty_from_depth(i, &ChainParams::default()) == 6)
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_window(){
+        let params = ChainParams {
+            retarget_window: 20,
+            bootstrap_difficulty: 1,
+            ..Default::default()
+        };
+        assert_eq!(get_difficulty_from_depth(0, &params), 0);
+        for i in 1..20{
+            assert_eq!(get_difficulty_from_depth(i, &params), 1);
         }
+        // the first retarget boundary moves onto the regular schedule
+        assert_eq!(get_difficulty_from_depth(20, &params), params.min_difficulty + params.difficulty_step);
+        assert_eq!(get_difficulty_from_depth(39, &params), params.min_difficulty + params.difficulty_step);
+        assert_eq!(get_difficulty_from_depth(40, &params), params.min_difficulty + 2 * params.difficulty_step);
+    }
+
+    #[test]
+    fn test_difficulty_boundaries(){
+        let params = ChainParams {
+            retarget_window: 1,
+            difficulty_step: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(get_difficulty_from_depth(u64::MAX, &params), u64::MAX);
+        assert_eq!(get_difficulty_from_depth(u64::MAX, &ChainParams::default()), MIN_DIFFICULTY + DIFFICULTY_STEP * (u64::MAX / RETARGET_WINDOW));
     }
 
     #[test]
@@ -4,4 +4,7 @@ pub mod pow;
 pub mod difficulty;
 pub mod transactions;
 pub mod communication;
-pub mod reputation;
\ No newline at end of file
+pub mod reputation;
+pub mod params;
+pub mod clock;
+pub mod reward;
\ No newline at end of file
@@ -0,0 +1,179 @@
+use std::str::FromStr;
+
+use pillar_crypto::types::StdByteArray;
+
+use crate::{primitives::block::{Ancestry, MAX_FUTURE_DRIFT}, protocol::difficulty::{DIFFICULTY_STEP, MIN_DIFFICULTY, RETARGET_WINDOW}};
+
+/// Consensus parameters for a chain
+/// The defaults are the parameters of the main chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainParams {
+    /// the number of blocks between difficulty steps
+    pub retarget_window: u64,
+    /// the difficulty used below the first full retarget window
+    pub bootstrap_difficulty: u64,
+    /// the difficulty the schedule is built from after the bootstrap window
+    pub min_difficulty: u64,
+    /// the increase in difficulty applied at every retarget boundary
+    pub difficulty_step: u64,
+    /// a block known to be on the canonical chain
+    pub checkpoint: Option<Checkpoint>,
+    /// how far into the future a block timestamp may be, in seconds
+    pub max_future_drift: u64,
+    /// blocks up to this many seconds past the drift are held until valid, instead of rejected
+    pub future_hold_window: u64,
+    /// how many blocks back a difficulty target may lag the expected one, to tolerate propagation races
+    pub difficulty_grace_window: u64,
+    /// a block trusted to be valid, to speed up initial sync
+    ///
+    /// THIS IS A TRUST ASSUMPTION. Transaction signatures in blocks at or below its depth are not checked,
+    /// so a node is only as safe as whoever chose this hash. Proof of work, linkage, balances, nonces,
+    /// and the state root are still checked, and the block at its depth must have its hash.
+    /// Everything above it is fully validated.
+    pub assume_valid: Option<Checkpoint>,
+    /// let a block have the same timestamp as its parent, instead of requiring a later one.
+    /// Timestamps are in whole seconds, so chains producing several blocks a second need this
+    pub allow_equal_timestamps: bool,
+    /// stricter rules turned on from a depth onward, without a hard fork - a rule activated at depth 0 holds for the whole chain
+    pub soft_forks: SoftForks,
+}
+
+/// A trusted (depth, hash) pair - any block at this depth must have this hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub depth: u64,
+    pub hash: StdByteArray,
+}
+
+/// Parses a checkpoint given as `<depth>:<hash>`, with the hash as 64 hex characters - such as from a `--checkpoint` option
+impl FromStr for Checkpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (depth, hash) = s.split_once(':').ok_or("Expected <depth>:<hash>")?;
+        let depth = depth.trim().parse::<u64>().map_err(|e| format!("Bad checkpoint depth: {e}"))?;
+        let hash = hash.trim();
+        if hash.len() != 64 || !hash.is_ascii() {
+            return Err("Checkpoint hash must be 64 hex characters".into());
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hash[2 * i..2 * i + 2], 16).map_err(|e| format!("Bad checkpoint hash: {e}"))?;
+        }
+        Ok(Checkpoint { depth, hash: bytes })
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            retarget_window: RETARGET_WINDOW,
+            bootstrap_difficulty: MIN_DIFFICULTY,
+            min_difficulty: MIN_DIFFICULTY,
+            difficulty_step: DIFFICULTY_STEP,
+            checkpoint: None,
+            max_future_drift: MAX_FUTURE_DRIFT,
+            future_hold_window: 0,
+            difficulty_grace_window: 0,
+            assume_valid: None,
+            allow_equal_timestamps: false,
+            soft_forks: SoftForks::default(),
+        }
+    }
+}
+
+/// A stricter validation rule, that a soft fork can activate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// reject transactions from senders without an account, instead of treating them as empty accounts.
+    /// Empty accounts can still send zero amount transactions, such as key rotations.
+    RejectUnknownSenders,
+    /// require every block to carry a VRF proof, by its miner, over the seed of the previous block.
+    /// Off for pure proof of work - this is groundwork for leader election.
+    RequireVrfProof,
+    /// reject blocks whose miner address is not a valid public key under the signature scheme,
+    /// so rewards cannot be paid to an address nobody can spend from
+    RequireValidMinerAddress,
+}
+
+const N_RULES: usize = 3;
+
+/// The depth at which each rule activates. Blocks below it follow the old rules,
+/// so they stay valid to nodes that know the fork, and new blocks stay valid to nodes that do not.
+///
+/// Activation is by depth alone. Block headers carry no version field, so miners cannot signal readiness for a fork,
+/// and every node must be configured with the same activation depth. The rules are enforced wherever a block is
+/// validated - by the chain, and by `Block::connect_to_header`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SoftForks {
+    activations: [Option<u64>; N_RULES],
+}
+
+impl SoftForks {
+    /// Enforce `rule` on blocks at `depth` and deeper
+    pub fn activate(mut self, rule: Rule, depth: u64) -> Self {
+        self.activations[rule as usize] = Some(depth);
+        self
+    }
+
+    /// The depth `rule` activates at, if it is scheduled
+    pub fn activation_depth(&self, rule: Rule) -> Option<u64> {
+        self.activations[rule as usize]
+    }
+
+    /// Whether a soft fork enforces `rule` on a block at `depth`
+    pub fn is_active(&self, rule: Rule, depth: u64) -> bool {
+        self.activation_depth(rule).is_some_and(|activation| depth >= activation)
+    }
+}
+
+impl ChainParams {
+    /// Whether `rule` applies to a block at `depth`
+    pub fn enforces(&self, rule: Rule, depth: u64) -> bool {
+        self.soft_forks.is_active(rule, depth)
+    }
+
+    /// The earliest timestamp a child of a block with `parent_timestamp` may have
+    pub fn earliest_timestamp_after(&self, parent_timestamp: u64) -> u64 {
+        if self.allow_equal_timestamps {
+            parent_timestamp
+        } else {
+            parent_timestamp.saturating_add(1)
+        }
+    }
+
+    /// Whether `timestamp` is late enough for a child of a block with `parent_timestamp`.
+    /// It must be later, or may be equal if `allow_equal_timestamps` is set
+    pub fn is_timestamp_ordered(&self, parent_timestamp: u64, timestamp: u64) -> bool {
+        if self.allow_equal_timestamps {
+            timestamp >= parent_timestamp
+        } else {
+            timestamp > parent_timestamp
+        }
+    }
+
+    /// Whether transaction signatures in the block with `hash` are trusted through `assume_valid` -
+    /// only if `ancestry` shows the assumed valid block builds on it. Being below it is not enough
+    pub fn is_assumed_valid(&self, hash: StdByteArray, ancestry: &impl Ancestry) -> bool {
+        self.assume_valid.is_some_and(|assume_valid| ancestry.is_ancestor(hash, assume_valid.hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checkpoint;
+
+    #[test]
+    fn test_parse_checkpoint() {
+        let hash = "00".repeat(31) + "ff";
+        let checkpoint: Checkpoint = format!("12:{hash}").parse().unwrap();
+        assert_eq!(checkpoint.depth, 12);
+        assert_eq!(checkpoint.hash[..31], [0; 31]);
+        assert_eq!(checkpoint.hash[31], 0xff);
+        assert_eq!(format!(" 12 : {} ", hash.to_uppercase()).parse::<Checkpoint>(), Ok(checkpoint));
+
+        for bad in ["12", format!("x:{hash}").as_str(), "12:00ff", format!("12:{hash}00").as_str(), format!("12:{}zz", &hash[..62]).as_str()] {
+            assert!(bad.parse::<Checkpoint>().is_err(), "{bad}");
+        }
+    }
+}
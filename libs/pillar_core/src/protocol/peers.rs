@@ -1,6 +1,32 @@
 use std::collections::HashSet;
 
-use crate::{nodes::{node::Node, peer::Peer}, primitives::messages::Message};
+use pillar_crypto::hashing::{DefaultHash, Hashable};
+use rand::{rng, seq::IteratorRandom};
+
+use crate::{nodes::{node::Node, peer::Peer}, primitives::{errors::QueryError, messages::Message}};
+
+use super::chain::query_header_at_depth;
+
+/// The default number of peers asked for their header at the tip depth in each eclipse check
+pub const ECLIPSE_SAMPLE_SIZE: usize = 8;
+
+/// How the headers sampled peers hold at the local tip depth compare with the local tip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TipAgreement {
+    /// peers with the local tip at its depth
+    pub agreeing: usize,
+    /// peers with another block at the depth
+    pub disagreeing: usize,
+    /// peers that failed to answer, or whose chains are not that deep
+    pub unanswered: usize,
+}
+
+impl TipAgreement {
+    /// Whether most of the peers that answered disagree with the local tip - a sign the node may be eclipsed
+    pub fn is_alert(&self) -> bool {
+        self.disagreeing > self.agreeing
+    }
+}
 
 /// Find new peers by queerying the existing peers
 /// and adding them to the list of peers
@@ -44,6 +70,84 @@ pub async fn discover_peers(node: &mut Node) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Makes `request` to a random peer, retrying on failure under the node's retry policy.
+/// Each retry waits out the policy's backoff, then goes to a peer not yet tried - or any peer, once all have been.
+/// Failures count against the peer, and a peer that fails too many requests in a row is dropped.
+///
+/// # Returns
+/// * `Ok(response)` - The first successful response
+/// * `Err(e)` - The last failure, or `NoReply` if there are no peers to ask
+pub async fn request_with_retries<T, F, Fut>(node: &Node, mut request: F) -> Result<T, QueryError>
+where
+    F: FnMut(Peer) -> Fut,
+    Fut: Future<Output = Result<T, QueryError>>,
+{
+    let policy = node.retry_policy;
+    let mut tried = HashSet::new();
+    let mut last_error = QueryError::NoReply;
+    for attempt in 0..policy.max_attempts.max(1) {
+        if attempt > 0 {
+            tokio::time::sleep(policy.delay(attempt)).await;
+        }
+        let peer = {
+            let peers = node.inner.peers.lock().await;
+            let untried = peers.values().filter(|peer| !tried.contains(&peer.public_key)).choose(&mut rng());
+            match untried.or_else(|| peers.values().choose(&mut rng())) {
+                Some(peer) => peer.clone(),
+                None => break,
+            }
+        };
+        tried.insert(peer.public_key);
+        match request(peer.clone()).await {
+            Ok(response) => {
+                node.inner.request_failures.lock().await.record_success(&peer.public_key);
+                return Ok(response);
+            }
+            Err(e) => {
+                tracing::debug!("Request to peer {:?} failed on attempt {}: {}", peer.public_key, attempt + 1, e);
+                let mut failures = node.inner.request_failures.lock().await;
+                if failures.record_failure(&peer.public_key) {
+                    tracing::warn!("Peer {:?} failed {} requests in a row - disconnecting", peer.public_key, failures.max_failures);
+                    node.inner.peers.lock().await.remove(&peer.public_key);
+                }
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Asks up to `sample_size` random peers for their header at the depth of the local tip, and compares it with the tip.
+/// Logs a warning if most of the peers that answer disagree, as the peers the node syncs from may all be on another chain.
+///
+/// # Returns
+/// * `Ok(agreement)` - How the sampled peers compare with the local tip
+/// * `Err(InsufficientInfo)` - The node has no chain to check
+pub async fn check_tip_agreement(node: &Node, sample_size: usize) -> Result<TipAgreement, QueryError> {
+    let (tip, depth) = match node.inner.chain.lock().await.as_ref() {
+        Some(chain) => (chain.deepest_hash, chain.depth),
+        None => return Err(QueryError::InsufficientInfo("No chain to check".into())),
+    };
+    let sample = node.inner.peers.lock().await.values().cloned().choose_multiple(&mut rng(), sample_size);
+    let initializing_peer: Peer = node.into();
+    let mut agreement = TipAgreement::default();
+    for mut peer in sample {
+        let header = query_header_at_depth(&mut peer, &initializing_peer, depth).await;
+        match header.ok().flatten().map(|header| header.hash(&mut DefaultHash::new())) {
+            Some(Ok(hash)) if hash == tip => agreement.agreeing += 1,
+            Some(Ok(_)) => agreement.disagreeing += 1,
+            _ => agreement.unanswered += 1,
+        }
+    }
+    if agreement.is_alert() {
+        tracing::warn!(
+            "{} of {} sampled peers disagree with the tip at depth {} - the node may be eclipsed",
+            agreement.disagreeing, agreement.agreeing + agreement.disagreeing, depth
+        );
+    }
+    Ok(agreement)
+}
+
 #[cfg(test)]
 mod tests {
     use pillar_crypto::serialization::PillarSerialize;
@@ -52,16 +156,15 @@ mod tests {
     use super::*;
     use crate::nodes::{node::Node, peer::Peer};
     use crate::primitives::messages::{get_declaration_length, Versions};
+    use crate::nodes::retry::{RequestFailures, RetryPolicy};
     use std::net::{IpAddr, Ipv4Addr};
     use std::str::FromStr;
+    use std::sync::{atomic::{AtomicU32, Ordering}, Arc};
+    use std::time::{Duration, Instant};
 
     #[tokio::test]
     async fn test_discover_peers_adds_new_peers() {
-        let existing_peer = Peer {
-            public_key: [3; 32],
-            ip_address: IpAddr::V4(Ipv4Addr::from_str("127.0.0.2").unwrap()),
-            port: 8081,
-        };
+        let existing_peer = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.2").unwrap()), 8081);
         let listener = tokio::net::TcpListener::bind(format!(
             "{}:{}",
             existing_peer.ip_address, existing_peer.port
@@ -78,16 +181,8 @@ mod tests {
         );
  
         // Mock new peer to be discovered
-        let new_peer = Peer {
-            public_key: [4; 32],
-            ip_address: IpAddr::V4(Ipv4Addr::from_str("127.0.0.3").unwrap()),
-            port: 8082,
-        };
-        let new_peer2 = Peer {
-            public_key: [5; 32],
-            ip_address: IpAddr::V4(Ipv4Addr::from_str("127.0.0.3").unwrap()),
-            port: 8082,
-        };
+        let new_peer = Peer::new([4; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.3").unwrap()), 8082);
+        let new_peer2 = Peer::new([5; 32], IpAddr::V4(Ipv4Addr::from_str("127.0.0.3").unwrap()), 8082);
 
         // Mock peer response
         let mock_response = Message::PeerResponse(vec![new_peer.clone(), new_peer2.clone()]);
@@ -107,7 +202,7 @@ mod tests {
                 }
                 _ => panic!("Expected a declaration message"),
             }
-            let mut buffer = vec![0; serialized.len() as usize];
+            let mut buffer = vec![0; serialized.len()];
             stream.read_exact(&mut buffer).await.unwrap();
             let message: Message = PillarSerialize::deserialize_pillar(&buffer).unwrap();
             match message {
@@ -130,4 +225,48 @@ mod tests {
         assert!(peers.contains_key(&new_peer2.public_key));
         assert_eq!(peers.len(), 3); // Existing + new peer
     }
+
+    #[tokio::test]
+    async fn test_retry_falls_back_to_another_peer() {
+        let flaky = Peer::new([3; 32], IpAddr::V4(Ipv4Addr::LOCALHOST), 8081);
+        let good = Peer::new([4; 32], IpAddr::V4(Ipv4Addr::LOCALHOST), 8082);
+        let mut node = Node::new([1; 32], [2; 32], IpAddr::V4(Ipv4Addr::LOCALHOST), 8080, vec![flaky.clone(), good.clone()], None, None);
+        node.retry_policy = RetryPolicy { max_attempts: 4, base_delay: Duration::from_millis(20), jitter: Duration::ZERO };
+
+        // the flaky peer always fails - whichever peer is asked first, the good one answers within two attempts
+        let asked = Arc::new(std::sync::Mutex::new(vec![]));
+        let response = request_with_retries(&node, |peer| {
+            let asked = asked.clone();
+            async move {
+                asked.lock().unwrap().push(peer.public_key);
+                if peer.public_key == [3; 32] { Err(QueryError::NoReply) } else { Ok(peer.public_key) }
+            }
+        }).await;
+        assert_eq!(response.unwrap(), good.public_key);
+        let asked = asked.lock().unwrap().clone();
+        assert_eq!(asked.last(), Some(&good.public_key));
+        assert!(asked.len() <= 2 && !asked[..asked.len() - 1].contains(&good.public_key));
+
+        // a single peer failing twice is retried after 20 then 40ms
+        node.inner.peers.lock().await.remove(&good.public_key);
+        let attempts = Arc::new(AtomicU32::new(0));
+        let start = Instant::now();
+        let response = request_with_retries(&node, |_| {
+            let attempts = attempts.clone();
+            async move {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 { Err(QueryError::NoReply) } else { Ok(()) }
+            }
+        }).await;
+        assert!(response.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        // a success resets the peer's failures
+        assert_eq!(node.inner.request_failures.lock().await.failures(&flaky.public_key), 0);
+
+        // a peer that keeps failing is dropped, and then there is no one left to ask
+        *node.inner.request_failures.lock().await = RequestFailures::new(2);
+        let response: Result<(), _> = request_with_retries(&node, |_| async { Err(QueryError::InvalidResponse) }).await;
+        assert!(matches!(response, Err(QueryError::InvalidResponse)));
+        assert!(node.inner.peers.lock().await.is_empty());
+    }
 }
@@ -1,16 +1,51 @@
-use std::cmp::min;
+use std::{cmp::min, ops::RangeInclusive};
 
 use flume::Receiver;
-use pillar_crypto::{hashing::{HashFunction, Hashable}, types::StdByteArray};
+use pillar_crypto::{hashing::{DefaultHash, HashFunction, Hashable}, types::StdByteArray};
 
 
 use crate::primitives::block::{Block, BlockHeader};
 
-use super::difficulty::_get_base_difficulty_from_depth;
+use super::{difficulty::get_difficulty_from_depth, params::ChainParams};
 
 pub const POR_THRESHOLD: f64 = 50f64;
 pub const POR_INCLUSION_MINIMUM: f64 = 1f64;
 pub const POR_MINER_SHARE_DIVISOR: u64 = 2;
+/// the nonces each mining worker has to itself - the nonce space split between 2^16 workers
+pub const NONCES_PER_WORKER: u64 = 1 << 48;
+/// domain separation for the starting nonce of mining workers
+const WORKER_NONCE_TAG: &[u8] = b"pillar/worker-nonce";
+
+/// One of a miner's mining workers.
+/// Workers start grinding from a nonce derived from the miner address, the extranonce and their index,
+/// rather than from 0, so two workers of the same miner never grind the same nonces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MiningWorker {
+    /// the index of the worker among the miner's workers
+    pub index: u16,
+    /// moves every worker to fresh nonces - change it to grind the same block again
+    pub extranonce: u64,
+}
+
+impl MiningWorker {
+    pub fn new(index: u16, extranonce: u64) -> Self {
+        MiningWorker { index, extranonce }
+    }
+
+    /// The nonces this worker grinds when mining as `address`.
+    /// Workers with the same address and extranonce get disjoint ranges, aligned to `NONCES_PER_WORKER`
+    pub fn nonces(&self, address: &StdByteArray) -> RangeInclusive<u64> {
+        let mut hasher = DefaultHash::new();
+        hasher.update(WORKER_NONCE_TAG);
+        hasher.update(address);
+        hasher.update(self.extranonce.to_le_bytes());
+        let digest = hasher.digest().expect("Hashing failed");
+        let base = u64::from_le_bytes(digest[..8].try_into().unwrap()) & !(NONCES_PER_WORKER - 1);
+        // aligned, so the range never wraps
+        let start = base.wrapping_add(self.index as u64 * NONCES_PER_WORKER);
+        start..=start + (NONCES_PER_WORKER - 1)
+    }
+}
 
 pub fn is_valid_hash(difficulty: u64, hash: &StdByteArray) -> bool {
     // check for 'difficulty' leading 0 bits
@@ -26,6 +61,23 @@ pub fn is_valid_hash(difficulty: u64, hash: &StdByteArray) -> bool {
     leading_zeros >= difficulty
 }
 
+/// The expected number of hashes needed to meet a difficulty target
+/// Difficulty is a count of leading zero bits, so the work is 2^difficulty
+pub fn get_work_from_difficulty(difficulty: u64) -> u128 {
+    if difficulty >= u128::BITS as u64 {
+        return u128::MAX;
+    }
+    1u128 << difficulty
+}
+
+/// Whether the cummulative reputation of the stampers is high enough to mine under PoR
+pub fn is_por_enabled(reputations: &[f64]) -> bool {
+    let cummulative_reputation: f64 = reputations.iter().filter(
+        |&&rep| rep >= POR_INCLUSION_MINIMUM
+    ).sum();
+    cummulative_reputation > POR_THRESHOLD
+}
+
 /// Get the difficulty for a block based on its header and the state trie
 /// This function enables swap to PoR (Proof of Reputation) mining
 /// Difficulty is reduced if the cummulative reputation of the stampers is above a threshold
@@ -34,20 +86,21 @@ pub fn is_valid_hash(difficulty: u64, hash: &StdByteArray) -> bool {
 /// # Arguments
 /// * `header` - the block header
 /// * `reputations` - the reputations of the stampers
+/// * `params` - the consensus parameters of the chain
 pub fn get_difficulty_for_block(
     header: &BlockHeader, 
-    reputations: &Vec<f64>,
+    reputations: &[f64],
+    params: &ChainParams,
 ) -> (u64, bool) {
-    let cummulative_reputation: f64 = reputations.iter().filter(
-        |&&rep| rep >= POR_INCLUSION_MINIMUM
-    ).sum();
-
-    if cummulative_reputation > POR_THRESHOLD {
+    if is_por_enabled(reputations) {
+        let cummulative_reputation: f64 = reputations.iter().filter(
+            |&&rep| rep >= POR_INCLUSION_MINIMUM
+        ).sum();
         // if the cummulative reputation is above the threshold, we use the depth to determine difficulty
         // reduce the depth argument. -1 depth for every 10 reputation points
-        return (_get_base_difficulty_from_depth(min(1, header.depth - (cummulative_reputation / 10.0) as u64)), true);
+        return (get_difficulty_from_depth(min(1, header.depth.saturating_sub((cummulative_reputation / 10.0) as u64)), params), true);
     }
-    (_get_base_difficulty_from_depth(header.depth), false)
+    (get_difficulty_from_depth(header.depth, params), false)
 }
 
 pub async fn mine(
@@ -55,21 +108,50 @@ pub async fn mine(
     address: StdByteArray,
     state_root: StdByteArray,
     reputations: Vec<f64>,
+    params: &ChainParams,
     abort_signal: Option<Receiver<u64>>, 
-    mut hash_function: impl HashFunction
+    hash_function: impl HashFunction
 ){
-    // the block is already pupulated
-    let (difficulty, _) = get_difficulty_for_block(&block.header, &reputations);
+    let difficulty = prepare_for_mining(block, address, state_root, &reputations, params);
+    grind(block, difficulty, 0..=u64::MAX, abort_signal, hash_function);
+}
+
+/// Mines like `mine`, grinding only the nonces of `worker`.
+/// If the worker runs out of nonces, the block stays unmined
+pub async fn mine_as_worker(
+    block: &mut Block, 
+    address: StdByteArray,
+    state_root: StdByteArray,
+    reputations: Vec<f64>,
+    params: &ChainParams,
+    abort_signal: Option<Receiver<u64>>, 
+    worker: MiningWorker,
+){
+    let difficulty = prepare_for_mining(block, address, state_root, &reputations, params);
+    grind(block, difficulty, worker.nonces(&address), abort_signal, DefaultHash::new());
+}
 
-    block.header.nonce = 0;
-    block.header.miner_address = Some(address);
+/// Fills in the header fields that are fixed while mining, and returns the difficulty to meet
+fn prepare_for_mining(block: &mut Block, address: StdByteArray, state_root: StdByteArray, reputations: &[f64], params: &ChainParams) -> u64 {
+    // the block is already pupulated
+    let (difficulty, _) = get_difficulty_for_block(&block.header, reputations, params);
+    block.set_miner(address);
     block.header.state_root = Some(state_root);
     block.header.difficulty_target = Some(difficulty);
+    difficulty
+}
+
+/// Tries the nonces in order until the header hash meets the difficulty, the nonces run out, or mining is aborted
+fn grind(block: &mut Block, difficulty: u64, nonces: RangeInclusive<u64>, abort_signal: Option<Receiver<u64>>, mut hash_function: impl HashFunction) {
+    let last = *nonces.end();
+    block.header.nonce = *nonces.start();
     loop {
         match block.header.hash(&mut hash_function){
             Ok(hash) => {
                 if is_valid_hash(difficulty, &hash) {
-                    block.hash = Some(hash);
+                    // store the hash through the header it came from, so the two cannot disagree
+                    let finalized = block.finalize(&mut hash_function).expect("Hashing failed");
+                    debug_assert_eq!(finalized, hash);
                     break;
                 }
             },
@@ -77,12 +159,129 @@ pub async fn mine(
                 panic!("Hashing failed");
             }
         }
-        if let Some(ref signal) = abort_signal{
-            if let Ok(d) = signal.try_recv() {
-                // if we receive a signal to abort, we stop mining
-                if d == block.header.depth {return;}
-            }
+        if let Some(ref signal) = abort_signal
+            && let Ok(d) = signal.try_recv() {
+            // if we receive a signal to abort, we stop mining
+            if d == block.header.depth {return;}
+        }
+        if block.header.nonce == last {
+            return; // nonces exhausted - the block stays unmined
         }
         block.header.nonce += 1;
     }
-}
\ No newline at end of file
+}
+
+/// Whether the difficulty target of a block is acceptable
+/// The target must be the expected difficulty, or, within the grace window of the chain parameters,
+/// the difficulty expected for one of the preceding depths.
+/// 
+/// # Arguments
+/// * `header` - the block header
+/// * `reputations` - the reputations of the stampers
+/// * `params` - the consensus parameters of the chain
+pub fn is_difficulty_accepted(
+    header: &BlockHeader,
+    reputations: &[f64],
+    params: &ChainParams,
+) -> bool {
+    let Some(target) = header.difficulty_target else {
+        return false;
+    };
+    (0..=params.difficulty_grace_window.min(header.depth)).any(|lag| {
+        let mut lagging = *header;
+        lagging.depth = header.depth - lag;
+        get_difficulty_for_block(&lagging, reputations, params).0 == target
+    })
+}
+
+/// Whether the difficulty committed in a header is one the retarget allows, without any chain state
+/// Light clients use this to trust the committed difficulty after checking the proof of work.
+/// The difficulty must be the retarget for the depth, or for a depth within the grace window, so it is never
+/// easier than the retarget allows. Stamps do not change this - a block mined under PoR commits to an easier
+/// difficulty that only the reputations of its stampers justify, and those are not known without the chain.
+pub fn is_committed_difficulty_valid(header: &BlockHeader, params: &ChainParams) -> bool {
+    let Some(committed) = header.difficulty_target else {
+        return false;
+    };
+    // only the genesis block has the difficulty of depth 0
+    let lowest = header.depth.saturating_sub(params.difficulty_grace_window).max(header.depth.min(1));
+    (lowest..=header.depth).any(|depth| committed == get_difficulty_from_depth(depth, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use pillar_crypto::{hashing::DefaultHash, signing::{DefaultSigner, SigFunction, SigVerFunction, Signable}};
+
+    use crate::{blockchain::chain::Chain, primitives::{block::{Block, BlockTail, Stamp}, transaction::Transaction}, protocol::{difficulty::get_difficulty_from_depth, params::ChainParams}};
+
+    use super::{is_committed_difficulty_valid, mine_as_worker, MiningWorker, NONCES_PER_WORKER};
+
+    #[test]
+    fn test_worker_nonces_disjoint() {
+        let address = [4; 32];
+        let ranges = (0..8).map(|index| MiningWorker::new(index, 0).nonces(&address)).collect::<Vec<_>>();
+        for (i, a) in ranges.iter().enumerate() {
+            assert_eq!(a.end() - a.start(), NONCES_PER_WORKER - 1);
+            for b in &ranges[i + 1..] {
+                assert!(a.end() < b.start() || b.end() < a.start(), "{a:?} overlaps {b:?}");
+            }
+        }
+        // the start is derived, not 0, and depends on the miner and the extranonce
+        assert_eq!(MiningWorker::new(3, 0).nonces(&address), ranges[3]);
+        assert_ne!(MiningWorker::new(3, 1).nonces(&address), ranges[3]);
+        assert_ne!(MiningWorker::new(3, 0).nonces(&[5; 32]), ranges[3]);
+        // the last worker does not wrap
+        let last = MiningWorker::new(u16::MAX, 0).nonces(&address);
+        assert!(last.start() < last.end());
+    }
+
+    #[tokio::test]
+    async fn test_worker_mined_block_validates() {
+        let mut chain = Chain::new_with_genesis();
+        let parent = chain.headers[&chain.deepest_hash];
+        let mut signing_key = DefaultSigner::generate_random();
+        let mut transaction = Transaction::new(signing_key.get_verifying_function().to_bytes(), [3; 32], 0, 0, 0, &mut DefaultHash::new());
+        transaction.sign(&mut signing_key);
+        let (address, worker) = ([4; 32], MiningWorker::new(5, 9));
+        let mut block = Block::new(
+            chain.deepest_hash, 0, chain.clock.now(), vec![transaction], Some(address),
+            BlockTail::default().stamps, 1, None, None, &mut DefaultHash::new()
+        );
+        let state_root = chain.state_manager.branch_from_block(&block, &parent);
+        mine_as_worker(&mut block, address, state_root, vec![], &chain.params, None, worker).await;
+        assert!(worker.nonces(&address).contains(&block.header.nonce));
+        assert!(chain.add_new_block(block).is_ok());
+    }
+
+    #[test]
+    fn test_committed_difficulty_never_easier() {
+        let mut params = ChainParams { retarget_window: 10, ..Default::default() };
+        let mut header = Block::new(
+            [0; 32], 0, 0, vec![Transaction::new([1; 32], [2; 32], 0, 0, 0, &mut DefaultHash::new())], Some([4; 32]),
+            BlockTail::default().stamps, 25, None, None, &mut DefaultHash::new()
+        ).header;
+        let expected = get_difficulty_from_depth(25, &params);
+        header.difficulty_target = Some(expected);
+        assert!(is_committed_difficulty_valid(&header, &params));
+        // stamps do not make an easier difficulty acceptable
+        header.tail.stamps[0] = Stamp { signature: [1; 64], address: [5; 32] };
+        assert!(header.tail.n_stamps() > 0);
+        for easier in [0, get_difficulty_from_depth(1, &params), expected - 1] {
+            header.difficulty_target = Some(easier);
+            assert!(!is_committed_difficulty_valid(&header, &params), "accepted {easier}");
+        }
+        // a lagging retarget is only accepted within the grace window
+        let lagging = get_difficulty_from_depth(15, &params);
+        header.difficulty_target = Some(lagging);
+        assert!(!is_committed_difficulty_valid(&header, &params));
+        params.difficulty_grace_window = 10;
+        assert!(is_committed_difficulty_valid(&header, &params));
+        // but never down to the difficulty of the genesis block
+        header.depth = 5;
+        params.difficulty_grace_window = 100;
+        header.difficulty_target = Some(0);
+        assert!(!is_committed_difficulty_valid(&header, &params));
+        header.difficulty_target = None;
+        assert!(!is_committed_difficulty_valid(&header, &params));
+    }
+}
@@ -11,6 +11,10 @@ const MINING_WORTH_MAX: f64 = 1f64;
 // scale the worth of transmitting a block vs mining it
 pub const BLOCK_STAMP_SCALING: f64 = 0.01f64;
 pub const N_TRANSMISSION_SIGNATURES: usize = 10;
+// half lives of history kept per node - a block is worth at most 0.5^20, about one millionth, when it is dropped
+pub const HISTORY_HALF_LIVES: u64 = 20;
+// seconds of history kept per node, by block timestamp
+pub const HISTORY_HORIZON: u64 = HISTORY_HALF_LIVES * MINING_WORTH_HALF_LIFE as u64 * 24 * 60 * 60;
 
 /// this function will scale the worth of a block over time
 /// the worth of a block is 1 at the time of mining
@@ -66,6 +70,7 @@ pub fn nth_percentile_peer(lower_n: f32, upper_n: f32, chain: &Chain) -> Vec<Std
     peers
 }
 
+#[allow(dead_code)]
 /// Given a node, query all peers for their reputations
 /// This will return a vector of peers that are between the lower_n-th and upper_n-th percentile
 /// Only takes the inetrsection of all peer responses
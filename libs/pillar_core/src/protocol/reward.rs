@@ -0,0 +1,67 @@
+use std::fmt::Debug;
+
+use pillar_crypto::types::StdByteArray;
+
+/// Decides how the miner's share of a block reward is paid out
+/// The outputs must sum to the reward, and the state root of every block commits to them
+pub trait RewardPolicy: Debug + Send + Sync {
+    /// The (address, amount) pairs to credit for a block
+    /// 
+    /// # Arguments
+    /// 
+    /// * `miner` - The address of the miner of the block
+    /// * `depth` - The depth of the block
+    /// * `reward` - The miner's share of the block reward
+    fn outputs(&self, miner: StdByteArray, depth: u64, reward: u64) -> Vec<(StdByteArray, u64)>;
+}
+
+/// The whole reward goes to the miner
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinerRewardPolicy;
+
+impl RewardPolicy for MinerRewardPolicy {
+    fn outputs(&self, miner: StdByteArray, _depth: u64, reward: u64) -> Vec<(StdByteArray, u64)> {
+        vec![(miner, reward)]
+    }
+}
+
+/// A percentage of the reward goes to a treasury, and the rest to the miner
+#[derive(Debug, Clone, Copy)]
+pub struct TreasuryRewardPolicy {
+    /// the address of the treasury
+    pub treasury: StdByteArray,
+    /// the percentage of the reward paid to the treasury, capped at 100
+    pub treasury_percent: u64,
+}
+
+impl RewardPolicy for TreasuryRewardPolicy {
+    fn outputs(&self, miner: StdByteArray, _depth: u64, reward: u64) -> Vec<(StdByteArray, u64)> {
+        let cut = (reward as u128 * self.treasury_percent.min(100) as u128 / 100) as u64;
+        let mut outputs = vec![(miner, reward - cut)];
+        if cut > 0 {
+            outputs.push((self.treasury, cut));
+        }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_miner_policy() {
+        assert_eq!(MinerRewardPolicy.outputs([1; 32], 5, 100), vec![([1; 32], 100)]);
+        assert_eq!(MinerRewardPolicy.outputs([1; 32], 5, 0), vec![([1; 32], 0)]);
+    }
+
+    #[test]
+    fn test_treasury_policy() {
+        let policy = TreasuryRewardPolicy { treasury: [9; 32], treasury_percent: 10 };
+        assert_eq!(policy.outputs([1; 32], 5, 105), vec![([1; 32], 95), ([9; 32], 10)]);
+        // nothing to the treasury when the cut rounds to zero
+        assert_eq!(policy.outputs([1; 32], 5, 9), vec![([1; 32], 9)]);
+        let policy = TreasuryRewardPolicy { treasury: [9; 32], treasury_percent: 250 };
+        assert_eq!(policy.outputs([1; 32], 5, u64::MAX), vec![([1; 32], 0), ([9; 32], u64::MAX)]);
+    }
+}
@@ -1,8 +1,17 @@
+use std::collections::HashSet;
+
 use flume::Receiver;
-use pillar_crypto::{hashing::{DefaultHash, Hashable}, proofs::verify_proof_of_inclusion, signing::{SigFunction, Signable}, types::StdByteArray};
+use pillar_crypto::{hashing::{DefaultHash, Hashable}, proofs::verify_proof_of_inclusion, signing::Signable, types::StdByteArray};
 use tracing::instrument;
 
-use crate::{accounting::{account::TransactionStub, wallet::Wallet}, nodes::node::{Broadcaster, Node}, primitives::{block::BlockHeader, errors::QueryError, messages::Message, transaction::Transaction}};
+use crate::{accounting::{account::TransactionStub, wallet::Wallet}, nodes::{node::{Broadcaster, Node}, peer::Peer}, primitives::{block::BlockHeader, errors::QueryError, messages::Message, transaction::Transaction}, protocol::peers::request_with_retries};
+
+/// The default number of transactions served in one mempool sync response
+pub const MAX_MEMPOOL_TX_PER_RESPONSE: usize = 256;
+/// The most sync requests made in one mempool reconciliation
+pub const MAX_RECONCILE_ROUNDS: usize = 16;
+/// The most transactions taken in during one mempool reconciliation
+pub const MAX_RECONCILE_TRANSACTIONS: usize = 4096;
 
 /// Submit a transaction to the network
 /// 
@@ -17,7 +26,7 @@ use crate::{accounting::{account::TransactionStub, wallet::Wallet}, nodes::node:
 /// # Returns
 /// * `Ok(Some(receiver))` - If the transaction was acknowledged and a callback was registered
 /// * `Ok(None)` - If the transaction was acknowledged but no callback was registered
-/// * `Err(e)` - If the transaction was not acknowledged or an error occurred
+/// * `Err(e)` - If the transaction was not acknowledged or an error occurred, or the node is read-only
 pub async fn submit_transaction(
     node: &mut Node, 
     wallet: &mut Wallet,
@@ -26,6 +35,9 @@ pub async fn submit_transaction(
     register_completion_callback: bool,
     timestamp: Option<u64>
 ) -> Result<(Option<Receiver<BlockHeader>>, Transaction), QueryError> {
+    if node.read_only {
+        return Err(QueryError::ReadOnly);
+    }
     let nonce = wallet.nonce; wallet.nonce += 1;
 
     let timestamp = match timestamp{
@@ -76,26 +88,100 @@ pub async fn submit_transaction(
     }
 }
 
+/// Asks peers for the proof that `transaction` is in the block with `header`, under the node's retry policy.
+/// A proof that does not verify counts as a failed request, so the next attempt goes to another peer
+///
+/// # Returns
+/// * `true` - if a peer proved the transaction is in the block
+/// * `false` - if no peer did before the attempts ran out
 #[instrument(skip(node, header))]
 pub async fn get_transaction_proof(node: &mut Node, transaction: &Transaction, header: &BlockHeader) -> bool{
-    let message = Message::TransactionProofRequest(TransactionStub { 
+    let stub = TransactionStub { 
         block_hash: header.hash(&mut DefaultHash::new()).unwrap(), 
-        transaction_hash: transaction.hash });
-
-    let results = node.broadcast(&message).await.unwrap();
-    for (i, result) in results.iter().enumerate() {
-        if let Message::TransactionProofResponse(proof) = result {
-            if verify_proof_of_inclusion(
-                *transaction,
-                proof,
-                header.merkle_root,
-                &mut DefaultHash::new()
-            ){
-                tracing::info!("Transaction proof verified by peer {}", i);
-                return true;
+        transaction_hash: transaction.hash };
+    let (transaction, merkle_root) = (*transaction, header.merkle_root);
+    let initializing_peer: Peer = (&*node).into();
+    let proof = request_with_retries(node, |mut peer| {
+        let (stub, initializing_peer) = (stub.clone(), initializing_peer.clone());
+        async move {
+            let response = peer.communicate(&Message::TransactionProofRequest(stub), &initializing_peer).await
+                .map_err(QueryError::IOError)?;
+            match response {
+                Message::TransactionProofResponse(proof) if verify_proof_of_inclusion(transaction, &proof, merkle_root, &mut DefaultHash::new()) => Ok(proof),
+                _ => Err(QueryError::InvalidResponse),
             }
         }
+    }).await;
+    match proof {
+        Ok(_) => {
+            tracing::info!("Transaction proof verified");
+            true
+        },
+        Err(e) => {
+            tracing::info!("No proof available: {}", e);
+            false
+        }
     }
-    tracing::info!("No proof available, with {} responses.", results.len());
-    return false;
-}
\ No newline at end of file
+}
+
+/// Reconcile the mempool with a peer, so both hold the union of their pooled transactions.
+/// The node sends the hashes it has, receives what it is missing along with what the peer is missing,
+/// then sends those. Only missing transactions cross the network.
+/// The peer serves a capped number of transactions per response, so the node asks again,
+/// with its grown set of hashes, until a response brings nothing new.
+/// At most `MAX_RECONCILE_ROUNDS` requests are made, and at most `MAX_RECONCILE_TRANSACTIONS` transactions are taken in.
+/// Received transactions are validated against the top of the chain before they are pooled;
+/// the hashes of rejected ones are sent along with the pooled ones, so the peer does not serve them again.
+///
+/// # Returns
+/// * `Ok((received, sent))` - The number of transactions pooled and sent
+/// * `Err(e)` - If the node has no pool or chain, or the peer did not reply
+#[instrument(skip(node, peer), fields(peer = ?peer.public_key))]
+pub async fn reconcile_mempool(node: &Node, peer: &mut Peer) -> Result<(usize, usize), QueryError> {
+    let Some(pool) = &node.miner_pool else {
+        return Err(QueryError::InsufficientInfo("Node has no mempool".into()));
+    };
+    let mut received = 0;
+    let mut processed = 0;
+    let mut requested = HashSet::new();
+    let mut rejected = HashSet::new();
+    for _ in 0..MAX_RECONCILE_ROUNDS {
+        let mut known = pool.transaction_ids();
+        known.extend(rejected.iter().copied());
+        let response = peer.communicate(&Message::MempoolSyncRequest(known.clone()), &node.into()).await
+            .map_err(QueryError::IOError)?;
+        let Message::MempoolSyncResponse(missing, to_request) = response else {
+            return Err(QueryError::InvalidResponse);
+        };
+        requested.extend(to_request);
+        let mut new = missing.into_iter().filter(|t| !known.contains(&t.hash)).collect::<Vec<_>>();
+        new.truncate(MAX_RECONCILE_TRANSACTIONS - processed);
+        if new.is_empty() {
+            break;
+        }
+        processed += new.len();
+        let chain = node.inner.chain.lock().await;
+        let Some(chain) = chain.as_ref() else {
+            return Err(QueryError::InsufficientInfo("Node has no chain".into()));
+        };
+        let (added, refused) = pool.add_applicable(new, chain);
+        received += added;
+        rejected.extend(refused);
+        if processed >= MAX_RECONCILE_TRANSACTIONS {
+            break;
+        }
+    }
+    let to_send: Vec<Transaction> = pool.pending_transactions().into_iter()
+        .filter(|t| requested.contains(&t.hash))
+        .collect();
+    let sent = to_send.len();
+    if sent > 0 {
+        let response = peer.communicate(&Message::MempoolTransactions(to_send), &node.into()).await
+            .map_err(QueryError::IOError)?;
+        if !matches!(response, Message::TransactionAck) {
+            return Err(QueryError::InvalidResponse);
+        }
+    }
+    tracing::info!("Reconciled mempool - received {}, sent {}", received, sent);
+    Ok((received, sent))
+}
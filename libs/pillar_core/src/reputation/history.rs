@@ -3,7 +3,7 @@ use std::{cmp::max, collections::HashSet};
 use pillar_crypto::{hashing::{HashFunction, Hashable}, types::StdByteArray};
 use serde::{Deserialize, Serialize};
 
-use crate::{blockchain::{chain_shard::ChainShard, TrimmableChain}, primitives::block::BlockHeader, protocol::reputation::{block_worth_scaling_fn, BLOCK_STAMP_SCALING, N_TRANSMISSION_SIGNATURES}};
+use crate::{accounting::account::Account, blockchain::{chain::Chain, chain_shard::ChainShard, TrimmableChain}, primitives::block::BlockHeader, protocol::reputation::{block_worth_scaling_fn, BLOCK_STAMP_SCALING, HISTORY_HORIZON, N_TRANSMISSION_SIGNATURES}};
 
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -90,6 +90,7 @@ impl NodeHistory{
             panic!("Block does not belong to this miner");
         }
         self.blocks_mined.push(block.into());
+        self.prune(block.timestamp);
     }
 
     /// Settle a new block into the history of the node
@@ -101,6 +102,39 @@ impl NodeHistory{
         }
         // now push the block into the history
         self.blocks_stamped.push(head.into());
+        self.prune(head.timestamp);
+    }
+
+    /// Drops the blocks stamped more than `HISTORY_HORIZON` before `timestamp`, so the history stays bounded as the chain grows.
+    /// By then a block has decayed through `HISTORY_HALF_LIVES` half lives, and is worth next to nothing to the reputation.
+    /// The history is part of the account state, so this is a consensus rule - every node must prune at the same
+    /// timestamps, or their state roots differ
+    fn prune(&mut self, timestamp: u64){
+        let recent = |shard: &HeaderShard| shard.timestamp.saturating_add(HISTORY_HORIZON) > timestamp;
+        self.blocks_mined.retain(recent);
+        self.blocks_stamped.retain(recent);
+    }
+
+    /// Verifies the history is consistent with the chain
+    /// Checks:
+    /// * The history belongs to the account
+    /// * Every mined block is in the chain, and was mined by the account
+    /// * Every stamped block is in the chain, and was stamped by the account
+    pub fn verify(&self, account: &Account, chain: &Chain) -> bool {
+        if account.address != self.public_key {
+            return false;
+        }
+        let headers = chain.get_block_headers();
+        let in_chain = |shard: &HeaderShard, by_account: &dyn Fn(&BlockHeader) -> bool| {
+            headers.iter().any(|header| HeaderShard::from(**header) == *shard && by_account(header))
+        };
+        let mined = self.blocks_mined.iter().all(
+            |shard| in_chain(shard, &|header| header.miner_address == Some(self.public_key))
+        );
+        let stamped = self.blocks_stamped.iter().all(
+            |shard| in_chain(shard, &|header| header.tail.get_stampers().contains(&self.public_key))
+        );
+        mined && stamped
     }
 
     pub fn compute_mining_reputation(
@@ -154,4 +188,88 @@ impl NodeHistory{
     pub fn n_blocks_stamped(&self) -> usize {
         self.blocks_stamped.len()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use pillar_crypto::hashing::DefaultHash;
+    use pillar_crypto::signing::{DefaultSigner, SigFunction, SigVerFunction, Signable};
+
+    use crate::fixtures::mined_block;
+    use crate::primitives::block::Stamp;
+    use crate::primitives::transaction::Transaction;
+    use crate::protocol::reputation::HISTORY_HALF_LIVES;
+
+    use super::*;
+
+    /// Builds a chain with two blocks mined by the returned miner
+    async fn mined_chain() -> (Chain, StdByteArray) {
+        let mut chain = Chain::new_with_genesis();
+        let mut signing_key = DefaultSigner::generate_random();
+        let miner = signing_key.get_verifying_function().to_bytes();
+        for depth in 1..=2 {
+            let mut transaction = Transaction::new(miner, [2; 32], 0, 0, depth - 1, &mut DefaultHash::new());
+            transaction.sign(&mut signing_key);
+            let block = mined_block(&mut chain, vec![transaction], miner).await;
+            chain.add_new_block(block).unwrap();
+        }
+        (chain, miner)
+    }
+
+    #[tokio::test]
+    async fn test_verify_consistent_history() {
+        let (chain, miner) = mined_chain().await;
+        let account = chain.state_manager.get_account(&miner, chain.get_state_root().unwrap()).unwrap();
+        let history = account.history.clone().unwrap();
+        assert_eq!(history.n_blocks_mined(), 2);
+        assert!(history.verify(&account, &chain));
+        // the history does not belong to another account
+        assert!(!history.verify(&Account::new([7; 32], 0), &chain));
+    }
+
+    #[tokio::test]
+    async fn test_verify_tampered_history() {
+        let (chain, miner) = mined_chain().await;
+        let account = chain.state_manager.get_account(&miner, chain.get_state_root().unwrap()).unwrap();
+
+        let mut history = account.history.clone().unwrap();
+        history.blocks_mined[0].timestamp += 1;
+        assert!(!history.verify(&account, &chain));
+
+        let mut history = account.history.clone().unwrap();
+        history.blocks_mined[1].n_stamps = 1;
+        assert!(!history.verify(&account, &chain));
+
+        // claims to have stamped a block it did not stamp
+        let mut history = account.history.clone().unwrap();
+        history.blocks_stamped.push(history.blocks_mined[0].clone());
+        assert!(!history.verify(&account, &chain));
+    }
+
+    #[tokio::test]
+    async fn test_history_pruned() {
+        let (chain, miner) = mined_chain().await;
+        let mut header = chain.headers[&chain.deepest_hash];
+        header.tail.stamps[0] = Stamp { signature: [1; 64], address: miner };
+        let mut history = NodeHistory::new(miner);
+        let step = HISTORY_HORIZON / 100;
+        let blocks = 300;
+        for depth in 1..=blocks {
+            header.depth = depth;
+            header.timestamp = depth * step;
+            history.settle_miner(header);
+            history.settle_stampers(header);
+        }
+        // only the blocks within the horizon are kept, and they are the most recent
+        assert_eq!(history.n_blocks_mined(), 100);
+        assert_eq!(history.n_blocks_stamped(), 100);
+        assert_eq!(history.blocks_mined.first().unwrap().depth, blocks - 99);
+        assert_eq!(history.blocks_mined.last().unwrap().depth, blocks);
+        // what was dropped was worth next to nothing
+        let now = blocks * step;
+        let dropped = block_worth_scaling_fn((blocks - 100) * step, now);
+        assert!((dropped - 0.5f64.powi(HISTORY_HALF_LIVES as i32)).abs() < 1e-12);
+        // recent blocks still count toward reputation
+        assert!(history.compute_reputation(now) > 0.0);
+    }
+}
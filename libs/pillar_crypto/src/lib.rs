@@ -4,4 +4,5 @@ pub mod signing;
 pub mod merkle_trie;
 pub mod proofs;
 pub mod types;
-pub mod serialization;
\ No newline at end of file
+pub mod serialization;
+pub mod vrf;
\ No newline at end of file
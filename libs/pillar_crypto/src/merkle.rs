@@ -75,6 +75,8 @@ impl MerkleTree {
 }
 
 /// Generate a Merkle tree from the given data
+/// The tree is built level by level without recursion, so the stack use does not grow with the input.
+/// Each level is written over the one below it, so only a single level of keys is held at a time.
 pub fn generate_tree(data: Vec<&impl Hashable>, hash_function: &mut impl HashFunction) -> Result<MerkleTree, std::io::Error> {
     if data.is_empty() {
         return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Data is empty"));
@@ -83,7 +85,7 @@ pub fn generate_tree(data: Vec<&impl Hashable>, hash_function: &mut impl HashFun
     let mut tree = MerkleTree::new();
 
     // Create leaves
-    let mut leaves: Vec<NodeKey> = data.into_iter().map(|item| {
+    let leaves: Vec<NodeKey> = data.into_iter().map(|item| {
         let item_hash = item.hash(hash_function).expect("hashing failed");
         hash_function.update(item_hash);
         let node = TreeNode {
@@ -95,51 +97,74 @@ pub fn generate_tree(data: Vec<&impl Hashable>, hash_function: &mut impl HashFun
         tree.nodes.insert(node)
     }).collect();
     
-    let leaves_clone = leaves.clone();
-
     // Build up the tree
-    while leaves.len() > 1 {
-        if leaves.len() % 2 != 0 {
-            leaves.push(*leaves.last().unwrap());
-        }
-
-        leaves = leaves.chunks(2).map(|pair| {
-            let (left_key, right_key) = (pair[0], pair[1]);
-            let left_hash = tree.nodes[left_key].hash;
-            let right_hash = tree.nodes[right_key].hash;
+    let root = reduce_levels(leaves.clone(), |left_key, right_key| {
+        hash_function.update(tree.nodes[left_key].hash);
+        hash_function.update(tree.nodes[right_key].hash);
 
-            hash_function.update(left_hash);
-            hash_function.update(right_hash);
-
-            let new_node = TreeNode {
-                left: Some(left_key),
-                right: Some(right_key),
-                parent: None,
-                hash: hash_function.digest().expect("Hashing failed"),
-            };
+        let new_node = TreeNode {
+            left: Some(left_key),
+            right: Some(right_key),
+            parent: None,
+            hash: hash_function.digest().expect("Hashing failed"),
+        };
 
-            let parent_key = tree.nodes.insert(new_node);
-            
-            tree.nodes[left_key].parent = Some(parent_key);
-            tree.nodes[right_key].parent = Some(parent_key);
+        let parent_key = tree.nodes.insert(new_node);
 
-            parent_key
-        }).collect();
-    }
+        tree.nodes[left_key].parent = Some(parent_key);
+        tree.nodes[right_key].parent = Some(parent_key);
+        Ok(parent_key)
+    })?;
 
-    tree.root = Some(leaves[0]);
-    tree.leaves = Some(leaves_clone);
+    tree.root = Some(root);
+    tree.leaves = Some(leaves);
 
     Ok(tree)
 }
 
+/// Computes only the root of the Merkle tree over the given data - the same root as `generate_tree`.
+/// Each level of hashes is written over the one below it and no nodes are kept,
+/// so this is lighter than `generate_tree` for callers that do not need proofs.
+pub fn merkle_root_of(data: &[impl Hashable], hash_function: &mut impl HashFunction) -> Result<StdByteArray, std::io::Error> {
+    if data.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Data is empty"));
+    }
+    let leaves = data.iter().map(|item| {
+        let item_hash = item.hash(hash_function)?;
+        hash_function.update(item_hash);
+        hash_function.digest()
+    }).collect::<Result<Vec<_>, _>>()?;
+
+    reduce_levels(leaves, |left, right| {
+        hash_function.update(left);
+        hash_function.update(right);
+        hash_function.digest()
+    })
+}
+
+/// Joins `level` in pairs until one item is left, duplicating the last item of an odd level.
+/// Each level is written over the one below it
+fn reduce_levels<T: Copy>(mut level: Vec<T>, mut join: impl FnMut(T, T) -> Result<T, std::io::Error>) -> Result<T, std::io::Error> {
+    while level.len() > 1 {
+        if !level.len().is_multiple_of(2) {
+            level.push(*level.last().unwrap());
+        }
+        let n_parents = level.len() / 2;
+        for i in 0..n_parents {
+            // the pair at 2i and 2i + 1 has been read, so slot i is free
+            level[i] = join(level[2 * i], level[2 * i + 1])?;
+        }
+        level.truncate(n_parents);
+    }
+    Ok(level[0])
+}
 
 #[cfg(test)]
 mod tests {
     use serde::{Deserialize, Serialize};
 
     use super::*;
-    use crate::proofs::{generate_proof_of_inclusion, verify_proof_of_inclusion};
+    use crate::proofs::{generate_proof_of_inclusion, proof_leaf_index, verify_compact_proof_of_inclusion, verify_indexed_proof_of_inclusion, verify_proof_of_inclusion, CompactMerkleProof, HashDirection, VersionedMerkleProof};
     use crate::hashing::DefaultHash;
 
     #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -374,6 +399,16 @@ mod tests {
         assert!(merkle_tree.leaves.is_some());
     }
 
+    #[test]
+    fn test_merkle_root_of() {
+        for n in [1, 2, 3, 4, 5, 7, 8, 13, 64, 100] {
+            let data: Vec<TransactionHeader> = (0..n).map(|nonce| TransactionHeader::new([0; 32], [1; 32], 0, 0, nonce)).collect();
+            let tree = generate_tree(data.iter().collect(), &mut DefaultHash::new()).unwrap();
+            assert_eq!(merkle_root_of(&data, &mut DefaultHash::new()).unwrap(), tree.get_root_hash().unwrap(), "{n} items");
+        }
+        assert!(merkle_root_of(&[] as &[TransactionHeader], &mut DefaultHash::new()).is_err());
+    }
+
     #[test]
     fn test_odd_tree_proof(){
         let mut hash_function = DefaultHash::new();
@@ -408,4 +443,106 @@ mod tests {
         // test failure
         assert!(!verify_proof_of_inclusion(transaction2, &proof, merkle_tree.nodes[merkle_tree.root.unwrap()].hash, &mut hash_function));
     }
+
+    #[test]
+    fn test_indexed_proof(){
+        let mut hash_function = DefaultHash::new();
+        let transactions = (0..5).map(|nonce| TransactionHeader::new([0; 32], [0; 32], 0, 0, nonce)).collect::<Vec<_>>();
+        let merkle_tree = generate_tree(transactions.iter().collect(), &mut hash_function).unwrap();
+        let root = merkle_tree.nodes[merkle_tree.root.unwrap()].hash;
+        let proof_of = |i: usize| generate_proof_of_inclusion(&merkle_tree, transactions[i].hash(&mut DefaultHash::new()).unwrap(), &mut DefaultHash::new()).unwrap();
+
+        for (i, transaction) in transactions.iter().enumerate() {
+            let proof = proof_of(i);
+            assert_eq!(proof_leaf_index(&proof), Some(i as u64));
+            assert!(verify_indexed_proof_of_inclusion(*transaction, &proof, root, i as u64, 5, &mut hash_function));
+            // the wrong position, or a count the tree does not have
+            assert!(!verify_indexed_proof_of_inclusion(*transaction, &proof, root, (i as u64 + 1) % 5, 5, &mut hash_function));
+            assert!(!verify_indexed_proof_of_inclusion(*transaction, &proof, root, i as u64, 9, &mut hash_function));
+        }
+
+        // the last leaf pads its level with a copy of itself - the copy proves as leaf 5 of 5
+        let mut padding = proof_of(4);
+        assert_eq!(padding.directions[0], HashDirection::Right);
+        padding.directions[0] = HashDirection::Left;
+        assert!(verify_proof_of_inclusion(transactions[4], &padding, root, &mut hash_function));
+        assert_eq!(proof_leaf_index(&padding), Some(5));
+        assert!(!verify_indexed_proof_of_inclusion(transactions[4], &padding, root, 5, 5, &mut hash_function));
+        // and claiming it is within a larger count does not help, as the tree is the wrong shape
+        assert!(!verify_indexed_proof_of_inclusion(transactions[4], &padding, root, 5, 9, &mut hash_function));
+    }
+
+    #[test]
+    fn test_compact_proof(){
+        let mut hash_function = DefaultHash::new();
+        let transactions = (0..1000).map(
+            |i| TransactionHeader::new([0; 32], [0; 32], 0, 0, i)
+        ).collect::<Vec<_>>();
+        let merkle_tree = generate_tree(transactions.iter().collect(), &mut hash_function).unwrap();
+        let root = merkle_tree.nodes[merkle_tree.root.unwrap()].hash;
+        for i in [0, 1, 511, 998, 999] {
+            let transaction = transactions[i];
+            let proof = generate_proof_of_inclusion(&merkle_tree, transaction.hash(&mut DefaultHash::new()).unwrap(), &mut hash_function).unwrap();
+            let compact = CompactMerkleProof::from(&proof);
+            assert_eq!(compact.expand(), Some(proof.clone()));
+            // verifies identically
+            assert!(verify_proof_of_inclusion(transaction, &proof, root, &mut hash_function));
+            assert!(verify_compact_proof_of_inclusion(transaction, &compact, root, &mut hash_function));
+            let other = transactions[(i + 1) % transactions.len()];
+            assert!(!verify_proof_of_inclusion(other, &proof, root, &mut hash_function));
+            assert!(!verify_compact_proof_of_inclusion(other, &compact, root, &mut hash_function));
+            // and is smaller
+            let verbose = VersionedMerkleProof::Verbose(proof);
+            let compact = VersionedMerkleProof::Compact(compact);
+            assert!(bincode::serialize(&compact).unwrap().len() < bincode::serialize(&verbose).unwrap().len());
+            // the tag selects the verifier after a round trip
+            let decoded: VersionedMerkleProof = bincode::deserialize(&bincode::serialize(&compact).unwrap()).unwrap();
+            assert!(decoded.verify(transaction, root, &mut hash_function));
+            assert!(verbose.verify(transaction, root, &mut hash_function));
+        }
+    }
+
+    #[test]
+    fn test_compact_proof_malformed_bitmap(){
+        let mut hash_function = DefaultHash::new();
+        let transactions = (0..8).map(
+            |i| TransactionHeader::new([0; 32], [0; 32], 0, 0, i)
+        ).collect::<Vec<_>>();
+        let merkle_tree = generate_tree(transactions.iter().collect(), &mut hash_function).unwrap();
+        let root = merkle_tree.nodes[merkle_tree.root.unwrap()].hash;
+        let proof = generate_proof_of_inclusion(&merkle_tree, transactions[0].hash(&mut DefaultHash::new()).unwrap(), &mut hash_function).unwrap();
+        let compact = CompactMerkleProof::from(&proof);
+        assert_eq!(compact.directions.len(), 1);
+
+        let mut extra_byte = compact.clone();
+        extra_byte.directions.push(0);
+        assert!(extra_byte.expand().is_none());
+        assert!(!verify_compact_proof_of_inclusion(transactions[0], &extra_byte, root, &mut hash_function));
+
+        // a bit set past the last level
+        let mut stray_bit = compact.clone();
+        stray_bit.directions[0] |= 1 << proof.hashes.len();
+        assert!(stray_bit.expand().is_none());
+    }
+
+    #[test]
+    fn test_large_tree_on_small_stack(){
+        // a recursive build would need stack proportional to the input; this must fit in a small thread stack
+        let handle = std::thread::Builder::new().stack_size(64 * 1024).spawn(|| {
+            let mut hash_function = DefaultHash::new();
+            let transactions = (0..100_001).map(
+                |i| TransactionHeader::new([1; 32], [2; 32], i, 0, i)
+            ).collect::<Vec<_>>();
+            let merkle_tree = generate_tree(transactions.iter().collect(), &mut hash_function).unwrap();
+            assert_eq!(merkle_tree.leaves.as_ref().unwrap().len(), transactions.len());
+            let root = merkle_tree.get_root_hash().unwrap();
+            for i in [0, 65_536, 100_000] {
+                let transaction = transactions[i];
+                let proof = generate_proof_of_inclusion(&merkle_tree, transaction.hash(&mut DefaultHash::new()).unwrap(), &mut hash_function).unwrap();
+                assert_eq!(proof.hashes.len(), 17);
+                assert!(verify_proof_of_inclusion(transaction, &proof, root, &mut hash_function));
+            }
+        }).unwrap();
+        handle.join().unwrap();
+    }
 }
@@ -1,4 +1,4 @@
-use std::{collections::{HashMap, HashSet, VecDeque}, fmt::Debug, marker::PhantomData};
+use std::{collections::{hash_map::Entry, HashMap, HashSet, VecDeque}, fmt::Debug, marker::PhantomData};
 
 use serde::{Deserialize, Serialize};
 use slotmap::{new_key_type, SlotMap};
@@ -16,9 +16,8 @@ new_key_type! { pub struct NodeKey; }
 /// however, unchanged data will be shared with old root 
 /// 
 /// Generics K and V are not required for this to work; however it is good to avoid mismatches
-
 pub struct TrieNode<V: for<'a> Deserialize<'a>> {
-    _phantum: PhantomData<V>,
+    _phantum: PhantomData<fn() -> V>, // a marker only - nodes are shared across threads whatever V is
     references: u16, // track for deletions
     pub(crate) children: [Option<NodeKey>; 16], // 16 children for each nibble (0-9, a-f)
     pub(crate) value: Option<Vec<u8>>, // Account state
@@ -48,9 +47,38 @@ impl<T: for<'a> Deserialize<'a>> TrieNode<T>{
 
 
 pub struct MerkleTrie<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> {
-    _phantum: PhantomData<K>,
+    _phantum: PhantomData<fn() -> K>,
     pub(crate) nodes: SlotMap<NodeKey, TrieNode<V>>, // SlotMap to store Trie nodes
     pub(crate) roots: HashMap<StdByteArray, NodeKey>,
+    // how many times each root was created - a root is only trimmed once every creator has trimmed it
+    root_references: HashMap<StdByteArray, usize>,
+    /// How many threads hash the subtrees of a new root. 1 hashes on the calling thread
+    pub hash_threads: usize,
+}
+
+/// A depth first walk over the values under one root. See `MerkleTrie::iter`
+pub struct TrieIter<'a, K: Hashable, V: Serialize + for<'b> Deserialize<'b>> {
+    trie: &'a MerkleTrie<K, V>,
+    // nodes still to visit, the next on top
+    stack: Vec<NodeKey>,
+}
+
+impl<K: Hashable, V: Serialize + for<'b> Deserialize<'b>> Iterator for TrieIter<'_, K, V> {
+    type Item = (NodeKey, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(current_key) = self.stack.pop() {
+            let Some(node) = self.trie.nodes.get(current_key) else {
+                continue;
+            };
+            // lowest nibble on top, so values come out in key order
+            self.stack.extend(node.children.iter().rev().flatten());
+            if let Some(value) = &node.value {
+                return Some((current_key, bincode::deserialize(value).unwrap()));
+            }
+        }
+        None
+    }
 }
 
 /// Hash and convert the key to nibbles
@@ -73,6 +101,8 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
             _phantum: PhantomData,
             nodes: SlotMap::with_key(),
             roots: HashMap::new(),
+            root_references: HashMap::new(),
+            hash_threads: 1,
         }
     }
 
@@ -87,8 +117,9 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
 
         self._insert(key, value, genesis_key).expect("Failed to insert genesis node");
 
-        let inital_hash = self.get_hash_for(genesis_key, &mut DefaultHash::new()).unwrap();
+        let inital_hash = self.get_hash_parallel(genesis_key, self.hash_threads).unwrap();
         self.roots.insert(inital_hash, genesis_key);
+        self.root_references.insert(inital_hash, 1);
         Ok(inital_hash)
     }
 
@@ -158,7 +189,12 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
             }
         }
         let serialized = self.nodes.get(current_node_key).unwrap().value.as_ref();
-        serialized.map(|data| bincode::deserialize(&mut data.clone()).unwrap())
+        serialized.map(|data| bincode::deserialize(data).unwrap())
+    }
+
+    /// The number of nodes stored in the trie, across every root
+    pub fn n_nodes(&self) -> usize {
+        self.nodes.len()
     }
 
     /// Retreives all values stored in the trie starting from the given root.
@@ -170,7 +206,7 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
     /// * `Vec<&[u8]>` containing references to all serialized values in the trie.
     pub fn get_all(&self, root: StdByteArray) -> Vec<V> {
         let mut values = Vec::new();
-        if self.roots.get(&root).is_none() {
+        if !self.roots.contains_key(&root) {
             return values;
         };
 
@@ -181,16 +217,31 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
             if let Some(value) = &node.value{
                 values.push(bincode::deserialize(value).unwrap());
             }
-            for child in node.children.iter(){
-                if let Some(child_key) = child{
-                    visit_queue.push_back(child_key);
-                }
+            for child_key in node.children.iter().flatten(){
+                visit_queue.push_back(child_key);
             }
         }
 
         values
     }
-    
+
+    /// Iterates the values stored under the given root, with the node holding each, in key order.
+    /// Values are deserialized as they are yielded, so the whole state is never held at once
+    ///
+    /// # Arguments
+    /// * `root` - The hash of the root node to start traversal from. An unknown root yields nothing.
+    pub fn iter(&self, root: StdByteArray) -> TrieIter<'_, K, V> {
+        TrieIter {
+            trie: self,
+            stack: self.roots.get(&root).copied().into_iter().collect(),
+        }
+    }
+
+    /// The value stored at `node`, if the node still exists and holds one
+    pub fn get_at(&self, node: NodeKey) -> Option<V> {
+        let serialized = self.nodes.get(node)?.value.as_ref()?;
+        bincode::deserialize(serialized).ok()
+    }
 
     /// Creates a new branch of the trie.
     /// This branch will yield a new root.
@@ -222,11 +273,10 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
         let origin = origin.unwrap();
 
         let origin_root_key = *self.roots.get(&origin).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Origin root not found"))?;
-        let origin_root = self.nodes.get(origin_root_key).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Root node not found"))?.clone();
-        let new_root_key = self.nodes.insert(origin_root.clone());
+        let new_root_key = self.copy_node(origin_root_key)?;
+        // the nodes made for this branch - they are changed in place, everything else is copied first
+        let mut new_keys: HashSet<NodeKey> = HashSet::from([new_root_key]);
 
-        let mut new_keys: HashSet<NodeKey> = HashSet::new();
-        
         for (key, value) in updates {
             let nibbles = to_nibbles(&key);
             let mut current_node_key = new_root_key;
@@ -235,33 +285,19 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
                 let index: usize = nibble as usize;
                 let current_child_opt = self.nodes.get(current_node_key).unwrap().children[index];
 
-                let new_child_key = if let Some(child_key) = current_child_opt {
-                    if new_keys.contains(&child_key) {
-                        // If the child is already cloned, reuse the cloned key
-                        child_key
-                    } else {   
-                        let cloned_child = self.nodes.get(child_key).unwrap().clone();
-                        self.nodes.insert(cloned_child)
-                    }
-                } else {
-                    let k = self.nodes.insert(TrieNode::new());
-                    new_keys.insert(k);
-                    k
+                let new_child_key = match current_child_opt {
+                    // already copied for this branch
+                    Some(child_key) if new_keys.contains(&child_key) => child_key,
+                    Some(child_key) => {
+                        let copy = self.copy_node(child_key)?;
+                        // the copy of the current node points at the new child instead
+                        self.nodes.get_mut(child_key).unwrap().references -= 1;
+                        copy
+                    },
+                    None => self.nodes.insert(TrieNode::new()),
                 };
-
-                // THE CURRENT_NODE MUST BE ADDED BEFORE THE REFERENCE COUNTS
-                let current_node = self.nodes.get_mut(current_node_key).unwrap();
-                current_node.children[index] = Some(new_child_key);
-                // let mut updated_node = current_node;
-                for child in self.nodes.get_mut(current_node_key).unwrap().children.clone().iter_mut() {
-                    if let Some(child_key) = child {
-                        if !new_keys.contains(child_key){
-                            let child_node = self.nodes.get_mut(*child_key).unwrap();
-                            child_node.references += 1;
-                        }
-                    }
-                }
-                // increment references for all the children of the new 
+                new_keys.insert(new_child_key);
+                self.nodes.get_mut(current_node_key).unwrap().children[index] = Some(new_child_key);
                 current_node_key = new_child_key;
             }
             // update the value in the new branch
@@ -269,15 +305,52 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
             current_node.value = Some(bincode::serialize(&value).map_err(std::io::Error::other)?);
         }
 
-        let new_root_hash = self.get_hash_for(new_root_key, &mut DefaultHash::new()).unwrap();
-        self.roots.insert(new_root_hash, new_root_key);
+        let new_root_hash = self.get_hash_parallel(new_root_key, self.hash_threads).unwrap();
+        match self.roots.entry(new_root_hash) {
+            // the same state is already held - share it, and drop the copy
+            Entry::Occupied(_) => self.trim_nodes(new_root_key),
+            Entry::Vacant(entry) => {
+                entry.insert(new_root_key);
+            }
+        }
+        *self.root_references.entry(new_root_hash).or_default() += 1;
         Ok(new_root_hash)
     }
 
-    pub fn trim_branch(&mut self, root: StdByteArray) -> Result<(), std::io::Error> {
-        let root_key = self.roots.get(&root).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Root not found"))?;
+    /// Copies the node at `key` into a new node, referenced once. Its children gain a reference, from the copy
+    fn copy_node(&mut self, key: NodeKey) -> Result<NodeKey, std::io::Error> {
+        let mut copy = self.nodes.get(key).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Node not found"))?.clone();
+        copy.references = 1;
+        for child in copy.children.iter().flatten() {
+            self.nodes.get_mut(*child).unwrap().references += 1;
+        }
+        Ok(self.nodes.insert(copy))
+    }
+
+    /// Releases one reference to `root`, removing the nodes only it uses once nothing else references it.
+    /// Branching to the same state twice gives the same root, so each branch must be trimmed before the root goes
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the root was removed
+    /// * `Ok(false)` if the root is still referenced
+    /// * `Err(std::io::Error)` if the root is not found
+    pub fn trim_branch(&mut self, root: StdByteArray) -> Result<bool, std::io::Error> {
+        let root_key = *self.roots.get(&root).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Root not found"))?;
+        let references = self.root_references.entry(root).or_insert(1);
+        *references -= 1;
+        if *references > 0 {
+            return Ok(false);
+        }
+        self.root_references.remove(&root);
+        self.trim_nodes(root_key);
+        self.roots.remove(&root);
+        Ok(true)
+    }
+
+    /// Removes the nodes under `root_key` that nothing else references
+    fn trim_nodes(&mut self, root_key: NodeKey) {
         let mut visit_queue = VecDeque::new();
-        visit_queue.push_back(*root_key);
+        visit_queue.push_back(root_key);
 
         while let Some(current_key) = visit_queue.pop_front() {
             if let Some(node) = self.nodes.get_mut(current_key) {
@@ -295,9 +368,6 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
                 }
             }
         }
-        self.roots.remove(&root);
-        Ok(())
-        
     }
 
     /// Computes the hash for the given node.
@@ -310,13 +380,54 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
     /// * `None` if the node does not exist or has no value.
     pub fn get_hash_for(&self, node: NodeKey, hash_function: &mut impl HashFunction) -> Option<StdByteArray> {
         let node = self.nodes.get(node).expect("Node not found");
+        let children = node.children.iter().enumerate()
+            .filter_map(|(i, child)| child.map(|child_key| (i, self.get_hash_for(child_key, &mut DefaultHash::new()).unwrap())));
+        Self::hash_node(node, children, hash_function)
+    }
+
+    /// Computes the hash for the given node, hashing its subtrees on up to `threads` threads.
+    /// The children of the node are split between the threads, so at most 16 are used.
+    /// The hash is the same as from `get_hash_for` with a `DefaultHash`, whatever the number of threads.
+    pub fn get_hash_parallel(&self, node: NodeKey, threads: usize) -> Option<StdByteArray> {
+        let groups = self.partition_children(node, threads);
+        if groups.len() <= 1 {
+            return self.get_hash_for(node, &mut DefaultHash::new());
+        }
+        let children = std::thread::scope(|scope| {
+            let workers = groups.into_iter().map(|group| scope.spawn(move || {
+                group.into_iter()
+                    .map(|(i, child_key)| (i, self.get_hash_for(child_key, &mut DefaultHash::new()).unwrap()))
+                    .collect::<Vec<_>>()
+            })).collect::<Vec<_>>();
+            // groups are in nibble order, so joining in order keeps the children in order
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+        });
+        Self::hash_node(self.nodes.get(node).expect("Node not found"), children, &mut DefaultHash::new())
+    }
+
+    /// The children of a node, split into at most `threads` groups of neighbouring nibbles, in nibble order
+    pub(crate) fn partition_children(&self, node: NodeKey, threads: usize) -> Vec<Vec<(usize, NodeKey)>> {
+        let children = self.nodes.get(node).expect("Node not found").children.iter().enumerate()
+            .filter_map(|(i, child)| child.map(|child_key| (i, child_key)))
+            .collect::<Vec<_>>();
+        if children.is_empty() {
+            return vec![];
+        }
+        children.chunks(children.len().div_ceil(threads.max(1))).map(<[_]>::to_vec).collect()
+    }
+
+    /// Hashes a node from the hashes of its children, in nibble order, and its value.
+    /// `None` if the node has neither.
+    fn hash_node(
+        node: &TrieNode<V>,
+        children: impl IntoIterator<Item = (usize, StdByteArray)>,
+        hash_function: &mut impl HashFunction
+    ) -> Option<StdByteArray> {
         let mut valid = false; 
-        for (i, child) in node.children.iter().enumerate() {
-            if let Some(child_key) = child {
-                hash_function.update([i as u8]);
-                hash_function.update(self.get_hash_for(*child_key, &mut DefaultHash::new()).unwrap());
-                valid = true;
-            }
+        for (i, child_hash) in children {
+            hash_function.update([i as u8]);
+            hash_function.update(child_hash);
+            valid = true;
         }
 
         if let Some(value) = &node.value {
@@ -335,7 +446,7 @@ impl<K: Hashable, V: Serialize + for<'a> Deserialize<'a>> MerkleTrie<K, V>{
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{hashing::DefaultHash, proofs::generate_proof_of_state};
+    use crate::{hashing::DefaultHash, proofs::{generate_proof_of_state, trie_path, verify_trie_range}};
 
     #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
     struct AccountState {
@@ -666,6 +777,62 @@ mod tests {
         assert!(!all_values.contains(&account4));
     }
 
+    #[test]
+    fn test_parallel_hash() {
+        use rand::Rng;
+        let mut rng = rand::rng();
+        for n_accounts in [1, 2, 5, 40, 300] {
+            let mut trie = MerkleTrie::<StdByteArray, AccountState>::new();
+            let mut parallel = MerkleTrie::<StdByteArray, AccountState>::new();
+            parallel.hash_threads = 4;
+            let accounts = (0..n_accounts)
+                .map(|_| (rng.random::<StdByteArray>(), AccountState { balance: rng.random(), nonce: rng.random() }))
+                .collect::<HashMap<_, _>>();
+            let genesis = trie.create_genesis([0; 32], AccountState { balance: 0, nonce: 0 }).unwrap();
+            assert_eq!(parallel.create_genesis([0; 32], AccountState { balance: 0, nonce: 0 }).unwrap(), genesis);
+            // a trie hashing on several threads commits to the same roots
+            let root = trie.branch(Some(genesis), accounts.clone()).unwrap();
+            assert_eq!(parallel.branch(Some(genesis), accounts).unwrap(), root);
+
+            let root_key = trie.roots[&root];
+            for threads in [0, 1, 2, 3, 7, 16, 32] {
+                assert_eq!(trie.get_hash_parallel(root_key, threads), Some(root));
+                // one group of subtrees per thread, at most
+                let groups = trie.partition_children(root_key, threads);
+                assert!(groups.len() <= threads.max(1));
+                let n_children = trie.nodes[root_key].children.iter().flatten().count();
+                assert_eq!(groups.iter().map(Vec::len).sum::<usize>(), n_children);
+                assert!(groups.concat().windows(2).all(|pair| pair[0].0 < pair[1].0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut trie = MerkleTrie::<&str, AccountState>::new();
+        let keys = ["account0", "account1", "account2", "account3"];
+        let root = trie.create_genesis(keys[0], AccountState { balance: 0, nonce: 0 }).unwrap();
+        for (i, key) in keys.iter().enumerate().skip(1) {
+            trie.insert(key, AccountState { balance: i as u64, nonce: 0 }, root).unwrap();
+        }
+        let branched = trie.branch(Some(root), HashMap::from([("account1", AccountState { balance: 10, nonce: 1 })])).unwrap();
+
+        let values: Vec<_> = trie.iter(branched).collect();
+        let mut all = trie.get_all(branched);
+        all.sort_by_key(|account| account.balance);
+        let mut streamed: Vec<_> = values.iter().map(|(_, value)| value.clone()).collect();
+        streamed.sort_by_key(|account| account.balance);
+        assert_eq!(streamed, all);
+        // in order of the hashed keys
+        let mut hashed: Vec<_> = keys.iter().map(|key| (key.hash(&mut DefaultHash::new()).unwrap(), trie.get(key, branched).unwrap())).collect();
+        hashed.sort_by_key(|(hash, _)| *hash);
+        assert_eq!(values.iter().map(|(_, value)| value.clone()).collect::<Vec<_>>(), hashed.into_iter().map(|(_, value)| value).collect::<Vec<_>>());
+        for (node, value) in values {
+            assert_eq!(trie.get_at(node), Some(value));
+        }
+        assert_eq!(trie.iter([9; 32]).count(), 0);
+    }
+
     #[test]
     fn test_trim(){
         let initial_account_info = AccountState { balance: 100, nonce: 1 };
@@ -718,6 +885,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_trim_shared_root(){
+        let mut trie = MerkleTrie::<&str, AccountState>::new();
+        let initial_root = trie.create_genesis("account0", AccountState { balance: 100, nonce: 1 }).expect("Failed to create genesis");
+        let updates = HashMap::from([("account1", AccountState { balance: 200, nonce: 2 })]);
+        let first = trie.branch(Some(initial_root), updates.clone()).unwrap();
+        let nodes = trie.n_nodes();
+        // the same state again shares the root, without new nodes
+        let second = trie.branch(Some(initial_root), updates).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(trie.n_nodes(), nodes);
+
+        // the root outlives all but the last trim
+        assert!(!trie.trim_branch(second).unwrap());
+        assert_eq!(trie.get(&"account1", first), Some(AccountState { balance: 200, nonce: 2 }));
+        assert!(trie.trim_branch(first).unwrap());
+        assert!(trie.get(&"account1", first).is_none());
+        assert!(trie.trim_branch(first).is_err());
+        assert!(trie.get(&"account0", initial_root).is_some());
+    }
+
     #[test]
     fn test_trim_complex(){
         let initial_account_info = AccountState { balance: 100, nonce: 1 };
@@ -767,4 +955,44 @@ mod tests {
 
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_trie_range_proofs() {
+        let mut trie = MerkleTrie::<String, AccountState>::new();
+        let root = trie.create_genesis("account0".into(), AccountState { balance: 0, nonce: 0 }).unwrap();
+        let updates = (1..24).map(|i| (format!("account{i}"), AccountState { balance: i, nonce: 0 })).collect();
+        let root = trie.branch(Some(root), updates).unwrap();
+        let mut keys = (0..24).map(|i| {
+            let key = format!("account{i}");
+            (trie_path(&key), generate_proof_of_state(&trie, key, Some(root), &mut DefaultHash::new()).unwrap().0)
+        }).collect::<Vec<_>>();
+        keys.sort_by_key(|(path, _)| *path);
+        let all = keys.iter().map(|(path, proof)| (*path, proof)).collect::<Vec<_>>();
+        let (min, max) = ([0; 32], [0xff; 32]);
+
+        // the whole trie, and a run between two keys, are complete
+        assert!(verify_trie_range(&all, min, max));
+        assert!(verify_trie_range(&all[3..9], all[3].0, all[8].0));
+        // a key left out, at either end or in the middle, is caught
+        assert!(!verify_trie_range(&all[1..], min, max));
+        assert!(!verify_trie_range(&all[..23], min, max));
+        assert!(!verify_trie_range(&[&all[..10], &all[11..]].concat(), min, max));
+        // as is a proof for another path, or keys out of order
+        assert!(!verify_trie_range(&[(all[1].0, all[0].1)], all[1].0, all[1].0));
+        assert!(!verify_trie_range(&[all[1], all[0]], min, max));
+        assert!(!verify_trie_range(&[], min, max));
+
+        // just past a key, nothing lies before the next one - its neighbours show it
+        let mut gap = all[4].0;
+        let last = gap.iter().rposition(|b| *b != 0xff).unwrap();
+        gap[last] += 1;
+        gap[last + 1..].fill(0);
+        assert!(gap < all[5].0);
+        assert!(verify_trie_range(&all[4..6], gap, gap));
+        // keys further out can leave something between them and the range
+        assert!(!verify_trie_range(&[all[3], all[5]], gap, gap));
+        // past the last key, it alone shows there is nothing more
+        assert!(verify_trie_range(&all[23..], all[23].0, max));
+        assert!(verify_trie_range(&all[22..], all[22].0, max));
+    }
+}
@@ -43,11 +43,10 @@ pub fn generate_proof_of_inclusion(merkle_tree: &MerkleTree, data: StdByteArray,
                 hashes.push(nodes[right_key].hash);
                 directions.push(HashDirection::Right);
             }
-        } else if parent.right == Some(current_key) {
-            if let Some(left_key) = parent.left {
-                hashes.push(nodes[left_key].hash);
-                directions.push(HashDirection::Left);
-            }
+        } else if parent.right == Some(current_key)
+            && let Some(left_key) = parent.left {
+            hashes.push(nodes[left_key].hash);
+            directions.push(HashDirection::Left);
         }
         current_key = parent_key;
     }
@@ -82,11 +81,126 @@ pub fn verify_proof_of_inclusion<T: Into<StdByteArray>>(data: T, proof: &MerkleP
     current_hash == root
 }
 
+/// The position of the leaf a proof starts from, read off its directions - a sibling on the left means a right child.
+/// None if the proof is too long for the position to fit
+pub fn proof_leaf_index(proof: &MerkleProof) -> Option<u64> {
+    if proof.directions.len() > u64::BITS as usize {
+        return None;
+    }
+    Some(proof.directions.iter().enumerate().fold(0, |index, (level, direction)| match direction {
+        HashDirection::Left => index | (1 << level),
+        HashDirection::Right => index,
+    }))
+}
+
+/// The number of levels in a proof for a tree of `leaves` leaves.
+/// Odd levels are padded with a copy of their last node, so every level has a sibling
+pub fn proof_levels(leaves: u64) -> usize {
+    leaves.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Verify that `data` is leaf `index` of a tree of `leaf_count` leaves under `root`.
+/// The proof must have the levels of a tree that size and lead to that position. An index at or past the count
+/// is rejected even where it verifies - the copy of the last leaf that pads an odd level proves like a real one
+pub fn verify_indexed_proof_of_inclusion<T: Into<StdByteArray>>(
+    data: T,
+    proof: &MerkleProof,
+    root: StdByteArray,
+    index: u64,
+    leaf_count: u64,
+    hash_function: &mut impl HashFunction
+) -> bool {
+    index < leaf_count
+        && proof.directions.len() == proof_levels(leaf_count)
+        && proof.hashes.len() == proof.directions.len()
+        && proof_leaf_index(proof) == Some(index)
+        && verify_proof_of_inclusion(data, proof, root, hash_function)
+}
+
+/// A Merkle proof with the directions packed into a bitmap, one bit per level
+/// A set bit means the sibling hash is on the left
+#[derive(Debug, Clone, Deserialize, Serialize, Hash, PartialEq, Eq)]
+pub struct CompactMerkleProof {
+    pub hashes: Vec<StdByteArray>,
+    pub directions: Vec<u8>,
+    pub root: StdByteArray,
+}
+
+impl From<&MerkleProof> for CompactMerkleProof {
+    fn from(proof: &MerkleProof) -> Self {
+        let mut directions = vec![0u8; proof.directions.len().div_ceil(8)];
+        for (i, direction) in proof.directions.iter().enumerate() {
+            if *direction == HashDirection::Left {
+                directions[i / 8] |= 1 << (i % 8);
+            }
+        }
+        CompactMerkleProof {
+            hashes: proof.hashes.clone(),
+            directions,
+            root: proof.root,
+        }
+    }
+}
+
+impl CompactMerkleProof {
+    /// Expands the bitmap back into a proof with a direction per level
+    /// Returns None if the bitmap does not have exactly one bit per hash
+    pub fn expand(&self) -> Option<MerkleProof> {
+        if self.directions.len() != self.hashes.len().div_ceil(8) {
+            return None;
+        }
+        // unused trailing bits must be clear, so each proof has a single encoding
+        let used = self.hashes.len() % 8;
+        if used != 0 && self.directions.last().is_some_and(|last| last >> used != 0) {
+            return None;
+        }
+        let directions = (0..self.hashes.len()).map(|i| {
+            if self.directions[i / 8] & (1 << (i % 8)) != 0 {
+                HashDirection::Left
+            } else {
+                HashDirection::Right
+            }
+        }).collect();
+        Some(MerkleProof {
+            hashes: self.hashes.clone(),
+            directions,
+            root: self.root,
+        })
+    }
+}
+
+/// Verify a compact Merkle proof
+pub fn verify_compact_proof_of_inclusion<T: Into<StdByteArray>>(data: T, proof: &CompactMerkleProof, root: StdByteArray, hash_function: &mut impl HashFunction) -> bool {
+    match proof.expand() {
+        Some(proof) => verify_proof_of_inclusion(data, &proof, root, hash_function),
+        None => false,
+    }
+}
+
+/// A Merkle proof tagged with its encoding, so either can be sent and verified
+#[derive(Debug, Clone, Deserialize, Serialize, Hash, PartialEq, Eq)]
+pub enum VersionedMerkleProof {
+    /// A direction per level
+    Verbose(MerkleProof),
+    /// Directions packed into a bitmap
+    Compact(CompactMerkleProof),
+}
+
+impl VersionedMerkleProof {
+    /// Verify the proof with the verifier for its encoding
+    pub fn verify<T: Into<StdByteArray>>(&self, data: T, root: StdByteArray, hash_function: &mut impl HashFunction) -> bool {
+        match self {
+            VersionedMerkleProof::Verbose(proof) => verify_proof_of_inclusion(data, proof, root, hash_function),
+            VersionedMerkleProof::Compact(proof) => verify_compact_proof_of_inclusion(data, proof, root, hash_function),
+        }
+    }
+}
+
 // ============================================================================================
 // Trie proofs to follow
 // TODO generalize
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct TrieMerkleProof {
     pub steps: Vec<ProofStep>,
 }
@@ -97,8 +211,8 @@ impl TrieMerkleProof {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub(crate) struct ProofStep {
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProofStep {
     // native is the index of the value on which they are constructing the proof
     pub native: u8,
     // Give the indices and hashes of the siblings in the trie
@@ -163,6 +277,9 @@ impl ProofStep {
 impl TrieMerkleProof {
     pub fn verify(&self, native_data: Vec<u8>, root_hash: StdByteArray, hash_function: &mut impl HashFunction) -> bool {
         let steps = self.steps.iter().rev().collect::<Vec<_>>();
+        if steps.is_empty() {
+            return false;
+        }
         let mut current_hash = steps[0].compute_level_first(native_data, hash_function);
         for step in &steps[1..] {
             current_hash = step.compute_level(current_hash, hash_function);
@@ -217,4 +334,60 @@ where K: Hashable, V: Serialize + for<'a> Deserialize<'a>
     }
         
     Some((TrieMerkleProof::new(steps), value.unwrap()))
-}
\ No newline at end of file
+}
+/// The path of `key` in a trie - the hash its nibbles are taken from.
+/// Tries hold their keys in path order, so ranges over a trie are ranges of paths
+pub fn trie_path(key: &impl Hashable) -> StdByteArray {
+    key.hash(&mut crate::hashing::DefaultHash::new()).expect("Hashing failed")
+}
+
+impl TrieMerkleProof {
+    /// Whether the proof is for the value at `path`
+    fn follows(&self, nibbles: &[u8]) -> bool {
+        self.steps.len() == nibbles.len() + 1 && self.steps.iter().zip(nibbles).all(|(step, nibble)| step.native == *nibble)
+    }
+
+    /// The subtrees beside the proven path, each as its depth and nibble.
+    /// Every other key in the trie is under exactly one of them
+    fn siblings(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.steps.iter().enumerate().flat_map(|(depth, step)| step.siblings.iter().map(move |(nibble, _)| (depth, *nibble)))
+    }
+}
+
+/// Checks that a trie holds no key with a path from `start` to `end` inclusive, other than the proven `keys`.
+/// `keys` are the paths of proven values, strictly ascending, each with its proof. They may begin with the key
+/// just before `start` and end with the key just after `end` - these neighbours show where the range ends,
+/// and an empty range is shown by its neighbours alone.
+/// The proofs themselves must be verified against the root separately
+pub fn verify_trie_range(keys: &[(StdByteArray, &TrieMerkleProof)], start: StdByteArray, end: StdByteArray) -> bool {
+    let nibbles = |path: &StdByteArray| path.iter().flat_map(|b| [b >> 4, b & 0x0F]).collect::<Vec<_>>();
+    let (start, end) = (nibbles(&start), nibbles(&end));
+    // the keys under a sibling share its prefix, so comparing prefixes places the whole subtree
+    let prefix = |path: &[u8], depth: usize, nibble: u8| [&path[..depth], &[nibble]].concat();
+    let below_start = |prefix: &[u8]| prefix < &start[..prefix.len()];
+    let above_end = |prefix: &[u8]| prefix > &end[..prefix.len()];
+
+    let (Some((_, first_proof)), Some((_, last_proof))) = (keys.first(), keys.last()) else {
+        return false;
+    };
+    let paths = keys.iter().map(|(path, _)| nibbles(path)).collect::<Vec<_>>();
+    let in_order = paths.windows(2).all(|pair| pair[0] < pair[1]);
+    if !in_order || !keys.iter().zip(&paths).all(|((_, proof), path)| proof.follows(path)) {
+        return false;
+    }
+    let (first, last) = (&paths[0], &paths[paths.len() - 1]);
+    // nothing between the start and the first key
+    let left = first_proof.siblings().filter(|(depth, nibble)| *nibble < first[*depth])
+        .all(|(depth, nibble)| below_start(&prefix(first, depth, nibble)));
+    // nothing between the last key and the end
+    let right = last_proof.siblings().filter(|(depth, nibble)| *nibble > last[*depth])
+        .all(|(depth, nibble)| above_end(&prefix(last, depth, nibble)));
+    // nothing between neighbours - a key between them would be beside one of their paths, below where they part
+    let contiguous = keys.windows(2).zip(paths.windows(2)).all(|(proofs, pair)| {
+        let (a, b) = (&pair[0], &pair[1]);
+        let parted = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        proofs[0].1.siblings().all(|(depth, nibble)| depth < parted || (depth == parted && (nibble < a[depth] || nibble >= b[depth])) || (depth > parted && nibble < a[depth]))
+            && proofs[1].1.siblings().all(|(depth, nibble)| depth <= parted || nibble > b[depth])
+    });
+    left && right && contiguous
+}
@@ -1,5 +1,17 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 
+/// Decodes bincode written by `bincode::serialize`, rejecting input that is not exactly one value.
+/// Fixed size fields such as hashes have no length prefix, so data of the wrong length either runs short,
+/// or leaves bytes over - both are errors here, where `bincode::deserialize` would ignore the extra bytes
+pub fn deserialize_exact<T: for<'a> Deserialize<'a>>(data: &[u8]) -> Result<T, std::io::Error> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(data)
+        .map_err(std::io::Error::other)
+}
+
 pub trait PillarSerialize : Serialize + for<'a> Deserialize<'a> + Sized {
     fn serialize_pillar(&self) -> Result<Vec<u8>, std::io::Error> {
         let encoded = bincode::serialize(&self)
@@ -11,8 +23,6 @@ pub trait PillarSerialize : Serialize + for<'a> Deserialize<'a> + Sized {
     fn deserialize_pillar(data: &[u8]) -> Result<Self, std::io::Error> {
         let decompressed = lz4_flex::decompress_size_prepended(data)
             .map_err(std::io::Error::other)?;
-        let decoded = bincode::deserialize::<Self>(&decompressed)
-            .map_err(std::io::Error::other)?;
-        Ok(decoded)
+        deserialize_exact(&decompressed)
     }
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
+use curve25519_dalek::edwards::CompressedEdwardsY;
 use ed25519::signature::SignerMut;
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use rand_core::OsRng;
@@ -69,11 +70,38 @@ pub trait SigVerFunction<const K: usize, const S: usize>{
     /// * `true` if the signature is valid, `false` otherwise.
     fn verify(&self, signature: &[u8; S], target: &impl Signable<S>) -> bool;
 
+    /// Verifies a set of signatures, possibly under different keys, together.
+    /// Schemes that support batch verification should override this - by default each signature is checked in turn.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - The verifier, signature, and signed target of every signature.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if every signature is valid, `false` otherwise.
+    fn verify_batch<T: Signable<S>>(batch: &[(Self, [u8; S], &T)]) -> bool where Self: Sized {
+        batch.iter().all(|(verifier, signature, target)| verifier.verify(signature, *target))
+    }
+
     fn to_bytes(&self) -> [u8; K];
 
     fn from_bytes(bytes: &[u8; K]) -> Self;
 }
 
+/// Verifies signatures in batches of at most `batch_size` with `SigVerFunction::verify_batch`.
+/// A batch size of 0 or 1 verifies every signature on its own.
+///
+/// # Returns
+///
+/// * `true` if every signature is valid, `false` otherwise.
+pub fn verify_in_batches<const K: usize, const S: usize, V: SigVerFunction<K, S>, T: Signable<S>>(items: &[(V, [u8; S], &T)], batch_size: usize) -> bool {
+    if batch_size <= 1 {
+        return items.iter().all(|(verifier, signature, target)| verifier.verify(signature, *target));
+    }
+    items.chunks(batch_size).all(V::verify_batch)
+}
+
 /// Default signer is the ed25519 signing function
 pub struct DefaultSigner{
     private_key: SigningKey
@@ -90,6 +118,11 @@ impl DefaultVerifier{
             public_key: VerifyingKey::from_bytes(&public_key).expect("Invlaid public key")
         }
     }
+
+    /// Whether the bytes decode to a usable public key
+    pub fn is_valid_key(public_key: &StdByteArray) -> bool{
+        VerifyingKey::from_bytes(public_key).is_ok()
+    }
 }
 
 impl DefaultSigner{
@@ -121,6 +154,16 @@ impl SigFunction<32,32, 64> for DefaultSigner{
     }
 }
 
+/// Whether a batch check agrees with `verify_strict` on this signature - the batch check is cofactorless and
+/// does not screen its points, so the commitment and key must both be canonical points in the prime order subgroup
+fn is_batchable(public_key: &VerifyingKey, signature: &ed25519::Signature) -> bool {
+    let in_prime_subgroup = |bytes: [u8; 32]| {
+        let compressed = CompressedEdwardsY(bytes);
+        compressed.decompress().is_some_and(|point| point.compress() == compressed && point.is_torsion_free() && !point.is_small_order())
+    };
+    in_prime_subgroup(*signature.r_bytes()) && in_prime_subgroup(public_key.to_bytes())
+}
+
 impl SigVerFunction<32, 64> for DefaultVerifier{
     fn verify(&self, signature: &[u8; 64], target: &impl Signable<64>) -> bool{
         let signature = ed25519::Signature::from_bytes(signature);
@@ -128,6 +171,18 @@ impl SigVerFunction<32, 64> for DefaultVerifier{
         self.public_key.verify_strict(target.get_signing_bytes().as_ref(), &signature).is_ok()
     }
 
+    /// Checks the batch with `ed25519_dalek::verify_batch`, or each signature in turn if the batch could judge any of them differently.
+    fn verify_batch<T: Signable<64>>(batch: &[(Self, [u8; 64], &T)]) -> bool {
+        let signatures = batch.iter().map(|(_, signature, _)| ed25519::Signature::from_bytes(signature)).collect::<Vec<_>>();
+        let keys = batch.iter().map(|(verifier, _, _)| verifier.public_key).collect::<Vec<_>>();
+        if !keys.iter().zip(&signatures).all(|(key, signature)| is_batchable(key, signature)) {
+            return batch.iter().all(|(verifier, signature, target)| verifier.verify(signature, *target));
+        }
+        let signing_bytes = batch.iter().map(|(_, _, target)| target.get_signing_bytes()).collect::<Vec<_>>();
+        let messages = signing_bytes.iter().map(|bytes| bytes.as_ref()).collect::<Vec<_>>();
+        ed25519_dalek::verify_batch(&messages, &signatures, &keys).is_ok()
+    }
+
     fn to_bytes(&self) -> StdByteArray{
         self.public_key.to_bytes()
     }
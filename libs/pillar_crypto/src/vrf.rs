@@ -0,0 +1,140 @@
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_512};
+
+use crate::types::StdByteArray;
+
+/// Prefixed to every VRF hash, so they never collide with other uses of the hash function
+const DOMAIN: &[u8] = b"pillar-vrf";
+const HASH_TO_CURVE_TAG: u8 = 1;
+const NONCE_TAG: u8 = 2;
+const CHALLENGE_TAG: u8 = 3;
+const OUTPUT_TAG: u8 = 4;
+
+/// A proof that a key holder computed the unique VRF output for some input.
+/// Only the private key can make a proof, and anyone with the public key can check it.
+///
+/// This is an ECVRF over edwards25519 in the style of RFC 9381, using SHA3-512 and
+/// try-and-increment hashing to the curve. It uses the same keys as the default signer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VrfProof {
+    /// the private key times the input hashed to the curve
+    pub gamma: [u8; 32],
+    /// the truncated challenge
+    pub challenge: [u8; 16],
+    /// the response to the challenge
+    pub response: [u8; 32],
+}
+
+impl VrfProof {
+    /// Proves the VRF output of an ed25519 private key over `input`
+    pub fn new(private_key: &StdByteArray, input: &[u8]) -> Self {
+        let signing_key = SigningKey::from_bytes(private_key);
+        let secret = signing_key.to_scalar();
+        let public_key = signing_key.verifying_key().to_bytes();
+        let public_point = EdwardsPoint::mul_base(&secret);
+        let input_point = hash_to_curve(&public_key, input);
+        let gamma = input_point * secret;
+        let nonce = Scalar::from_bytes_mod_order_wide(
+            &hash_wide(NONCE_TAG, &[&secret.to_bytes(), &input_point.compress().to_bytes()])
+        );
+        let challenge = challenge(&[public_point, input_point, gamma, EdwardsPoint::mul_base(&nonce), input_point * nonce]);
+        let response = nonce + challenge_scalar(&challenge) * secret;
+        VrfProof {
+            gamma: gamma.compress().to_bytes(),
+            challenge,
+            response: response.to_bytes(),
+        }
+    }
+
+    /// Verifies the proof for `input` under an ed25519 public key
+    ///
+    /// # Returns
+    ///
+    /// * `Some(output)` - the VRF output, if the proof is valid
+    /// * `None` - if the proof, or the public key, is invalid
+    pub fn verify(&self, public_key: &StdByteArray, input: &[u8]) -> Option<StdByteArray> {
+        let public_point = CompressedEdwardsY(*public_key).decompress()?;
+        if public_point.is_small_order() {
+            return None;
+        }
+        let gamma = CompressedEdwardsY(self.gamma).decompress()?;
+        let response = Option::<Scalar>::from(Scalar::from_canonical_bytes(self.response))?;
+        let challenge_value = challenge_scalar(&self.challenge);
+        let input_point = hash_to_curve(public_key, input);
+        // response * B - challenge * public key, and response * H - challenge * gamma
+        let u = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-challenge_value, &public_point, &response);
+        let v = input_point * response - gamma * challenge_value;
+        if challenge(&[public_point, input_point, gamma, u, v]) != self.challenge {
+            return None;
+        }
+        let output = hash_wide(OUTPUT_TAG, &[&gamma.mul_by_cofactor().compress().to_bytes()]);
+        Some(output[..32].try_into().unwrap())
+    }
+}
+
+fn hash_wide(tag: u8, parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha3_512::new();
+    hasher.update(DOMAIN);
+    hasher.update([tag]);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Hashes the key and input to a point of prime order, trying counters until one decodes
+fn hash_to_curve(public_key: &StdByteArray, input: &[u8]) -> EdwardsPoint {
+    (0..=u8::MAX).find_map(|counter| {
+        let hash = hash_wide(HASH_TO_CURVE_TAG, &[public_key, input, &[counter]]);
+        let point = CompressedEdwardsY(hash[..32].try_into().unwrap()).decompress()?.mul_by_cofactor();
+        (!point.is_identity()).then_some(point)
+    }).expect("No counter hashed to the curve")
+}
+
+fn challenge(points: &[EdwardsPoint; 5]) -> [u8; 16] {
+    let compressed = points.map(|point| point.compress().to_bytes());
+    let parts = compressed.iter().map(|bytes| bytes.as_slice()).collect::<Vec<_>>();
+    hash_wide(CHALLENGE_TAG, &parts)[..16].try_into().unwrap()
+}
+
+fn challenge_scalar(challenge: &[u8; 16]) -> Scalar {
+    let mut bytes = [0; 32];
+    bytes[..16].copy_from_slice(challenge);
+    Scalar::from_bytes_mod_order(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signing::{DefaultSigner, SigFunction, SigVerFunction};
+
+    use super::VrfProof;
+
+    #[test]
+    fn test_vrf_proof() {
+        let signer = DefaultSigner::generate_random();
+        let (private_key, public_key) = (signer.to_bytes(), signer.get_verifying_function().to_bytes());
+        let proof = VrfProof::new(&private_key, b"seed");
+        let output = proof.verify(&public_key, b"seed").expect("valid proof");
+        // the output is unique to the key and input
+        assert_eq!(VrfProof::new(&private_key, b"seed"), proof);
+        let other = VrfProof::new(&private_key, b"other seed");
+        assert_ne!(other.verify(&public_key, b"other seed"), Some(output));
+
+        assert_eq!(proof.verify(&public_key, b"other seed"), None);
+        let stranger = DefaultSigner::generate_random().get_verifying_function().to_bytes();
+        assert_eq!(proof.verify(&stranger, b"seed"), None);
+        for i in 0..3 {
+            let mut forged = proof;
+            match i {
+                0 => forged.gamma[0] ^= 1,
+                1 => forged.challenge[0] ^= 1,
+                _ => forged.response[0] ^= 1,
+            }
+            assert_eq!(forged.verify(&public_key, b"seed"), None);
+        }
+    }
+}